@@ -1,11 +1,127 @@
 #![no_std]
 
+pub const IP_VERSION_4: u32 = 4;
+pub const IP_VERSION_6: u32 = 6;
+
+/// Index in the `CONFIG` array holding the minimum interval, in nanoseconds, between two scale
+/// requests emitted for the same destination IP.
+pub const CONFIG_SCALE_REQUEST_INTERVAL_NS: u32 = 0;
+/// Index in the `CONFIG` array enabling (non-zero) inspection of VXLAN and Geneve inner packets.
+pub const CONFIG_DECAP_OVERLAY: u32 = 1;
+/// Index in the `CONFIG` array holding the minimum interval, in nanoseconds, between two events
+/// emitted for passed traffic to the same destination IP.
+pub const CONFIG_PASS_EVENT_INTERVAL_NS: u32 = 2;
+pub const CONFIG_ENTRIES: u32 = 3;
+
+/// Port used in service list keys for services whose ports are unknown; matches any destination
+/// port.
+pub const ANY_PORT: u16 = 0;
+
+/// Version of the `PacketLog` layout. Bump it on any change to `PacketLog` so a userspace binary
+/// refuses to run against an eBPF object built from a different layout.
+pub const PACKET_LOG_VERSION: u32 = 2;
+
+/// Event sent from the XDP program to userspace. Shared by both sides, so the layout must not
+/// contain implicit padding.
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct PacketLog {
     pub ipv4_address: u32,
     pub action: i32,
+    pub ip_version: u32,
+    pub ipv6_address: [u8; 16],
+    pub port: u16,
+    pub src_port: u16,
+    pub src_ipv4_address: u32,
+    pub src_ipv6_address: [u8; 16],
+    /// Sequence number of a TCP segment, needed to answer it with a reset.
+    pub tcp_seq: u32,
+    pub protocol: u8,
+    /// `UNAVAILABLE_*` action userspace must carry out for this packet, if any.
+    pub unavailable_action: u8,
+    /// Non-zero when the source is excluded from waking the service; such packets must not
+    /// count as activity.
+    pub excluded: u8,
+    /// Non-zero when the packet matched a watched node port on a local node address rather than
+    /// a service address; `port` is then the node port.
+    pub node_port: u8,
+}
+
+const _: () = assert!(core::mem::size_of::<PacketLog>() == 60);
+
+/// `SERVICE_LIST` and `POD_LIST` values: bit 0 is set while the backend is available, the bits
+/// from `UNAVAILABLE_ACTION_SHIFT` hold the `UNAVAILABLE_*` action applied to new flows while it
+/// is not.
+pub const SERVICE_AVAILABLE: u32 = 1;
+pub const UNAVAILABLE_ACTION_SHIFT: u32 = 8;
+
+/// Silently drop new flows to an unavailable service.
+pub const UNAVAILABLE_DROP: u8 = 0;
+/// Answer TCP SYNs to an unavailable service with a reset.
+pub const UNAVAILABLE_RESET: u8 = 1;
+/// Answer new flows to an unavailable service with an ICMP port unreachable.
+pub const UNAVAILABLE_ICMP: u8 = 2;
+
+/// Address under which per-destination state of node port traffic is kept, as all traffic to a
+/// node port shares the node's addresses: the unspecified address with the port in its last two
+/// bytes.
+pub const fn node_port_key(port: u16) -> [u8; 16] {
+    let port = port.to_be_bytes();
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, port[0], port[1]]
+}
+
+/// Node port encoded in a key built by `node_port_key`.
+pub fn node_port_from_key(key: &[u8; 16]) -> Option<u16> {
+    let port = u16::from_be_bytes([key[14], key[15]]);
+    (key[..14].iter().all(|b| *b == 0) && port != 0).then_some(port)
+}
+
+/// Key of the `SERVICE_LIST` map: a watched IPv4 ClusterIP and one of its service ports.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ServiceKeyV4 {
+    pub address: u32,
+    pub port: u16,
+    pub _padding: u16,
+}
+
+impl ServiceKeyV4 {
+    pub const fn new(address: u32, port: u16) -> Self {
+        Self { address, port, _padding: 0 }
+    }
+}
+
+/// Key of the `SERVICE_LIST_V6` map: a watched IPv6 ClusterIP and one of its service ports.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ServiceKeyV6 {
+    pub address: [u8; 16],
+    pub port: u16,
+    pub _padding: u16,
 }
 
+impl ServiceKeyV6 {
+    pub const fn new(address: [u8; 16], port: u16) -> Self {
+        Self { address, port, _padding: 0 }
+    }
+}
+
+/// Per-CPU packet counters of a watched destination IP, kept in the `SERVICE_COUNTERS` map.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServiceCounters {
+    pub passed: u64,
+    pub dropped: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PacketLog {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ServiceCounters {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ServiceKeyV4 {}
+
 #[cfg(feature = "user")]
-unsafe impl aya::Pod for PacketLog {}
\ No newline at end of file
+unsafe impl aya::Pod for ServiceKeyV6 {}
@@ -3,23 +3,123 @@
 
 use aya_ebpf::{
     bindings::xdp_action,
+    helpers::bpf_ktime_get_ns,
     macros::{map, xdp},
     programs::XdpContext,
-    maps::{HashMap, PerfEventArray},
+    maps::{lpm_trie::Key, Array, HashMap, LpmTrie, LruHashMap, PerCpuArray, PerCpuHashMap, RingBuf},
 };
 use aya_log_ebpf::info;
 use core::mem;
 use network_types::{
     eth::{EthHdr, EtherType},
-    ip::Ipv4Hdr,
+    ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
+    tcp::TcpHdr,
+    udp::UdpHdr,
 };
-use scale_to_zero_common::PacketLog;
+use scale_to_zero_common::{
+    node_port_key, PacketLog, ServiceCounters, ServiceKeyV4, ServiceKeyV6, ANY_PORT,
+    CONFIG_DECAP_OVERLAY, CONFIG_ENTRIES, CONFIG_PASS_EVENT_INTERVAL_NS,
+    CONFIG_SCALE_REQUEST_INTERVAL_NS, IP_VERSION_4, IP_VERSION_6, PACKET_LOG_VERSION,
+    SERVICE_AVAILABLE, UNAVAILABLE_ACTION_SHIFT, UNAVAILABLE_DROP,
+};
+
+const VXLAN_PORT: u16 = 4789;
+const VXLAN_HDR_LEN: usize = 8;
+const GENEVE_PORT: u16 = 6081;
+const GENEVE_PROTO_ETHERNET: u16 = 0x6558;
+
+#[repr(C)]
+struct GeneveHdr {
+    ver_opt_len: u8,
+    flags: u8,
+    protocol: [u8; 2],
+    vni: [u8; 3],
+    reserved: u8,
+}
+
+impl GeneveHdr {
+    const LEN: usize = mem::size_of::<GeneveHdr>();
+}
+
+#[map]
+static SCALE_REQUESTS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+// Never accessed; its max_entries and value size record the `PacketLog` layout this object
+// emits, for userspace to verify at load time
+#[map]
+static PACKET_LOG_LAYOUT: Array<PacketLog> =
+    Array::<PacketLog>::with_max_entries(PACKET_LOG_VERSION, 0);
+
+// Maps created with `pinned` live under the agent's bpffs pin path and survive restarts.
+#[map]
+static SERVICE_LIST: HashMap<ServiceKeyV4, u32> =
+    HashMap::<ServiceKeyV4, u32>::pinned(1024, 0);
+
+#[map]
+static SERVICE_LIST_V6: HashMap<ServiceKeyV6, u32> =
+    HashMap::<ServiceKeyV6, u32>::pinned(1024, 0);
+
+/// Pod IPs of headless services, matched on any port.
+#[map]
+static POD_LIST: LpmTrie<[u8; 4], u32> =
+    LpmTrie::<[u8; 4], u32>::pinned(1024, 0);
+
+#[map]
+static POD_LIST_V6: LpmTrie<[u8; 16], u32> =
+    LpmTrie::<[u8; 16], u32>::pinned(1024, 0);
+
+/// Node ports of watched services, with the same values as `SERVICE_LIST`.
+#[map]
+static NODEPORT_LIST: HashMap<u16, u32> = HashMap::<u16, u32>::pinned(1024, 0);
+
+/// Addresses of this node, IPv4 addresses stored IPv4-mapped; node ports only match traffic to
+/// these.
+#[map]
+static LOCAL_ADDRESSES: HashMap<[u8; 16], u8> = HashMap::<[u8; 16], u8>::with_max_entries(256, 0);
+
+
+/// Source prefixes that never count as activity, IPv4 prefixes stored IPv4-mapped.
+#[map]
+static SOURCE_EXCLUDE: LpmTrie<[u8; 16], u32> =
+    LpmTrie::<[u8; 16], u32>::with_max_entries(256, 0);
+
+/// Per-service source exclusions, keyed by destination address followed by source prefix.
+#[map]
+static SERVICE_SOURCE_EXCLUDE: LpmTrie<[u8; 32], u32> =
+    LpmTrie::<[u8; 32], u32>::with_max_entries(1024, 0);
+
+#[map]
+static CONFIG: Array<u64> = Array::<u64>::with_max_entries(CONFIG_ENTRIES, 0);
+
+/// Time of the last scale request emitted per destination IP, IPv4 addresses stored IPv4-mapped.
+#[map]
+static LAST_SCALE_REQUEST: LruHashMap<[u8; 16], u64> =
+    LruHashMap::<[u8; 16], u64>::with_max_entries(1024, 0);
+
+/// Time of the last event emitted for passed traffic per destination IP.
+#[map]
+static LAST_PASS_EVENT: LruHashMap<[u8; 16], u64> =
+    LruHashMap::<[u8; 16], u64>::with_max_entries(1024, 0);
+
+/// Time of the last matched packet per destination IP, read by userspace to refresh
+/// `last_packet_time` without an event per packet. LRU, so that once full a new address evicts
+/// the one seen longest ago instead of going unrecorded.
+#[map]
+static LAST_SEEN: LruHashMap<[u8; 16], u64> =
+    LruHashMap::<[u8; 16], u64>::with_max_entries(1024, 0);
+
+/// Number of scale requests suppressed by the in-kernel rate limit.
+#[map]
+static SUPPRESSED_SCALE_REQUESTS: PerCpuArray<u64> = PerCpuArray::<u64>::pinned(1, 0);
 
+/// Number of events dropped because the `SCALE_REQUESTS` ring buffer was full.
 #[map]
-static SCALE_REQUESTS: PerfEventArray<PacketLog> = PerfEventArray::new(0);
+static LOST_EVENTS: PerCpuArray<u64> = PerCpuArray::<u64>::with_max_entries(1, 0);
 
+/// Packets passed and dropped per watched destination IP, IPv4 addresses stored IPv4-mapped.
 #[map]
-static SERVICE_LIST: HashMap<u32, u32> = HashMap::<u32, u32>::with_max_entries(1024, 0);
+static SERVICE_COUNTERS: PerCpuHashMap<[u8; 16], ServiceCounters> =
+    PerCpuHashMap::<[u8; 16], ServiceCounters>::pinned(1024, 0);
 
 #[xdp]
 pub fn scale_to_zero(ctx: XdpContext) -> u32 {
@@ -43,49 +143,324 @@ unsafe fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
     unsafe { Ok(&*ptr) }
 }
 
-fn is_scalable_dst(address: u32) -> Option<u32> {
-    unsafe { SERVICE_LIST.get(&address).cloned() }
+fn is_scalable_dst(address: u32, port: u16) -> Option<u32> {
+    unsafe {
+        SERVICE_LIST
+            .get(&ServiceKeyV4::new(address, port))
+            .or_else(|| SERVICE_LIST.get(&ServiceKeyV4::new(address, ANY_PORT)))
+            .cloned()
+    }
+}
+
+fn is_scalable_dst_v6(address: [u8; 16], port: u16) -> Option<u32> {
+    unsafe {
+        SERVICE_LIST_V6
+            .get(&ServiceKeyV6::new(address, port))
+            .or_else(|| SERVICE_LIST_V6.get(&ServiceKeyV6::new(address, ANY_PORT)))
+            .cloned()
+    }
+}
+
+struct L4Ports {
+    port: u16,
+    src_port: u16,
+    protocol: u8,
+    tcp_seq: u32,
+    /// Whether the packet may start a new flow: a TCP SYN without ACK, or any UDP datagram.
+    opens_flow: bool,
+}
+
+fn is_node_port(address: &[u8; 16], port: u16) -> Option<u32> {
+    unsafe {
+        LOCAL_ADDRESSES.get(address)?;
+        NODEPORT_LIST.get(&port).cloned()
+    }
+}
+
+fn is_watched_pod(address: u32) -> Option<u32> {
+    POD_LIST.get(&Key::new(32, address.to_be_bytes())).cloned()
+}
+
+fn is_watched_pod_v6(address: [u8; 16]) -> Option<u32> {
+    POD_LIST_V6.get(&Key::new(128, address)).cloned()
+}
+
+/// Ports of a TCP or UDP segment starting at `offset`; `None` for other protocols.
+fn l4_ports(ctx: &XdpContext, proto: IpProto, offset: usize) -> Result<Option<L4Ports>, ()> {
+    match proto {
+        IpProto::Tcp => {
+            let tcphdr: *const TcpHdr = unsafe { ptr_at(ctx, offset)? };
+            let (syn, ack) = unsafe { ((*tcphdr).syn(), (*tcphdr).ack()) };
+            Ok(Some(L4Ports {
+                port: u16::from_be(unsafe { (*tcphdr).dest }),
+                src_port: u16::from_be(unsafe { (*tcphdr).source }),
+                protocol: IpProto::Tcp as u8,
+                tcp_seq: u32::from_be(unsafe { (*tcphdr).seq }),
+                opens_flow: syn != 0 && ack == 0,
+            }))
+        }
+        IpProto::Udp => {
+            let udphdr: *const UdpHdr = unsafe { ptr_at(ctx, offset)? };
+            Ok(Some(L4Ports {
+                port: unsafe { (*udphdr).dest() },
+                src_port: unsafe { (*udphdr).source() },
+                protocol: IpProto::Udp as u8,
+                tcp_seq: 0,
+                opens_flow: true,
+            }))
+        }
+        _ => Ok(None),
+    }
 }
 
 fn try_scale_to_zero(ctx: XdpContext) -> Result<u32, ()> {
     let ethhdr: *const EthHdr = unsafe { ptr_at(&ctx, 0)? };
     match unsafe { (*ethhdr).ether_type } {
-        EtherType::Ipv4 => {}
-        _ => return Ok(xdp_action::XDP_PASS),
+        EtherType::Ipv4 => try_ipv4(&ctx),
+        EtherType::Ipv6 => try_ipv6(&ctx),
+        _ => Ok(xdp_action::XDP_PASS),
     }
+}
+
+fn try_ipv4(ctx: &XdpContext) -> Result<u32, ()> {
+    let decap = CONFIG.get(CONFIG_DECAP_OVERLAY).copied().unwrap_or(0) != 0;
+    if decap {
+        if let Some(inner) = overlay_inner_ipv4(ctx, EthHdr::LEN)? {
+            // The verdict on the inner packet applies to the whole outer frame
+            return inspect_ipv4(ctx, inner);
+        }
+    }
+    inspect_ipv4(ctx, EthHdr::LEN)
+}
 
-    let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
+/// Offset of the inner IPv4 header when the IPv4 packet at `offset` is VXLAN or Geneve
+/// encapsulated Ethernet carrying IPv4.
+fn overlay_inner_ipv4(ctx: &XdpContext, offset: usize) -> Result<Option<usize>, ()> {
+    let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(ctx, offset)? };
+    if unsafe { (*ipv4hdr).proto } != IpProto::Udp {
+        return Ok(None);
+    }
+
+    let udp_offset = offset + unsafe { (*ipv4hdr).ihl() } as usize * 4;
+    let udphdr: *const UdpHdr = unsafe { ptr_at(ctx, udp_offset)? };
+    let tunnel_offset = udp_offset + UdpHdr::LEN;
+
+    let inner_eth_offset = match unsafe { (*udphdr).dest() } {
+        VXLAN_PORT => tunnel_offset + VXLAN_HDR_LEN,
+        GENEVE_PORT => {
+            let genevehdr: *const GeneveHdr = unsafe { ptr_at(ctx, tunnel_offset)? };
+            if u16::from_be_bytes(unsafe { (*genevehdr).protocol }) != GENEVE_PROTO_ETHERNET {
+                return Ok(None);
+            }
+            let options_len = (unsafe { (*genevehdr).ver_opt_len } & 0x3f) as usize * 4;
+            tunnel_offset + GeneveHdr::LEN + options_len
+        }
+        _ => return Ok(None),
+    };
+
+    let inner_ethhdr: *const EthHdr = unsafe { ptr_at(ctx, inner_eth_offset)? };
+    match unsafe { (*inner_ethhdr).ether_type } {
+        EtherType::Ipv4 => Ok(Some(inner_eth_offset + EthHdr::LEN)),
+        _ => Ok(None),
+    }
+}
+
+fn inspect_ipv4(ctx: &XdpContext, offset: usize) -> Result<u32, ()> {
+    let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(ctx, offset)? };
     let dst = u32::from_be_bytes(unsafe { (*ipv4hdr).dst_addr });
-    let _src = u32::from_be_bytes(unsafe { (*ipv4hdr).src_addr });
-
-    match is_scalable_dst(dst) {
-        Some(value) => {
-            info!(&ctx, "Detected scalable destination: {:i}", dst);
-            if value == 0 {
-                SCALE_REQUESTS.output(
-                    &ctx,
-                    &PacketLog {
-                        ipv4_address: dst,
-                        action: 1,
-                    },
-                    0,
-                );
-                return Ok(xdp_action::XDP_DROP);
+    let src = u32::from_be_bytes(unsafe { (*ipv4hdr).src_addr });
+
+    let header_len = unsafe { (*ipv4hdr).ihl() } as usize * 4;
+    let l4 = match l4_ports(ctx, unsafe { (*ipv4hdr).proto }, offset + header_len)? {
+        Some(l4) => l4,
+        None => return Ok(xdp_action::XDP_PASS),
+    };
+
+    let (value, node_port) = match is_scalable_dst(dst, l4.port).or_else(|| is_watched_pod(dst)) {
+        Some(value) => (value, false),
+        None => match is_node_port(&ipv4_mapped(dst), l4.port) {
+            Some(value) => (value, true),
+            None => return Ok(xdp_action::XDP_PASS),
+        },
+    };
+
+    info!(ctx, "Detected scalable destination: {:i}:{}", dst, l4.port);
+    let log = PacketLog {
+        ipv4_address: dst,
+        action: 0,
+        ip_version: IP_VERSION_4,
+        ipv6_address: [0; 16],
+        port: l4.port,
+        src_port: l4.src_port,
+        src_ipv4_address: src,
+        src_ipv6_address: [0; 16],
+        tcp_seq: l4.tcp_seq,
+        protocol: l4.protocol,
+        unavailable_action: UNAVAILABLE_DROP,
+        excluded: 0,
+        node_port: node_port as u8,
+    };
+    Ok(handle_scalable_dst(value, log, l4.opens_flow))
+}
+
+fn try_ipv6(ctx: &XdpContext) -> Result<u32, ()> {
+    let ipv6hdr: *const Ipv6Hdr = unsafe { ptr_at(ctx, EthHdr::LEN)? };
+    let dst = unsafe { (*ipv6hdr).dst_addr };
+    let src = unsafe { (*ipv6hdr).src_addr };
+
+    // Extension headers are not walked; such packets are treated as unrelated traffic.
+    let l4 = match l4_ports(ctx, unsafe { (*ipv6hdr).next_hdr }, EthHdr::LEN + Ipv6Hdr::LEN)? {
+        Some(l4) => l4,
+        None => return Ok(xdp_action::XDP_PASS),
+    };
+
+    let (value, node_port) = match is_scalable_dst_v6(dst, l4.port).or_else(|| is_watched_pod_v6(dst)) {
+        Some(value) => (value, false),
+        None => match is_node_port(&dst, l4.port) {
+            Some(value) => (value, true),
+            None => return Ok(xdp_action::XDP_PASS),
+        },
+    };
+
+    info!(ctx, "Detected scalable destination: {:i}:{}", dst, l4.port);
+    let log = PacketLog {
+        ipv4_address: 0,
+        action: 0,
+        ip_version: IP_VERSION_6,
+        ipv6_address: dst,
+        port: l4.port,
+        src_port: l4.src_port,
+        src_ipv4_address: 0,
+        src_ipv6_address: src,
+        tcp_seq: l4.tcp_seq,
+        protocol: l4.protocol,
+        unavailable_action: UNAVAILABLE_DROP,
+        excluded: 0,
+        node_port: node_port as u8,
+    };
+    Ok(handle_scalable_dst(value, log, l4.opens_flow))
+}
+
+fn handle_scalable_dst(value: u32, mut log: PacketLog, opens_flow: bool) -> u32 {
+    let key = dst_key(&log);
+    let now = unsafe { bpf_ktime_get_ns() };
+    let available = value & SERVICE_AVAILABLE != 0;
+    count_packet(&key, !available);
+
+    // Excluded sources (probes, monitoring) are passed or dropped like any other traffic but
+    // never keep a service awake or wake it up
+    let excluded = source_excluded(&key, &src_key(&log));
+    if !excluded {
+        let _ = LAST_SEEN.insert(&key, &now, 0);
+    }
+    log.excluded = excluded as u8;
+
+    if !available {
+        // Retransmits and mid-flow segments are dropped without a scale request; the SYN that
+        // started the connection already asked for one.
+        if opens_flow {
+            let scale_up = !excluded
+                && throttle(&LAST_SCALE_REQUEST, &key, now, CONFIG_SCALE_REQUEST_INTERVAL_NS);
+            if !scale_up && !excluded {
+                if let Some(suppressed) = SUPPRESSED_SCALE_REQUESTS.get_ptr_mut(0) {
+                    unsafe { *suppressed += 1 };
+                }
             }
-            SCALE_REQUESTS.output(
-                &ctx,
-                &PacketLog {
-                    ipv4_address: dst,
-                    action: 0,
-                },
-                0,
-            );
-            return Ok(xdp_action::XDP_PASS);
+
+            // Rejecting is done by userspace, which needs an event for every new flow
+            log.unavailable_action = (value >> UNAVAILABLE_ACTION_SHIFT) as u8;
+            if scale_up || log.unavailable_action != UNAVAILABLE_DROP {
+                log.action = scale_up as i32;
+                emit(&log);
+            }
+        }
+        return xdp_action::XDP_DROP;
+    }
+
+    // Activity is tracked through LAST_SEEN; events for passed traffic only keep dependency
+    // bookkeeping in userspace fresh
+    if !excluded && throttle(&LAST_PASS_EVENT, &key, now, CONFIG_PASS_EVENT_INTERVAL_NS) {
+        emit(&log);
+    }
+    xdp_action::XDP_PASS
+}
+
+/// Sends `log` to userspace, counting it as lost when the ring buffer is full.
+fn emit(log: &PacketLog) {
+    if SCALE_REQUESTS.output(log, 0).is_err() {
+        if let Some(lost) = LOST_EVENTS.get_ptr_mut(0) {
+            unsafe { *lost += 1 };
         }
+    }
+}
+
+/// Destination address of `log` as 16 bytes, IPv4 addresses IPv4-mapped. Node port traffic is
+/// keyed by its node port instead.
+fn dst_key(log: &PacketLog) -> [u8; 16] {
+    if log.node_port != 0 {
+        node_port_key(log.port)
+    } else if log.ip_version == IP_VERSION_6 {
+        log.ipv6_address
+    } else {
+        ipv4_mapped(log.ipv4_address)
+    }
+}
+
+/// Source address of `log` as 16 bytes, IPv4 addresses IPv4-mapped.
+fn src_key(log: &PacketLog) -> [u8; 16] {
+    if log.ip_version == IP_VERSION_6 {
+        log.src_ipv6_address
+    } else {
+        ipv4_mapped(log.src_ipv4_address)
+    }
+}
+
+fn ipv4_mapped(address: u32) -> [u8; 16] {
+    let v4 = address.to_be_bytes();
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, v4[0], v4[1], v4[2], v4[3]]
+}
+
+fn source_excluded(dst: &[u8; 16], src: &[u8; 16]) -> bool {
+    if SOURCE_EXCLUDE.get(&Key::new(128, *src)).is_some() {
+        return true;
+    }
+
+    let mut service_src = [0u8; 32];
+    service_src[..16].copy_from_slice(dst);
+    service_src[16..].copy_from_slice(src);
+    SERVICE_SOURCE_EXCLUDE.get(&Key::new(256, service_src)).is_some()
+}
+
+fn count_packet(key: &[u8; 16], dropped: bool) {
+    match SERVICE_COUNTERS.get_ptr_mut(key) {
+        Some(counters) => unsafe {
+            if dropped {
+                (*counters).dropped += 1;
+            } else {
+                (*counters).passed += 1;
+            }
+        },
         None => {
-            return Ok(xdp_action::XDP_PASS);
+            let counters = ServiceCounters {
+                passed: !dropped as u64,
+                dropped: dropped as u64,
+            };
+            let _ = SERVICE_COUNTERS.insert(key, &counters, 0);
         }
-    };
+    }
+}
+
+/// Returns whether an event for `key` may be emitted now, given the interval configured at
+/// `CONFIG[interval_index]`, and records the emission in `last_emitted` if so.
+fn throttle(last_emitted: &LruHashMap<[u8; 16], u64>, key: &[u8; 16], now: u64, interval_index: u32) -> bool {
+    let interval = CONFIG.get(interval_index).copied().unwrap_or(0);
+    if let Some(last) = unsafe { last_emitted.get(key) } {
+        if now.saturating_sub(*last) < interval {
+            return false;
+        }
+    }
+    let _ = last_emitted.insert(key, &now, 0);
+    true
 }
 
 #[cfg(not(test))]
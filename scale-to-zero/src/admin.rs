@@ -0,0 +1,248 @@
+//! Admin API under `/admin` on the metrics listener, enabled by `--admin-api` or
+//! `ADMIN_API_ENABLED=true`. With `ADMIN_API_TOKEN` set, requests must carry it as `Authorization: Bearer <token>`.
+//!
+//! - `GET /admin/services`: every watched service with its state, idle time and statistics
+//! - `GET /admin/services/{service}`: one of them, with its latest scale actions
+//! - `POST /admin/services/{service}/scale-up`: scales it up as traffic to it would
+//! - `POST /admin/services/{service}/scale-down`: scales it down as if it were idle
+//! - `POST /admin/services/{service}/pause?seconds=N`: pauses its automatic scaling, for
+//!   `ADMIN_PAUSE_SECONDS` (3600 by default) without `seconds`
+//! - `DELETE /admin/services/{service}/pause`: resumes it
+//! - `GET /admin/events?namespace=&service=&since=`: scale actions kept in memory, oldest first,
+//!   since a Unix time or RFC 3339 timestamp
+//!
+//! `{service}` is the key of the service in `WATCHED_SERVICES`, its ClusterIP, or its
+//! `namespace/name`.
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use k8s_openapi::chrono;
+use log::info;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::kubernetes::coordination;
+use crate::kubernetes::history::{self, ScaleRecord};
+use crate::kubernetes::models::{read_watched_services, ServiceData, ServiceStats, SERVICE_STATS};
+use crate::kubernetes::scaler::{self, ForcedScaleDown, ScaleUpOutcome};
+use crate::stats;
+
+/// Source recorded for scale actions requested through the API.
+const SOURCE: &str = "admin API";
+
+/// Scale actions shown with a single service.
+const RECENT_EVENTS: usize = 10;
+
+#[derive(Serialize)]
+struct ServiceView<'a> {
+    key: &'a str,
+    state: &'static str,
+    idle_seconds: i64,
+    #[serde(flatten)]
+    service: &'a ServiceData,
+    stats: ServiceStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recent_events: Option<Vec<ScaleRecord>>,
+}
+
+pub async fn handle(request: Request<Body>) -> Response<Body> {
+    if !crate::config::get().admin_api {
+        return respond(StatusCode::NOT_FOUND, json!({ "error": "the admin API is disabled" }));
+    }
+    let config = crate::config::get();
+    if let Some(token) = config.admin_api_token.as_ref() {
+        let authorized = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|bearer| bearer == token.0);
+        if !authorized {
+            return respond(StatusCode::UNAUTHORIZED, json!({ "error": "missing or wrong bearer token" }));
+        }
+    }
+
+    let path = request.uri().path().trim_end_matches('/');
+    if path == "/admin/events" {
+        return match *request.method() {
+            Method::GET => list_events(&request),
+            _ => method_not_allowed(),
+        };
+    }
+    let Some(rest) = path.strip_prefix("/admin/services") else {
+        return respond(StatusCode::NOT_FOUND, json!({ "error": "not found" }));
+    };
+    if rest.is_empty() {
+        return match *request.method() {
+            Method::GET => respond(StatusCode::OK, list_services()),
+            _ => method_not_allowed(),
+        };
+    }
+
+    let rest = rest.trim_start_matches('/');
+    let (service, action) = match rest.rsplit_once('/') {
+        Some((service, action @ ("scale-up" | "scale-down" | "pause"))) => (service, Some(action)),
+        _ => (rest, None),
+    };
+    let Some(key) = resolve(service) else {
+        return respond(StatusCode::NOT_FOUND, json!({ "error": format!("service {} is not watched", service) }));
+    };
+
+    match (request.method().clone(), action) {
+        (Method::GET, None) => match describe(&key) {
+            Some(view) => respond(StatusCode::OK, view),
+            None => respond(StatusCode::NOT_FOUND, json!({ "error": format!("service {} is not watched", key) })),
+        },
+        (Method::POST, Some("scale-up")) => scale_up(key).await,
+        (Method::POST, Some("scale-down")) => scale_down(&key).await,
+        (Method::POST, Some("pause")) => {
+            let seconds = query_param(&request, "seconds")
+                .and_then(|seconds| seconds.parse::<i64>().ok())
+                .unwrap_or_else(default_pause_seconds);
+            if seconds <= 0 {
+                return respond(StatusCode::BAD_REQUEST, json!({ "error": "seconds must be positive" }));
+            }
+            let until = chrono::Utc::now().timestamp() + seconds;
+            scaler::set_paused(&key, Some(until)).await;
+            respond(StatusCode::OK, json!({ "key": key, "paused_until": until }))
+        }
+        (Method::DELETE, Some("pause")) => {
+            scaler::set_paused(&key, None).await;
+            respond(StatusCode::OK, json!({ "key": key, "paused_until": null }))
+        }
+        _ => method_not_allowed(),
+    }
+}
+
+/// Seconds a pause lasts without `seconds`, from `ADMIN_PAUSE_SECONDS`.
+fn default_pause_seconds() -> i64 {
+    crate::config::get().admin_pause.as_secs() as i64
+}
+
+/// The `WATCHED_SERVICES` key of `service`, given as a key or as `namespace/name`.
+fn resolve(service: &str) -> Option<String> {
+    let watched_services = read_watched_services();
+    if watched_services.contains_key(service) {
+        return Some(service.to_string());
+    }
+    let (namespace, name) = service.split_once('/')?;
+    watched_services
+        .iter()
+        .find(|(_, watched)| watched.namespace == namespace && watched.name == name)
+        .map(|(key, _)| key.clone())
+}
+
+fn list_services() -> serde_json::Value {
+    let now = chrono::Utc::now().timestamp();
+    let watched_services = read_watched_services();
+    let service_stats = SERVICE_STATS.lock().unwrap().clone();
+    let mut views: Vec<ServiceView> = watched_services
+        .iter()
+        .map(|(key, service)| view(key, service, &service_stats, now))
+        .collect();
+    views.sort_by(|a, b| (&a.service.namespace, &a.service.name).cmp(&(&b.service.namespace, &b.service.name)));
+    json!(views)
+}
+
+fn describe(key: &str) -> Option<serde_json::Value> {
+    let now = chrono::Utc::now().timestamp();
+    let watched_services = read_watched_services();
+    let service_stats = SERVICE_STATS.lock().unwrap().clone();
+    watched_services
+        .get_key_value(key)
+        .map(|(key, service)| {
+            let mut view = view(key, service, &service_stats, now);
+            view.recent_events = Some(history::recent(key, RECENT_EVENTS));
+            json!(view)
+        })
+}
+
+fn list_events(request: &Request<Body>) -> Response<Body> {
+    let since = match query_param(request, "since").filter(|since| !since.is_empty()) {
+        None => None,
+        Some(since) => match since.parse::<i64>().ok().or_else(|| {
+            chrono::DateTime::parse_from_rfc3339(since).ok().map(|time| time.timestamp())
+        }) {
+            Some(since) => Some(since),
+            None => {
+                return respond(StatusCode::BAD_REQUEST,
+                    json!({ "error": "since must be a Unix time or an RFC 3339 timestamp" }));
+            }
+        },
+    };
+    let namespace = query_param(request, "namespace").filter(|namespace| !namespace.is_empty());
+    let service = query_param(request, "service").filter(|service| !service.is_empty());
+    respond(StatusCode::OK, json!(history::query(namespace, service, since)))
+}
+
+fn view<'a>(
+    key: &'a str,
+    service: &'a ServiceData,
+    service_stats: &std::collections::HashMap<String, ServiceStats>,
+    now: i64,
+) -> ServiceView<'a> {
+    ServiceView {
+        key,
+        state: stats::service_state(service, now),
+        idle_seconds: now - service.last_packet_time.get(),
+        service,
+        stats: service_stats.get(key).copied().unwrap_or_default(),
+        recent_events: None,
+    }
+}
+
+/// Scales the service up through the same path as traffic, forwarding the request to the
+/// leader when another node scales.
+async fn scale_up(key: String) -> Response<Body> {
+    info!(target: "scale_up", "Scale-up of {} requested through the admin API", key);
+    let (status, outcome) = match scaler::scale_up(key.clone(), SOURCE.to_string()).await {
+        Ok(ScaleUpOutcome::ScaledUp) => (StatusCode::OK, "scaled-up"),
+        Ok(ScaleUpOutcome::RateLimited) => (StatusCode::TOO_MANY_REQUESTS, "rate-limited"),
+        Ok(ScaleUpOutcome::Quarantined) => (StatusCode::CONFLICT, "quarantined"),
+        Ok(ScaleUpOutcome::Paused) => (StatusCode::CONFLICT, "paused"),
+        Ok(ScaleUpOutcome::NotLeader) => match coordination::forward_wake(&key, SOURCE).await {
+            Ok(()) => (StatusCode::ACCEPTED, "forwarded-to-leader"),
+            Err(e) => return error(e),
+        },
+        Err(e) => return error(e),
+    };
+    respond(status, json!({ "key": key, "outcome": outcome }))
+}
+
+async fn scale_down(key: &str) -> Response<Body> {
+    info!(target: "scale_down", "Scale-down of {} requested through the admin API", key);
+    let (status, outcome) = match scaler::force_scale_down(key, SOURCE).await {
+        Ok(ForcedScaleDown::ScaledDown) => (StatusCode::OK, "scaled-down"),
+        Ok(ForcedScaleDown::AlreadyScaledDown) => (StatusCode::OK, "already-scaled-down"),
+        Ok(ForcedScaleDown::NotLeader) => (StatusCode::CONFLICT, "not-leader"),
+        Err(e) => return error(e),
+    };
+    respond(status, json!({ "key": key, "outcome": outcome }))
+}
+
+fn query_param<'a>(request: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn error(e: anyhow::Error) -> Response<Body> {
+    respond(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": format!("{:#}", e) }))
+}
+
+fn method_not_allowed() -> Response<Body> {
+    respond(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "method not allowed" }))
+}
+
+fn respond(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, hyper::header::HeaderValue::from_static("application/json"));
+    response
+}
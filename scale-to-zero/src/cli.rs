@@ -0,0 +1,449 @@
+//! Command line of the agent. Every flag falls back to the env var the agent was configured with
+//! before it had one, so existing deployments keep working unchanged.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::builder::BoolishValueParser;
+use clap::{Parser, ValueEnum};
+use log::{error, info, LevelFilter};
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+
+use crate::config::Config;
+use crate::health::FailurePolicy;
+use crate::kubernetes::{controller, etcd_coordinator};
+use crate::xdp;
+
+/// Scales Kubernetes workloads to zero while their services are idle, and back up on the first
+/// packet to them.
+#[derive(Debug, Clone, Parser)]
+#[command(
+    version,
+    after_help = "Durations are a number, in the unit of the env var, or one with a unit such as 500ms, 30s, 5m or 2h.\n\
+                  Every setting can also be given in the --config file, named as its flag."
+)]
+pub struct Cli {
+    /// YAML file with any of the settings below, named as their flags without the dashes in
+    /// front. Flags and env vars win over it. It is read again when it changes
+    #[arg(long, env = "CONFIG_FILE", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Interfaces to attach to, as comma-separated names or globs such as eth*. Defaults to all
+    /// of them, and failing to attach to one named here is an error
+    #[arg(long, env = "ATTACH_INTERFACES", value_name = "PATTERNS")]
+    pub interfaces: Option<String>,
+
+    /// Interfaces never to attach to, as comma-separated names or globs. Wins over --interfaces
+    #[arg(long, env = "EXCLUDE_INTERFACES", value_name = "PATTERNS")]
+    pub exclude_interfaces: Option<String>,
+
+    /// XDP attach mode: skb, driver, hw, or auto for driver mode where the NIC supports it
+    #[arg(long, env = "XDP_ATTACH_MODE", value_name = "MODE")]
+    pub xdp_mode: Option<String>,
+
+    /// What to do about another XDP program on an interface: refuse or replace. An interface
+    /// taken over by another agent, or 3 times in a row by anything else, is left to it and
+    /// marked down
+    #[arg(long, env = "XDP_CONFLICT_POLICY", value_name = "POLICY")]
+    pub xdp_conflict_policy: Option<String>,
+
+    /// How often service changes are synced to the eBPF maps [default: 100ms]
+    #[arg(long, env = "MAP_SYNC_INTERVAL_MS", value_name = "DURATION", value_parser = parse_millis)]
+    pub map_sync_interval: Option<Duration>,
+
+    /// How often services are checked for scale-down [default: 1s]
+    #[arg(long, env = "SCALE_CHECK_INTERVAL_MS", value_name = "DURATION", value_parser = parse_millis)]
+    pub scale_check_interval: Option<Duration>,
+
+    /// How often new interfaces are looked for to attach to [default: 10s]
+    #[arg(long, env = "INTERFACE_SCAN_INTERVAL_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub interface_scan_interval: Option<Duration>,
+
+    /// How often the program is checked to still be the one attached to each interface
+    /// [default: 30s]
+    #[arg(long, env = "XDP_VERIFY_INTERVAL_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub xdp_verify_interval: Option<Duration>,
+
+    /// Size in bytes of the scale request ring buffer, a power of two and a multiple of the page
+    /// size [default: 262144]
+    #[arg(long, env = "SCALE_REQUESTS_RING_BUFFER_SIZE", value_name = "BYTES")]
+    pub ring_buffer_size: Option<u32>,
+
+    /// Entries of each service map of the eBPF program [default: 1024]
+    #[arg(long, env = "MAX_WATCHED_SERVICES", value_name = "COUNT")]
+    pub max_watched_services: Option<u32>,
+
+    /// Least time between two scale requests of a service from the eBPF program [default: 1s]
+    #[arg(long, env = "SCALE_REQUEST_INTERVAL_MS", value_name = "DURATION", value_parser = parse_millis)]
+    pub scale_request_interval: Option<Duration>,
+
+    /// Least time between two events of traffic to an available service from the eBPF program
+    /// [default: 5s]
+    #[arg(long, env = "PASS_EVENT_INTERVAL_MS", value_name = "DURATION", value_parser = parse_millis)]
+    pub pass_event_interval: Option<Duration>,
+
+    /// Look for the ClusterIP inside VXLAN and Geneve encapsulated traffic, for overlay CNIs
+    #[arg(long, env = "DECAP_OVERLAY", value_name = "BOOL", num_args = 0..=1,
+          default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pub decap_overlay: Option<bool>,
+
+    /// Where the eBPF maps are pinned, so a restarted agent keeps filtering
+    /// [default: /sys/fs/bpf/scale-to-zero]
+    #[arg(long, env = "BPF_PIN_PATH", value_name = "PATH")]
+    pub bpf_pin_path: Option<PathBuf>,
+
+    /// Remove the pinned maps at startup, e.g. after their layout changed
+    #[arg(long, env = "FRESH_START", value_name = "BOOL", num_args = 0..=1,
+          default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pub fresh_start: Option<bool>,
+
+    /// eBPF object loaded in place of the running program on SIGHUP
+    #[arg(long, env = "EBPF_RELOAD_PATH", value_name = "PATH")]
+    pub ebpf_reload_path: Option<PathBuf>,
+
+    /// Clear the service maps on shutdown, letting all traffic through until the next agent is up
+    #[arg(long, env = "CLEAR_SERVICE_LIST_ON_SHUTDOWN", value_name = "BOOL", num_args = 0..=1,
+          default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pub clear_service_list_on_shutdown: Option<bool>,
+
+    /// How long to wait at startup for the services to be listed before scaling [default: 2m]
+    #[arg(long, env = "STARTUP_SYNC_TIMEOUT_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub startup_sync_timeout: Option<Duration>,
+
+    /// Port serving Prometheus metrics, the health probes and the admin API. Nothing is served
+    /// without it
+    #[arg(long, env = "METRICS_PORT", value_name = "PORT")]
+    pub metrics_port: Option<u16>,
+
+    /// Serve the admin API under /admin on the metrics port
+    #[arg(long, env = "ADMIN_API_ENABLED", value_name = "BOOL", num_args = 0..=1,
+          default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pub admin_api: Option<bool>,
+
+    /// Token the admin API requires as `Authorization: Bearer <token>`. Without it the API is open
+    #[arg(long, env = "ADMIN_API_TOKEN", value_name = "TOKEN", hide_env_values = true)]
+    pub admin_api_token: Option<String>,
+
+    /// How long a pause through the admin API lasts when the request does not say [default: 1h]
+    #[arg(long, env = "ADMIN_PAUSE_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub admin_pause: Option<Duration>,
+
+    /// Scale-down time of services without a scale-to-zero/scale-down-time annotation, which are
+    /// skipped without it
+    #[arg(long, env = "DEFAULT_SCALE_DOWN_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub default_scale_down_time: Option<Duration>,
+
+    /// Least scale-down time, which shorter ones are raised to [default: 30s]
+    #[arg(long, env = "MIN_SCALE_DOWN_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub min_scale_down_time: Option<Duration>,
+
+    /// Least time between scale-ups of services without a scale-to-zero/scale-up-cooldown
+    /// annotation [default: 5s]
+    #[arg(long, env = "DEFAULT_SCALE_UP_COOLDOWN_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub default_scale_up_cooldown: Option<Duration>,
+
+    /// Time services without a scale-to-zero/min-uptime annotation stay up after a scale-up
+    /// [default: 0]
+    #[arg(long, env = "DEFAULT_MIN_UPTIME_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub default_min_uptime: Option<Duration>,
+
+    /// What happens to new flows to services scaled to zero without a
+    /// scale-to-zero/unavailable-action annotation: drop, reset or icmp-unreachable [default: drop]
+    #[arg(long, env = "UNAVAILABLE_ACTION", value_name = "ACTION")]
+    pub unavailable_action: Option<String>,
+
+    /// How long scaling a workload by hand pauses its scale-down [default: 1h]
+    #[arg(long, env = "MANUAL_OVERRIDE_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub manual_override: Option<Duration>,
+
+    /// Comma-separated namespaces to watch. Defaults to all of them
+    #[arg(long, env = "WATCH_NAMESPACES", value_name = "NAMESPACES")]
+    pub watch_namespaces: Option<String>,
+
+    /// Label selector the watched services must match
+    #[arg(long, env = "WATCH_LABEL_SELECTOR", value_name = "SELECTOR")]
+    pub watch_label_selector: Option<String>,
+
+    /// Levels of dependencies scaled up along with a service [default: 10]
+    #[arg(long, env = "MAX_DEPENDENCY_DEPTH", value_name = "LEVELS")]
+    pub max_dependency_depth: Option<usize>,
+
+    /// Time a scaled-up service gets to become available [default: 2m]
+    #[arg(long, env = "SCALE_UP_TIMEOUT_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub scale_up_timeout: Option<Duration>,
+
+    /// Failed scale-ups in a row after which traffic no longer scales a service up for a while
+    /// [default: 3]
+    #[arg(long, env = "CIRCUIT_BREAKER_THRESHOLD", value_name = "COUNT")]
+    pub circuit_breaker_threshold: Option<u32>,
+
+    /// How long traffic no longer scales up a service that failed to scale up too often
+    /// [default: 5m]
+    #[arg(long, env = "CIRCUIT_BREAKER_COOLDOWN_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub circuit_breaker_cooldown: Option<Duration>,
+
+    /// Attempts of a failed scale operation before it is given up [default: 5]
+    #[arg(long, env = "SCALE_RETRY_MAX_ATTEMPTS", value_name = "COUNT")]
+    pub scale_retry_max_attempts: Option<u32>,
+
+    /// Scale services down even when their pre-scale-down hook fails
+    #[arg(long, env = "PRE_SCALE_DOWN_HOOK_FAIL_OPEN", value_name = "BOOL", num_args = 0..=1,
+          default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pub pre_scale_down_hook_fail_open: Option<bool>,
+
+    /// Time each call of a pre-scale-down hook gets [default: 10s]
+    #[arg(long, env = "PRE_SCALE_DOWN_HOOK_TIMEOUT_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub pre_scale_down_hook_timeout: Option<Duration>,
+
+    /// Calls of a failing pre-scale-down hook before it is given up [default: 3]
+    #[arg(long, env = "PRE_SCALE_DOWN_HOOK_ATTEMPTS", value_name = "COUNT")]
+    pub pre_scale_down_hook_attempts: Option<u32>,
+
+    /// Endpoint of scale-to-zero/check-active-connections=true, as port/path
+    /// [default: 9090/connections]
+    #[arg(long, env = "ACTIVE_CONNECTIONS_ENDPOINT", value_name = "PORT/PATH")]
+    pub active_connections_endpoint: Option<String>,
+
+    /// Time a pod gets to report its active connections [default: 5s]
+    #[arg(long, env = "ACTIVE_CONNECTIONS_TIMEOUT_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub active_connections_timeout: Option<Duration>,
+
+    /// How HPAs of services without a scale-to-zero/hpa-suspend-strategy annotation are
+    /// suspended: delete or min-replicas [default: delete]
+    #[arg(long, env = "HPA_SUSPEND_STRATEGY", value_name = "STRATEGY")]
+    pub hpa_suspend_strategy: Option<String>,
+
+    /// minReplicas of an HPA suspended with the min-replicas strategy [default: 1]
+    #[arg(long, env = "HPA_SUSPEND_MIN_REPLICAS", value_name = "REPLICAS")]
+    pub hpa_suspend_min_replicas: Option<i32>,
+
+    /// How often HPAs are compared with what the agent expects of them [default: 1m]
+    #[arg(long, env = "HPA_RECONCILE_INTERVAL_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub hpa_reconcile_interval: Option<Duration>,
+
+    /// HPAs repaired at most per reconciliation [default: 5]
+    #[arg(long, env = "HPA_RECONCILE_MAX_REPAIRS", value_name = "COUNT")]
+    pub hpa_reconcile_max_repairs: Option<usize>,
+
+    /// Only log what would be scaled, leaving workloads, HPAs and KEDA ScaledObjects untouched
+    #[arg(long, env = "DRY_RUN", value_name = "BOOL", num_args = 0..=1,
+          default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pub dry_run: Option<bool>,
+
+    /// Where agents on different nodes elect the one that scales: etcd, kube or none
+    /// [default: none, or etcd with USE_ETCD_COORDINATION=true]
+    #[arg(long, env = "COORDINATION_BACKEND", value_name = "BACKEND")]
+    pub coordination_backend: Option<String>,
+
+    /// Comma-separated etcd endpoints of the etcd backend [default: http://etcd:2379]
+    #[arg(long, env = "ETCD_ENDPOINTS", value_name = "URLS")]
+    pub etcd_endpoints: Option<String>,
+
+    /// Namespace of the Lease and ConfigMap of the kube backend [default: the agent's own]
+    #[arg(long, env = "COORDINATION_NAMESPACE", value_name = "NAMESPACE")]
+    pub coordination_namespace: Option<String>,
+
+    /// How often packet times are shared with the other nodes [default: 5s]
+    #[arg(long, env = "COORDINATION_SYNC_INTERVAL_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub coordination_sync_interval: Option<Duration>,
+
+    /// Least time between two wakes of a service forwarded to the leader [default: 1s]
+    #[arg(long, env = "COORDINATION_WAKE_FORWARD_INTERVAL_MS", value_name = "DURATION", value_parser = parse_millis)]
+    pub coordination_wake_forward_interval: Option<Duration>,
+
+    /// Time without a heartbeat after which the leader deletes the state of a node [default: 10m]
+    #[arg(long, env = "COORDINATION_DEAD_NODE_CLEANUP_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub coordination_dead_node_cleanup: Option<Duration>,
+
+    /// Failed syncs in a row after which a node falls back to its local state [default: 3]
+    #[arg(long, env = "COORDINATION_DEGRADED_AFTER_FAILURES", value_name = "COUNT")]
+    pub coordination_degraded_after_failures: Option<u32>,
+
+    /// Write the status of each watched service in its annotations [default: true]
+    #[arg(long, env = "SERVICE_STATUS_ANNOTATIONS", value_name = "BOOL", num_args = 0..=1,
+          default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pub service_status_annotations: Option<bool>,
+
+    /// Least time between two updates of the status annotations [default: 10s]
+    #[arg(long, env = "SERVICE_STATUS_INTERVAL_MS", value_name = "DURATION", value_parser = parse_millis)]
+    pub service_status_interval: Option<Duration>,
+
+    /// Least time between two Events of the same reason on an object [default: 5m]
+    #[arg(long, env = "EVENT_INTERVAL_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub event_interval: Option<Duration>,
+
+    /// Scale actions kept for the admin API [default: 1000]
+    #[arg(long, env = "SCALE_HISTORY_SIZE", value_name = "COUNT")]
+    pub scale_history_size: Option<usize>,
+
+    /// Comma-separated CIDRs whose traffic never wakes a service
+    #[arg(long, env = "EXCLUDE_SOURCE_CIDRS", value_name = "CIDRS")]
+    pub exclude_source_cidrs: Option<String>,
+
+    /// Scale requests the eBPF program may queue before they are dropped [default: 4096]
+    #[arg(long, env = "PACKET_QUEUE_SIZE", value_name = "COUNT")]
+    pub packet_queue_size: Option<usize>,
+
+    /// Scale requests arriving within this time of each other are handled together [default: 5ms]
+    #[arg(long, env = "PACKET_COALESCE_WINDOW_MS", value_name = "DURATION", value_parser = parse_millis)]
+    pub packet_coalesce_window: Option<Duration>,
+
+    /// How often the eBPF maps are fully reconciled with the watched services [default: 30s]
+    #[arg(long, env = "MAP_FULL_SYNC_INTERVAL_MS", value_name = "DURATION", value_parser = parse_millis)]
+    pub map_full_sync_interval: Option<Duration>,
+
+    /// Random delay of up to this much added to periodic loops, so agents on different nodes
+    /// drift apart [default: 0]
+    #[arg(long, env = "LOOP_JITTER_MS", value_name = "DURATION", value_parser = parse_millis)]
+    pub loop_jitter: Option<Duration>,
+
+    /// How often the config file is checked for changes [default: 5s]
+    #[arg(long, env = "CONFIG_RELOAD_INTERVAL_MS", value_name = "DURATION", value_parser = parse_millis)]
+    pub config_reload_interval: Option<Duration>,
+
+    /// Restarts in a row after which a failing background task is given up [default: 5]
+    #[arg(long, env = "TASK_MAX_RESTARTS", value_name = "COUNT")]
+    pub task_max_restarts: Option<u32>,
+
+    /// Time without a tick after which a loop is considered stuck [default: 2m]
+    #[arg(long, env = "HEALTH_STALE_AFTER_SECONDS", value_name = "DURATION", value_parser = parse_seconds)]
+    pub health_stale_after: Option<Duration>,
+
+    /// What to do about a background task that keeps failing [default: restart]
+    #[arg(long, env = "TASK_FAILURE_POLICY", value_enum)]
+    pub task_failure_policy: Option<FailurePolicy>,
+
+    /// Format of the log lines [default: text]
+    #[arg(long, env = "LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Level of every log target: off, error, warn, info, debug or trace. Without it RUST_LOG
+    /// filters them, or the config file sets it [default: error]
+    #[arg(long, value_name = "LEVEL", value_parser = parse_level)]
+    pub log_level: Option<LevelFilter>,
+
+    /// Check the configuration, the interfaces and access to Kubernetes and etcd, then exit
+    /// without loading the eBPF program
+    #[arg(long)]
+    pub validate_config: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Parses a duration, taking a plain number as milliseconds.
+pub fn parse_millis(value: &str) -> Result<Duration, String> {
+    parse_duration(value, Duration::from_millis(1))
+}
+
+/// Parses a duration, taking a plain number as seconds.
+pub fn parse_seconds(value: &str) -> Result<Duration, String> {
+    parse_duration(value, Duration::from_secs(1))
+}
+
+pub fn parse_level(value: &str) -> Result<LevelFilter, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("unknown level '{}', expected off, error, warn, info, debug or trace", value))
+}
+
+fn parse_duration(value: &str, plain_unit: Duration) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u32 = number
+        .parse()
+        .map_err(|_| format!("'{}' is not a duration such as 500ms, 30s, 5m or 2h", value))?;
+    let unit = match unit.trim() {
+        "" => plain_unit,
+        "ms" => Duration::from_millis(1),
+        "s" => Duration::from_secs(1),
+        "m" => Duration::from_secs(60),
+        "h" => Duration::from_secs(3600),
+        other => return Err(format!("unknown unit '{}' in '{}', expected ms, s, m or h", other, value)),
+    };
+    Ok(unit * number)
+}
+
+/// Checks what the agent needs to start with `config`, logging the outcome of each check. Fails
+/// if any does.
+pub async fn validate_config(config: &Config) -> anyhow::Result<()> {
+    let mut failed = Vec::new();
+    let mut report = |check: &'static str, result: anyhow::Result<String>| match result {
+        Ok(outcome) => info!("{}: {}", check, outcome),
+        Err(e) => {
+            error!("{}: {:#}", check, e);
+            failed.push(check);
+        }
+    };
+
+    report("Settings", Ok(format!("{:?}", config)));
+    report("Ring buffer", crate::ring_buf_size(config).map(|size| format!("{} bytes", size)));
+    report("Interfaces", attachable_interfaces(config));
+
+    let version = async {
+        let client = kube::Client::try_default().await.context("no Kubernetes configuration")?;
+        let version = client.apiserver_version().await.context("failed to reach the apiserver")?;
+        anyhow::Ok(format!("reachable, version {}", version.git_version))
+    };
+    let reachable = version.await;
+    let reached = reachable.is_ok();
+    report("Kubernetes API", reachable);
+    if reached {
+        report("Kubernetes permissions", match controller::verify_permissions().await {
+            Ok(true) => Ok("complete".to_string()),
+            Ok(false) => Err(anyhow::anyhow!("some are missing, see above")),
+            Err(e) => Err(e),
+        });
+    }
+
+    if config.coordination_backend == "etcd" {
+        let endpoints = config.etcd_endpoints.clone();
+        let described = endpoints.join(", ");
+        let checked = tokio::time::timeout(Duration::from_secs(15), etcd_coordinator::check_connectivity(endpoints)).await;
+        report("etcd", match checked {
+            Ok(Ok(())) => Ok(format!("reachable at {}", described)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(anyhow::anyhow!("no answer from {} within 15s", described)),
+        });
+    }
+
+    if !failed.is_empty() {
+        anyhow::bail!("invalid configuration: {} failed", failed.join(", "));
+    }
+    info!("Configuration is valid");
+    Ok(())
+}
+
+/// The interfaces the agent would attach to, failing if there are none or if one named in
+/// `ATTACH_INTERFACES` does not exist.
+fn attachable_interfaces(config: &Config) -> anyhow::Result<String> {
+    let interfaces: Vec<String> = NetworkInterface::show()
+        .context("failed to list interfaces")?
+        .into_iter()
+        .map(|itf| itf.name)
+        .collect();
+    let filter = xdp::InterfaceFilter::new(config);
+    let missing = filter.missing(&interfaces);
+    if !missing.is_empty() {
+        anyhow::bail!("ATTACH_INTERFACES names unknown interfaces: {}", missing.join(", "));
+    }
+    let mut attachable: Vec<&str> = interfaces
+        .iter()
+        .filter(|itf| filter.allows(itf))
+        .map(String::as_str)
+        .collect();
+    attachable.sort();
+    attachable.dedup();
+    if attachable.is_empty() {
+        anyhow::bail!("no interface is left to attach to");
+    }
+    Ok(attachable.join(", "))
+}
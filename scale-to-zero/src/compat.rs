@@ -0,0 +1,71 @@
+use std::{mem, path::Path};
+
+use anyhow::{bail, Context, Result};
+use aya::{
+    maps::{Array, MapData, MapInfo},
+    Ebpf,
+};
+use scale_to_zero_common::{
+    PacketLog, ServiceCounters, ServiceKeyV4, ServiceKeyV6, PACKET_LOG_VERSION,
+};
+
+/// Key and value sizes of the maps pinned across restarts, as this binary reads them. LPM trie
+/// keys carry a 4-byte prefix length in front of the address.
+const PINNED_MAPS: &[(&str, usize, usize)] = &[
+    ("SERVICE_LIST", mem::size_of::<ServiceKeyV4>(), mem::size_of::<u32>()),
+    ("SERVICE_LIST_V6", mem::size_of::<ServiceKeyV6>(), mem::size_of::<u32>()),
+    ("POD_LIST", 4 + 4, mem::size_of::<u32>()),
+    ("POD_LIST_V6", 4 + 16, mem::size_of::<u32>()),
+    ("NODEPORT_LIST", mem::size_of::<u16>(), mem::size_of::<u32>()),
+    ("SUPPRESSED_SCALE_REQUESTS", mem::size_of::<u32>(), mem::size_of::<u64>()),
+    ("SERVICE_COUNTERS", 16, mem::size_of::<ServiceCounters>()),
+];
+
+/// Checks maps left pinned by a previous run before they are reused. aya reopens pinned maps by
+/// name without comparing their definition, so a layout change would otherwise go unnoticed.
+pub fn verify_pinned_maps(pin_path: &Path) -> Result<()> {
+    for (name, key_size, value_size) in PINNED_MAPS {
+        let path = pin_path.join(name);
+        if !path.exists() {
+            continue;
+        }
+        let info = MapInfo::from_pin(&path)
+            .with_context(|| format!("failed to inspect pinned map {}", path.display()))?;
+        if info.key_size() as usize != *key_size || info.value_size() as usize != *value_size {
+            bail!(
+                "pinned map {} has key/value size {}/{} but this build expects {}/{}; \
+                 restart with FRESH_START=true to recreate the pinned maps",
+                path.display(),
+                info.key_size(),
+                info.value_size(),
+                key_size,
+                value_size
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the loaded eBPF object emits the `PacketLog` layout this binary decodes.
+pub fn verify_packet_log_layout(ebpf: &Ebpf) -> Result<()> {
+    let map = ebpf.map("PACKET_LOG_LAYOUT").context(
+        "eBPF object does not record its PacketLog layout; it was built from an older \
+         scale-to-zero-common, rebuild the eBPF program",
+    )?;
+    let layout: Array<&MapData, PacketLog> = Array::try_from(map).with_context(|| {
+        format!(
+            "eBPF object emits a PacketLog of a different size than the {} bytes this binary \
+             expects; rebuild the eBPF program",
+            mem::size_of::<PacketLog>()
+        )
+    })?;
+    if layout.len() != PACKET_LOG_VERSION {
+        bail!(
+            "eBPF object emits PacketLog version {} but this binary expects version {}; \
+             rebuild the eBPF program",
+            layout.len(),
+            PACKET_LOG_VERSION
+        );
+    }
+    Ok(())
+}
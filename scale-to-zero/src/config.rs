@@ -0,0 +1,782 @@
+//! Settings of the agent. Each comes from its flag, else its env var, else the YAML file given
+//! with `--config`, else its default. The file may be a mounted ConfigMap: it is read again when
+//! it changes, and the settings that can change at runtime are applied.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{info, warn, LevelFilter};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use tokio::sync::watch;
+
+use crate::cli::{self, Cli, LogFormat};
+use crate::health::FailurePolicy;
+use crate::kubernetes::models::HpaSuspendStrategy;
+use crate::kubernetes::{connections, controller, hpa_controller};
+use crate::xdp::{AttachMode, ConflictPolicy};
+
+static CURRENT: Lazy<RwLock<Arc<Config>>> = Lazy::new(Default::default);
+
+/// The settings in effect.
+pub fn get() -> Arc<Config> {
+    CURRENT.read().unwrap().clone()
+}
+
+pub fn set(config: Config) {
+    *CURRENT.write().unwrap() = Arc::new(config);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Names or glob patterns of the interfaces to attach to, all of them if empty.
+    pub interfaces: Vec<String>,
+    pub exclude_interfaces: Vec<String>,
+    pub xdp_mode: AttachMode,
+    pub xdp_conflict_policy: ConflictPolicy,
+    pub map_sync_interval: Duration,
+    pub scale_check_interval: Duration,
+    pub interface_scan_interval: Duration,
+    pub xdp_verify_interval: Duration,
+    /// Size in bytes of the `SCALE_REQUESTS` ring buffer.
+    pub ring_buffer_size: u32,
+    /// Capacity of the service maps of the eBPF program.
+    pub max_watched_services: u32,
+    pub scale_request_interval: Duration,
+    pub pass_event_interval: Duration,
+    pub decap_overlay: bool,
+    pub bpf_pin_path: PathBuf,
+    /// Whether the pinned maps are removed at startup.
+    pub fresh_start: bool,
+    /// eBPF object to load on SIGHUP, which is ignored without one.
+    pub ebpf_reload_path: Option<PathBuf>,
+    pub clear_service_list_on_shutdown: bool,
+    pub startup_sync_timeout: Duration,
+    pub task_failure_policy: FailurePolicy,
+    pub metrics_port: Option<u16>,
+    pub admin_api: bool,
+    pub admin_api_token: Option<Secret>,
+    pub admin_pause: Duration,
+    /// Scale-down time of services without the annotation, which are skipped without one.
+    pub default_scale_down_time: Option<Duration>,
+    pub min_scale_down_time: Duration,
+    pub default_scale_up_cooldown: Duration,
+    pub default_min_uptime: Duration,
+    /// `drop`, `reset` or `icmp-unreachable`.
+    pub unavailable_action: String,
+    pub manual_override: Duration,
+    /// Namespaces to watch, all of them if empty.
+    pub watch_namespaces: Vec<String>,
+    pub watch_label_selector: Option<String>,
+    pub max_dependency_depth: usize,
+    pub scale_up_timeout: Duration,
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown: Duration,
+    pub scale_retry_max_attempts: u32,
+    pub pre_scale_down_hook_fail_open: bool,
+    pub pre_scale_down_hook_timeout: Duration,
+    pub pre_scale_down_hook_attempts: u32,
+    /// `port/path` of `scale-to-zero/check-active-connections=true`.
+    pub active_connections_endpoint: String,
+    pub active_connections_timeout: Duration,
+    pub hpa_suspend_strategy: HpaSuspendStrategy,
+    pub hpa_suspend_min_replicas: i32,
+    pub hpa_reconcile_interval: Duration,
+    pub hpa_reconcile_max_repairs: usize,
+    pub dry_run: bool,
+    /// `etcd`, `kube` or `none`.
+    pub coordination_backend: String,
+    pub etcd_endpoints: Vec<String>,
+    /// Namespace of the kube backend's objects, the agent's own if unset.
+    pub coordination_namespace: Option<String>,
+    pub coordination_sync_interval: Duration,
+    pub coordination_wake_forward_interval: Duration,
+    pub coordination_dead_node_cleanup: Duration,
+    pub coordination_degraded_after_failures: u32,
+    pub service_status_annotations: bool,
+    pub service_status_interval: Duration,
+    pub event_interval: Duration,
+    pub scale_history_size: usize,
+    /// CIDRs whose traffic never wakes a service.
+    pub exclude_source_cidrs: Vec<String>,
+    pub packet_queue_size: usize,
+    pub packet_coalesce_window: Duration,
+    /// How often the service maps are fully reconciled, reading back every kernel entry to
+    /// repair drift. In between, syncs only write what changed.
+    pub map_full_sync_interval: Duration,
+    pub loop_jitter: Duration,
+    pub config_reload_interval: Duration,
+    pub task_max_restarts: u32,
+    pub health_stale_after: Duration,
+    pub log_format: LogFormat,
+    /// Level of every log target, or `None` where `RUST_LOG` filters them instead.
+    pub log_level: Option<LevelFilter>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            interfaces: Vec::new(),
+            exclude_interfaces: Vec::new(),
+            xdp_mode: AttachMode::Skb,
+            xdp_conflict_policy: ConflictPolicy::Refuse,
+            map_sync_interval: Duration::from_millis(100),
+            scale_check_interval: Duration::from_secs(1),
+            interface_scan_interval: Duration::from_secs(10),
+            xdp_verify_interval: Duration::from_secs(30),
+            ring_buffer_size: 256 * 1024,
+            max_watched_services: 1024,
+            scale_request_interval: Duration::from_secs(1),
+            pass_event_interval: Duration::from_secs(5),
+            decap_overlay: false,
+            bpf_pin_path: PathBuf::from("/sys/fs/bpf/scale-to-zero"),
+            fresh_start: false,
+            ebpf_reload_path: None,
+            clear_service_list_on_shutdown: false,
+            startup_sync_timeout: Duration::from_secs(120),
+            task_failure_policy: FailurePolicy::Restart,
+            metrics_port: None,
+            admin_api: false,
+            admin_api_token: None,
+            admin_pause: Duration::from_secs(3600),
+            default_scale_down_time: None,
+            min_scale_down_time: Duration::from_secs(30),
+            default_scale_up_cooldown: Duration::from_secs(5),
+            default_min_uptime: Duration::ZERO,
+            unavailable_action: "drop".to_string(),
+            manual_override: Duration::from_secs(3600),
+            watch_namespaces: Vec::new(),
+            watch_label_selector: None,
+            max_dependency_depth: 10,
+            scale_up_timeout: Duration::from_secs(120),
+            circuit_breaker_threshold: 3,
+            circuit_breaker_cooldown: Duration::from_secs(300),
+            scale_retry_max_attempts: 5,
+            pre_scale_down_hook_fail_open: false,
+            pre_scale_down_hook_timeout: Duration::from_secs(10),
+            pre_scale_down_hook_attempts: 3,
+            active_connections_endpoint: "9090/connections".to_string(),
+            active_connections_timeout: Duration::from_secs(5),
+            hpa_suspend_strategy: HpaSuspendStrategy::Delete,
+            hpa_suspend_min_replicas: 1,
+            hpa_reconcile_interval: Duration::from_secs(60),
+            hpa_reconcile_max_repairs: 5,
+            dry_run: false,
+            coordination_backend: "none".to_string(),
+            etcd_endpoints: vec!["http://etcd:2379".to_string()],
+            coordination_namespace: None,
+            coordination_sync_interval: Duration::from_secs(5),
+            coordination_wake_forward_interval: Duration::from_secs(1),
+            coordination_dead_node_cleanup: Duration::from_secs(600),
+            coordination_degraded_after_failures: 3,
+            service_status_annotations: true,
+            service_status_interval: Duration::from_secs(10),
+            event_interval: Duration::from_secs(300),
+            scale_history_size: 1000,
+            exclude_source_cidrs: Vec::new(),
+            packet_queue_size: 4096,
+            packet_coalesce_window: Duration::from_millis(5),
+            map_full_sync_interval: Duration::from_secs(30),
+            loop_jitter: Duration::ZERO,
+            config_reload_interval: Duration::from_secs(5),
+            task_max_restarts: 5,
+            health_stale_after: Duration::from_secs(120),
+            log_format: LogFormat::Text,
+            // env_logger only logs errors without RUST_LOG
+            log_level: Some(LevelFilter::Error),
+        }
+    }
+}
+
+/// The `--config` file, with the same settings as the flags under the same names.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct File {
+    interfaces: Option<Vec<String>>,
+    exclude_interfaces: Option<Vec<String>>,
+    xdp_mode: Option<String>,
+    xdp_conflict_policy: Option<String>,
+    map_sync_interval: Option<FileDuration>,
+    scale_check_interval: Option<FileDuration>,
+    interface_scan_interval: Option<FileDuration>,
+    xdp_verify_interval: Option<FileDuration>,
+    ring_buffer_size: Option<u32>,
+    max_watched_services: Option<u32>,
+    scale_request_interval: Option<FileDuration>,
+    pass_event_interval: Option<FileDuration>,
+    decap_overlay: Option<bool>,
+    bpf_pin_path: Option<PathBuf>,
+    fresh_start: Option<bool>,
+    ebpf_reload_path: Option<PathBuf>,
+    clear_service_list_on_shutdown: Option<bool>,
+    startup_sync_timeout: Option<FileDuration>,
+    task_failure_policy: Option<FailurePolicy>,
+    metrics_port: Option<u16>,
+    admin_api: Option<bool>,
+    admin_api_token: Option<Secret>,
+    admin_pause: Option<FileDuration>,
+    default_scale_down_time: Option<FileDuration>,
+    min_scale_down_time: Option<FileDuration>,
+    default_scale_up_cooldown: Option<FileDuration>,
+    default_min_uptime: Option<FileDuration>,
+    unavailable_action: Option<String>,
+    manual_override: Option<FileDuration>,
+    watch_namespaces: Option<Vec<String>>,
+    watch_label_selector: Option<String>,
+    max_dependency_depth: Option<usize>,
+    scale_up_timeout: Option<FileDuration>,
+    circuit_breaker_threshold: Option<u32>,
+    circuit_breaker_cooldown: Option<FileDuration>,
+    scale_retry_max_attempts: Option<u32>,
+    pre_scale_down_hook_fail_open: Option<bool>,
+    pre_scale_down_hook_timeout: Option<FileDuration>,
+    pre_scale_down_hook_attempts: Option<u32>,
+    active_connections_endpoint: Option<String>,
+    active_connections_timeout: Option<FileDuration>,
+    hpa_suspend_strategy: Option<String>,
+    hpa_suspend_min_replicas: Option<i32>,
+    hpa_reconcile_interval: Option<FileDuration>,
+    hpa_reconcile_max_repairs: Option<usize>,
+    dry_run: Option<bool>,
+    coordination_backend: Option<String>,
+    etcd_endpoints: Option<Vec<String>>,
+    coordination_namespace: Option<String>,
+    coordination_sync_interval: Option<FileDuration>,
+    coordination_wake_forward_interval: Option<FileDuration>,
+    coordination_dead_node_cleanup: Option<FileDuration>,
+    coordination_degraded_after_failures: Option<u32>,
+    service_status_annotations: Option<bool>,
+    service_status_interval: Option<FileDuration>,
+    event_interval: Option<FileDuration>,
+    scale_history_size: Option<usize>,
+    exclude_source_cidrs: Option<Vec<String>>,
+    packet_queue_size: Option<usize>,
+    packet_coalesce_window: Option<FileDuration>,
+    map_full_sync_interval: Option<FileDuration>,
+    loop_jitter: Option<FileDuration>,
+    config_reload_interval: Option<FileDuration>,
+    task_max_restarts: Option<u32>,
+    health_stale_after: Option<FileDuration>,
+    log_format: Option<LogFormat>,
+    log_level: Option<String>,
+}
+
+/// A setting kept out of the logs and `--validate-config`, such as a token.
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// A duration in the file, a number in the unit of the setting's env var or a string such as
+/// `30s`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum FileDuration {
+    Number(u64),
+    Text(String),
+}
+
+impl FileDuration {
+    fn parse(&self, name: &str, parse: fn(&str) -> Result<Duration, String>) -> Result<Duration> {
+        let parsed = match self {
+            FileDuration::Number(number) => parse(&number.to_string()),
+            FileDuration::Text(text) => parse(text),
+        };
+        parsed.map_err(|e| anyhow::anyhow!("{}: {}", name, e))
+    }
+}
+
+impl File {
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        // An empty file, e.g. a ConfigMap key without settings yet, sets nothing
+        if contents.trim().is_empty() {
+            return Ok(File::default());
+        }
+        Ok(serde_yaml::from_str(contents)?)
+    }
+}
+
+impl Config {
+    /// Settings from `cli`, which already fell back to the env vars, then `file`, then the
+    /// defaults.
+    pub fn resolve(cli: &Cli, file: &File) -> Result<Self> {
+        let defaults = Config::default();
+        let patterns = |flag: &Option<String>, file: &Option<Vec<String>>| match flag {
+            Some(flag) => split(flag),
+            None => file.clone().unwrap_or_default(),
+        };
+        let duration = |flag: Option<Duration>,
+                        file: &Option<FileDuration>,
+                        name: &str,
+                        parse: fn(&str) -> Result<Duration, String>| match (flag, file) {
+            (Some(flag), _) => Ok(Some(flag)),
+            (None, Some(file)) => file.parse(name, parse).map(Some),
+            (None, None) => Ok(None),
+        };
+        let seconds = |flag: Option<Duration>, file: &Option<FileDuration>, name: &str| {
+            duration(flag, file, name, cli::parse_seconds)
+        };
+        let millis = |flag: Option<Duration>, file: &Option<FileDuration>, name: &str| {
+            duration(flag, file, name, cli::parse_millis)
+        };
+
+        let xdp_mode = match cli.xdp_mode.as_ref().or(file.xdp_mode.as_ref()) {
+            Some(mode) => AttachMode::parse(mode)
+                .ok_or_else(|| anyhow::anyhow!("unknown XDP attach mode '{}', expected skb, driver, hw or auto", mode))?,
+            None => defaults.xdp_mode,
+        };
+        let xdp_conflict_policy = match cli.xdp_conflict_policy.as_ref().or(file.xdp_conflict_policy.as_ref()) {
+            Some(policy) if policy.trim().eq_ignore_ascii_case("chain") => {
+                anyhow::bail!("XDP conflict policy 'chain' is not supported, expected refuse or replace")
+            }
+            Some(policy) => ConflictPolicy::parse(policy).ok_or_else(|| {
+                anyhow::anyhow!("unknown XDP conflict policy '{}', expected refuse or replace", policy)
+            })?,
+            None => defaults.xdp_conflict_policy,
+        };
+        let coordination_backend = match cli.coordination_backend.as_ref().or(file.coordination_backend.as_ref()) {
+            Some(backend) => backend.trim().to_lowercase(),
+            // Before COORDINATION_BACKEND there was only etcd
+            None if env_flag("USE_ETCD_COORDINATION") => "etcd".to_string(),
+            None => defaults.coordination_backend,
+        };
+        if !matches!(coordination_backend.as_str(), "etcd" | "kube" | "none") {
+            anyhow::bail!("unknown coordination backend '{}', expected etcd, kube or none", coordination_backend);
+        }
+        let etcd_endpoints = match (&cli.etcd_endpoints, &file.etcd_endpoints) {
+            (Some(flag), _) => split(flag),
+            (None, Some(file)) => file.clone(),
+            (None, None) => defaults.etcd_endpoints,
+        };
+        let unavailable_action = cli
+            .unavailable_action
+            .clone()
+            .or_else(|| file.unavailable_action.clone())
+            .map(|action| action.trim().to_string())
+            .unwrap_or(defaults.unavailable_action);
+        if !matches!(unavailable_action.as_str(), "drop" | "reset" | "icmp-unreachable") {
+            anyhow::bail!("unknown unavailable action '{}', expected drop, reset or icmp-unreachable", unavailable_action);
+        }
+        let hpa_suspend_strategy = match cli.hpa_suspend_strategy.as_ref().or(file.hpa_suspend_strategy.as_ref()) {
+            Some(strategy) => hpa_controller::parse_strategy(strategy).ok_or_else(|| {
+                anyhow::anyhow!("unknown HPA suspend strategy '{}', expected delete or min-replicas", strategy)
+            })?,
+            None => defaults.hpa_suspend_strategy,
+        };
+        let active_connections_endpoint = cli
+            .active_connections_endpoint
+            .clone()
+            .or_else(|| file.active_connections_endpoint.clone())
+            .map(|endpoint| endpoint.trim().to_string())
+            .unwrap_or(defaults.active_connections_endpoint);
+        if !matches!(connections::parse_endpoint(&active_connections_endpoint), Ok(Some(_))) {
+            anyhow::bail!("active connections endpoint '{}' is not port/path", active_connections_endpoint);
+        }
+        let exclude_source_cidrs = patterns(&cli.exclude_source_cidrs, &file.exclude_source_cidrs);
+        if let Some(cidr) = exclude_source_cidrs.iter().find(|cidr| crate::utils::parse_cidr(cidr).is_none()) {
+            anyhow::bail!("invalid CIDR in exclude-source-cidrs: {}", cidr);
+        }
+        let log_level = match (cli.log_level, &file.log_level) {
+            (Some(level), _) => Some(level),
+            _ if std::env::var_os("RUST_LOG").is_some() => None,
+            (None, Some(level)) => Some(cli::parse_level(level).map_err(|e| anyhow::anyhow!("log-level: {}", e))?),
+            (None, None) => defaults.log_level,
+        };
+
+        Ok(Config {
+            interfaces: patterns(&cli.interfaces, &file.interfaces),
+            exclude_interfaces: patterns(&cli.exclude_interfaces, &file.exclude_interfaces),
+            xdp_mode,
+            xdp_conflict_policy,
+            map_sync_interval: duration(cli.map_sync_interval, &file.map_sync_interval, "map-sync-interval", cli::parse_millis)?
+                .filter(|interval| !interval.is_zero())
+                .unwrap_or(defaults.map_sync_interval),
+            scale_check_interval: duration(cli.scale_check_interval, &file.scale_check_interval, "scale-check-interval", cli::parse_millis)?
+                .filter(|interval| !interval.is_zero())
+                .unwrap_or(defaults.scale_check_interval),
+            interface_scan_interval: duration(
+                cli.interface_scan_interval,
+                &file.interface_scan_interval,
+                "interface-scan-interval",
+                cli::parse_seconds,
+            )?
+            .filter(|interval| !interval.is_zero())
+            .unwrap_or(defaults.interface_scan_interval),
+            xdp_verify_interval: duration(cli.xdp_verify_interval, &file.xdp_verify_interval, "xdp-verify-interval", cli::parse_seconds)?
+                .filter(|interval| !interval.is_zero())
+                .unwrap_or(defaults.xdp_verify_interval),
+            ring_buffer_size: cli.ring_buffer_size.or(file.ring_buffer_size).unwrap_or(defaults.ring_buffer_size),
+            max_watched_services: cli
+                .max_watched_services
+                .or(file.max_watched_services)
+                .filter(|count| *count > 0)
+                .unwrap_or(defaults.max_watched_services),
+            scale_request_interval: duration(
+                cli.scale_request_interval,
+                &file.scale_request_interval,
+                "scale-request-interval",
+                cli::parse_millis,
+            )?
+            .unwrap_or(defaults.scale_request_interval),
+            pass_event_interval: duration(cli.pass_event_interval, &file.pass_event_interval, "pass-event-interval", cli::parse_millis)?
+                .unwrap_or(defaults.pass_event_interval),
+            decap_overlay: cli.decap_overlay.or(file.decap_overlay).unwrap_or(defaults.decap_overlay),
+            bpf_pin_path: cli.bpf_pin_path.clone().or_else(|| file.bpf_pin_path.clone()).unwrap_or(defaults.bpf_pin_path),
+            fresh_start: cli.fresh_start.or(file.fresh_start).unwrap_or(defaults.fresh_start),
+            ebpf_reload_path: cli.ebpf_reload_path.clone().or_else(|| file.ebpf_reload_path.clone()),
+            clear_service_list_on_shutdown: cli
+                .clear_service_list_on_shutdown
+                .or(file.clear_service_list_on_shutdown)
+                .unwrap_or(defaults.clear_service_list_on_shutdown),
+            startup_sync_timeout: duration(
+                cli.startup_sync_timeout,
+                &file.startup_sync_timeout,
+                "startup-sync-timeout",
+                cli::parse_seconds,
+            )?
+            .unwrap_or(defaults.startup_sync_timeout),
+            task_failure_policy: cli.task_failure_policy.or(file.task_failure_policy).unwrap_or(defaults.task_failure_policy),
+            metrics_port: cli.metrics_port.or(file.metrics_port).filter(|port| *port > 0),
+            admin_api: cli.admin_api.or(file.admin_api).unwrap_or(defaults.admin_api),
+            admin_api_token: cli
+                .admin_api_token
+                .clone()
+                .map(Secret)
+                .or_else(|| file.admin_api_token.clone())
+                .filter(|token| !token.0.is_empty()),
+            admin_pause: seconds(cli.admin_pause, &file.admin_pause, "admin-pause")?.unwrap_or(defaults.admin_pause),
+            default_scale_down_time: duration(
+                cli.default_scale_down_time,
+                &file.default_scale_down_time,
+                "default-scale-down-time",
+                cli::parse_seconds,
+            )?,
+            min_scale_down_time: seconds(cli.min_scale_down_time, &file.min_scale_down_time, "min-scale-down-time")?
+                .unwrap_or(defaults.min_scale_down_time),
+            default_scale_up_cooldown: seconds(
+                cli.default_scale_up_cooldown,
+                &file.default_scale_up_cooldown,
+                "default-scale-up-cooldown",
+            )?
+            .unwrap_or(defaults.default_scale_up_cooldown),
+            default_min_uptime: seconds(cli.default_min_uptime, &file.default_min_uptime, "default-min-uptime")?
+                .unwrap_or(defaults.default_min_uptime),
+            unavailable_action,
+            manual_override: seconds(cli.manual_override, &file.manual_override, "manual-override")?
+                .unwrap_or(defaults.manual_override),
+            watch_namespaces: patterns(&cli.watch_namespaces, &file.watch_namespaces),
+            watch_label_selector: cli
+                .watch_label_selector
+                .clone()
+                .or_else(|| file.watch_label_selector.clone())
+                .filter(|selector| !selector.trim().is_empty()),
+            max_dependency_depth: cli
+                .max_dependency_depth
+                .or(file.max_dependency_depth)
+                .unwrap_or(defaults.max_dependency_depth),
+            scale_up_timeout: seconds(cli.scale_up_timeout, &file.scale_up_timeout, "scale-up-timeout")?
+                .unwrap_or(defaults.scale_up_timeout),
+            circuit_breaker_threshold: cli
+                .circuit_breaker_threshold
+                .or(file.circuit_breaker_threshold)
+                .unwrap_or(defaults.circuit_breaker_threshold),
+            circuit_breaker_cooldown: seconds(cli.circuit_breaker_cooldown, &file.circuit_breaker_cooldown, "circuit-breaker-cooldown")?
+                .unwrap_or(defaults.circuit_breaker_cooldown),
+            scale_retry_max_attempts: cli
+                .scale_retry_max_attempts
+                .or(file.scale_retry_max_attempts)
+                .unwrap_or(defaults.scale_retry_max_attempts),
+            pre_scale_down_hook_fail_open: cli
+                .pre_scale_down_hook_fail_open
+                .or(file.pre_scale_down_hook_fail_open)
+                .unwrap_or(defaults.pre_scale_down_hook_fail_open),
+            pre_scale_down_hook_timeout: seconds(
+                cli.pre_scale_down_hook_timeout,
+                &file.pre_scale_down_hook_timeout,
+                "pre-scale-down-hook-timeout",
+            )?
+            .unwrap_or(defaults.pre_scale_down_hook_timeout),
+            pre_scale_down_hook_attempts: cli
+                .pre_scale_down_hook_attempts
+                .or(file.pre_scale_down_hook_attempts)
+                .unwrap_or(defaults.pre_scale_down_hook_attempts)
+                .max(1),
+            active_connections_endpoint,
+            active_connections_timeout: seconds(
+                cli.active_connections_timeout,
+                &file.active_connections_timeout,
+                "active-connections-timeout",
+            )?
+            .unwrap_or(defaults.active_connections_timeout),
+            hpa_suspend_strategy,
+            hpa_suspend_min_replicas: cli
+                .hpa_suspend_min_replicas
+                .or(file.hpa_suspend_min_replicas)
+                .filter(|replicas| *replicas >= 0)
+                .unwrap_or(defaults.hpa_suspend_min_replicas),
+            hpa_reconcile_interval: seconds(cli.hpa_reconcile_interval, &file.hpa_reconcile_interval, "hpa-reconcile-interval")?
+                .filter(|interval| !interval.is_zero())
+                .unwrap_or(defaults.hpa_reconcile_interval),
+            hpa_reconcile_max_repairs: cli
+                .hpa_reconcile_max_repairs
+                .or(file.hpa_reconcile_max_repairs)
+                .unwrap_or(defaults.hpa_reconcile_max_repairs),
+            dry_run: cli.dry_run.or(file.dry_run).unwrap_or(defaults.dry_run),
+            coordination_backend,
+            etcd_endpoints,
+            coordination_namespace: cli
+                .coordination_namespace
+                .clone()
+                .or_else(|| file.coordination_namespace.clone())
+                .filter(|namespace| !namespace.trim().is_empty()),
+            coordination_sync_interval: seconds(
+                cli.coordination_sync_interval,
+                &file.coordination_sync_interval,
+                "coordination-sync-interval",
+            )?
+            .filter(|interval| !interval.is_zero())
+            .unwrap_or(defaults.coordination_sync_interval),
+            coordination_wake_forward_interval: millis(
+                cli.coordination_wake_forward_interval,
+                &file.coordination_wake_forward_interval,
+                "coordination-wake-forward-interval",
+            )?
+            .unwrap_or(defaults.coordination_wake_forward_interval),
+            coordination_dead_node_cleanup: seconds(
+                cli.coordination_dead_node_cleanup,
+                &file.coordination_dead_node_cleanup,
+                "coordination-dead-node-cleanup",
+            )?
+            .unwrap_or(defaults.coordination_dead_node_cleanup),
+            coordination_degraded_after_failures: cli
+                .coordination_degraded_after_failures
+                .or(file.coordination_degraded_after_failures)
+                .unwrap_or(defaults.coordination_degraded_after_failures),
+            service_status_annotations: cli
+                .service_status_annotations
+                .or(file.service_status_annotations)
+                .unwrap_or(defaults.service_status_annotations),
+            service_status_interval: millis(cli.service_status_interval, &file.service_status_interval, "service-status-interval")?
+                .filter(|interval| !interval.is_zero())
+                .unwrap_or(defaults.service_status_interval),
+            event_interval: seconds(cli.event_interval, &file.event_interval, "event-interval")?.unwrap_or(defaults.event_interval),
+            scale_history_size: cli.scale_history_size.or(file.scale_history_size).unwrap_or(defaults.scale_history_size),
+            exclude_source_cidrs,
+            packet_queue_size: cli
+                .packet_queue_size
+                .or(file.packet_queue_size)
+                .filter(|size| *size > 0)
+                .unwrap_or(defaults.packet_queue_size),
+            packet_coalesce_window: millis(cli.packet_coalesce_window, &file.packet_coalesce_window, "packet-coalesce-window")?
+                .filter(|window| !window.is_zero())
+                .unwrap_or(defaults.packet_coalesce_window),
+            map_full_sync_interval: millis(cli.map_full_sync_interval, &file.map_full_sync_interval, "map-full-sync-interval")?
+                .filter(|interval| !interval.is_zero())
+                .unwrap_or(defaults.map_full_sync_interval),
+            loop_jitter: millis(cli.loop_jitter, &file.loop_jitter, "loop-jitter")?.unwrap_or(defaults.loop_jitter),
+            config_reload_interval: millis(cli.config_reload_interval, &file.config_reload_interval, "config-reload-interval")?
+                .filter(|interval| !interval.is_zero())
+                .unwrap_or(defaults.config_reload_interval),
+            task_max_restarts: cli.task_max_restarts.or(file.task_max_restarts).unwrap_or(defaults.task_max_restarts),
+            health_stale_after: seconds(cli.health_stale_after, &file.health_stale_after, "health-stale-after")?
+                .filter(|after| !after.is_zero())
+                .unwrap_or(defaults.health_stale_after),
+            log_format: cli.log_format.or(file.log_format).unwrap_or(defaults.log_format),
+            log_level,
+        })
+    }
+
+    /// Loads the settings at startup, from `cli` and the file it names.
+    pub fn load(cli: &Cli) -> Result<Self> {
+        let file = match &cli.config {
+            Some(path) => File::read(path)?,
+            None => File::default(),
+        };
+        Self::resolve(cli, &file)
+    }
+}
+
+fn split(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(false)
+}
+
+/// Reads the `--config` file again whenever it changes, every `config_reload_interval`, and
+/// applies what can change without a restart. Flags and env vars still win over the file, and a
+/// file that fails to parse leaves the settings as they are.
+pub async fn watch(cli: Cli, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let Some(path) = cli.config.clone() else {
+        return Ok(());
+    };
+    info!("Watching {} for changes every {:?}", path.display(), get().config_reload_interval);
+    let mut last = std::fs::read_to_string(&path).ok();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(get().config_reload_interval) => {}
+            _ = shutdown.changed() => return Ok(()),
+        }
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                // A ConfigMap volume swaps its files, and may be between versions
+                warn!("Failed to read {}, keeping the current settings: {}", path.display(), e);
+                continue;
+            }
+        };
+        if last.as_deref() == Some(contents.as_str()) {
+            continue;
+        }
+        last = Some(contents.clone());
+        info!("{} changed, reloading it", path.display());
+        match File::parse(&contents).and_then(|file| Config::resolve(&cli, &file)) {
+            Ok(loaded) => set(apply(&get(), loaded, &path)),
+            Err(e) => warn!("Invalid config file {}, keeping the current settings: {:#}", path.display(), e),
+        }
+    }
+}
+
+/// The settings in effect once those of `loaded` that can change at runtime are applied.
+fn apply(current: &Config, loaded: Config, path: &Path) -> Config {
+    let mut applied = current.clone();
+    let mut unchanged = true;
+    macro_rules! runtime {
+        ($($field:ident),*) => {$(
+            if current.$field != loaded.$field {
+                info!("{} changed from {:?} to {:?}", setting(stringify!($field)), current.$field, loaded.$field);
+                applied.$field = loaded.$field.clone();
+                unchanged = false;
+            }
+        )*};
+    }
+    macro_rules! restart {
+        ($($field:ident),*) => {$(
+            if current.$field != loaded.$field {
+                warn!("{} changed from {:?} to {:?}, which only takes effect after a restart",
+                      setting(stringify!($field)), current.$field, loaded.$field);
+                unchanged = false;
+            }
+        )*};
+    }
+
+    runtime!(
+        map_sync_interval,
+        scale_check_interval,
+        interface_scan_interval,
+        xdp_verify_interval,
+        task_failure_policy,
+        task_max_restarts,
+        health_stale_after,
+        dry_run,
+        log_level,
+        default_scale_down_time,
+        min_scale_down_time,
+        default_scale_up_cooldown,
+        default_min_uptime,
+        unavailable_action,
+        active_connections_endpoint,
+        hpa_suspend_strategy,
+        manual_override,
+        max_dependency_depth,
+        scale_up_timeout,
+        circuit_breaker_threshold,
+        circuit_breaker_cooldown,
+        scale_retry_max_attempts,
+        pre_scale_down_hook_fail_open,
+        pre_scale_down_hook_timeout,
+        pre_scale_down_hook_attempts,
+        active_connections_timeout,
+        hpa_suspend_min_replicas,
+        hpa_reconcile_interval,
+        hpa_reconcile_max_repairs,
+        admin_api_token,
+        admin_pause,
+        coordination_sync_interval,
+        coordination_wake_forward_interval,
+        coordination_dead_node_cleanup,
+        coordination_degraded_after_failures,
+        service_status_interval,
+        event_interval,
+        scale_history_size,
+        packet_coalesce_window,
+        map_full_sync_interval,
+        loop_jitter,
+        config_reload_interval
+    );
+    restart!(
+        interfaces,
+        exclude_interfaces,
+        xdp_mode,
+        xdp_conflict_policy,
+        ring_buffer_size,
+        max_watched_services,
+        scale_request_interval,
+        pass_event_interval,
+        decap_overlay,
+        bpf_pin_path,
+        fresh_start,
+        ebpf_reload_path,
+        clear_service_list_on_shutdown,
+        startup_sync_timeout,
+        metrics_port,
+        admin_api,
+        watch_namespaces,
+        watch_label_selector,
+        coordination_backend,
+        etcd_endpoints,
+        coordination_namespace,
+        service_status_annotations,
+        exclude_source_cidrs,
+        packet_queue_size,
+        log_format
+    );
+
+    if unchanged {
+        info!("{} changed no setting in effect", path.display());
+    }
+    if let Some(level) = applied.log_level
+        && current.log_level != applied.log_level
+    {
+        log::set_max_level(level);
+    }
+    // Services take the defaults when the watcher processes them, so it lists them all again
+    if current.default_scale_down_time != applied.default_scale_down_time
+        || current.min_scale_down_time != applied.min_scale_down_time
+        || current.default_scale_up_cooldown != applied.default_scale_up_cooldown
+        || current.default_min_uptime != applied.default_min_uptime
+        || current.unavailable_action != applied.unavailable_action
+        || current.active_connections_endpoint != applied.active_connections_endpoint
+        || current.hpa_suspend_strategy != applied.hpa_suspend_strategy
+    {
+        controller::RELIST.notify_one();
+    }
+    applied
+}
+
+/// The name of a setting in the file and as a flag.
+fn setting(field: &str) -> String {
+    field.replace('_', "-")
+}
@@ -0,0 +1,353 @@
+//! End-to-end tests against the cluster of the current kube context, such as a kind cluster
+//! (see the README). They are ignored by default, and share the agent's global state, so run
+//! them one at a time:
+//!
+//! ```shell
+//! cargo test -p scale-to-zero e2e -- --ignored --test-threads=1
+//! ```
+//!
+//! Each test deploys nginx into a namespace of its own and runs the watcher and the scaler on
+//! it. No eBPF program is loaded: packets to a ClusterIP are queued for `process_packets` as the
+//! ring buffer reader would.
+
+use std::future::Future;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::api::autoscaling::v2::HorizontalPodAutoscaler;
+use k8s_openapi::api::core::v1::{ConfigMap, Namespace, Service};
+use kube::api::{Api, DeleteParams, PostParams};
+use kube::Client;
+use scale_to_zero_common::{PacketLog, UNAVAILABLE_DROP};
+use serde_json::json;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::kubernetes::models::{read_watched_services, write_watched_services};
+use crate::kubernetes::{controller, coordination, scaler};
+use crate::utils;
+
+/// Idle seconds after which the test services are scaled down.
+const SCALE_DOWN_TIME: &str = "10";
+/// Longest a deployment may take to be scaled down, or to be ready after a scale-up.
+const TIMEOUT: Duration = Duration::from_secs(180);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Longest a forwarded wake may take until the service is available again.
+const WAKE_LATENCY_BUDGET: Duration = Duration::from_secs(60);
+
+/// The agent's watcher and scaler running against a namespace of the cluster.
+struct Cluster {
+    client: Client,
+    namespace: String,
+    shutdown: watch::Sender<bool>,
+    packets: mpsc::Sender<PacketLog>,
+    tasks: Vec<JoinHandle<Result<()>>>,
+}
+
+impl Cluster {
+    /// Creates `namespace`, replacing what a failed run left behind, and starts the agent on it.
+    async fn start(namespace: &str) -> Result<Self> {
+        let client = Client::try_default().await.context("no cluster to test against")?;
+        let namespaces: Api<Namespace> = Api::all(client.clone());
+        if namespaces.get_opt(namespace).await?.is_some() {
+            namespaces.delete(namespace, &DeleteParams::default()).await?;
+            eventually(&format!("namespace {} to be deleted", namespace), || async {
+                Ok(namespaces.get_opt(namespace).await?.is_none())
+            })
+            .await?;
+        }
+        let manifest = json!({ "apiVersion": "v1", "kind": "Namespace", "metadata": { "name": namespace } });
+        namespaces.create(&PostParams::default(), &serde_json::from_value(manifest)?).await?;
+
+        // The tests run one at a time, and the tasks of the previous one are stopped
+        let config = crate::config::get();
+        crate::config::set(crate::config::Config { watch_namespaces: vec![namespace.to_string()], ..(*config).clone() });
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let (packets, mut queue) = utils::packet_queue();
+        let tasks = vec![
+            tokio::spawn(controller::kube_event_watcher(shutdown_rx.clone())),
+            tokio::spawn(scaler::scale_down(shutdown_rx)),
+            tokio::spawn(async move {
+                utils::process_packets(&mut queue).await;
+                Ok(())
+            }),
+        ];
+        Ok(Cluster { client, namespace: namespace.to_string(), shutdown, packets, tasks })
+    }
+
+    /// Stops the agent and deletes the namespace.
+    async fn stop(self) -> Result<()> {
+        let _ = self.shutdown.send(true);
+        drop(self.packets);
+        for task in self.tasks {
+            task.await??;
+        }
+        write_watched_services().retain(|_, service| service.namespace != self.namespace);
+        let namespaces: Api<Namespace> = Api::all(self.client);
+        namespaces.delete(&self.namespace, &DeleteParams::default()).await?;
+        Ok(())
+    }
+
+    /// Deploys a single nginx replica as `name`, with a Service of the same name carrying
+    /// `annotations`, and returns the key the agent watches the Service under.
+    async fn deploy(&self, name: &str, mut annotations: serde_json::Value) -> Result<String> {
+        let deployment = json!({
+            "apiVersion": "apps/v1",
+            "kind": "Deployment",
+            "metadata": { "name": name },
+            "spec": {
+                "replicas": 1,
+                "selector": { "matchLabels": { "app": name } },
+                "template": {
+                    "metadata": { "labels": { "app": name } },
+                    "spec": {
+                        "containers": [{
+                            "name": "nginx",
+                            "image": "nginx:alpine",
+                            "ports": [{ "containerPort": 80 }],
+                            "resources": { "requests": { "cpu": "50m" } },
+                        }],
+                    },
+                },
+            },
+        });
+        self.deployments().create(&PostParams::default(), &serde_json::from_value(deployment)?).await?;
+        eventually(&format!("deployment {} to be ready", name), || self.ready(name)).await?;
+
+        annotations["scale-to-zero/reference"] = json!(format!("deployment/{}", name));
+        if annotations.get("scale-to-zero/scale-down-time").is_none() {
+            annotations["scale-to-zero/scale-down-time"] = json!(SCALE_DOWN_TIME);
+        }
+        let service = json!({
+            "apiVersion": "v1",
+            "kind": "Service",
+            "metadata": { "name": name, "annotations": annotations },
+            "spec": {
+                "selector": { "app": name },
+                "ports": [{ "protocol": "TCP", "port": 80, "targetPort": 80 }],
+            },
+        });
+        let services: Api<Service> = Api::namespaced(self.client.clone(), &self.namespace);
+        let service = services.create(&PostParams::default(), &serde_json::from_value(service)?).await?;
+        let key = service
+            .spec
+            .and_then(|spec| spec.cluster_ip)
+            .with_context(|| format!("service {} has no ClusterIP", name))?;
+
+        eventually(&format!("service {} to be watched as available", name), || async {
+            Ok(read_watched_services().get(&key).is_some_and(|service| service.backend_available))
+        })
+        .await?;
+        Ok(key)
+    }
+
+    fn deployments(&self) -> Api<Deployment> {
+        Api::namespaced(self.client.clone(), &self.namespace)
+    }
+
+    fn hpas(&self) -> Api<HorizontalPodAutoscaler> {
+        Api::namespaced(self.client.clone(), &self.namespace)
+    }
+
+    /// Desired replicas of deployment `name`.
+    async fn replicas(&self, name: &str) -> Result<i32> {
+        let deployment = self.deployments().get(name).await?;
+        Ok(deployment.spec.and_then(|spec| spec.replicas).unwrap_or(1))
+    }
+
+    /// Whether deployment `name` wants replicas and all of them are ready.
+    async fn ready(&self, name: &str) -> Result<bool> {
+        let deployment = self.deployments().get(name).await?;
+        let desired = deployment.spec.and_then(|spec| spec.replicas).unwrap_or(1);
+        let ready = deployment.status.and_then(|status| status.ready_replicas).unwrap_or(0);
+        Ok(desired > 0 && ready >= desired)
+    }
+
+    /// Waits for the service of deployment `name`, watched under `key`, to be scaled to zero.
+    async fn await_scaled_down(&self, name: &str, key: &str) -> Result<()> {
+        eventually(&format!("{} to be scaled to zero", name), || async {
+            let unavailable = read_watched_services().get(key).is_some_and(|service| !service.backend_available);
+            Ok(unavailable && self.replicas(name).await? == 0)
+        })
+        .await
+    }
+
+    /// Waits for the service of deployment `name`, watched under `key`, to be back up.
+    async fn await_scaled_up(&self, name: &str, key: &str) -> Result<()> {
+        eventually(&format!("{} to be scaled up", name), || async {
+            let available = read_watched_services().get(key).is_some_and(|service| service.backend_available);
+            Ok(available && self.ready(name).await?)
+        })
+        .await
+    }
+
+    /// Queues a TCP SYN to `key` held by the XDP program, as its ring buffer reader would.
+    fn send_packet(&self, key: &str) -> Result<()> {
+        let address: Ipv4Addr = key.parse().with_context(|| format!("{} is not an IPv4 ClusterIP", key))?;
+        let packet_log = PacketLog {
+            ipv4_address: u32::from(address),
+            action: 1,
+            ip_version: 4,
+            ipv6_address: [0; 16],
+            port: 80,
+            src_port: 40_000,
+            src_ipv4_address: u32::from(Ipv4Addr::new(10, 244, 0, 200)),
+            src_ipv6_address: [0; 16],
+            tcp_seq: 0,
+            protocol: 6,
+            unavailable_action: UNAVAILABLE_DROP,
+            excluded: 0,
+            node_port: 0,
+        };
+        utils::queue_packet(&self.packets, packet_log);
+        Ok(())
+    }
+}
+
+/// Polls `check` until it holds, failing after `TIMEOUT`.
+async fn eventually<F, Fut>(what: &str, mut check: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    loop {
+        if check().await? {
+            return Ok(());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!("timed out after {:?} waiting for {}", TIMEOUT, what);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a kind cluster"]
+async fn idle_deployment_is_scaled_to_zero_and_back_up_on_traffic() -> Result<()> {
+    let cluster = Cluster::start("stz-e2e-basic").await?;
+    let key = cluster.deploy("web", json!({})).await?;
+
+    cluster.await_scaled_down("web", &key).await?;
+
+    cluster.send_packet(&key)?;
+    cluster.await_scaled_up("web", &key).await?;
+    let service = read_watched_services()[&key].clone();
+    assert!(service.last_scale_up_time.is_some());
+    assert_eq!(service.wake_sources.last().map(|wake| wake.port), Some(80));
+
+    cluster.stop().await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a kind cluster"]
+async fn dependencies_go_down_after_and_come_up_before_their_dependents() -> Result<()> {
+    let cluster = Cluster::start("stz-e2e-dependencies").await?;
+    // The backend would be idle first, but is kept up as long as the frontend may call it
+    let backend = cluster.deploy("backend", json!({ "scale-to-zero/scale-down-time": "5" })).await?;
+    let frontend = cluster
+        .deploy("frontend", json!({ "scale-to-zero/dependencies": "stz-e2e-dependencies/backend" }))
+        .await?;
+
+    eventually("backend to be scaled to zero", || async {
+        let backend_replicas = cluster.replicas("backend").await?;
+        if backend_replicas == 0 {
+            assert_eq!(cluster.replicas("frontend").await?, 0, "backend was scaled down before its dependent");
+        }
+        Ok(backend_replicas == 0)
+    })
+    .await?;
+    cluster.await_scaled_down("frontend", &frontend).await?;
+    cluster.await_scaled_down("backend", &backend).await?;
+
+    cluster.send_packet(&frontend)?;
+    eventually("frontend to be scaled up", || async {
+        let frontend_replicas = cluster.replicas("frontend").await?;
+        if frontend_replicas > 0 {
+            assert!(cluster.ready("backend").await?, "frontend was scaled up before its dependency was ready");
+        }
+        Ok(frontend_replicas > 0)
+    })
+    .await?;
+    cluster.await_scaled_up("frontend", &frontend).await?;
+    cluster.await_scaled_up("backend", &backend).await?;
+
+    cluster.stop().await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a kind cluster"]
+async fn hpa_is_deleted_on_scale_down_and_recreated_on_scale_up() -> Result<()> {
+    let cluster = Cluster::start("stz-e2e-hpa").await?;
+    let key = cluster
+        .deploy("web", json!({
+            "scale-to-zero/hpa-enabled": "true",
+            "scale-to-zero/min-replicas": "1",
+            "scale-to-zero/max-replicas": "3",
+            "scale-to-zero/target-cpu-utilization": "50",
+        }))
+        .await?;
+    let hpas = cluster.hpas();
+    eventually("the initial HPA to be created", || async { Ok(hpas.get_opt("web-hpa").await?.is_some()) }).await?;
+
+    cluster.await_scaled_down("web", &key).await?;
+    eventually("the HPA to be deleted", || async { Ok(hpas.get_opt("web-hpa").await?.is_none()) }).await?;
+    assert!(read_watched_services()[&key].hpa_deleted);
+
+    cluster.send_packet(&key)?;
+    cluster.await_scaled_up("web", &key).await?;
+    eventually("the HPA to be recreated", || async { Ok(hpas.get_opt("web-hpa").await?.is_some()) }).await?;
+    let spec = hpas.get("web-hpa").await?.spec.context("recreated HPA has no spec")?;
+    assert_eq!(spec.min_replicas, Some(1));
+    assert_eq!(spec.max_replicas, 3);
+    assert_eq!(spec.scale_target_ref.name, "web");
+
+    cluster.stop().await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a kind cluster"]
+async fn forwarded_wake_scales_up_within_the_latency_budget() -> Result<()> {
+    let cluster = Cluster::start("stz-e2e-wake-forwarding").await?;
+    let key = cluster.deploy("web", json!({})).await?;
+    cluster.await_scaled_down("web", &key).await?;
+
+    // Coordinate through a Lease in the test's namespace, which this agent is the only one to
+    // compete for, so it serves the wakes it forwards as a follower would
+    let config = crate::config::get();
+    crate::config::set(crate::config::Config {
+        coordination_backend: "kube".to_string(),
+        coordination_namespace: Some(cluster.namespace.clone()),
+        ..(*config).clone()
+    });
+    // SAFETY: the tests run one at a time, and nothing reads it until `initialize`
+    unsafe { std::env::set_var("NODE_NAME", "stz-e2e-leader") };
+    coordination::initialize().await?;
+    eventually("this node to lead", || async { Ok(coordination::status().is_some_and(|status| status.is_leader)) })
+        .await?;
+    let server = tokio::spawn(coordination::serve_wake_requests(cluster.shutdown.subscribe()));
+
+    let started = tokio::time::Instant::now();
+    coordination::forward_wake(&key, "10.244.0.200:40000").await?;
+    cluster.await_scaled_up("web", &key).await?;
+    let latency = started.elapsed();
+    assert!(latency < WAKE_LATENCY_BUDGET, "forwarded wake took {:?}", latency);
+
+    // The served request is acknowledged by removing its marker from the Lease backend's state
+    let config_maps: Api<ConfigMap> = Api::namespaced(cluster.client.clone(), &cluster.namespace);
+    eventually("the wake request to be acknowledged", || async {
+        let state = config_maps.get_opt("scale-to-zero-coordination").await?;
+        let data = state.and_then(|state| state.data).unwrap_or_default();
+        Ok(!data.keys().any(|key| key.starts_with("wake.")))
+    })
+    .await?;
+
+    coordination::cleanup().await;
+    coordination::reset();
+    crate::config::set((*config).clone());
+    let _ = cluster.shutdown.send(true);
+    server.await??;
+    cluster.stop().await
+}
@@ -0,0 +1,221 @@
+//! Liveness and readiness of the agent, served at `/healthz` and `/readyz` next to the metrics,
+//! and the supervision of its background tasks.
+//!
+//! The agent is live while its loops keep ticking; it is ready once it is also live, none of its
+//! background tasks is down, its program is attached, every service is listed and coordination
+//! is initialized.
+//!
+//! A background task that fails or panics is restarted after a backoff, up to
+//! `TASK_MAX_RESTARTS` times in a row (5 by default). After that, or right away with
+//! `TASK_FAILURE_POLICY=exit`, the agent shuts down and exits with an error for Kubernetes to
+//! restart it.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use k8s_openapi::chrono;
+use log::error;
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+
+use crate::kubernetes::coordination::COORDINATION_INITIALIZED;
+use crate::kubernetes::models::{LAST_SCALE_DOWN_PASS, SERVICES_LISTED};
+
+/// Interfaces the XDP program is attached to, and the ones left to a foreign program that took
+/// them over.
+pub static ATTACHED_INTERFACES: AtomicUsize = AtomicUsize::new(0);
+static DOWN_INTERFACES: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Unix time the map sync loop last ran.
+pub static LAST_MAP_SYNC: AtomicI64 = AtomicI64::new(0);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Background tasks waiting to be restarted, or given up on.
+static DOWN_TASKS: Lazy<Mutex<Vec<&'static str>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Restarts of each background task since startup.
+pub static TASK_RESTARTS: Lazy<Mutex<BTreeMap<&'static str, u64>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// The first background task given up on, which takes the agent down.
+static GAVE_UP_ON: Mutex<Option<&'static str>> = Mutex::new(None);
+static GAVE_UP: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// A task that ran this long before failing is restarted as if it had never failed.
+const RESTART_RESET_AFTER: Duration = Duration::from_secs(600);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+/// What to do about a background task that failed or panicked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Restart it with backoff, up to `TASK_MAX_RESTARTS` times in a row
+    Restart,
+    /// Shut the agent down for Kubernetes to restart it
+    Exit,
+}
+
+/// Seconds without a tick after which a loop is considered stuck. A scale-down pass may wait on
+/// hooks and HPAs, so not too short.
+fn stale_after() -> i64 {
+    crate::config::get().health_stale_after.as_secs() as i64
+}
+
+/// Tasks ending from now on are stopping with the agent rather than failing.
+pub fn shutting_down() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+fn set_down(name: &'static str, down: bool) {
+    let mut down_tasks = DOWN_TASKS.lock().unwrap_or_else(|e| e.into_inner());
+    down_tasks.retain(|task| *task != name);
+    if down {
+        down_tasks.push(name);
+    }
+}
+
+fn give_up(name: &'static str) {
+    GAVE_UP_ON.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert(name);
+    GAVE_UP.notify_one();
+}
+
+/// Waits for a background task to be given up on, and returns its name. The agent should then
+/// shut down and exit with an error.
+pub async fn task_given_up() -> &'static str {
+    loop {
+        if let Some(name) = *GAVE_UP_ON.lock().unwrap_or_else(|e| e.into_inner()) {
+            return name;
+        }
+        GAVE_UP.notified().await;
+    }
+}
+
+/// Runs the background task `start` returns until it ends, restarting it or giving up on it as
+/// `TASK_FAILURE_POLICY` says when it returns an error or panics. Tasks that have nothing to do,
+/// such as coordination without a backend, return `Ok` right away. The task runs in a `JoinSet`
+/// of its own, so it is aborted along with this future.
+pub async fn supervised<F, Fut>(name: &'static str, mut start: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        let mut task = JoinSet::new();
+        task.spawn(start());
+        let reason = match task.join_next().await {
+            None | Some(Ok(Ok(()))) => return,
+            Some(Ok(Err(e))) => format!("{:#}", e),
+            Some(Err(e)) if e.is_panic() => {
+                let panic = e.into_panic();
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown cause".to_string());
+                format!("panicked: {}", message)
+            }
+            Some(Err(_)) => return,
+        };
+        if SHUTTING_DOWN.load(Ordering::SeqCst) {
+            return;
+        }
+        set_down(name, true);
+
+        if started.elapsed() >= RESTART_RESET_AFTER {
+            restarts = 0;
+        }
+        let max_restarts = crate::config::get().task_max_restarts;
+        if crate::config::get().task_failure_policy == FailurePolicy::Exit || restarts >= max_restarts {
+            error!("{} stopped, giving up after {} restarts: {}", name, restarts, reason);
+            give_up(name);
+            return;
+        }
+        restarts += 1;
+        let backoff = Duration::from_secs(1 << (restarts - 1).min(6)).min(MAX_RESTART_BACKOFF);
+        error!("{} stopped, restarting it in {:?} (restart {} of {}): {}", name, backoff, restarts, max_restarts, reason);
+        tokio::time::sleep(backoff).await;
+        *TASK_RESTARTS.lock().unwrap_or_else(|e| e.into_inner()).entry(name).or_default() += 1;
+        set_down(name, false);
+    }
+}
+
+/// Records which of `interfaces` the XDP program is attached to, and which are down.
+pub fn record_interfaces(interfaces: &[crate::xdp::AttachedInterface]) {
+    let down: Vec<String> = interfaces.iter().filter(|itf| itf.down).map(|itf| itf.name.clone()).collect();
+    ATTACHED_INTERFACES.store(interfaces.len() - down.len(), Ordering::Relaxed);
+    *DOWN_INTERFACES.lock().unwrap_or_else(|e| e.into_inner()) = down;
+}
+
+/// Why the agent is not live, empty if it is.
+pub fn liveness() -> Vec<String> {
+    let mut problems = Vec::new();
+    let now = chrono::Utc::now().timestamp();
+    // Loops only count once they first ticked, as startup may take a while
+    for (name, last_tick) in [("map sync", &LAST_MAP_SYNC), ("scale-down loop", &LAST_SCALE_DOWN_PASS)] {
+        let last_tick = last_tick.load(Ordering::Relaxed);
+        if last_tick > 0 && now - last_tick > stale_after() {
+            problems.push(format!("{} last ran {}s ago", name, now - last_tick));
+        }
+    }
+    problems
+}
+
+/// Why the agent is not ready, empty if it is.
+pub fn readiness() -> Vec<String> {
+    let mut problems = liveness();
+    problems.extend(
+        DOWN_TASKS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|name| format!("{} is down", name)),
+    );
+    problems.extend(
+        DOWN_INTERFACES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|name| format!("interface {} is down, taken over by another XDP program", name)),
+    );
+    if !COORDINATION_INITIALIZED.load(Ordering::SeqCst) {
+        problems.push("coordination is not initialized".to_string());
+    }
+    if !SERVICES_LISTED.load(Ordering::SeqCst) {
+        problems.push("services are not listed yet".to_string());
+    }
+    if ATTACHED_INTERFACES.load(Ordering::Relaxed) == 0 {
+        problems.push("the XDP program is not attached to any interface".to_string());
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    #[tokio::test]
+    async fn failed_task_is_restarted_until_it_succeeds() {
+        static RUNS: AtomicU32 = AtomicU32::new(0);
+        supervised("Flaky task", || async {
+            match RUNS.fetch_add(1, Ordering::SeqCst) {
+                0 => anyhow::bail!("failed"),
+                1 => panic!("panicked"),
+                _ => Ok(()),
+            }
+        })
+        .await;
+
+        assert_eq!(RUNS.load(Ordering::SeqCst), 3);
+        assert_eq!(TASK_RESTARTS.lock().unwrap().get("Flaky task"), Some(&2));
+        assert!(!readiness().iter().any(|problem| problem.starts_with("Flaky task")));
+        assert!(GAVE_UP_ON.lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::Result;
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+};
+use kube::api::{Api, PostParams};
+use kube::Client;
+
+/// What the agent does in every watched namespace, as `(group, resource, subresource, verb)`.
+const REQUIRED: &[(&str, &str, &str, &str)] = &[
+    ("", "services", "", "list"),
+    ("", "services", "", "watch"),
+    ("", "services", "", "patch"),
+    ("apps", "deployments", "", "get"),
+    ("apps", "deployments", "", "list"),
+    ("apps", "deployments", "", "watch"),
+    ("apps", "deployments", "", "patch"),
+    ("apps", "deployments", "scale", "get"),
+    ("apps", "deployments", "scale", "patch"),
+    ("apps", "statefulsets", "", "get"),
+    ("apps", "statefulsets", "", "list"),
+    ("apps", "statefulsets", "", "watch"),
+    ("apps", "statefulsets", "", "patch"),
+    ("apps", "statefulsets", "scale", "get"),
+    ("apps", "statefulsets", "scale", "patch"),
+    ("discovery.k8s.io", "endpointslices", "", "list"),
+    ("discovery.k8s.io", "endpointslices", "", "watch"),
+    ("events.k8s.io", "events", "", "create"),
+    ("autoscaling", "horizontalpodautoscalers", "", "get"),
+    ("autoscaling", "horizontalpodautoscalers", "", "create"),
+    ("autoscaling", "horizontalpodautoscalers", "", "patch"),
+    ("autoscaling", "horizontalpodautoscalers", "", "delete"),
+];
+
+/// The permissions from `REQUIRED` the agent's service account lacks in `namespace`, or
+/// cluster-wide for `None`, each described as e.g. `patch apps/deployments/scale`.
+pub async fn denied_permissions(client: &Client, namespace: Option<&str>) -> Result<Vec<String>> {
+    let reviews: Api<SelfSubjectAccessReview> = Api::all(client.clone());
+    let mut denied = Vec::new();
+    for (group, resource, subresource, verb) in REQUIRED {
+        let review = SelfSubjectAccessReview {
+            spec: SelfSubjectAccessReviewSpec {
+                resource_attributes: Some(ResourceAttributes {
+                    group: Some(group.to_string()),
+                    resource: Some(resource.to_string()),
+                    subresource: (!subresource.is_empty()).then(|| subresource.to_string()),
+                    verb: Some(verb.to_string()),
+                    namespace: namespace.map(str::to_string),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let allowed = reviews
+            .create(&PostParams::default(), &review)
+            .await?
+            .status
+            .is_some_and(|status| status.allowed);
+        if !allowed {
+            let resource = [*group, *resource, *subresource]
+                .iter()
+                .filter(|part| !part.is_empty())
+                .copied()
+                .collect::<Vec<_>>()
+                .join("/");
+            denied.push(format!("{} {}", verb, resource));
+        }
+    }
+    Ok(denied)
+}
@@ -0,0 +1,77 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context, Result};
+use log::warn;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::models::ServiceData;
+
+/// Where a service's pods report how many connections they are serving: an HTTP endpoint whose
+/// body is that number.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ConnectionsEndpoint {
+    pub port: u16,
+    pub path: String,
+}
+
+/// Parses a `scale-to-zero/check-active-connections` annotation: `true` for the endpoint in the
+/// `ACTIVE_CONNECTIONS_ENDPOINT` env var (`9090/connections` by default), `false`, or
+/// `port/path`. `Err` for anything else.
+pub fn parse_endpoint(value: &str) -> std::result::Result<Option<ConnectionsEndpoint>, String> {
+    let value = match value.trim() {
+        "false" => return Ok(None),
+        "true" => crate::config::get().active_connections_endpoint.clone(),
+        other => other.to_string(),
+    };
+    let (port, path) = value.split_once('/').unwrap_or((&value, ""));
+    let port = port
+        .parse::<u16>()
+        .ok()
+        .filter(|port| *port != 0)
+        .ok_or_else(|| format!("'{}' is not true, false or port/path", value))?;
+    Ok(Some(ConnectionsEndpoint {
+        port,
+        path: format!("/{}", path),
+    }))
+}
+
+/// Connections reported by the ready pods of `service`. A pod that does not answer within
+/// `ACTIVE_CONNECTIONS_TIMEOUT_SECONDS` (5 by default) counts as having none, so it cannot keep
+/// the service from being scaled down.
+pub async fn active_connections(service: &ServiceData, endpoint: &ConnectionsEndpoint) -> u64 {
+    let timeout = crate::config::get().active_connections_timeout;
+    let mut total = 0;
+    for address in &service.endpoint_addresses {
+        match tokio::time::timeout(timeout, query(address, endpoint)).await {
+            Ok(Ok(connections)) => total += connections,
+            Ok(Err(e)) => warn!(target: "scale_down", "Failed to get active connections of {}/{} from {}: {:#}",
+                                service.namespace, service.name, address, e),
+            Err(_) => warn!(target: "scale_down", "Pod {} of {}/{} did not report its active connections within {:?}",
+                            address, service.namespace, service.name, timeout),
+        }
+    }
+    total
+}
+
+async fn query(address: &str, endpoint: &ConnectionsEndpoint) -> Result<u64> {
+    let ip: IpAddr = address.parse().with_context(|| format!("invalid pod address {}", address))?;
+    let mut stream = TcpStream::connect(SocketAddr::new(ip, endpoint.port)).await?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        endpoint.path,
+        SocketAddr::new(ip, endpoint.port)
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    let (head, body) = response.split_once("\r\n\r\n").context("malformed HTTP response")?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        anyhow::bail!("{} returned '{}'", endpoint.path, status);
+    }
+    body.trim()
+        .parse()
+        .with_context(|| format!("{} returned '{}', expected a number of connections", endpoint.path, body.trim()))
+}
@@ -1,309 +1,1546 @@
-use anyhow::{Context, Ok};
+use anyhow::Ok;
 use futures::{stream, StreamExt, TryStreamExt};
 use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
 use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::api::discovery::v1::EndpointSlice;
 use k8s_openapi::chrono;
 use kube::Resource;
 use kube::{
-    api::Api,
-    runtime::{watcher, WatchStreamExt},
+    api::{Api, DynamicObject, ListParams, PartialObjectMeta, Patch, PatchParams},
+    runtime::{events::EventType, metadata_watcher, watcher, WatchStreamExt},
     Client, ResourceExt,
 };
 use log::{info, warn, error};
+use once_cell::sync::Lazy;
 use std::result::Result as StdResult;
-use std::collections::HashMap;
-use std::thread;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 
-use crate::kubernetes::models::{ServiceData, WorkloadReference, WATCHED_SERVICES};
+use scale_to_zero_common::{UNAVAILABLE_DROP, UNAVAILABLE_ICMP, UNAVAILABLE_RESET};
 
-pub async fn kube_event_watcher() -> anyhow::Result<()> {
-    let mut workload_service: HashMap<WorkloadReference, Service> = HashMap::new();
+use crate::kubernetes::workload::LabelSelector;
+use crate::kubernetes::connections::{self, ConnectionsEndpoint};
+use crate::kubernetes::ops::{KubeClient, KubeOps};
+use crate::kubernetes::schedule::{self, AllowedWindow};
+use crate::kubernetes::{access, dependency_graph, events, hooks, hpa_controller, retry, service_status, workload};
+use crate::kubernetes::models::{
+    read_watched_services, write_watched_services, HpaSuspendStrategy, PacketTime, ServiceData, WorkloadReference,
+    LAST_SCALED, SERVICES_LISTED, SERVICE_POLICIES, SERVICE_REFERENCES, WATCHER_ERRORS,
+};
+use crate::kubernetes::policy::{self, ScaleToZeroPolicy};
 
+/// Watches services and their workloads until shutdown. Errors restart the watch after an
+/// exponential backoff with jitter; the relist that follows prunes services deleted meanwhile.
+pub async fn kube_event_watcher(mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+    let mut backoff = WATCHER_MIN_BACKOFF;
+    loop {
+        let started = Instant::now();
+        let e = match watch_services(shutdown.clone()).await {
+            // Starting over lists everything again
+            StdResult::Ok(true) => continue,
+            StdResult::Ok(false) => return Ok(()),
+            Err(e) => e,
+        };
+        // A watch that ran for a while before failing starts over with a short delay
+        if started.elapsed() >= WATCHER_MAX_BACKOFF {
+            backoff = WATCHER_MIN_BACKOFF;
+        }
+        // Spread restarts of agents on different nodes hitting the same apiserver outage
+        let jitter = Duration::from_millis(
+            u64::from(chrono::Utc::now().timestamp_subsec_millis()) % (backoff.as_millis() as u64 / 2 + 1),
+        );
+        let delay = backoff + jitter;
+        let errors = WATCHER_ERRORS.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(target: "kube_event_watcher", "Kubernetes event watcher failed ({} errors so far), restarting in {:?}: {:#}",
+              errors, delay, e);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.changed() => return Ok(()),
+        }
+        backoff = (backoff * 2).min(WATCHER_MAX_BACKOFF);
+    }
+}
+
+/// Checks that the agent may do everything it needs in each watched namespace, or cluster-wide
+/// without `WATCH_NAMESPACES`, and logs each missing permission. A Role bound in only some
+/// namespaces shows up here rather than as watch errors later on.
+pub async fn verify_permissions() -> anyhow::Result<bool> {
     let client = Client::try_default().await?;
+    let scope = WatchScope::from_config();
+    let mut complete = true;
+    for namespace in scope.namespaces() {
+        let denied = access::denied_permissions(&client, namespace.as_deref()).await?;
+        if !denied.is_empty() {
+            complete = false;
+            error!(target: "kube_event_watcher", "Missing permissions in {}: {}",
+                   namespace.map_or("the cluster".to_string(), |namespace| format!("namespace {}", namespace)),
+                   denied.join(", "));
+        }
+    }
+    Ok(complete)
+}
+
+/// Waits until the first full listing of services has been processed, with their workloads
+/// resolved, so `WATCHED_SERVICES` covers every annotated service. Returns false if that takes
+/// longer than `timeout`.
+pub async fn wait_for_initial_listing(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !SERVICES_LISTED.load(Ordering::SeqCst) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    true
+}
 
-    let services: Api<Service> = Api::all(client.clone());
-    let deployments: Api<Deployment> = Api::all(client.clone());
-    let statefulsets: Api<StatefulSet> = Api::all(client.clone());
+const WATCHER_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const WATCHER_MAX_BACKOFF: Duration = Duration::from_secs(60);
 
-    info!(target: "kube_event_watcher", "watching for services, deployments, and statefulsets");
-    info!(target: "kube_event_watcher", "services: {:?}", services);
+/// Has the watcher list every service again, e.g. so they take a new default scale-down time.
+pub static RELIST: Lazy<tokio::sync::Notify> = Lazy::new(tokio::sync::Notify::new);
 
-    let svc_watcher = watcher(services, watcher::Config::default());
-    let deployment_watcher = watcher(deployments.clone(), watcher::Config::default());
-    let statefulset_watcher = watcher(statefulsets.clone(), watcher::Config::default());
+/// Returns whether it stopped because of `RELIST` rather than shutdown.
+async fn watch_services(mut shutdown: watch::Receiver<bool>) -> anyhow::Result<bool> {
+    let mut workload_service: HashMap<WorkloadReference, Service> = HashMap::new();
+    // Desired replicas of every workload in `workload_service`
+    let mut workload_replicas: HashMap<WorkloadReference, i32> = HashMap::new();
+    // Generation at which the replicas of a metadata-only watched workload were last read
+    let mut workload_generations: HashMap<WorkloadReference, i64> = HashMap::new();
+    // Ready addresses per EndpointSlice, grouped by "namespace/service-name"
+    let mut endpoint_slices: HashMap<String, HashMap<String, Vec<String>>> = HashMap::new();
 
-    let mut combo_stream = stream::select_all(vec![
-        svc_watcher
-            .applied_objects()
-            .map_ok(Watched::Service)
-            .boxed(),
-        deployment_watcher
-            .applied_objects()
-            .map_ok(Watched::Deployment)
-            .boxed(),
-        statefulset_watcher
+    let client = Client::try_default().await?;
+    let ops = KubeClient::new(client.clone());
+
+    let scope = WatchScope::from_config();
+    info!(target: "kube_event_watcher", "watching for services, deployments, and statefulsets in {}", scope);
+
+    let mut streams = Vec::new();
+    // Namespaces whose first service listing is still outstanding
+    let mut unlisted: HashSet<Option<String>> = HashSet::new();
+    for namespace in scope.namespaces() {
+        let mut service_config = watcher::Config::default();
+        if let Some(selector) = scope.label_selector.as_deref() {
+            service_config = service_config.labels(selector);
+        }
+        unlisted.insert(namespace.clone());
+        streams.push(service_events(scope.api(&client, namespace.as_deref()), service_config, namespace.clone()));
+
+        streams.push(workload_stream(
+            metadata_watcher(scope.api::<Deployment>(&client, namespace.as_deref()), watcher::Config::default())
+                .default_backoff(),
+            Watched::Deployment,
+            "deployment".to_string(),
+            namespace.clone(),
+        ));
+        streams.push(workload_stream(
+            metadata_watcher(scope.api::<StatefulSet>(&client, namespace.as_deref()), watcher::Config::default())
+                .default_backoff(),
+            Watched::StatefulSet,
+            "statefulset".to_string(),
+            namespace.clone(),
+        ));
+        streams.push(
+            watcher(
+                scope.api::<EndpointSlice>(&client, namespace.as_deref()),
+                watcher::Config::default().labels("kubernetes.io/service-name"),
+            )
+            .default_backoff()
             .applied_objects()
-            .map_ok(Watched::StatefulSet)
+            .map_ok(Watched::EndpointSlice)
             .boxed(),
-    ]);
-
-    #[allow(clippy::large_enum_variant)]
-    enum Watched {
-        Service(Service),
-        Deployment(Deployment),
-        StatefulSet(StatefulSet),
+        );
+    }
+    let first_namespace = scope.namespaces().into_iter().next().flatten();
+    if policy::crd_installed(&scope.api(&client, first_namespace.as_deref())).await? {
+        info!(target: "kube_event_watcher", "Watching ScaleToZeroPolicies in {}", scope);
+        for namespace in scope.namespaces() {
+            streams.push(policy_events(scope.api(&client, namespace.as_deref()), namespace));
+        }
+    } else {
+        info!(target: "kube_event_watcher",
+              "ScaleToZeroPolicy CRD is not installed or not readable, configuring services from annotations only");
     }
-    while let Some(o) = combo_stream.try_next().await? {
+    let mut combo_stream = stream::select_all(streams);
+    // Workload kinds other than Deployment and StatefulSet, and the namespace watched for them
+    let mut dynamic_watches: HashSet<(String, Option<String>)> = HashSet::new();
+    // Services whose workloads are found by label selector, by service key
+    let mut selector_services: HashMap<String, SelectorService> = HashMap::new();
+    // Services to process again because the workloads matching their selector changed
+    let mut requeued: Vec<Service> = Vec::new();
+    // ScaleToZeroPolicies by the "namespace/name" of the Service they target
+    let mut policies: HashMap<String, ScaleToZeroPolicy> = HashMap::new();
+    // Services whose workloads could not be looked up, by "namespace/name", with failed attempts
+    let mut retries: HashMap<String, (Service, u32)> = HashMap::new();
+
+    loop {
+        let o = if let Some(service) = requeued.pop() {
+            Watched::Service(service)
+        } else {
+            tokio::select! {
+            next = combo_stream.next() => match next {
+                Some(StdResult::Ok(o)) => o,
+                // The failed watcher retries on its own after a backoff and relists
+                Some(Err(e)) => {
+                    let errors = WATCHER_ERRORS.fetch_add(1, Ordering::Relaxed) + 1;
+                    warn!(target: "kube_event_watcher", "Watch error ({} errors so far), reconnecting: {}", errors, e);
+                    continue;
+                }
+                None => break,
+            },
+            _ = shutdown.changed() => {
+                info!(target: "kube_event_watcher", "Shutting down, no longer watching workloads");
+                break;
+            }
+            _ = RELIST.notified() => {
+                info!(target: "kube_event_watcher", "Listing every service again");
+                return Ok(true);
+            }
+            }
+        };
         match o {
-            Watched::Service(s) => {
+            Watched::Service(mut s) => {
+                let name_key = format!("{}/{}", s.namespace().unwrap_or_default(), s.name_any());
+                // This version supersedes one waiting for a retry
+                let attempts = retries.remove(&name_key).map_or(0, |(_, attempts)| attempts);
+                // A policy targeting the service takes precedence over its annotations
+                let policy_name = policies
+                    .get(&format!("{}/{}", s.namespace().unwrap_or_default(), s.name_any()))
+                    .map(|policy| {
+                        policy::overlay(&mut s, policy);
+                        policy.name_any()
+                    });
                 if !s
                     .annotations()
                     .contains_key("scale-to-zero/reference")
+                    && !s
+                        .annotations()
+                        .contains_key("scale-to-zero/reference-selector")
                     && !s
                         .annotations()
                         .contains_key("scale-to-zero/scale-down-time")
                 {
+                    // The annotations may have been removed from a service we were watching
+                    if let StdResult::Ok(key) = service_key(&s) {
+                        unwatch_service(&key, &mut workload_service, &mut workload_replicas, &mut endpoint_slices);
+                        selector_services.remove(&key);
+                    }
                     info!(target: "kube_event_watcher", "Service {} is not annotated, skipping", s.name_any());
                     continue;
                 }
 
-                let workload_ref = s
-                    .annotations()
-                    .get("scale-to-zero/reference")
-                    .unwrap()
-                    .clone();
-                let workload_ref_split: Vec<&str> = workload_ref.split('/').collect();
-
-                // Support both formats:
-                // 1. "deployment/name" (same namespace as service)
-                // 2. "deployment/namespace/name" (cross-namespace)
-                let (workload_type, workload_name, target_namespace) = match workload_ref_split.len() {
-                    2 => {
-                        let workload_type = workload_ref_split[0].to_string();
-                        let workload_name = workload_ref_split[1].to_string();
-                        let target_namespace = s.namespace().unwrap_or_default();
-                        (workload_type, workload_name, target_namespace)
+                let (workloads, selected) = match (
+                    s.annotations().get("scale-to-zero/reference").cloned(),
+                    parse_reference_selector(&s),
+                ) {
+                    // A comma-separated list of workloads that are scaled together
+                    (Some(workload_ref), _) => match workload_ref
+                        .split(',')
+                        .map(|reference| resolve_reference(reference.trim(), &s, &scope))
+                        .collect::<StdResult<Vec<_>, String>>()
+                    {
+                        StdResult::Ok(workloads) => (workloads, None),
+                        Err(problem) => {
+                            warn!(target: "kube_event_watcher", "Service {}: {}", s.name_any(), problem);
+                            publish_invalid_annotation(&client, &s, problem).await;
+                            continue;
+                        }
+                    },
+                    // Whichever workloads currently match the selector
+                    (None, Some(StdResult::Ok(selected))) => {
+                        let names = match workload::select(&client, &selected.kind, &selected.namespace, &selected.selector).await {
+                            StdResult::Ok(names) => names,
+                            Err(e) => {
+                                schedule_retry(s, attempts, e, &mut retries, &mut combo_stream);
+                                continue;
+                            }
+                        };
+                        let workloads: Vec<WorkloadReference> = names
+                            .into_iter()
+                            .map(|name| WorkloadReference {
+                                kind: selected.kind.clone(),
+                                name,
+                                namespace: selected.namespace.clone(),
+                            })
+                            .collect();
+                        if workloads.len() > 1 {
+                            warn!(target: "kube_event_watcher", "Service {}: selector {} matches {} {} workloads ({}), scaling them together",
+                                  s.name_any(), selected.selector, workloads.len(), selected.kind,
+                                  workloads.iter().map(|w| w.name.as_str()).collect::<Vec<_>>().join(", "));
+                        }
+                        (workloads, Some(selected))
                     }
-                    3 => {
-                        let workload_type = workload_ref_split[0].to_string();
-                        let target_namespace = workload_ref_split[1].to_string();
-                        let workload_name = workload_ref_split[2].to_string();
-                        (workload_type, workload_name, target_namespace)
+                    (None, Some(Err(problem))) => {
+                        warn!(target: "kube_event_watcher", "Service {}: {}", s.name_any(), problem);
+                        publish_invalid_annotation(&client, &s, problem).await;
+                        continue;
                     }
-                    _ => {
-                        warn!(
-                            target: "kube_event_watcher",
-                            "Service {} has invalid reference annotation: {} (expected 'type/name' or 'type/namespace/name')",
-                            s.name_any(),
-                            workload_ref
-                        );
+                    (None, None) => {
+                        warn!(target: "kube_event_watcher",
+                              "Service {} has neither a scale-to-zero/reference nor a scale-to-zero/reference-selector annotation, skipping",
+                              s.name_any());
+                        publish_invalid_annotation(
+                            &client,
+                            &s,
+                            "scale-to-zero/reference or scale-to-zero/reference-selector annotation is missing".to_string(),
+                        )
+                        .await;
                         continue;
                     }
                 };
 
-                let scale_down_time = s
-                    .annotations()
-                    .get("scale-to-zero/scale-down-time")
-                    .unwrap()
-                    .parse::<i64>()
-                    .context("Failed to parse scale-down-time")?;
-
-                let service_ip = s
-                    .spec
-                    .as_ref()
-                    .ok_or_else(|| {
-                        anyhow::anyhow!("Failed to get service spec for {}", s.name_any())
-                    })?
-                    .cluster_ip
-                    .as_ref()
-                    .ok_or_else(|| {
-                        anyhow::anyhow!("Failed to get cluster IP for {}", s.name_any())
-                    })?;
-
-                info!(target: "kube_watcher", "service: {}, workload_type: {}, workload_name: {}, scale_down_time: {}, service_ip: {}", s.name_any(), workload_type, workload_name, scale_down_time, service_ip);
-
-                let workload: anyhow::Result<()> = match workload_type.as_str() {
-                    "deployment" => {
-                        let deployment_api = Api::namespaced(client.clone(), &target_namespace);
-                        let deployment: Deployment = deployment_api
-                            .get(&workload_name)
-                            .await
-                            .context(format!("Failed to get deployment {} in namespace {}", workload_name, target_namespace))?;
-
-                        let replicas = deployment
-                            .spec
-                            .as_ref()
-                            .ok_or_else(|| {
-                                anyhow::anyhow!(
-                                    "Failed to get deployment spec for {}",
-                                    deployment.name_any()
-                                )
-                            })?
-                            .replicas
-                            .ok_or_else(|| {
-                                anyhow::anyhow!(
-                                    "Failed to get replicas for {}",
-                                    deployment.name_any()
-                                )
-                            })?;
-
-                        update_workload_status(
-                            "deployment".to_string(),
-                            deployment.name_any(),
-                            deployment.namespace(),
-                            replicas,
-                            &mut workload_service,
-                            s.clone(),
-                            service_ip.to_string(),
-                            scale_down_time,
-                        )
-                        .await?;
+                let (scale_down_time, problem) = parse_scale_down_time(&s);
+                if let Some(problem) = problem {
+                    warn!(target: "kube_event_watcher", "Service {}: {}", s.name_any(), problem);
+                    publish_invalid_annotation(&client, &s, problem).await;
+                }
+                let Some(scale_down_time) = scale_down_time else {
+                    warn!(target: "kube_event_watcher",
+                          "Service {} has no usable scale-down time and DEFAULT_SCALE_DOWN_SECONDS is not set, skipping",
+                          s.name_any());
+                    continue;
+                };
 
-                        Ok(())
+                // A freshly created service may not have its ClusterIP assigned yet; it is
+                // applied again once it does
+                let service_ip = match service_key(&s) {
+                    StdResult::Ok(key) => key,
+                    Err(e) => {
+                        info!(target: "kube_event_watcher", "Skipping service {} for now: {}", s.name_any(), e);
+                        continue;
                     }
-                    "statefulset" => {
-                        let statefulset_api = Api::namespaced(client.clone(), &target_namespace);
-                        let statefulset: StatefulSet = statefulset_api
-                            .get(&workload_name)
-                            .await
-                            .context(format!("Failed to get statefulset {} in namespace {}", workload_name, target_namespace))?;
-
-                        let replicas = statefulset
-                            .spec
-                            .as_ref()
-                            .ok_or_else(|| {
-                                anyhow::anyhow!(
-                                    "Failed to get deployment spec for {}",
-                                    statefulset.name_any()
-                                )
-                            })?
-                            .replicas
-                            .ok_or_else(|| {
-                                anyhow::anyhow!(
-                                    "Failed to get replicas for {}",
-                                    statefulset.name_any()
-                                )
-                            })?;
-
-                        update_workload_status(
-                            "statefulset".to_string(),
-                            statefulset.name_any(),
-                            statefulset.namespace(),
-                            replicas,
-                            &mut workload_service,
-                            s.clone(),
-                            service_ip.to_string(),
-                            scale_down_time,
-                        )
-                        .await?;
+                };
 
-                        Ok(())
+                info!(target: "kube_watcher", "service: {}, workloads: {}, scale_down_time: {}, service_ip: {}",
+                      s.name_any(), workloads.iter().map(|w| w.to_string()).collect::<Vec<_>>().join(", "),
+                      scale_down_time, service_ip);
+
+                if let Some(selected) = &selected {
+                    ensure_workload_watch(&mut dynamic_watches, &mut combo_stream, &client, &scope, &selected.kind, &selected.namespace)?;
+                }
+                let replicas = match fetch_replicas(&ops, &workloads).await {
+                    StdResult::Ok(replicas) => replicas,
+                    Err(e) => {
+                        schedule_retry(s, attempts, e, &mut retries, &mut combo_stream);
+                        continue;
                     }
-                    _ => Err(anyhow::anyhow!("Unknown workload type: {}", workload_type)),
                 };
+                let mut missing = Vec::new();
+                for (reference, replicas) in workloads.iter().zip(replicas) {
+                    match replicas {
+                        Some(replicas) => {
+                            workload_replicas.insert(reference.clone(), replicas);
+                        }
+                        None => {
+                            workload_replicas.remove(reference);
+                            missing.push(reference.clone());
+                        }
+                    }
 
-                if let Err(e) = workload {
-                    warn!(target: "kube_event_watcher", "Failed to get workload: {}", e);
-                    continue;
+                    ensure_workload_watch(&mut dynamic_watches, &mut combo_stream, &client, &scope, &reference.kind, &reference.namespace)?;
+                }
+
+                let workloads_empty = workloads.is_empty();
+                update_workload_status(
+                    &client,
+                    workloads,
+                    &mut workload_service,
+                    &mut workload_replicas,
+                    s.clone(),
+                    service_ip.to_string(),
+                    scale_down_time,
+                )
+                .await?;
+                // Other services' annotations may now resolve to this one
+                let listed = SERVICES_LISTED.load(Ordering::SeqCst);
+                for (key, service) in watched_service_objects(&workload_service, &selector_services) {
+                    if key == service_ip {
+                        publish_resolved_graph(&client, &s, &key, listed).await;
+                    } else if listed {
+                        publish_resolved_graph(&client, &service, &key, false).await;
+                    }
+                }
+                match policy_name {
+                    Some(name) => {
+                        SERVICE_POLICIES
+                            .lock()
+                            .unwrap()
+                            .insert(service_ip.clone(), (s.namespace().unwrap_or_default(), name));
+                    }
+                    None => {
+                        SERVICE_POLICIES.lock().unwrap().remove(&service_ip);
+                    }
+                }
+                for reference in &missing {
+                    warn!(target: "kube_event_watcher", "Service {} references {}, which does not exist",
+                          s.name_any(), reference);
+                    events::publish_for_service(
+                        &client,
+                        &service_ip,
+                        EventType::Warning,
+                        "WorkloadMissing",
+                        format!("{} does not exist; scaling is paused until it is created", reference),
+                    )
+                    .await;
+                }
+                match selected {
+                    Some(selected) => {
+                        if workloads_empty {
+                            warn!(target: "kube_event_watcher", "Service {}: no {} in namespace {} matches selector {}",
+                                  s.name_any(), selected.kind, selected.namespace, selected.selector);
+                            events::publish_for_service(
+                                &client,
+                                &service_ip,
+                                EventType::Warning,
+                                "WorkloadMissing",
+                                format!("no {} matches selector {}; scaling is paused until one does", selected.kind, selected.selector),
+                            )
+                            .await;
+                        }
+                        selector_services.insert(service_ip.clone(), SelectorService { service: s.clone(), ..selected });
+                    }
+                    None => {
+                        selector_services.remove(&service_ip);
+                    }
                 }
+                apply_pod_ips(&service_ip, &endpoint_slices);
+                apply_ready_endpoints(
+                    &format!("{}/{}", s.namespace().unwrap_or_default(), s.name_any()),
+                    &endpoint_slices,
+                    &workload_replicas,
+                );
             }
             Watched::Deployment(d) => {
-                process_resource(d, &workload_service)?;
+                requeue_selector_services(&d, &workload_service, &selector_services, &mut requeued);
+                process_resource(&ops, d, &workload_service, &mut workload_replicas, &mut workload_generations).await?;
             }
             Watched::StatefulSet(sts) => {
-                process_resource(sts, &workload_service)?;
+                requeue_selector_services(&sts, &workload_service, &selector_services, &mut requeued);
+                process_resource(&ops, sts, &workload_service, &mut workload_replicas, &mut workload_generations).await?;
+            }
+            Watched::Workload(workload) => {
+                requeue_selector_services(&workload, &workload_service, &selector_services, &mut requeued);
+                process_resource(&ops, workload, &workload_service, &mut workload_replicas, &mut workload_generations).await?;
+            }
+            Watched::WorkloadDeleted(reference) => {
+                match selecting_service(&reference, &workload_service, &selector_services) {
+                    Some(service) => requeued.push(service),
+                    None => report_missing_workload(&client, &reference, &workload_service, &mut workload_replicas).await,
+                }
+            }
+            Watched::WorkloadsListed { kind, namespace, listed } => {
+                let missing: Vec<WorkloadReference> = workload_replicas
+                    .keys()
+                    .filter(|workload| workload.kind == kind)
+                    .filter(|workload| namespace.as_ref().is_none_or(|namespace| &workload.namespace == namespace))
+                    .filter(|workload| !listed.contains(*workload))
+                    .cloned()
+                    .collect();
+                workload_generations.retain(|workload, _| workload_service.contains_key(workload));
+                for reference in missing {
+                    match selecting_service(&reference, &workload_service, &selector_services) {
+                        Some(service) => requeued.push(service),
+                        None => report_missing_workload(&client, &reference, &workload_service, &mut workload_replicas).await,
+                    }
+                }
+            }
+            Watched::RetryService(name_key) => {
+                // Gone if a newer version of the service was processed meanwhile
+                if let Some((service, _)) = retries.get(&name_key) {
+                    requeued.push(service.clone());
+                }
+            }
+            Watched::ServiceDeleted(s) => {
+                retries.remove(&format!("{}/{}", s.namespace().unwrap_or_default(), s.name_any()));
+                if let StdResult::Ok(key) = service_key(&s) {
+                    unwatch_service(&key, &mut workload_service, &mut workload_replicas, &mut endpoint_slices);
+                    selector_services.remove(&key);
+                }
+            }
+            Watched::ServicesListed { namespace, listed } => {
+                let watched: Vec<String> = match &namespace {
+                    None => read_watched_services().keys().cloned().collect(),
+                    // Looked up by the Service's own namespace, which outlives a watcher restart
+                    Some(namespace) => SERVICE_REFERENCES
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|(_, reference)| reference.namespace.as_ref() == Some(namespace))
+                        .map(|(key, _)| key.clone())
+                        .collect(),
+                };
+                for key in watched.iter().filter(|key| !listed.contains(*key)) {
+                    unwatch_service(key, &mut workload_service, &mut workload_replicas, &mut endpoint_slices);
+                    selector_services.remove(key);
+                }
+                unlisted.remove(&namespace);
+                if unlisted.is_empty() && !SERVICES_LISTED.swap(true, Ordering::SeqCst) {
+                    info!(target: "kube_event_watcher", "Initial service listing complete");
+                    // Dependencies on services listed later could not be checked until now
+                    for (key, service) in watched_service_objects(&workload_service, &selector_services) {
+                        publish_resolved_graph(&client, &service, &key, true).await;
+                    }
+                }
+            }
+            Watched::EndpointSlice(slice) => {
+                if let Some(key) = record_endpoint_slice(&slice, &mut endpoint_slices) {
+                    apply_pod_ips(&key, &endpoint_slices);
+                    apply_ready_endpoints(&key, &endpoint_slices, &workload_replicas);
+                }
+            }
+            Watched::Policy(p) => {
+                let target = policy::target_key(&p);
+                // A policy pointed at another service releases the one it configured before
+                let retargeted: Vec<String> = policies
+                    .iter()
+                    .filter(|(key, existing)| **key != target && same_policy(existing, &p))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in retargeted {
+                    policies.remove(&key);
+                    requeue_policy_target(&client, &scope, &key, &mut requeued).await?;
+                }
+                if let Some(existing) = policies.get(&target).filter(|existing| !same_policy(existing, &p)) {
+                    warn!(target: "kube_event_watcher", "ScaleToZeroPolicies {} and {} both target service {}, using {}",
+                          existing.name_any(), p.name_any(), target, p.name_any());
+                }
+                policies.insert(target.clone(), p.clone());
+                if !requeue_policy_target(&client, &scope, &target, &mut requeued).await? {
+                    warn!(target: "kube_event_watcher", "ScaleToZeroPolicy {} targets service {}, which does not exist",
+                          p.name_any(), target);
+                    events::publish(
+                        &client,
+                        p.object_ref(&()),
+                        EventType::Warning,
+                        "ServiceMissing",
+                        format!("service {} does not exist", p.spec.target_service),
+                    )
+                    .await;
+                }
+            }
+            Watched::PolicyDeleted(p) => {
+                let target = policy::target_key(&p);
+                if policies.get(&target).is_some_and(|existing| same_policy(existing, &p)) {
+                    policies.remove(&target);
+                    requeue_policy_target(&client, &scope, &target, &mut requeued).await?;
+                }
+            }
+            Watched::PoliciesListed { namespace, listed } => {
+                // Policies deleted while the watch was down are missing from the listing
+                let stale: Vec<String> = policies
+                    .iter()
+                    .filter(|(_, existing)| namespace.is_none() || existing.namespace() == namespace)
+                    .filter(|(_, existing)| {
+                        !listed.contains(&format!("{}/{}", existing.namespace().unwrap_or_default(), existing.name_any()))
+                    })
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in stale {
+                    policies.remove(&key);
+                    requeue_policy_target(&client, &scope, &key, &mut requeued).await?;
+                }
             }
         }
     }
-    Ok(())
+    Ok(false)
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Watched {
+    Service(Service),
+    /// The Service "namespace/name" is due to be processed again after failing to resolve.
+    RetryService(String),
+    Deployment(PartialObjectMeta<Deployment>),
+    StatefulSet(PartialObjectMeta<StatefulSet>),
+    /// Any other scalable workload kind referenced by a service.
+    Workload(DynamicWorkload),
+    WorkloadDeleted(WorkloadReference),
+    /// End of a full listing of workloads of `kind` in `namespace`, or in all namespaces for `None`.
+    WorkloadsListed {
+        kind: String,
+        namespace: Option<String>,
+        listed: HashSet<WorkloadReference>,
+    },
+    EndpointSlice(EndpointSlice),
+    ServiceDeleted(Service),
+    /// End of a full service listing of `namespace`, or of all namespaces for `None`.
+    ServicesListed {
+        namespace: Option<String>,
+        listed: HashSet<String>,
+    },
+    Policy(ScaleToZeroPolicy),
+    PolicyDeleted(ScaleToZeroPolicy),
+    /// End of a full policy listing of `namespace`, with the "namespace/name" of each policy.
+    PoliciesListed {
+        namespace: Option<String>,
+        listed: HashSet<String>,
+    },
+}
+
+/// Namespaces and services the watcher is limited to, from `WATCH_NAMESPACES` and
+/// `WATCH_LABEL_SELECTOR`. Without namespaces everything is watched cluster-wide.
+struct WatchScope {
+    namespaces: Vec<String>,
+    label_selector: Option<String>,
+}
+
+impl WatchScope {
+    fn from_config() -> Self {
+        let config = crate::config::get();
+        WatchScope {
+            namespaces: config.watch_namespaces.clone(),
+            label_selector: config.watch_label_selector.clone(),
+        }
+    }
+
+    /// One entry per watcher to run: each configured namespace, or `None` for all of them.
+    fn namespaces(&self) -> Vec<Option<String>> {
+        if self.namespaces.is_empty() {
+            vec![None]
+        } else {
+            self.namespaces.iter().cloned().map(Some).collect()
+        }
+    }
+
+    fn allows(&self, namespace: &str) -> bool {
+        self.namespaces.is_empty() || self.namespaces.iter().any(|allowed| allowed == namespace)
+    }
+
+    fn api<K>(&self, client: &Client, namespace: Option<&str>) -> Api<K>
+    where
+        K: Resource<Scope = k8s_openapi::NamespaceResourceScope>,
+        <K as Resource>::DynamicType: Default,
+    {
+        match namespace {
+            Some(namespace) => Api::namespaced(client.clone(), namespace),
+            None => Api::all(client.clone()),
+        }
+    }
+}
+
+impl std::fmt::Display for WatchScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.namespaces.is_empty() {
+            write!(f, "all namespaces")?;
+        } else {
+            write!(f, "namespaces {}", self.namespaces.join(", "))?;
+        }
+        if let Some(selector) = &self.label_selector {
+            write!(f, " (services matching {})", selector)?;
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
+/// Service events of one watcher. A full listing is followed by a marker so we know when
+/// `WATCHED_SERVICES` is complete. Updates of only the agent's status annotations are dropped, or
+/// writing them would have the watcher process the service again, and again.
+fn service_events(
+    services: Api<Service>,
+    config: watcher::Config,
+    namespace: Option<String>,
+) -> futures::stream::BoxStream<'static, StdResult<Watched, watcher::Error>> {
+    // Fingerprint of the last version of each service, by "namespace/name"
+    let mut fingerprints: HashMap<String, u64> = HashMap::new();
+    watcher(services, config)
+        .default_backoff()
+        .map_ok(move |event| {
+            let name_key = |s: &Service| format!("{}/{}", s.namespace().unwrap_or_default(), s.name_any());
+            let watched: Vec<_> = match event {
+                watcher::Event::Applied(s) => {
+                    let fingerprint = service_status::fingerprint(&s);
+                    if fingerprints.insert(name_key(&s), fingerprint) == Some(fingerprint) {
+                        vec![]
+                    } else {
+                        vec![Watched::Service(s)]
+                    }
+                }
+                watcher::Event::Deleted(s) => {
+                    fingerprints.remove(&name_key(&s));
+                    vec![Watched::ServiceDeleted(s)]
+                }
+                watcher::Event::Restarted(services) => {
+                    fingerprints = services.iter().map(|s| (name_key(s), service_status::fingerprint(s))).collect();
+                    // Services deleted while the watch was down are missing from the listing
+                    let listed = services.iter().filter_map(|s| service_key(s).ok()).collect();
+                    services
+                        .into_iter()
+                        .map(Watched::Service)
+                        .chain(std::iter::once(Watched::ServicesListed {
+                            namespace: namespace.clone(),
+                            listed,
+                        }))
+                        .collect()
+                }
+            };
+            stream::iter(watched.into_iter().map(StdResult::Ok))
+        })
+        .try_flatten()
+        .boxed()
+}
+
+/// Looks up the replicas of each of `workloads`, `None` for the ones that do not exist.
+async fn fetch_replicas(ops: &dyn KubeOps, workloads: &[WorkloadReference]) -> anyhow::Result<Vec<Option<i32>>> {
+    let mut replicas = Vec::with_capacity(workloads.len());
+    for reference in workloads {
+        replicas.push(ops.get_replicas(reference).await?);
+    }
+    Ok(replicas)
+}
+
+/// Processes `service` again after an exponential backoff, as its workloads could not be looked
+/// up, e.g. while the API server is briefly unavailable. Workloads that simply do not exist yet
+/// need no retry; they are bound when their watcher sees them created.
+fn schedule_retry(
+    service: Service,
+    attempts: u32,
+    error: anyhow::Error,
+    retries: &mut HashMap<String, (Service, u32)>,
+    combo_stream: &mut stream::SelectAll<futures::stream::BoxStream<'static, StdResult<Watched, watcher::Error>>>,
+) {
+    let name_key = format!("{}/{}", service.namespace().unwrap_or_default(), service.name_any());
+    let delay = WATCHER_MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts))
+        .min(WATCHER_MAX_BACKOFF);
+    warn!(target: "kube_event_watcher", "Failed to resolve the workloads of service {}, retrying in {:?}: {:#}",
+          name_key, delay, error);
+    retries.insert(name_key.clone(), (service, attempts + 1));
+    combo_stream.push(
+        stream::once(async move {
+            tokio::time::sleep(delay).await;
+            StdResult::Ok(Watched::RetryService(name_key))
+        })
+        .boxed(),
+    );
+}
+
+fn policy_events(
+    policies: Api<ScaleToZeroPolicy>,
+    namespace: Option<String>,
+) -> futures::stream::BoxStream<'static, StdResult<Watched, watcher::Error>> {
+    watcher(policies, watcher::Config::default())
+        .default_backoff()
+        .map_ok(move |event| {
+            let watched: Vec<_> = match event {
+                watcher::Event::Applied(p) => vec![Watched::Policy(p)],
+                watcher::Event::Deleted(p) => vec![Watched::PolicyDeleted(p)],
+                watcher::Event::Restarted(policies) => {
+                    let listed = policies
+                        .iter()
+                        .map(|p| format!("{}/{}", p.namespace().unwrap_or_default(), p.name_any()))
+                        .collect();
+                    policies
+                        .into_iter()
+                        .map(Watched::Policy)
+                        .chain(std::iter::once(Watched::PoliciesListed {
+                            namespace: namespace.clone(),
+                            listed,
+                        }))
+                        .collect()
+                }
+            };
+            stream::iter(watched.into_iter().map(StdResult::Ok))
+        })
+        .try_flatten()
+        .boxed()
+}
+
+fn same_policy(a: &ScaleToZeroPolicy, b: &ScaleToZeroPolicy) -> bool {
+    a.namespace() == b.namespace() && a.name_any() == b.name_any()
+}
+
+/// Queues the Service "namespace/name" to be processed again after its policy changed, if it
+/// exists within the watched scope. Returns whether it does.
+async fn requeue_policy_target(
+    client: &Client,
+    scope: &WatchScope,
+    key: &str,
+    requeued: &mut Vec<Service>,
+) -> anyhow::Result<bool> {
+    let Some((namespace, name)) = key.split_once('/') else {
+        return Ok(false);
+    };
+    if !scope.allows(namespace) {
+        return Ok(false);
+    }
+    let mut params = ListParams::default().fields(&format!("metadata.name={}", name));
+    if let Some(selector) = scope.label_selector.as_deref() {
+        params = params.labels(selector);
+    }
+    let services: Api<Service> = Api::namespaced(client.clone(), namespace);
+    let Some(service) = services.list(&params).await?.items.into_iter().next() else {
+        return Ok(false);
+    };
+    requeued.push(service);
+    Ok(true)
 }
 
 trait K8sResource {
     fn name(&self) -> String;
     fn kind(&self) -> String;
     fn namespace_(&self) -> Option<String>;
+    /// `None` if the object at hand does not carry `spec.replicas`.
     fn replicas(&self) -> Option<i32>;
+    fn labels_(&self) -> BTreeMap<String, String>;
+    fn generation(&self) -> Option<i64>;
+
+    fn reference(&self) -> Option<WorkloadReference> {
+        Some(WorkloadReference {
+            kind: self.kind(),
+            name: self.name(),
+            namespace: self.namespace_()?,
+        })
+    }
 }
 
-impl K8sResource for Deployment {
+/// Deployments and StatefulSets are watched by metadata only, which leaves out `spec.replicas`
+/// but keeps the agent's memory flat on clusters with thousands of them.
+impl<K: Resource<DynamicType = ()>> K8sResource for PartialObjectMeta<K> {
     fn name(&self) -> String {
         self.name_any()
     }
 
     fn kind(&self) -> String {
-        "deployment".to_string()
+        K::kind(&()).to_lowercase()
     }
 
     fn namespace_(&self) -> Option<String> {
-        self.meta().namespace.clone()
+        self.metadata.namespace.clone()
+    }
+
+    fn labels_(&self) -> BTreeMap<String, String> {
+        self.labels().clone()
+    }
+
+    fn generation(&self) -> Option<i64> {
+        self.metadata.generation
     }
 
     fn replicas(&self) -> Option<i32> {
-        let spec = self.spec.as_ref();
-        match spec {
-            None => None,
-            Some(spec) => spec.replicas,
-        }
+        None
     }
 }
 
-impl K8sResource for StatefulSet {
+/// A workload of a kind only known at runtime, as stored in `ServiceData::kind`.
+struct DynamicWorkload {
+    kind: String,
+    object: DynamicObject,
+}
+
+impl K8sResource for DynamicWorkload {
     fn name(&self) -> String {
-        self.name_any()
+        self.object.name_any()
     }
 
     fn kind(&self) -> String {
-        "statefulset".to_string()
+        self.kind.clone()
     }
 
     fn namespace_(&self) -> Option<String> {
-        self.meta().namespace.clone()
+        self.object.namespace()
+    }
+
+    fn labels_(&self) -> BTreeMap<String, String> {
+        self.object.labels().clone()
+    }
+
+    fn generation(&self) -> Option<i64> {
+        self.object.metadata.generation
     }
 
     fn replicas(&self) -> Option<i32> {
-        let spec = self.spec.as_ref();
-        match spec {
-            None => None,
-            Some(spec) => spec.replicas,
-        }
+        self.object.data["spec"]["replicas"]
+            .as_i64()
+            .and_then(|replicas| i32::try_from(replicas).ok())
     }
 }
 
-fn process_resource<T: K8sResource>(
+/// Watches workloads of `kind` in `namespace` unless they already are. Deployments and
+/// StatefulSets are always watched; other kinds once a service refers to them.
+fn ensure_workload_watch(
+    dynamic_watches: &mut HashSet<(String, Option<String>)>,
+    combo_stream: &mut stream::SelectAll<futures::stream::BoxStream<'static, StdResult<Watched, watcher::Error>>>,
+    client: &Client,
+    scope: &WatchScope,
+    kind: &str,
+    namespace: &str,
+) -> anyhow::Result<()> {
+    if kind == "deployment" || kind == "statefulset" {
+        return Ok(());
+    }
+    let watch_namespace = if scope.namespaces.is_empty() { None } else { Some(namespace.to_string()) };
+    if dynamic_watches.insert((kind.to_string(), watch_namespace.clone())) {
+        info!(target: "kube_event_watcher", "Watching {} workloads in {}",
+              kind, watch_namespace.as_deref().unwrap_or("all namespaces"));
+        combo_stream.push(workload_events(client, kind, watch_namespace.as_deref())?);
+    }
+    Ok(())
+}
+
+fn workload_events(
+    client: &Client,
+    kind: &str,
+    namespace: Option<&str>,
+) -> anyhow::Result<futures::stream::BoxStream<'static, StdResult<Watched, watcher::Error>>> {
+    let resource = workload::api_resource(kind)?;
+    let api: Api<DynamicObject> = match namespace {
+        Some(namespace) => Api::namespaced_with(client.clone(), namespace, &resource),
+        None => Api::all_with(client.clone(), &resource),
+    };
+    let workload_kind = kind.to_string();
+    let events = watcher(api, watcher::Config::default())
+        .default_backoff()
+        .map_ok(move |event| {
+            let wrap = |object| DynamicWorkload { kind: workload_kind.clone(), object };
+            match event {
+                watcher::Event::Applied(object) => watcher::Event::Applied(wrap(object)),
+                watcher::Event::Deleted(object) => watcher::Event::Deleted(wrap(object)),
+                watcher::Event::Restarted(objects) => {
+                    watcher::Event::Restarted(objects.into_iter().map(wrap).collect())
+                }
+            }
+        });
+    Ok(workload_stream(events, Watched::Workload, kind.to_string(), namespace.map(str::to_string)))
+}
+
+/// Turns the events of a workload watcher into `Watched` items. Deletions are reported by
+/// reference, and each full listing of `kind` in `namespace` (`None` for all) is followed by
+/// `WorkloadsListed`, so workloads deleted while the watch was down are noticed.
+fn workload_stream<K>(
+    events: impl futures::Stream<Item = StdResult<watcher::Event<K>, watcher::Error>> + Send + 'static,
+    applied: fn(K) -> Watched,
+    kind: String,
+    namespace: Option<String>,
+) -> futures::stream::BoxStream<'static, StdResult<Watched, watcher::Error>>
+where
+    K: K8sResource + Send + 'static,
+{
+    events
+        .map_ok(move |event| {
+            let watched: Vec<_> = match event {
+                watcher::Event::Applied(workload) => vec![applied(workload)],
+                watcher::Event::Deleted(workload) => {
+                    workload.reference().map(Watched::WorkloadDeleted).into_iter().collect()
+                }
+                watcher::Event::Restarted(workloads) => {
+                    let listed = workloads.iter().filter_map(|workload| workload.reference()).collect();
+                    workloads
+                        .into_iter()
+                        .map(applied)
+                        .chain(std::iter::once(Watched::WorkloadsListed {
+                            kind: kind.clone(),
+                            namespace: namespace.clone(),
+                            listed,
+                        }))
+                        .collect()
+                }
+            };
+            stream::iter(watched.into_iter().map(StdResult::Ok))
+        })
+        .try_flatten()
+        .boxed()
+}
+
+/// Records the replicas of a watched workload that belongs to a service and refreshes the
+/// service's availability. Without `spec.replicas` in the event they are read through the scale
+/// subresource, only when the generation shows the spec may have changed.
+async fn process_resource<T: K8sResource>(
+    ops: &dyn KubeOps,
     resource: T,
     workload_service: &HashMap<WorkloadReference, Service>,
+    workload_replicas: &mut HashMap<WorkloadReference, i32>,
+    workload_generations: &mut HashMap<WorkloadReference, i64>,
 ) -> anyhow::Result<()> {
-    let service = workload_service.get(&WorkloadReference {
-        kind: resource.kind(),
-        name: resource.name(),
-        namespace: resource
-            .namespace_()
-            .ok_or_else(|| anyhow::anyhow!("Failed to get namespace for {}", resource.kind()))?,
-    });
+    let reference = resource
+        .reference()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get namespace for {}", resource.kind()))?;
+    let service = workload_service.get(&reference);
     let service = match service {
         Some(s) => s,
         None => return Ok(()),
     };
 
-    let replicas = resource
-        .replicas()
-        .ok_or_else(|| anyhow::anyhow!("Failed to get replicas for {}", resource.name()))?;
+    let replicas = match resource.replicas() {
+        Some(replicas) => replicas,
+        None => {
+            let generation = resource.generation();
+            if generation.is_some()
+                && workload_replicas.contains_key(&reference)
+                && workload_generations.get(&reference) == generation.as_ref()
+            {
+                return Ok(());
+            }
+            match ops.get_replicas(&reference).await {
+                StdResult::Ok(Some(replicas)) => {
+                    if let Some(generation) = generation {
+                        workload_generations.insert(reference.clone(), generation);
+                    }
+                    replicas
+                }
+                // Deleted in the meantime; the deletion event follows
+                StdResult::Ok(None) => return Ok(()),
+                Err(e) => {
+                    warn!(target: "kube_event_watcher", "Failed to read the replicas of {}: {:#}", reference, e);
+                    return Ok(());
+                }
+            }
+        }
+    };
 
-    thread::sleep(std::time::Duration::from_secs(2));
+    // The service entry is created before its workload is mapped here, so it is already present
+    let service_ip = service_key(service)?;
+    let previous = workload_replicas.insert(reference.clone(), replicas);
+    // Replicas changing to anything but what the agent last scaled to were changed by hand
+    let scaled_by_hand = previous.is_some_and(|previous| previous != replicas)
+        && LAST_SCALED.lock().unwrap().get(&reference) != Some(&replicas);
+    let mut overridden = None;
+    {
+        let mut watched_services = write_watched_services();
+        if let Some(service_data) = watched_services.get_mut(&service_ip) {
+            let was_missing = service_data.workload_missing;
+            refresh_availability(service_data, workload_replicas);
+            if was_missing && !service_data.workload_missing {
+                info!(target: "kube_event_watcher", "Workload {} of service {}/{} is back, resuming scaling",
+                      reference, service_data.namespace, service_data.name);
+            }
+            // Replicas set by the HPA are expected
+            if scaled_by_hand && !service_data.autoscaled() {
+                let duration = manual_override_seconds();
+                service_data.manual_override_until = Some(chrono::Utc::now().timestamp() + duration);
+                warn!(target: "kube_event_watcher", "{} of service {}/{} was scaled to {} by hand, not scaling it down for {}s",
+                      reference, service_data.namespace, service_data.name, replicas, duration);
+                overridden = Some(duration);
+            }
+        }
+    }
+    if let Some(duration) = overridden {
+        ops.publish_event(&service_ip, EventType::Normal, "ManualScaleDetected",
+            format!("{} was scaled to {} replicas by hand, scale-down is paused for {}s or until the service is \
+                     annotated with scale-to-zero/resume", reference, replicas, duration)).await;
+        ops.record_policy_event(&service_ip, "ManualScaleDetected").await;
+    }
+    Ok(())
+}
+
+/// How long a manual scale pauses scale-down, from `MANUAL_OVERRIDE_SECONDS`.
+fn manual_override_seconds() -> i64 {
+    crate::config::get().manual_override.as_secs() as i64
+}
+
+/// A service whose workloads are the ones of `kind` in `namespace` matching `selector`, taken
+/// from its `scale-to-zero/reference-selector` and `scale-to-zero/reference-kind` annotations.
+struct SelectorService {
+    service: Service,
+    kind: String,
+    namespace: String,
+    selector: LabelSelector,
+}
 
-    let service_ip = service
+/// Parses the `scale-to-zero/reference-selector` annotation of `service`, if it has one.
+/// Workloads are Deployments unless `scale-to-zero/reference-kind` names another kind.
+fn parse_reference_selector(service: &Service) -> Option<StdResult<SelectorService, String>> {
+    let raw = service.annotations().get("scale-to-zero/reference-selector")?;
+    let parse = || {
+        let selector = LabelSelector::parse(raw)
+            .map_err(|e| format!("invalid scale-to-zero/reference-selector '{}': {}", raw, e))?;
+        let kind = match service.annotations().get("scale-to-zero/reference-kind") {
+            Some(kind) if kind.contains('/') => kind.trim().to_string(),
+            Some(kind) => kind.trim().to_lowercase(),
+            None => "deployment".to_string(),
+        };
+        workload::api_resource(&kind).map_err(|e| format!("invalid scale-to-zero/reference-kind: {:#}", e))?;
+        StdResult::Ok(SelectorService {
+            service: service.clone(),
+            kind,
+            namespace: service.namespace().unwrap_or_default(),
+            selector,
+        })
+    };
+    Some(parse())
+}
+
+/// Queues the selector services whose set of workloads changes with `resource`: ones it starts
+/// matching, and the one it was mapped to if it no longer matches.
+fn requeue_selector_services<T: K8sResource>(
+    resource: &T,
+    workload_service: &HashMap<WorkloadReference, Service>,
+    selector_services: &HashMap<String, SelectorService>,
+    requeued: &mut Vec<Service>,
+) {
+    let Some(reference) = resource.reference() else {
+        return;
+    };
+    let labels = resource.labels_();
+    let mapped = workload_service.get(&reference).and_then(|service| service_key(service).ok());
+    for (key, selected) in selector_services {
+        if selected.kind != reference.kind || selected.namespace != reference.namespace {
+            continue;
+        }
+        if selected.selector.matches(&labels) != (mapped.as_deref() == Some(key.as_str())) {
+            requeued.push(selected.service.clone());
+        }
+    }
+}
+
+/// The selector service a deleted workload was mapped to, which is resolved again rather than
+/// paused waiting for that particular workload to come back.
+fn selecting_service(
+    reference: &WorkloadReference,
+    workload_service: &HashMap<WorkloadReference, Service>,
+    selector_services: &HashMap<String, SelectorService>,
+) -> Option<Service> {
+    let key = service_key(workload_service.get(reference)?).ok()?;
+    selector_services.get(&key).map(|selected| selected.service.clone())
+}
+
+async fn report_missing_workload(
+    client: &Client,
+    reference: &WorkloadReference,
+    workload_service: &HashMap<WorkloadReference, Service>,
+    workload_replicas: &mut HashMap<WorkloadReference, i32>,
+) {
+    let Some(service_ip) = process_workload_deleted(reference, workload_service, workload_replicas) else {
+        return;
+    };
+    warn!(target: "kube_event_watcher", "Workload {} of service {} was deleted, pausing scaling until it is recreated",
+          reference, service_ip);
+    events::publish_for_service(
+        client,
+        &service_ip,
+        EventType::Warning,
+        "WorkloadMissing",
+        format!("{} no longer exists; scaling is paused until it is recreated", reference),
+    )
+    .await;
+}
+
+/// Forgets the replicas of a deleted workload and marks its service as unavailable with a
+/// missing workload, returning the service's key. Scaling it is paused until the workload is
+/// recreated and shows up again in `process_resource`.
+fn process_workload_deleted(
+    reference: &WorkloadReference,
+    workload_service: &HashMap<WorkloadReference, Service>,
+    workload_replicas: &mut HashMap<WorkloadReference, i32>,
+) -> Option<String> {
+    let service_ip = service_key(workload_service.get(reference)?).ok()?;
+    workload_replicas.remove(reference);
+
+    let mut watched_services = write_watched_services();
+    let service_data = watched_services.get_mut(&service_ip)?;
+    refresh_availability(service_data, workload_replicas);
+    Some(service_ip)
+}
+
+fn refresh_availability(service: &mut ServiceData, workload_replicas: &HashMap<WorkloadReference, i32>) {
+    // A selector that matches nothing leaves the service without workloads
+    service.workload_missing = service.workloads.is_empty()
+        || service.workloads.iter().any(|workload| !workload_replicas.contains_key(workload));
+    service.backend_available = backend_available(service, workload_replicas);
+}
+
+/// A service is available once every one of its workloads has at least one replica and, if its
+/// EndpointSlices have been seen, one of them lists a ready endpoint. Until then new flows keep
+/// being held, rather than refused by a Service without endpoints while pods start. Without
+/// EndpointSlices, a pending scale-up is completed by the scaler once a replica is ready.
+fn backend_available(service: &ServiceData, workload_replicas: &HashMap<WorkloadReference, i32>) -> bool {
+    workloads_available(&service.workloads, workload_replicas)
+        && match service.ready_endpoints {
+            Some(ready) => ready > 0,
+            None => service.scale_up_started.is_none(),
+        }
+}
+
+fn workloads_available(workloads: &[WorkloadReference], workload_replicas: &HashMap<WorkloadReference, i32>) -> bool {
+    !workloads.is_empty()
+        && workloads
+            .iter()
+        .all(|workload| workload_replicas.get(workload).is_some_and(|replicas| *replicas >= 1))
+}
+
+/// Resolves one entry of the `scale-to-zero/reference` annotation of `service`, describing what
+/// is wrong with it otherwise.
+fn resolve_reference(reference: &str, service: &Service, scope: &WatchScope) -> StdResult<WorkloadReference, String> {
+    let parsed = workload::parse_reference(reference).ok_or_else(|| {
+        format!(
+            "invalid reference '{}', expected 'type/name', 'type/namespace/name' or 'group/version/Kind[/namespace]/name'",
+            reference
+        )
+    })?;
+    // Without a namespace the workload lives next to the service
+    let namespace = match parsed.namespace {
+        Some(namespace) => namespace,
+        None => service.namespace().unwrap_or_default(),
+    };
+    if !scope.allows(&namespace) {
+        return Err(format!("reference {} is outside the watched namespaces", reference));
+    }
+    workload::api_resource(&parsed.kind).map_err(|e| format!("{:#}", e))?;
+    StdResult::Ok(WorkloadReference {
+        kind: parsed.kind,
+        name: parsed.name,
+        namespace,
+    })
+}
+
+/// Key of `service` in `WATCHED_SERVICES`: its ClusterIP, or "namespace/name" for headless
+/// services, whose traffic is matched on the pod IPs of their endpoints instead.
+fn service_key(service: &Service) -> anyhow::Result<String> {
+    let cluster_ip = service
         .spec
         .as_ref()
         .ok_or_else(|| anyhow::anyhow!("Failed to get service spec for {}", service.name_any()))?
         .cluster_ip
         .as_ref()
+        .filter(|cluster_ip| !cluster_ip.is_empty())
         .ok_or_else(|| anyhow::anyhow!("Failed to get cluster IP for {}", service.name_any()))?;
-    {
-        let mut watched_services = WATCHED_SERVICES.lock().unwrap();
-        let service_data = watched_services.get_mut(service_ip).unwrap();
-        service_data.backend_available = replicas >= 1;
+
+    if cluster_ip == "None" {
+        Ok(format!("{}/{}", service.namespace().unwrap_or_default(), service.name_any()))
+    } else {
+        Ok(canonical_ip(cluster_ip).unwrap_or_else(|| cluster_ip.clone()))
+    }
+}
+
+/// `ip` in the form packet addresses are formatted in, so IPv6 addresses written with leading
+/// zeros or without `::` compression still match the service they belong to.
+fn canonical_ip(ip: &str) -> Option<String> {
+    ip.parse::<std::net::IpAddr>().ok().map(|ip| ip.to_string())
+}
+
+/// Stops watching the service stored under `key` after it was deleted or lost its annotations.
+/// Its addresses leave the eBPF maps on the next `sync_data`, so a recycled ClusterIP is not
+/// mistaken for the old service.
+fn unwatch_service(
+    key: &str,
+    workload_service: &mut HashMap<WorkloadReference, Service>,
+    workload_replicas: &mut HashMap<WorkloadReference, i32>,
+    endpoint_slices: &mut HashMap<String, HashMap<String, Vec<String>>>,
+) {
+    workload_service.retain(|_, service| service_key(service).ok().as_deref() != Some(key));
+    workload_replicas.retain(|workload, _| workload_service.contains_key(workload));
+    endpoint_slices.remove(key);
+    LAST_SCALED.lock().unwrap().retain(|workload, _| workload_service.contains_key(workload));
+    retry::clear(key);
+    SERVICE_REFERENCES.lock().unwrap().remove(key);
+    SERVICE_POLICIES.lock().unwrap().remove(key);
+
+    let mut watched_services = write_watched_services();
+    if let Some(service) = watched_services.remove(key) {
+        info!(target: "kube_event_watcher", "No longer watching service {}/{} ({})",
+              service.namespace, service.name, key);
+        refresh_dependency_cycles(&mut watched_services);
+    }
+}
+
+/// Recomputes `ServiceData::dependency_cycle` for every service and returns the services that
+/// newly joined a cycle, with the cycle described by "namespace/name".
+fn refresh_dependency_cycles(watched_services: &mut HashMap<String, ServiceData>) -> Vec<(String, String)> {
+    let mut cycle_of: HashMap<String, Vec<String>> = HashMap::new();
+    for cycle in dependency_graph::find_cycles(watched_services) {
+        for key in &cycle {
+            cycle_of.insert(key.clone(), cycle.iter().filter(|other| *other != key).cloned().collect());
+        }
+    }
+    let mut joined = Vec::new();
+    for (key, cycle) in cycle_of.iter() {
+        if watched_services.get(key).is_some_and(|service| service.dependency_cycle.is_empty()) {
+            let mut members: Vec<String> = std::iter::once(key)
+                .chain(cycle.iter())
+                .filter_map(|member| watched_services.get(member))
+                .map(|service| format!("{}/{}", service.namespace, service.name))
+                .collect();
+            members.sort();
+            joined.push((key.clone(), members.join(", ")));
+        }
+    }
+    for (key, service) in watched_services.iter_mut() {
+        service.dependency_cycle = cycle_of.remove(key).unwrap_or_default();
+    }
+    joined
+}
+
+/// Stores the ready addresses of `slice`, returning the "namespace/service-name" it belongs to.
+fn record_endpoint_slice(
+    slice: &EndpointSlice,
+    endpoint_slices: &mut HashMap<String, HashMap<String, Vec<String>>>,
+) -> Option<String> {
+    let service_name = slice.labels().get("kubernetes.io/service-name")?;
+    let key = format!("{}/{}", slice.namespace().unwrap_or_default(), service_name);
+
+    let addresses = slice
+        .endpoints
+        .iter()
+        .filter(|endpoint| {
+            endpoint
+                .conditions
+                .as_ref()
+                .and_then(|conditions| conditions.ready)
+                .unwrap_or(true)
+        })
+        .flat_map(|endpoint| endpoint.addresses.iter())
+        .map(|address| canonical_ip(address).unwrap_or_else(|| address.clone()))
+        .collect();
+
+    endpoint_slices
+        .entry(key.clone())
+        .or_default()
+        .insert(slice.name_any(), addresses);
+    Some(key)
+}
+
+/// Copies the endpoint addresses of a watched headless service into its `pod_ips`. An empty set
+/// leaves the last known addresses in place, so traffic to a stale pod IP still wakes the
+/// workload while it is at zero.
+fn apply_pod_ips(key: &str, endpoint_slices: &HashMap<String, HashMap<String, Vec<String>>>) {
+    let Some(slices) = endpoint_slices.get(key) else { return };
+
+    let mut pod_ips: Vec<String> = slices.values().flatten().cloned().collect();
+    pod_ips.sort();
+    pod_ips.dedup();
+    if pod_ips.is_empty() {
+        return;
+    }
+
+    let mut watched_services = write_watched_services();
+    if let Some(service) = watched_services.get_mut(key) {
+        if service.pod_ips != pod_ips {
+            info!(target: "kube_event_watcher", "Headless service {} now has pod IPs {:?}", key, pod_ips);
+            service.pod_ips = pod_ips;
+        }
+    }
+}
+
+/// Records the number of ready endpoints of the service named by `key` ("namespace/name") and
+/// updates its availability.
+fn apply_ready_endpoints(
+    key: &str,
+    endpoint_slices: &HashMap<String, HashMap<String, Vec<String>>>,
+    workload_replicas: &HashMap<WorkloadReference, i32>,
+) {
+    let Some(slices) = endpoint_slices.get(key) else { return };
+    let ready: usize = slices.values().map(|addresses| addresses.len()).sum();
+    let mut addresses: Vec<String> = slices.values().flatten().cloned().collect();
+    addresses.sort();
+    addresses.dedup();
+
+    let mut watched_services = write_watched_services();
+    let Some(service) = watched_services
+        .values_mut()
+        .find(|service| format!("{}/{}", service.namespace, service.name) == key)
+    else {
+        return;
+    };
+    service.ready_endpoints = Some(ready);
+    service.endpoint_addresses = addresses;
+    let available = backend_available(service, workload_replicas);
+    if service.backend_available != available {
+        info!(target: "kube_event_watcher", "Service {} has {} ready endpoints, now {}",
+              key, ready, if available { "available" } else { "unavailable" });
+        service.backend_available = available;
+    }
+}
+
+/// Idle seconds before the service's workload is scaled down, from the
+/// `scale-to-zero/scale-down-time` annotation (seconds, or a duration like `30s`, `5m`, `2h`) or
+/// the configured default scale-down time, raised to at least `MIN_SCALE_DOWN_SECONDS`. Also
+/// returns what was wrong with the annotation, if anything, to be reported on the service.
+fn parse_scale_down_time(service: &Service) -> (Option<i64>, Option<String>) {
+    let config = crate::config::get();
+    let default = config.default_scale_down_time.map(|default| default.as_secs() as i64);
+    let minimum = config.min_scale_down_time.as_secs() as i64;
+
+    let fallback = match default {
+        Some(default) => format!("using the default of {}s", default.max(minimum)),
+        None => "and DEFAULT_SCALE_DOWN_SECONDS is not set".to_string(),
+    };
+    let (scale_down_time, problem) = match service.annotations().get("scale-to-zero/scale-down-time") {
+        None => (default, None),
+        Some(raw) => match parse_duration_secs(raw) {
+            Some(seconds) if seconds < minimum => (
+                Some(minimum),
+                Some(format!("scale-down time {} is below the minimum of {}s, using {}s", raw, minimum, minimum)),
+            ),
+            Some(seconds) => (Some(seconds), None),
+            None => (default, Some(format!("invalid scale-down time '{}', {}", raw, fallback))),
+        },
+    };
+    (scale_down_time.map(|seconds| seconds.max(minimum)), problem)
+}
+
+/// Seconds between scale-ups of the service, from the `scale-to-zero/scale-up-cooldown`
+/// annotation (seconds or a duration like `30s`) or the `DEFAULT_SCALE_UP_COOLDOWN_SECONDS` env
+/// var, 5 by default. Also returns what was wrong with the annotation, if anything.
+fn parse_scale_up_cooldown(service: &Service) -> (i64, Option<String>) {
+    let default = crate::config::get().default_scale_up_cooldown.as_secs() as i64;
+    match service.annotations().get("scale-to-zero/scale-up-cooldown") {
+        None => (default, None),
+        Some(raw) => match parse_duration_secs(raw) {
+            Some(seconds) => (seconds, None),
+            None => (default, Some(format!("invalid scale-up cooldown '{}', using {}s", raw, default))),
+        },
+    }
+}
+
+/// Seconds a service stays up after a scale-up, from the `scale-to-zero/min-uptime` annotation
+/// (seconds or a duration like `5m`) or the `DEFAULT_MIN_UPTIME_SECONDS` env var, 0 by default.
+/// Also returns what was wrong with the annotation, if anything.
+fn parse_min_uptime(service: &Service) -> (i64, Option<String>) {
+    let default = crate::config::get().default_min_uptime.as_secs() as i64;
+    match service.annotations().get("scale-to-zero/min-uptime") {
+        None => (default, None),
+        Some(raw) => match parse_duration_secs(raw) {
+            Some(seconds) => (seconds, None),
+            None => (default, Some(format!("invalid minimum uptime '{}', using {}s", raw, default))),
+        },
+    }
+}
+
+/// Replicas to scale each workload up to from the `scale-to-zero/scale-up-replicas` annotation,
+/// overriding the ones remembered from the last scale-down. Also returns what was wrong with it.
+fn parse_scale_up_replicas(service: &Service) -> (Option<i32>, Option<String>) {
+    match service.annotations().get("scale-to-zero/scale-up-replicas") {
+        None => (None, None),
+        Some(raw) => match raw.trim().parse::<i32>().ok().filter(|replicas| *replicas >= 1) {
+            Some(replicas) => (Some(replicas), None),
+            None => (None, Some(format!("invalid scale-up replicas '{}', restoring the replicas from before the scale-down", raw))),
+        },
+    }
+}
+
+/// Replicas to scale each workload down to when idle from the `scale-to-zero/idle-replicas`
+/// annotation, 0 by default. Also returns what was wrong with it.
+fn parse_idle_replicas(service: &Service) -> (i32, Option<String>) {
+    match service.annotations().get("scale-to-zero/idle-replicas") {
+        None => (0, None),
+        Some(raw) => match raw.trim().parse::<i32>().ok().filter(|replicas| *replicas >= 0) {
+            Some(replicas) => (replicas, None),
+            None => (0, Some(format!("invalid idle replicas '{}', scaling to zero", raw))),
+        },
+    }
+}
+
+/// Parses raw seconds or a single-unit duration such as `30s`, `5m` or `2h`.
+fn parse_duration_secs(value: &str) -> Option<i64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last()? {
+        's' => (&value[..value.len() - 1], 1),
+        'm' => (&value[..value.len() - 1], 60),
+        'h' => (&value[..value.len() - 1], 3600),
+        _ => (value, 1),
+    };
+    number
+        .trim()
+        .parse::<i64>()
+        .ok()
+        .filter(|number| *number >= 0)
+        .and_then(|number| number.checked_mul(multiplier))
+}
+
+async fn publish_invalid_annotation(client: &Client, service: &Service, note: String) {
+    events::publish(client, service.object_ref(&()), EventType::Warning, "InvalidAnnotation", note).await;
+}
+
+/// Action for new flows while the service is scaled to zero, from the
+/// `scale-to-zero/unavailable-action` annotation or the `UNAVAILABLE_ACTION` env var.
+fn parse_unavailable_action(service: &Service) -> u8 {
+    let action = service
+        .annotations()
+        .get("scale-to-zero/unavailable-action")
+        .cloned()
+        .unwrap_or_else(|| crate::config::get().unavailable_action.clone());
+
+    match action.trim() {
+        "drop" => UNAVAILABLE_DROP,
+        "reset" => UNAVAILABLE_RESET,
+        "icmp-unreachable" => UNAVAILABLE_ICMP,
+        other => {
+            warn!(target: "kube_event_watcher", "Service {} has unknown unavailable action '{}', dropping instead",
+                  service.name_any(), other);
+            UNAVAILABLE_DROP
+        }
     }
-    Ok(())
+}
+
+/// The `scale-to-zero/check-active-connections` endpoint, and what was wrong with it if anything.
+fn parse_active_connections_check(service: &Service) -> (Option<ConnectionsEndpoint>, Option<String>) {
+    match service.annotations().get("scale-to-zero/check-active-connections") {
+        None => (None, None),
+        Some(raw) => match connections::parse_endpoint(raw) {
+            StdResult::Ok(endpoint) => (endpoint, None),
+            Err(e) => (None, Some(format!("invalid active connections check {}, scaling down without it", e))),
+        },
+    }
+}
+
+/// Whether `scale-to-zero/scale-down-independently` lets the service be scaled down while its
+/// dependents are up, and what was wrong with it if anything.
+fn parse_scale_down_independently(service: &Service) -> (bool, Option<String>) {
+    match service.annotations().get("scale-to-zero/scale-down-independently") {
+        None => (false, None),
+        Some(raw) => match raw.trim().parse::<bool>() {
+            StdResult::Ok(independent) => (independent, None),
+            Err(_) => (false, Some(format!("invalid scale-down-independently '{}', waiting for dependents", raw))),
+        },
+    }
+}
+
+/// How the service's HPA is suspended, from the `scale-to-zero/hpa-suspend-strategy` annotation
+/// or the `HPA_SUSPEND_STRATEGY` env var, and what was wrong with the annotation if anything.
+fn parse_hpa_suspend_strategy(service: &Service) -> (HpaSuspendStrategy, Option<String>) {
+    let default = hpa_controller::default_strategy();
+    match service.annotations().get("scale-to-zero/hpa-suspend-strategy") {
+        None => (default, None),
+        Some(raw) => match hpa_controller::parse_strategy(raw) {
+            Some(strategy) => (strategy, None),
+            None => (default, Some(format!("invalid HPA suspend strategy '{}', expected delete or min-replicas", raw))),
+        },
+    }
+}
+
+/// The `scale-to-zero/pre-scale-down-hook` URL, and what was wrong with it if anything.
+fn parse_pre_scale_down_hook(service: &Service) -> (Option<String>, Option<String>) {
+    match service.annotations().get("scale-to-zero/pre-scale-down-hook") {
+        None => (None, None),
+        Some(raw) => match hooks::parse_hook_url(raw) {
+            StdResult::Ok(url) => (Some(url), None),
+            Err(e) => (None, Some(format!("invalid pre-scale-down hook: {}, scaling down without it", e))),
+        },
+    }
+}
+
+/// The `scale-to-zero/allowed-window` windows, and what was wrong with them if anything. An
+/// invalid annotation allows scale-down at any time rather than never.
+fn parse_allowed_windows(service: &Service) -> (Vec<AllowedWindow>, Option<String>) {
+    match service.annotations().get("scale-to-zero/allowed-window") {
+        None => (Vec::new(), None),
+        Some(raw) => match schedule::parse_windows(raw) {
+            StdResult::Ok(windows) => (windows, None),
+            Err(e) => (Vec::new(), Some(format!("invalid allowed window '{}': {}, scaling down at any time", raw, e))),
+        },
+    }
+}
+
+fn parse_excluded_sources(service: &Service) -> Vec<String> {
+    let cidrs: Vec<String> = service
+        .annotations()
+        .get("scale-to-zero/exclude-sources")
+        .map(|cidrs| {
+            cidrs
+                .split(',')
+                .map(|cidr| cidr.trim().to_string())
+                .filter(|cidr| !cidr.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    for cidr in cidrs.iter().filter(|cidr| crate::utils::parse_cidr(cidr).is_none()) {
+        warn!(target: "kube_event_watcher", "Service {} has invalid exclude-sources entry '{}', ignoring it",
+              service.name_any(), cidr);
+    }
+    cidrs
 }
 
 fn parse_dependencies_annotation(service: &Service) -> Vec<String> {
@@ -334,13 +1571,120 @@ fn parse_dependents_annotation(service: &Service) -> Vec<String> {
         .unwrap_or_else(Vec::new)
 }
 
-fn calculate_scaling_priority(service: &Service) -> i32 {
+/// The latest Service object of every watched service, by its key.
+fn watched_service_objects(
+    workload_service: &HashMap<WorkloadReference, Service>,
+    selector_services: &HashMap<String, SelectorService>,
+) -> HashMap<String, Service> {
+    workload_service
+        .values()
+        .filter_map(|service| Some((service_key(service).ok()?, service.clone())))
+        .chain(selector_services.iter().map(|(key, selected)| (key.clone(), selected.service.clone())))
+        .collect()
+}
+
+/// Informational annotations written back to watched Services, so operators can check the
+/// priority each ended up with and which services its dependencies matched.
+const RESOLVED_PRIORITY: &str = "scale-to-zero/resolved-priority";
+const RESOLVED_DEPENDENCIES: &str = "scale-to-zero/resolved-dependencies";
+const RESOLVED_DEPENDENTS: &str = "scale-to-zero/resolved-dependents";
+
+/// Resolves the dependencies and dependents of the service stored under `key`, warning about
+/// entries that match no watched service if `report_unresolved`, and writes the priority and the
+/// resolved service keys back to `service` as annotations when they changed.
+async fn publish_resolved_graph(client: &Client, service: &Service, key: &str, report_unresolved: bool) {
+    let mut unresolved = Vec::new();
+    let resolved = {
+        let watched_services = read_watched_services();
+        let Some(data) = watched_services.get(key) else {
+            return;
+        };
+        let mut describe = |targets: &[String]| {
+            targets
+                .iter()
+                .filter_map(|target| {
+                    let mut keys = dependency_graph::resolve_target(&watched_services, target);
+                    if keys.is_empty() {
+                        unresolved.push(target.clone());
+                        return None;
+                    }
+                    keys.sort();
+                    Some(format!("{}={}", target, keys.join("|")))
+                })
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        [
+            (RESOLVED_PRIORITY, data.scaling_priority.to_string()),
+            (RESOLVED_DEPENDENCIES, describe(&data.dependencies)),
+            (RESOLVED_DEPENDENTS, describe(&data.dependents)),
+        ]
+    };
+
+    if report_unresolved && !unresolved.is_empty() {
+        warn!(target: "kube_event_watcher", "Service {}: {} match no watched service",
+              service.name_any(), unresolved.join(", "));
+        events::publish(
+            client,
+            service.object_ref(&()),
+            EventType::Warning,
+            "UnresolvedDependency",
+            format!("{} match no watched service", unresolved.join(", ")),
+        )
+        .await;
+    }
+
+    // Empty values remove the annotation
+    let annotations = service.annotations();
+    let changed: serde_json::Map<String, serde_json::Value> = resolved
+        .into_iter()
+        .filter(|(name, value)| annotations.get(*name).map(String::as_str).unwrap_or_default() != value)
+        .map(|(name, value)| {
+            let value = if value.is_empty() { serde_json::Value::Null } else { serde_json::Value::String(value) };
+            (name.to_string(), value)
+        })
+        .collect();
+    if changed.is_empty() {
+        return;
+    }
+    let services: Api<Service> = Api::namespaced(client.clone(), &service.namespace().unwrap_or_default());
+    let patch = Patch::Merge(serde_json::json!({ "metadata": { "annotations": changed } }));
+    if let Err(e) = services.patch(&service.name_any(), &PatchParams::default(), &patch).await {
+        warn!(target: "kube_event_watcher", "Failed to annotate service {} with its resolved dependencies: {}",
+              service.name_any(), e);
+    }
+}
+
+/// Range explicit `scale-to-zero/scaling-priority` values are clamped to.
+const MIN_SCALING_PRIORITY: i32 = 0;
+const MAX_SCALING_PRIORITY: i32 = 1000;
+
+/// The scaling priority of `service`, with what was wrong with an explicit
+/// `scale-to-zero/scaling-priority` annotation if it could not be used as is.
+fn calculate_scaling_priority(service: &Service) -> (i32, Option<String>) {
+    let mut problem = None;
     if let Some(priority_str) = service.annotations().get("scale-to-zero/scaling-priority") {
-        if let std::result::Result::Ok(priority) = priority_str.parse::<i32>() {
-            return priority;
+        match priority_str.trim().parse::<i32>() {
+            StdResult::Ok(priority) if (MIN_SCALING_PRIORITY..=MAX_SCALING_PRIORITY).contains(&priority) => {
+                return (priority, None);
+            }
+            StdResult::Ok(priority) => {
+                let clamped = priority.clamp(MIN_SCALING_PRIORITY, MAX_SCALING_PRIORITY);
+                return (
+                    clamped,
+                    Some(format!("scaling priority {} is outside {}..={}, using {}",
+                                 priority, MIN_SCALING_PRIORITY, MAX_SCALING_PRIORITY, clamped)),
+                );
+            }
+            Err(_) => {
+                problem = Some(format!("invalid scaling priority '{}', deriving it from the dependencies", priority_str));
+            }
         }
     }
-    
+    (derived_scaling_priority(service), problem)
+}
+
+fn derived_scaling_priority(service: &Service) -> i32 {
     let dependencies = parse_dependencies_annotation(service);
     let dependents = parse_dependents_annotation(service);
     
@@ -354,44 +1698,89 @@ fn calculate_scaling_priority(service: &Service) -> i32 {
 }
 
 async fn update_workload_status(
-    kind: String,
-    name: String,
-    namespace: Option<String>,
-    replicas: i32,
+    client: &Client,
+    workloads: Vec<WorkloadReference>,
     workload_service: &mut HashMap<WorkloadReference, Service>,
+    workload_replicas: &mut HashMap<WorkloadReference, i32>,
     service: Service,
     service_ip: String,
     scale_down_time: i64,
 ) -> anyhow::Result<()> {
-    let namespace = match namespace {
-        Some(ns) => ns,
-        None => return Err(anyhow::anyhow!("Failed to get namespace for {}", name)),
-    };
+    let workloads_ready = workloads_available(&workloads, workload_replicas);
 
-    info!(target: "update_workload_status", "updating workload status for service: {}, kind: {}, name: {}, namespace: {}, replicas: {}, service_ip: {}, scale_down_time: {}", service.name_any(), kind, name, namespace, replicas, service_ip, scale_down_time);
+    info!(target: "update_workload_status", "updating workload status for service: {}, workloads: {}, workloads ready: {}, service_ip: {}, scale_down_time: {}",
+          service.name_any(), workloads.iter().map(|w| w.to_string()).collect::<Vec<_>>().join(", "),
+          workloads_ready, service_ip, scale_down_time);
 
-    thread::sleep(std::time::Duration::from_secs(2));
-
-    workload_service.insert(
-        WorkloadReference {
-            kind: kind.clone(),
-            name: name.clone(),
-            namespace: namespace.clone(),
-        },
-        service.clone(),
-    );
+    // A recreated service comes back with a new ClusterIP; its state moves to the new key
+    let same_service = |existing: &Service| {
+        existing.namespace() == service.namespace() && existing.name_any() == service.name_any()
+    };
+    let previous_key = workload_service
+        .values()
+        .find(|existing| same_service(existing))
+        .and_then(|existing| service_key(existing).ok())
+        .filter(|key| key != &service_ip);
+    // The service may also have been pointed at different workloads since it was last seen
+    workload_service.retain(|_, existing| !same_service(existing));
+    for workload in &workloads {
+        workload_service.insert(workload.clone(), service.clone());
+    }
+    workload_replicas.retain(|workload, _| workload_service.contains_key(workload));
     let dependencies = parse_dependencies_annotation(&service);
     let dependents = parse_dependents_annotation(&service);
-    let scaling_priority = calculate_scaling_priority(&service);
+    let (scaling_priority, problem) = calculate_scaling_priority(&service);
+    if let Some(problem) = problem {
+        warn!(target: "update_workload_status", "Service {}: {}", service.name_any(), problem);
+        publish_invalid_annotation(client, &service, problem).await;
+    }
+    let (scale_up_cooldown, cooldown_problem) = parse_scale_up_cooldown(&service);
+    let (scale_up_replicas, replicas_problem) = parse_scale_up_replicas(&service);
+    let (active_connections_endpoint, connections_problem) = parse_active_connections_check(&service);
+    let (allowed_windows, windows_problem) = parse_allowed_windows(&service);
+    let (min_uptime, uptime_problem) = parse_min_uptime(&service);
+    let (pre_scale_down_hook, hook_problem) = parse_pre_scale_down_hook(&service);
+    let (idle_replicas, idle_replicas_problem) = parse_idle_replicas(&service);
+    let (scale_down_independently, independently_problem) = parse_scale_down_independently(&service);
+    let (hpa_suspend_strategy, strategy_problem) = parse_hpa_suspend_strategy(&service);
+    let problems = [
+        cooldown_problem,
+        replicas_problem,
+        connections_problem,
+        windows_problem,
+        uptime_problem,
+        hook_problem,
+        idle_replicas_problem,
+        independently_problem,
+        strategy_problem,
+    ];
+    for problem in problems.into_iter().flatten() {
+        warn!(target: "update_workload_status", "Service {}: {}", service.name_any(), problem);
+        publish_invalid_annotation(client, &service, problem).await;
+    }
+    let unavailable_action = parse_unavailable_action(&service);
+    let excluded_sources = parse_excluded_sources(&service);
     
     info!(target: "update_workload_status", "Service {} has {} dependencies, {} dependents, scaling priority: {}", 
           service.name_any(), dependencies.len(), dependents.len(), scaling_priority);
     
     let annotations = service.annotations();
-    let hpa_enabled = annotations
+    let resume = annotations.contains_key("scale-to-zero/resume");
+    let keda_scaled_object = annotations
+        .get("scale-to-zero/keda-scaledobject")
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+    let hpa_requested = annotations
         .get("scale-to-zero/hpa-enabled")
         .map(|v| v == "true")
         .unwrap_or(false);
+    // KEDA owns the HPA of a ScaledObject
+    let hpa_enabled = hpa_requested && keda_scaled_object.is_none();
+    if let Some(scaled_object) = keda_scaled_object.as_ref().filter(|_| hpa_requested) {
+        let problem = format!("hpa-enabled is ignored, as KEDA ScaledObject {} scales the workload", scaled_object);
+        warn!(target: "update_workload_status", "Service {}: {}", service.name_any(), problem);
+        publish_invalid_annotation(client, &service, problem).await;
+    }
     
     let hpa_name = if hpa_enabled {
         annotations
@@ -402,7 +1791,7 @@ async fn update_workload_status(
         None
     };
     
-    let hpa_config = if hpa_enabled {
+    let mut hpa_config = if hpa_enabled {
         let min_replicas = annotations
             .get("scale-to-zero/min-replicas")
             .and_then(|v| v.parse::<i32>().ok());
@@ -415,58 +1804,291 @@ async fn update_workload_status(
         let target_cpu_utilization_percentage = annotations
             .get("scale-to-zero/target-cpu-utilization")
             .and_then(|v| v.parse::<i32>().ok());
-            
+
+        let target_memory_utilization_percentage = annotations
+            .get("scale-to-zero/target-memory-utilization")
+            .and_then(|v| v.parse::<i32>().ok());
+
+        // Invalid metrics leave the HPA with the CPU and memory targets
+        let metrics = match annotations.get("scale-to-zero/hpa-metrics").map(|raw| (raw, hpa_controller::parse_metrics(raw))) {
+            Some((raw, StdResult::Ok(_))) => Some(raw.clone()),
+            Some((_, Err(e))) => {
+                let problem = format!("invalid HPA metrics: {}", e);
+                warn!(target: "update_workload_status", "Service {}: {}", service.name_any(), problem);
+                publish_invalid_annotation(client, &service, problem).await;
+                None
+            }
+            None => None,
+        };
+
+        // A behavior captured from a live HPA replaces this one once the HPA is suspended
+        let behavior = match annotations.get("scale-to-zero/hpa-behavior").map(|raw| (raw, hpa_controller::parse_behavior(raw))) {
+            Some((raw, StdResult::Ok(_))) => Some(raw.clone()),
+            Some((_, Err(e))) => {
+                let problem = format!("invalid HPA behavior: {}, using the Kubernetes defaults", e);
+                warn!(target: "update_workload_status", "Service {}: {}", service.name_any(), problem);
+                publish_invalid_annotation(client, &service, problem).await;
+                None
+            }
+            None => None,
+        };
+
         Some(crate::kubernetes::models::HPAConfig {
             min_replicas,
             max_replicas,
             target_cpu_utilization_percentage,
-            metrics: None,
-            behavior: None,
+            target_memory_utilization_percentage,
+            metrics,
+            behavior,
+            scale_target_ref: None,
         })
     } else {
         None
     };
 
+    // Dual-stack services carry the other family's address in spec.cluster_ips
+    let secondary_ips: Vec<String> = service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.cluster_ips.clone())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|ip| canonical_ip(ip))
+        .filter(|ip| ip != &service_ip)
+        .collect();
+
+    // LoadBalancer ingress IPs and external IPs reach the same backends as the ClusterIP
+    let external_ips: Vec<String> = service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.external_ips.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .chain(
+            service
+                .status
+                .as_ref()
+                .and_then(|status| status.load_balancer.as_ref())
+                .and_then(|lb| lb.ingress.as_ref())
+                .into_iter()
+                .flatten()
+                .filter_map(|ingress| ingress.ip.clone()),
+        )
+        .filter_map(|ip| canonical_ip(&ip))
+        .filter(|ip| ip != &service_ip)
+        .collect();
+
+    // Only traffic to one of the service ports counts as activity
+    let ports: Vec<u16> = service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.ports.as_ref())
+        .map(|ports| ports.iter().filter_map(|p| u16::try_from(p.port).ok()).collect())
+        .unwrap_or_default();
+
+    // NodePort and LoadBalancer services can also be reached on every node's addresses
+    let node_ports: Vec<u16> = service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.ports.as_ref())
+        .map(|ports| {
+            ports
+                .iter()
+                .filter_map(|p| p.node_port.and_then(|node_port| u16::try_from(node_port).ok()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // An HPA deleted before the agent restarted is only remembered on the workload it scaled
+    let known = previous_key.is_some() || read_watched_services().contains_key(&service_ip);
+    let stored_hpa_config = match workloads.iter().find(|workload| crate::kubernetes::models::is_hpa_target(workload)) {
+        Some(target) if hpa_enabled && !known => {
+            workload::get_stored_hpa_config(client, &target.kind, &target.namespace, &target.name)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!(target: "update_workload_status", "Failed to read the stored HPA config of {}: {:#}", target, e);
+                    None
+                })
+        }
+        _ => None,
+    };
+
+    let initial_hpa;
+    let new_cycles;
+    let resumed;
     {
-        let mut watched_services = WATCHED_SERVICES.lock().unwrap();
-
-        watched_services.insert(
-            service_ip.clone(),
-            ServiceData {
-                scale_down_time,
-                last_packet_time: chrono::Utc::now().timestamp(),
-                kind: kind.clone(),
-                name: name.clone(),
-                namespace: namespace.clone(),
-                backend_available: replicas >= 1,
-                dependencies,
-                dependents,
-                hpa_enabled,
-                hpa_name: hpa_name.clone(),
-                hpa_deleted: false,
-                hpa_config: hpa_config.clone(),
-                scaling_priority,
-            },
-        );
+        let mut watched_services = write_watched_services();
+        let mut service_references = SERVICE_REFERENCES.lock().unwrap();
+        let moved = previous_key.and_then(|key| {
+            service_references.remove(&key);
+            SERVICE_POLICIES.lock().unwrap().remove(&key);
+            let previous = watched_services.remove(&key)?;
+            info!(target: "update_workload_status", "Service {} moved from {} to {}",
+                  service.name_any(), key, service_ip);
+            Some(previous)
+        });
+        // Annotation edits merge into the existing entry, so the idle timer keeps running and a
+        // suspended HPA is still recreated from the configuration captured when it was deleted
+        let existing = moved.or_else(|| watched_services.remove(&service_ip));
+        let (wake_sources, pod_ips, ready_endpoints, endpoint_addresses) = existing
+            .as_ref()
+            .map(|existing| {
+                (existing.wake_sources.clone(), existing.pod_ips.clone(), existing.ready_endpoints, existing.endpoint_addresses.clone())
+            })
+            .unwrap_or_default();
+        let last_packet_time = existing
+            .as_ref()
+            .map(|existing| existing.last_packet_time.get())
+            .unwrap_or_else(|| chrono::Utc::now().timestamp());
+        // A stored HPA config means the agent deleted the HPA; it is recreated right away if the
+        // service is up
+        let hpa_deleted = hpa_enabled
+            && match &existing {
+                Some(existing) => existing.hpa_deleted,
+                None => stored_hpa_config.is_some() && !workloads_ready,
+            };
+        resumed = resume && existing.as_ref().is_some_and(|existing| existing.manual_override_until.is_some());
+        // An adopted HPA's own spec outranks the annotations
+        let captured = match &existing {
+            Some(existing) => existing.hpa_config.clone().filter(|_| hpa_deleted || existing.hpa_adopted),
+            None => stored_hpa_config,
+        };
+        if let Some(captured) = captured {
+            hpa_config = Some(captured);
+        }
+        let rebound = existing.as_ref().is_some_and(|existing| existing.workloads != workloads);
+        if rebound {
+            info!(target: "update_workload_status", "Service {} now references {}, keeping its idle timer",
+                  service.name_any(), workloads.iter().map(|w| w.to_string()).collect::<Vec<_>>().join(", "));
+        }
+        service_references.insert(service_ip.clone(), service.object_ref(&()));
+
+        let mut service_data = ServiceData {
+            scale_down_time,
+            scale_up_cooldown,
+            min_uptime,
+            last_scale_up_time: existing.as_ref().and_then(|existing| existing.last_scale_up_time),
+            last_scale_up_request: existing.as_ref().and_then(|existing| existing.last_scale_up_request),
+            last_packet_time: PacketTime::new(last_packet_time),
+            name: service.name_any(),
+            namespace: service.namespace().unwrap_or_default(),
+            workloads: workloads.clone(),
+            backend_available: workloads_ready,
+            dependencies,
+            dependents,
+            hpa_enabled,
+            hpa_name: hpa_name.clone(),
+            hpa_deleted,
+            hpa_suspend_strategy,
+            hpa_adopted: hpa_enabled && existing.as_ref().is_some_and(|existing| existing.hpa_adopted),
+            keda_scaled_object,
+            hpa_config: hpa_config.clone(),
+            scaling_priority,
+            secondary_ips,
+            ports,
+            wake_sources,
+            pod_ips,
+            unavailable_action,
+            excluded_sources,
+            node_ports,
+            external_ips,
+            ready_endpoints,
+            endpoint_addresses,
+            active_connections_endpoint,
+            pre_scale_down_hook,
+            allowed_windows,
+            manual_override_until: existing
+                .as_ref()
+                .and_then(|existing| existing.manual_override_until)
+                .filter(|_| !resume),
+            paused_until: existing.as_ref().and_then(|existing| existing.paused_until),
+            workload_missing: false,
+            dependency_cycle: existing.as_ref().map(|existing| existing.dependency_cycle.clone()).unwrap_or_default(),
+            scale_up_started: existing.as_ref().and_then(|existing| existing.scale_up_started),
+            scale_up_failed: existing.as_ref().is_some_and(|existing| existing.scale_up_failed),
+            scale_up_failures: existing.as_ref().map_or(0, |existing| existing.scale_up_failures),
+            circuit_open_until: existing.as_ref().and_then(|existing| existing.circuit_open_until),
+            scale_up_replicas,
+            previous_replicas: existing.as_ref().map(|existing| existing.previous_replicas.clone()).unwrap_or_default(),
+            idle_replicas,
+            // A changed floor is applied by the next scale-down
+            at_idle_floor: existing
+                .as_ref()
+                .is_some_and(|existing| existing.at_idle_floor && existing.idle_replicas == idle_replicas),
+            scale_down_independently,
+        };
+        // Availability is derived from the replicas and endpoints seen so far, not reset
+        refresh_availability(&mut service_data, workload_replicas);
+        watched_services.insert(service_ip.clone(), service_data);
+        initial_hpa = !hpa_deleted;
+        new_cycles = refresh_dependency_cycles(&mut watched_services);
+    }
+    for (key, cycle) in new_cycles {
+        warn!(target: "update_workload_status", "Service {} is in a dependency cycle ({}); traffic is not propagated within it",
+              key, cycle);
+        events::publish_for_service(
+            client,
+            &key,
+            EventType::Warning,
+            "DependencyCycle",
+            format!("dependencies form a cycle ({}); traffic to one of these services no longer keeps the others awake", cycle),
+        )
+        .await;
     }
 
-    if hpa_enabled && replicas >= 1 {
-        if let (Some(hpa_name), Some(hpa_config)) = (hpa_name, hpa_config) {
-            info!("Creating initial HPA for service {}/{}", namespace, name);
-            
+    // The annotation is consumed, so it can be applied again after the next manual scale
+    if resume {
+        if resumed {
+            info!(target: "update_workload_status", "Service {} was annotated with scale-to-zero/resume, resuming scale-down",
+                  service.name_any());
+        }
+        let services: Api<Service> = Api::namespaced(client.clone(), &service.namespace().unwrap_or_default());
+        let patch = Patch::Merge(serde_json::json!({ "metadata": { "annotations": { "scale-to-zero/resume": null } } }));
+        if let Err(e) = services.patch(&service.name_any(), &PatchParams::default(), &patch).await {
+            warn!(target: "update_workload_status", "Failed to remove scale-to-zero/resume from service {}: {}",
+                  service.name_any(), e);
+        }
+    }
+
+    // HPAs only ever target a Deployment or StatefulSet; a suspended one is recreated when the
+    // service wakes. One that already exists is adopted as it is, so it keeps the metrics and
+    // behavior it was given.
+    let hpa_target = workloads.iter().find(|workload| crate::kubernetes::models::is_hpa_target(workload));
+    if hpa_enabled && workloads_ready && initial_hpa {
+        if let (Some(hpa_target), Some(hpa_name), Some(hpa_config)) = (hpa_target, hpa_name, hpa_config) {
             let service_ip_clone = service_ip.clone();
-            let namespace_clone = namespace.clone();
-            let name_clone = name.clone();
+            let namespace_clone = hpa_target.namespace.clone();
+            let target_clone = hpa_target.clone();
+            let name_clone = hpa_target.name.clone();
             let hpa_name_clone = hpa_name.clone();
             let hpa_config_clone = hpa_config.clone();
             
             tokio::spawn(async move {
-                let hpa_controller_result = super::hpa_controller::HPASuspensionController::new().await;
+                let hpa_controller_result = super::hpa_controller::HPASuspensionController::shared().await;
                 if let StdResult::Ok(hpa_controller) = hpa_controller_result {
-                    if let Err(e) = hpa_controller.recreate_hpa(&namespace_clone, &hpa_name_clone, &name_clone, &hpa_config_clone).await {
-                        error!("Failed to create initial HPA for service {}: {}", service_ip_clone, e);
-                    } else {
-                        info!("Successfully created initial HPA for service {}/{}", namespace_clone, name_clone);
+                    // An HPA suspended by lowering its minReplicas gets it back before being adopted
+                    if hpa_suspend_strategy == HpaSuspendStrategy::MinReplicas
+                        && let Err(e) = hpa_controller.restore_min_replicas(&namespace_clone, &hpa_name_clone).await
+                    {
+                        warn!("Failed to resume HPA {}/{}: {:#}", namespace_clone, hpa_name_clone, e);
+                    }
+                    match hpa_controller.adopt_hpa(&namespace_clone, &hpa_name_clone).await {
+                        StdResult::Ok(Some(adopted)) => {
+                            info!("Adopted existing HPA {}/{} for service {}", namespace_clone, hpa_name_clone, service_ip_clone);
+                            if let Some(service) = write_watched_services().get_mut(&service_ip_clone) {
+                                service.hpa_config = Some(adopted);
+                                service.hpa_adopted = true;
+                            }
+                        }
+                        StdResult::Ok(None) => {
+                            info!("Creating initial HPA for service {}/{}", namespace_clone, name_clone);
+                            if let Err(e) = hpa_controller.recreate_hpa(&namespace_clone, &hpa_name_clone, &target_clone, &hpa_config_clone).await {
+                                error!("Failed to create initial HPA for service {}: {}", service_ip_clone, e);
+                            } else {
+                                info!("Successfully created initial HPA for service {}/{}", namespace_clone, name_clone);
+                            }
+                        }
+                        Err(e) => error!("Failed to look up HPA {}/{}: {:#}", namespace_clone, hpa_name_clone, e),
                     }
                 } else {
                     error!("Failed to create HPA controller for initial HPA creation");
@@ -0,0 +1,745 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use k8s_openapi::chrono;
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+
+use super::etcd_coordinator::EtcdCoordinator;
+use super::lease_coordinator::KubeCoordinator;
+use super::models::{read_watched_services, write_watched_services, ServiceData, SERVICE_LIST_CHANGED};
+use super::scaler::{self, ScaleUpOutcome};
+
+/// Set once `initialize` set up the configured backend, or found none configured.
+pub static COORDINATION_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Times this node became or stopped being the leader.
+pub static LEADERSHIP_CHANGES: AtomicU64 = AtomicU64::new(0);
+
+/// Set while the coordination backend cannot be reached and the agent runs on its local state
+/// alone, as in single-node mode, and the times that happened.
+pub static COORDINATION_DEGRADED: AtomicBool = AtomicBool::new(false);
+pub static COORDINATION_OUTAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Failed coordination operations since the last successful sync, and since startup.
+static CONSECUTIVE_FAILURES: AtomicU32 = AtomicU32::new(0);
+pub static COORDINATION_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// When this node last became the leader, 0 while it is not, and when coordination started and
+/// the push and pull of packet times last succeeded, in seconds since the epoch.
+static LEADER_SINCE: AtomicI64 = AtomicI64::new(0);
+static STARTED_AT: AtomicI64 = AtomicI64::new(0);
+static LAST_PUSH: AtomicI64 = AtomicI64::new(0);
+static LAST_PULL: AtomicI64 = AtomicI64::new(0);
+
+static COORDINATOR: Mutex<Option<Arc<dyn CoordinationBackend>>> = Mutex::new(None);
+
+/// When this node last forwarded a wake request per service, so that the packets of one wake
+/// make a single request.
+static FORWARDED_WAKES: Lazy<Mutex<HashMap<String, tokio::time::Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Wake requests older than this are dropped instead of served, as their client gave up long
+/// ago.
+const WAKE_REQUEST_MAX_AGE_MS: i64 = 120_000;
+/// How often a node waiting to lead checks whether it does.
+const LEADERSHIP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A follower asking the leader to scale up a service that received traffic on it. There is
+/// one per service, so requests from several packets or nodes coalesce into one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WakeRequest {
+    pub service: String,
+    pub source: String,
+    pub node_id: String,
+    /// Milliseconds since the epoch.
+    pub requested_at: i64,
+}
+
+/// The leader and the other live nodes, as last seen by this node.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Membership {
+    pub leader_id: Option<String>,
+    pub peers: Vec<PeerStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerStatus {
+    pub node_id: String,
+    /// Seconds since the epoch.
+    pub last_heartbeat: i64,
+}
+
+/// What this node knows about coordination, for debugging multi-node setups. Timestamps are in
+/// seconds since the epoch.
+#[derive(Debug, Clone, Serialize)]
+pub struct CoordinatorStatus {
+    pub backend: &'static str,
+    pub node_id: String,
+    pub is_leader: bool,
+    pub leader_id: Option<String>,
+    pub leader_since: Option<i64>,
+    pub peers: Vec<PeerStatus>,
+    pub last_push: Option<i64>,
+    pub last_pull: Option<i64>,
+    /// Seconds since this node last both pushed and pulled packet times, i.e. how stale its
+    /// view of the other nodes' traffic may be.
+    pub sync_lag_seconds: i64,
+    pub leadership_changes: u64,
+    pub degraded: bool,
+    pub outages: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+}
+
+/// Where agents on different nodes elect the one that scales, and share the packet times and
+/// availability of services. Backends start their own election when created.
+#[async_trait]
+pub trait CoordinationBackend: Send + Sync {
+    /// The `COORDINATION_BACKEND` value selecting it.
+    fn backend(&self) -> &'static str;
+
+    fn node_id(&self) -> &str;
+
+    fn is_leader(&self) -> bool;
+
+    fn membership(&self) -> Membership;
+
+    /// Writes the packet times of services that received traffic on this node since the last
+    /// push.
+    async fn push_service_data(&self) -> Result<()>;
+
+    /// Merges the packet times of every live node into `WATCHED_SERVICES`.
+    async fn pull_service_data(&self) -> Result<()>;
+
+    /// Publishes service availability if this node is the leader.
+    async fn push_service_list(&self) -> Result<()>;
+
+    /// Applies the availability published by the leader as it changes, until dropped.
+    async fn watch_service_list(&self);
+
+    /// Writes the marker asking the leader to serve `request`, replacing any earlier one for
+    /// the same service.
+    async fn request_wake(&self, request: &WakeRequest) -> Result<()>;
+
+    /// Sends pending wake requests to `requests`, those written before the call included, as
+    /// they arrive until dropped.
+    async fn watch_wake_requests(&self, requests: mpsc::UnboundedSender<WakeRequest>);
+
+    /// Removes the marker of the wake request for `service` once served.
+    async fn acknowledge_wake(&self, service: &str) -> Result<()>;
+
+    /// Hands leadership over and leaves, without waiting for anything to expire.
+    async fn cleanup(&self);
+}
+
+/// Connects the configured backend.
+pub async fn initialize() -> Result<()> {
+    let config = crate::config::get();
+    let coordinator: Arc<dyn CoordinationBackend> = match config.coordination_backend.as_str() {
+        "etcd" => {
+            let etcd_endpoints = config.etcd_endpoints.clone();
+            info!("Initializing etcd coordination with endpoints: {:?}", etcd_endpoints);
+            Arc::new(EtcdCoordinator::start(etcd_endpoints).await?)
+        }
+        "kube" => {
+            info!("Initializing coordination through Kubernetes Leases");
+            Arc::new(KubeCoordinator::start().await?)
+        }
+        "none" => {
+            info!("Running in single-node mode (no coordination)");
+            COORDINATION_INITIALIZED.store(true, Ordering::SeqCst);
+            return Ok(());
+        }
+        other => anyhow::bail!("invalid COORDINATION_BACKEND '{}', expected etcd, kube or none", other),
+    };
+    STARTED_AT.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+    *COORDINATOR.lock().unwrap() = Some(coordinator);
+    COORDINATION_INITIALIZED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Goes back to running without coordination, for tests that initialized it.
+#[cfg(test)]
+pub fn reset() {
+    *COORDINATOR.lock().unwrap() = None;
+    CONSECUTIVE_FAILURES.store(0, Ordering::Relaxed);
+    COORDINATION_DEGRADED.store(false, Ordering::Relaxed);
+}
+
+/// Coordinates through `coordinator` instead of the configured backend, for tests.
+#[cfg(test)]
+pub fn install(coordinator: Arc<dyn CoordinationBackend>) {
+    STARTED_AT.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+    *COORDINATOR.lock().unwrap() = Some(coordinator);
+    COORDINATION_INITIALIZED.store(true, Ordering::SeqCst);
+}
+
+fn coordinator() -> Option<Arc<dyn CoordinationBackend>> {
+    COORDINATOR.lock().unwrap().clone()
+}
+
+/// Whether this node may scale workloads: it is the leader, or runs without coordination,
+/// whether configured so or because the backend is unavailable. Other nodes keep tracking
+/// traffic but leave scaling to the leader.
+pub fn may_scale() -> bool {
+    coordinator().is_none_or(|c| c.is_leader() || is_degraded())
+}
+
+pub fn is_degraded() -> bool {
+    COORDINATION_DEGRADED.load(Ordering::Relaxed)
+}
+
+/// The coordination status of this node, `None` without coordination.
+pub fn status() -> Option<CoordinatorStatus> {
+    let coordinator = coordinator()?;
+    let timestamp = |time: &AtomicI64| Some(time.load(Ordering::Relaxed)).filter(|time| *time > 0);
+    let last_push = timestamp(&LAST_PUSH);
+    let last_pull = timestamp(&LAST_PULL);
+    let synced = last_push.zip(last_pull).map(|(push, pull)| push.min(pull));
+    let Membership { leader_id, peers } = coordinator.membership();
+    Some(CoordinatorStatus {
+        backend: coordinator.backend(),
+        node_id: coordinator.node_id().to_string(),
+        is_leader: coordinator.is_leader(),
+        leader_id,
+        leader_since: timestamp(&LEADER_SINCE),
+        peers,
+        last_push,
+        last_pull,
+        sync_lag_seconds: chrono::Utc::now().timestamp() - synced.unwrap_or(STARTED_AT.load(Ordering::Relaxed)),
+        leadership_changes: LEADERSHIP_CHANGES.load(Ordering::Relaxed),
+        degraded: is_degraded(),
+        outages: COORDINATION_OUTAGES.load(Ordering::Relaxed),
+        failures: COORDINATION_FAILURES.load(Ordering::Relaxed),
+        consecutive_failures: CONSECUTIVE_FAILURES.load(Ordering::Relaxed),
+    })
+}
+
+/// Shares packet times every `COORDINATION_SYNC_INTERVAL_SECONDS` (5 by default) until
+/// shutdown: pushes this node's and merges everyone else's. Returns right away without
+/// coordination.
+///
+/// The sync doubles as the health check of the backend: while it is unavailable the packet
+/// times seen meanwhile stay in `WATCHED_SERVICES`, and the first successful sync afterwards
+/// pushes them all and resumes coordination.
+pub async fn sync_service_data(mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let Some(coordinator) = coordinator() else {
+        return Ok(());
+    };
+    loop {
+        let synced = async {
+            coordinator.push_service_data().await.context("Failed to push service data")?;
+            LAST_PUSH.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+            coordinator.pull_service_data().await.context("Failed to pull service data")?;
+            LAST_PULL.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+            anyhow::Ok(())
+        };
+        match synced.await {
+            Ok(()) => record_success(coordinator.node_id()),
+            Err(e) => record_failure(coordinator.node_id(), "Coordination sync failed", &e),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(crate::config::get().coordination_sync_interval) => {}
+            _ = shutdown.changed() => {
+                info!("Shutting down, stopping coordination sync");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Propagates service availability until shutdown: the leader publishes changes every map sync
+/// interval, and every node follows what the leader published. Returns right away without
+/// coordination.
+pub async fn sync_service_list(mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let Some(coordinator) = coordinator() else {
+        return Ok(());
+    };
+
+    let publish = async {
+        loop {
+            if let Err(e) = coordinator.push_service_list().await {
+                record_failure(coordinator.node_id(), "Failed to publish service availability", &e);
+            }
+            tokio::time::sleep(crate::config::get().map_sync_interval).await;
+        }
+    };
+    tokio::select! {
+        _ = publish => Ok(()),
+        _ = coordinator.watch_service_list() => Ok(()),
+        _ = shutdown.changed() => {
+            info!("Shutting down, stopping service list sync");
+            Ok(())
+        }
+    }
+}
+
+/// Asks the leader to scale up the service under `service_key`, which received traffic from
+/// `source` on this node while another node leads. Packets arriving within
+/// `COORDINATION_WAKE_FORWARD_INTERVAL_MS` (1s by default) of a forwarded request are not
+/// forwarded again.
+pub async fn forward_wake(service_key: &str, source: &str) -> Result<()> {
+    let Some(coordinator) = coordinator() else {
+        return Ok(());
+    };
+    let interval = crate::config::get().coordination_wake_forward_interval;
+    if !claim_forward(&mut FORWARDED_WAKES.lock().unwrap(), service_key, tokio::time::Instant::now(), interval) {
+        return Ok(());
+    }
+
+    info!(target: "scale_up", "Forwarding the wake of {} to the leader", service_key);
+    let request = WakeRequest {
+        service: service_key.to_string(),
+        source: source.to_string(),
+        node_id: coordinator.node_id().to_string(),
+        requested_at: chrono::Utc::now().timestamp_millis(),
+    };
+    coordinator.request_wake(&request).await
+}
+
+/// Whether the wake of `service_key` is to be forwarded at `now`, recording it if so. Forwards
+/// more than `interval` ago are forgotten, so only services woken within it are kept.
+fn claim_forward(
+    forwarded: &mut HashMap<String, tokio::time::Instant>,
+    service_key: &str,
+    now: tokio::time::Instant,
+    interval: Duration,
+) -> bool {
+    forwarded.retain(|_, last| now.duration_since(*last) < interval);
+    if forwarded.contains_key(service_key) {
+        return false;
+    }
+    forwarded.insert(service_key.to_string(), now);
+    true
+}
+
+/// Serves the wake requests forwarded by followers while this node leads, until shutdown.
+/// Returns right away without coordination.
+pub async fn serve_wake_requests(mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let Some(coordinator) = coordinator() else {
+        return Ok(());
+    };
+    let (sender, mut requests) = mpsc::unbounded_channel();
+
+    // Watches only while leading, and from scratch on every election, so a new leader picks up
+    // the requests its predecessor left
+    let watch = async {
+        loop {
+            if coordinator.is_leader() {
+                let lost_leadership = async {
+                    while coordinator.is_leader() {
+                        tokio::time::sleep(LEADERSHIP_POLL_INTERVAL).await;
+                    }
+                };
+                tokio::select! {
+                    _ = coordinator.watch_wake_requests(sender.clone()) => {}
+                    _ = lost_leadership => {}
+                }
+            }
+            tokio::time::sleep(LEADERSHIP_POLL_INTERVAL).await;
+        }
+    };
+    let serve = async {
+        let in_flight: Arc<Mutex<HashSet<String>>> = Arc::default();
+        while let Some(request) = requests.recv().await {
+            if !in_flight.lock().unwrap().insert(request.service.clone()) {
+                continue;
+            }
+            let coordinator = coordinator.clone();
+            let in_flight = in_flight.clone();
+            tokio::spawn(async move {
+                serve_wake_request(coordinator.as_ref(), request.clone()).await;
+                in_flight.lock().unwrap().remove(&request.service);
+            });
+        }
+    };
+    tokio::select! {
+        _ = watch => Ok(()),
+        _ = serve => Ok(()),
+        _ = shutdown.changed() => {
+            info!("Shutting down, no longer serving wake requests");
+            Ok(())
+        }
+    }
+}
+
+async fn serve_wake_request(coordinator: &dyn CoordinationBackend, request: WakeRequest) {
+    let age = chrono::Utc::now().timestamp_millis() - request.requested_at;
+    if age <= WAKE_REQUEST_MAX_AGE_MS {
+        let source = format!("{} via node {}", request.source, request.node_id);
+        match scaler::scale_up(request.service.clone(), source).await {
+            // Left for the next leader
+            Ok(ScaleUpOutcome::NotLeader) => return,
+            Ok(ScaleUpOutcome::ScaledUp) => {
+                info!(target: "scale_up", "Served the wake of {} forwarded by node {}, {} ms after the request",
+                      request.service, request.node_id, chrono::Utc::now().timestamp_millis() - request.requested_at);
+            }
+            Ok(ScaleUpOutcome::RateLimited | ScaleUpOutcome::Quarantined | ScaleUpOutcome::Paused) => {}
+            Err(e) => warn!(target: "scale_up", "Failed to serve the wake of {} forwarded by node {}: {:#}",
+                            request.service, request.node_id, e),
+        }
+    } else {
+        debug!("Dropping the wake request for {} made {} ms ago", request.service, age);
+    }
+    if let Err(e) = coordinator.acknowledge_wake(&request.service).await {
+        warn!("Failed to acknowledge the wake request for {}: {:#}", request.service, e);
+    }
+}
+
+pub async fn cleanup() {
+    if let Some(coordinator) = coordinator() {
+        coordinator.cleanup().await;
+    }
+}
+
+/// The node's name from the `NODE_NAME` downward API env var, falling back to `POD_NAME` and
+/// the hostname. It stays the same across restarts, so a restarted agent takes over the state
+/// it wrote before.
+pub(super) async fn node_id() -> Result<String> {
+    for name in ["NODE_NAME", "POD_NAME"] {
+        if let Ok(value) = std::env::var(name)
+            && !value.trim().is_empty()
+        {
+            return Ok(value.trim().to_string());
+        }
+    }
+    let hostname = tokio::fs::read_to_string("/etc/hostname")
+        .await
+        .context("NODE_NAME is not set and the hostname cannot be read")?
+        .trim()
+        .to_string();
+    Ok(hostname)
+}
+
+/// Seconds after which the leader deletes the state of a node without a heartbeat.
+pub(super) fn dead_node_cleanup_after() -> i64 {
+    crate::config::get().coordination_dead_node_cleanup.as_secs() as i64
+}
+
+/// Counts and logs this node becoming or stopping being the leader.
+pub(super) fn leadership_changed(node_id: &str, leader: bool) {
+    LEADERSHIP_CHANGES.fetch_add(1, Ordering::Relaxed);
+    LEADER_SINCE.store(if leader { chrono::Utc::now().timestamp() } else { 0 }, Ordering::Relaxed);
+    if leader {
+        info!("Node {} became the leader", node_id);
+    } else {
+        warn!("Node {} is no longer the leader", node_id);
+    }
+}
+
+/// Logs a failed coordination operation, only at debug level once degraded so that an outage
+/// does not flood the log, and degrades coordination after `COORDINATION_DEGRADED_AFTER_FAILURES`
+/// (3 by default) failures without a successful sync.
+pub(super) fn record_failure(node_id: &str, what: &str, error: &anyhow::Error) {
+    COORDINATION_FAILURES.fetch_add(1, Ordering::Relaxed);
+    if is_degraded() {
+        debug!("{} on node {}: {:#}", what, node_id, error);
+        return;
+    }
+    warn!("{} on node {}: {:#}", what, node_id, error);
+    let threshold = crate::config::get().coordination_degraded_after_failures;
+    let failures = CONSECUTIVE_FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+    if failures >= threshold && !COORDINATION_DEGRADED.swap(true, Ordering::Relaxed) {
+        COORDINATION_OUTAGES.fetch_add(1, Ordering::Relaxed);
+        error!("Coordination backend is unavailable, node {} falls back to single-node mode on its local state until it returns",
+               node_id);
+    }
+}
+
+/// Resumes coordination after a successful sync, if the backend was unavailable.
+fn record_success(node_id: &str) {
+    CONSECUTIVE_FAILURES.store(0, Ordering::Relaxed);
+    if COORDINATION_DEGRADED.swap(false, Ordering::Relaxed) {
+        info!("Coordination backend is reachable again, node {} resumes coordination", node_id);
+    }
+}
+
+/// Applies the availability of the service under `key` published by the leader.
+pub(super) fn apply_availability(key: &str, backend_available: bool) {
+    let mut services = write_watched_services();
+    if let Some(service) = services.get_mut(key)
+        && service.backend_available != backend_available
+    {
+        info!("Leader reports backends of {}/{} as {}", service.namespace, service.name,
+              if backend_available { "available" } else { "unavailable" });
+        service.backend_available = backend_available;
+        SERVICE_LIST_CHANGED.notify_one();
+    }
+}
+
+/// What a backend has written or read so far, so that it only writes what changed, and the nodes
+/// it has seen.
+#[derive(Clone, Default)]
+pub(super) struct SyncState {
+    /// The packet time last pushed or pulled per service, so that only services seeing new
+    /// traffic are written.
+    synced_packet_times: Arc<Mutex<HashMap<String, i64>>>,
+    /// The availability last published per service while this node is the leader.
+    published_availability: Arc<Mutex<HashMap<String, bool>>>,
+    membership: Arc<Mutex<Membership>>,
+}
+
+impl SyncState {
+    /// Services whose packet time is newer than the one last pushed or pulled.
+    pub fn unsynced_services(&self) -> Vec<(String, ServiceData)> {
+        let services = read_watched_services();
+        let synced = self.synced_packet_times.lock().unwrap();
+        services
+            .iter()
+            .filter(|(key, service)| service.last_packet_time.get() > synced.get(*key).copied().unwrap_or(0))
+            .map(|(key, service)| (key.clone(), service.clone()))
+            .collect()
+    }
+
+    pub fn mark_synced(&self, key: String, packet_time: i64) {
+        self.synced_packet_times.lock().unwrap().insert(key, packet_time);
+    }
+
+    /// Moves the packet times of services forward to the latest seen on any node.
+    pub fn merge_packet_times(&self, latest: HashMap<String, i64>) {
+        let services = read_watched_services();
+        let mut synced = self.synced_packet_times.lock().unwrap();
+        for (key, packet_time) in latest {
+            if let Some(service) = services.get(&key)
+                && service.last_packet_time.touch(packet_time)
+            {
+                debug!("Service {} received traffic on another node at {}", key, packet_time);
+                // Not pushed back as this node's own traffic
+                synced.insert(key, packet_time);
+            }
+        }
+    }
+
+    /// Services whose availability changed since it was last published, and services
+    /// published but no longer watched.
+    pub fn availability_changes(&self) -> (Vec<(String, bool)>, Vec<String>) {
+        let services = read_watched_services();
+        let published = self.published_availability.lock().unwrap();
+        let changed = services
+            .iter()
+            .filter(|(key, service)| published.get(*key) != Some(&service.backend_available))
+            .map(|(key, service)| (key.clone(), service.backend_available))
+            .collect();
+        let removed = published.keys().filter(|key| !services.contains_key(*key)).cloned().collect();
+        (changed, removed)
+    }
+
+    /// Records the availability published for the service under `key`, `None` once removed.
+    pub fn mark_published(&self, key: String, backend_available: Option<bool>) {
+        let mut published = self.published_availability.lock().unwrap();
+        match backend_available {
+            Some(backend_available) => published.insert(key, backend_available),
+            None => published.remove(&key),
+        };
+    }
+
+    /// The availability last published per service.
+    pub fn published(&self) -> HashMap<String, bool> {
+        self.published_availability.lock().unwrap().clone()
+    }
+
+    /// Forgets what was published, as what a previous leader published may differ from this
+    /// node's view.
+    pub fn forget_published(&self) {
+        self.published_availability.lock().unwrap().clear();
+    }
+
+    pub fn membership(&self) -> Membership {
+        self.membership.lock().unwrap().clone()
+    }
+
+    /// Records the node seen holding leadership, `None` once it gave it up.
+    pub fn observe_leader(&self, leader_id: Option<String>) {
+        self.membership.lock().unwrap().leader_id = leader_id;
+    }
+
+    /// Records the other nodes seen alive and when they last sent a heartbeat.
+    pub fn observe_peers(&self, mut peers: Vec<PeerStatus>) {
+        peers.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        self.membership.lock().unwrap().peers = peers;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubernetes::hpa_controller::HPASuspensionController;
+    use crate::kubernetes::models::{PacketTime, WorkloadReference};
+    use crate::kubernetes::ops::{FakeCoordinator, FakeKube};
+
+    // The coordinator is global, so the tests installing one run one at a time, and each watches
+    // services of its own names as WATCHED_SERVICES is shared by every test
+
+    static INSTALLED: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// A fake coordinator installed until dropped.
+    struct Installed {
+        _serial: tokio::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Drop for Installed {
+        fn drop(&mut self) {
+            reset();
+        }
+    }
+
+    async fn install_fake(coordinator: Arc<FakeCoordinator>) -> Installed {
+        let serial = INSTALLED.lock().await;
+        install(coordinator);
+        Installed { _serial: serial }
+    }
+
+    fn watch_service(key: &str, service: ServiceData) {
+        write_watched_services().insert(key.to_string(), service);
+    }
+
+    #[tokio::test]
+    async fn only_the_leader_scales_unless_coordination_is_degraded() {
+        let coordinator = Arc::new(FakeCoordinator::new("node-a", false));
+        let _installed = install_fake(coordinator.clone()).await;
+        assert!(!may_scale());
+        coordinator.set_leader(true);
+        assert!(may_scale());
+
+        // A follower cut off from the backend runs on its own, as in single-node mode
+        coordinator.set_leader(false);
+        let error = anyhow::anyhow!("backend unreachable");
+        for _ in 0..crate::config::get().coordination_degraded_after_failures {
+            assert!(!may_scale());
+            record_failure("node-a", "Coordination sync failed", &error);
+        }
+        assert!(may_scale());
+        record_success("node-a");
+        assert!(!may_scale());
+
+        reset();
+        assert!(may_scale());
+    }
+
+    #[tokio::test]
+    async fn sync_pushes_own_packet_times_and_merges_newer_ones_of_other_nodes() {
+        let coordinator = Arc::new(FakeCoordinator::new("node-a", false));
+        let _installed = install_fake(coordinator.clone()).await;
+        let now = chrono::Utc::now().timestamp();
+        for key in ["coord-merge-newer", "coord-merge-older"] {
+            watch_service(key, ServiceData { last_packet_time: PacketTime::new(now - 60), ..Default::default() });
+        }
+        coordinator.set_node_state("node-b", HashMap::from([
+            ("coord-merge-newer".to_string(), now - 10),
+            ("coord-merge-older".to_string(), now - 120),
+        ]));
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let sync = tokio::spawn(sync_service_data(shutdown_rx));
+        while coordinator.pulls() == 0 {
+            tokio::task::yield_now().await;
+        }
+        shutdown.send(true).unwrap();
+        sync.await.unwrap().unwrap();
+
+        let pushed = coordinator.node_state("node-a");
+        assert_eq!(pushed.get("coord-merge-newer"), Some(&(now - 60)));
+        assert_eq!(pushed.get("coord-merge-older"), Some(&(now - 60)));
+        {
+            let services = read_watched_services();
+            assert_eq!(services["coord-merge-newer"].last_packet_time.get(), now - 10);
+            assert_eq!(services["coord-merge-older"].last_packet_time.get(), now - 60);
+        }
+        assert!(status().is_some_and(|status| status.last_push.is_some() && status.last_pull.is_some()));
+
+        // Traffic merged from another node is not pushed back as this node's own
+        coordinator.push_service_data().await.unwrap();
+        assert_eq!(coordinator.node_state("node-a").get("coord-merge-newer"), Some(&(now - 60)));
+    }
+
+    #[tokio::test]
+    async fn leader_publishes_availability_changes() {
+        let coordinator = Arc::new(FakeCoordinator::new("node-a", false));
+        let _installed = install_fake(coordinator.clone()).await;
+        watch_service("coord-availability", ServiceData { backend_available: true, ..Default::default() });
+
+        coordinator.push_service_list().await.unwrap();
+        assert_eq!(coordinator.availability().get("coord-availability"), None);
+
+        coordinator.set_leader(true);
+        coordinator.push_service_list().await.unwrap();
+        assert_eq!(coordinator.availability().get("coord-availability"), Some(&true));
+        write_watched_services().get_mut("coord-availability").unwrap().backend_available = false;
+        coordinator.push_service_list().await.unwrap();
+        assert_eq!(coordinator.availability().get("coord-availability"), Some(&false));
+        write_watched_services().remove("coord-availability");
+        coordinator.push_service_list().await.unwrap();
+        assert_eq!(coordinator.availability().get("coord-availability"), None);
+    }
+
+    #[tokio::test]
+    async fn forwarded_wake_is_requested_once_and_served_by_the_leader() {
+        let fake = Arc::new(FakeKube::default());
+        HPASuspensionController::install_shared(fake.clone());
+        let workload = WorkloadReference {
+            kind: "deployment".to_string(),
+            name: "coord-wake".to_string(),
+            namespace: "coordination-test".to_string(),
+        };
+        fake.add_workload(&workload, 0);
+        let coordinator = Arc::new(FakeCoordinator::new("node-a", false));
+        let _installed = install_fake(coordinator.clone()).await;
+        watch_service("coord-wake", ServiceData {
+            name: "coord-wake".to_string(),
+            namespace: "coordination-test".to_string(),
+            workloads: vec![workload.clone()],
+            previous_replicas: HashMap::from([(workload.clone(), 2)]),
+            backend_available: false,
+            ..Default::default()
+        });
+
+        forward_wake("coord-wake", "10.0.0.7").await.unwrap();
+        forward_wake("coord-wake", "10.0.0.7").await.unwrap();
+        assert_eq!(coordinator.wake_requests(), 1);
+        let pending = coordinator.pending_wakes();
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].source.as_str(), pending[0].node_id.as_str()), ("10.0.0.7", "node-a"));
+
+        // A follower leaves the request to the leader, which this node then becomes
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve_wake_requests(shutdown_rx));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(coordinator.acknowledged().is_empty());
+        coordinator.set_leader(true);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while coordinator.acknowledged().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the leader did not serve the wake");
+
+        assert_eq!(coordinator.acknowledged(), ["coord-wake"]);
+        assert!(coordinator.pending_wakes().is_empty());
+        assert_eq!(fake.workload(&workload).unwrap().replicas, 2);
+        shutdown.send(true).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn wakes_within_the_interval_are_forwarded_once_and_old_ones_forgotten() {
+        let mut forwarded = HashMap::new();
+        let interval = Duration::from_secs(1);
+        let start = tokio::time::Instant::now();
+
+        assert!(claim_forward(&mut forwarded, "10.96.0.1", start, interval));
+        assert!(!claim_forward(&mut forwarded, "10.96.0.1", start + Duration::from_millis(500), interval));
+        assert!(claim_forward(&mut forwarded, "10.96.0.2", start + Duration::from_millis(600), interval));
+
+        // The first service's forward has expired, so it is dropped when the next one is claimed
+        assert!(claim_forward(&mut forwarded, "10.96.0.2", start + Duration::from_millis(1700), interval));
+        assert_eq!(forwarded.len(), 1);
+        assert!(claim_forward(&mut forwarded, "10.96.0.1", start + Duration::from_millis(1800), interval));
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::{HashMap, HashSet};
+
+use super::models::ServiceData;
+
+/// Keys of the services a `scale-to-zero/dependencies` or `scale-to-zero/dependents` entry
+/// refers to: a `WATCHED_SERVICES` key, `namespace/name`, or a bare name in any namespace.
+pub fn resolve_target(services: &HashMap<String, ServiceData>, target: &str) -> Vec<String> {
+    if services.contains_key(target) {
+        return vec![target.to_string()];
+    }
+    services
+        .iter()
+        .filter(|(_, service)| match target.split_once('/') {
+            Some((namespace, name)) => service.namespace == namespace && service.name == name,
+            None => service.name == target,
+        })
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+/// Keys of the services that depend directly on `key`, declared either way round.
+pub fn direct_dependents(services: &HashMap<String, ServiceData>, key: &str) -> Vec<String> {
+    let Some(service) = services.get(key) else {
+        return Vec::new();
+    };
+    let mut dependents: Vec<String> = service
+        .dependents
+        .iter()
+        .flat_map(|target| resolve_target(services, target))
+        .collect();
+    dependents.extend(
+        services
+            .iter()
+            .filter(|(_, other)| {
+                other.dependencies.iter().any(|target| resolve_target(services, target).iter().any(|k| k == key))
+            })
+            .map(|(other, _)| other.clone()),
+    );
+    dependents.sort();
+    dependents.dedup();
+    dependents
+}
+
+/// Keys of the services `key` depends on, directly or through other services up to `max_depth`
+/// levels away, each listed once however many paths lead to it. Cycles end the walk.
+pub fn dependency_closure(services: &HashMap<String, ServiceData>, key: &str, max_depth: usize) -> Vec<String> {
+    let mut seen = HashSet::from([key.to_string()]);
+    let mut closure = Vec::new();
+    let mut level = vec![key.to_string()];
+    for _ in 0..max_depth {
+        let mut next = Vec::new();
+        for current in &level {
+            let Some(service) = services.get(current) else { continue };
+            let mut targets: Vec<String> = service
+                .dependencies
+                .iter()
+                .flat_map(|target| resolve_target(services, target))
+                .collect();
+            // "A depends on B" is also declared by B listing A as a dependent
+            targets.extend(
+                services
+                    .iter()
+                    .filter(|(_, other)| {
+                        other.dependents.iter().any(|target| resolve_target(services, target).contains(current))
+                    })
+                    .map(|(other, _)| other.clone()),
+            );
+            targets.sort();
+            for target in targets {
+                if seen.insert(target.clone()) {
+                    closure.push(target.clone());
+                    next.push(target);
+                }
+            }
+        }
+        if next.is_empty() {
+            break;
+        }
+        level = next;
+    }
+    closure
+}
+
+/// Groups of services whose dependencies lead back to themselves, directly or through other
+/// services, as their `WATCHED_SERVICES` keys. "A depends on B" is declared either by A listing
+/// B in its dependencies or by B listing A in its dependents.
+pub fn find_cycles(services: &HashMap<String, ServiceData>) -> Vec<Vec<String>> {
+    let mut edges: HashMap<&str, Vec<String>> = HashMap::new();
+    for (key, service) in services {
+        for target in &service.dependencies {
+            edges.entry(key.as_str()).or_default().extend(resolve_target(services, target));
+        }
+        for target in &service.dependents {
+            for dependent in resolve_target(services, target) {
+                if let Some((dependent, _)) = services.get_key_value(&dependent) {
+                    edges.entry(dependent.as_str()).or_default().push(key.clone());
+                }
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        edges: &edges,
+        index: HashMap::new(),
+        low_link: HashMap::new(),
+        stack: Vec::new(),
+        next_index: 0,
+        cycles: Vec::new(),
+    };
+    let mut keys: Vec<&str> = services.keys().map(String::as_str).collect();
+    keys.sort_unstable();
+    for key in keys {
+        if !tarjan.index.contains_key(key) {
+            tarjan.visit(key);
+        }
+    }
+    tarjan.cycles
+}
+
+/// Tarjan's strongly connected components; every component with more than one service, or a
+/// service depending on itself, is a cycle.
+struct Tarjan<'a> {
+    edges: &'a HashMap<&'a str, Vec<String>>,
+    index: HashMap<&'a str, usize>,
+    low_link: HashMap<&'a str, usize>,
+    stack: Vec<&'a str>,
+    next_index: usize,
+    cycles: Vec<Vec<String>>,
+}
+
+impl<'a> Tarjan<'a> {
+    fn visit(&mut self, key: &'a str) {
+        self.index.insert(key, self.next_index);
+        self.low_link.insert(key, self.next_index);
+        self.next_index += 1;
+        self.stack.push(key);
+
+        let targets = self.edges.get(key).map(Vec::as_slice).unwrap_or_default();
+        for target in targets {
+            let target = target.as_str();
+            if !self.index.contains_key(target) {
+                self.visit(target);
+                let low_link = self.low_link[key].min(self.low_link[target]);
+                self.low_link.insert(key, low_link);
+            } else if self.stack.contains(&target) {
+                let low_link = self.low_link[key].min(self.index[target]);
+                self.low_link.insert(key, low_link);
+            }
+        }
+
+        if self.low_link[key] == self.index[key] {
+            let mut component = Vec::new();
+            while let Some(member) = self.stack.pop() {
+                component.push(member.to_string());
+                if member == key {
+                    break;
+                }
+            }
+            let self_dependent = targets.iter().any(|target| target == key);
+            if component.len() > 1 || self_dependent {
+                component.sort();
+                self.cycles.push(component);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Services keyed by name, each depending on the services listed with it.
+    fn graph(dependencies: &[(&str, &[&str])]) -> HashMap<String, ServiceData> {
+        dependencies
+            .iter()
+            .map(|(name, targets)| {
+                let service = ServiceData {
+                    name: name.to_string(),
+                    namespace: "default".to_string(),
+                    dependencies: targets.iter().map(|target| target.to_string()).collect(),
+                    ..Default::default()
+                };
+                (name.to_string(), service)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diamond_lists_the_shared_dependency_once_after_both_paths() {
+        let services = graph(&[("a", &["b", "c"]), ("b", &["d"]), ("c", &["d"]), ("d", &[])]);
+
+        assert_eq!(dependency_closure(&services, "a", 10), ["b", "c", "d"]);
+    }
+
+    #[test]
+    fn closure_stops_at_max_depth() {
+        let services = graph(&[("a", &["b"]), ("b", &["c"]), ("c", &["d"]), ("d", &[])]);
+
+        assert_eq!(dependency_closure(&services, "a", 2), ["b", "c"]);
+        assert!(dependency_closure(&services, "a", 0).is_empty());
+    }
+
+    #[test]
+    fn service_depending_on_itself_is_a_cycle() {
+        let services = graph(&[("a", &["a"]), ("b", &[])]);
+
+        assert_eq!(find_cycles(&services), [["a"]]);
+    }
+
+    #[test]
+    fn services_depending_on_each_other_are_a_cycle() {
+        let services = graph(&[("a", &["b"]), ("b", &["a"]), ("c", &["a"])]);
+
+        assert_eq!(find_cycles(&services), [["a", "b"]]);
+    }
+
+    #[test]
+    fn transitive_cycle_is_found() {
+        let services = graph(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"]), ("d", &["c"])]);
+
+        assert_eq!(find_cycles(&services), [["a", "b", "c"]]);
+    }
+
+    #[test]
+    fn cycle_declared_through_dependents_is_found() {
+        // a depends on b, and a lists b as its dependent, so b depends on a as well
+        let mut services = graph(&[("a", &["b"]), ("b", &[])]);
+        services.get_mut("a").unwrap().dependents = vec!["default/b".to_string()];
+
+        assert_eq!(find_cycles(&services), [["a", "b"]]);
+    }
+
+    #[test]
+    fn acyclic_graph_has_no_cycles() {
+        let services = graph(&[("a", &["b", "c"]), ("b", &["d"]), ("c", &["d"]), ("d", &[])]);
+
+        assert!(find_cycles(&services).is_empty());
+    }
+}
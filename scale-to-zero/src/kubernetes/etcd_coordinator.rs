@@ -1,18 +1,48 @@
 use anyhow::{Context, Result};
-use etcd_rs::{Client, ClientConfig};
-use log::{info, debug};
+use async_trait::async_trait;
+use etcd_rs::{
+    Client, ClientConfig, EventType, KeyRange, KeyValueOp, LeaseOp, LeaseRevokeRequest, PutRequest, RangeRequest, TxnCmp, TxnOpResponse,
+    TxnRequest, WatchCreateRequest, WatchInbound, WatchOp,
+};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap as StdHashMap;
+use std::collections::{HashMap as StdHashMap, HashSet};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::kubernetes::coordination::{self, CoordinationBackend, Membership, PeerStatus, SyncState, WakeRequest};
 use crate::kubernetes::models::ServiceData;
 
 const LEADER_KEY: &str = "/etcd-coordination/leader";
 const NODE_HEARTBEAT_PREFIX: &str = "/etcd-coordination/heartbeats";
 const SERVICE_DATA_PREFIX: &str = "/etcd-coordination/services";
 const SERVICE_LIST_PREFIX: &str = "/etcd-coordination/service-list";
+const WAKE_REQUEST_PREFIX: &str = "/etcd-coordination/wake-requests";
 const HEARTBEAT_INTERVAL: u64 = 30;
 const LEADER_TTL: u64 = 45;
+/// Wait before campaigning again after losing the lease or the connection to etcd.
+const ELECTION_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Bounds of the backoff between attempts to watch `SERVICE_LIST_PREFIX` or `WAKE_REQUEST_PREFIX`.
+const MIN_WATCH_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_WATCH_BACKOFF: Duration = Duration::from_secs(60);
+
+async fn connect(etcd_endpoints: Vec<String>) -> Result<Client> {
+    Client::connect(ClientConfig {
+        endpoints: etcd_endpoints.into_iter().map(|s| s.into()).collect(),
+        auth: None,
+        connect_timeout: Duration::from_secs(10),
+        http2_keep_alive_interval: Duration::from_secs(30),
+    }).await
+        .context("Failed to connect to etcd")
+}
+
+/// Connects to etcd and reads the heartbeats of the agents, without joining them.
+pub async fn check_connectivity(etcd_endpoints: Vec<String>) -> Result<()> {
+    let client = connect(etcd_endpoints).await?;
+    client.get_by_prefix(format!("{}/", NODE_HEARTBEAT_PREFIX)).await
+        .context("Failed to read from etcd")?;
+    Ok(())
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EtcdServiceData {
@@ -20,6 +50,15 @@ pub struct EtcdServiceData {
     pub updated_at: i64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeHeartbeat {
+    pub node_id: String,
+    pub since: i64,
+    /// Rewritten on every renewal of the heartbeat lease.
+    #[serde(default)]
+    pub last_seen: i64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LeaderInfo {
     pub node_id: String,
@@ -27,9 +66,11 @@ pub struct LeaderInfo {
     pub lease_id: u64,
 }
 
+/// Whether a service's backends are available, as published by the leader under
+/// `SERVICE_LIST_PREFIX/<service>`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EtcdServiceListEntry {
-    pub ip: u32,
+    pub backend_available: bool,
     pub updated_at: i64,
 }
 
@@ -40,21 +81,21 @@ pub struct EtcdCoordinator {
     is_leader: Arc<Mutex<bool>>,
     heartbeat_lease_id: Arc<Mutex<Option<u64>>>,
     leader_lease_id: Arc<Mutex<Option<u64>>>,
+    sync: SyncState,
 }
 
-pub static ETCD_COORDINATOR: Mutex<Option<EtcdCoordinator>> = Mutex::new(None);
-
 impl EtcdCoordinator {
-    pub async fn new(etcd_endpoints: Vec<String>) -> Result<Self> {
-        let client = Client::connect(ClientConfig {
-            endpoints: etcd_endpoints.into_iter().map(|s| s.into()).collect(),
-            auth: None,
-            connect_timeout: Duration::from_secs(10),
-            http2_keep_alive_interval: Duration::from_secs(30),
-        }).await
-            .context("Failed to connect to etcd")?;
+    /// Connects to etcd and starts the heartbeat and leader election of this node.
+    pub async fn start(etcd_endpoints: Vec<String>) -> Result<Self> {
+        let coordinator = Self::new(etcd_endpoints).await?;
+        coordinator.spawn();
+        Ok(coordinator)
+    }
+
+    async fn new(etcd_endpoints: Vec<String>) -> Result<Self> {
+        let client = connect(etcd_endpoints).await?;
         
-        let node_id = Self::generate_node_id().await?;
+        let node_id = coordination::node_id().await?;
         
         info!("Created EtcdCoordinator for node: {}", node_id);
         
@@ -64,139 +105,451 @@ impl EtcdCoordinator {
             is_leader: Arc::new(Mutex::new(false)),
             heartbeat_lease_id: Arc::new(Mutex::new(None)),
             leader_lease_id: Arc::new(Mutex::new(None)),
+            sync: SyncState::default(),
         })
     }
 
-    async fn generate_node_id() -> Result<String> {
-        let hostname = tokio::fs::read_to_string("/etc/hostname")
-            .await
-            .unwrap_or_else(|_| "unknown".to_string())
-            .trim()
-            .to_string();
-        
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-        Ok(format!("{}-{}", hostname, timestamp))
+    fn spawn(&self) {
+        info!("Starting EtcdCoordinator for node: {}", self.node_id);
+
+        let coordinator = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = coordinator.heartbeat().await {
+                    coordination::record_failure(&coordinator.node_id, "Heartbeat interrupted", &e);
+                }
+                tokio::time::sleep(ELECTION_RETRY_INTERVAL).await;
+            }
+        });
+
+        let coordinator = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = coordinator.campaign().await {
+                    coordination::record_failure(&coordinator.node_id, "Leader election interrupted", &e);
+                }
+                coordinator.set_leader(false);
+                tokio::time::sleep(ELECTION_RETRY_INTERVAL).await;
+            }
+        });
     }
 
-    pub async fn start(&self) -> Result<()> {
-        info!("Starting EtcdCoordinator for node: {}", self.node_id);
-        
-        *self.is_leader.lock().unwrap() = true;
-        info!("Simplified mode: assuming leadership");
-        
-        Ok(())
+    fn set_leader(&self, leader: bool) {
+        let was_leader = std::mem::replace(&mut *self.is_leader.lock().unwrap(), leader);
+        if was_leader != leader {
+            if leader {
+                self.sync.forget_published();
+                self.sync.observe_leader(Some(self.node_id.clone()));
+            }
+            coordination::leadership_changed(&self.node_id, leader);
+        }
     }
 
-    pub fn is_leader(&self) -> bool {
-        *self.is_leader.lock().unwrap()
+    /// Keeps `NODE_HEARTBEAT_PREFIX/<node>` alive on a lease refreshed every `HEARTBEAT_INTERVAL`,
+    /// so the other nodes know this one is alive. Only returns once the lease is lost.
+    async fn heartbeat(&self) -> Result<()> {
+        let lease = self.client.grant_lease(Duration::from_secs(HEARTBEAT_INTERVAL * 3)).await
+            .context("Failed to grant a heartbeat lease")?;
+        *self.heartbeat_lease_id.lock().unwrap() = Some(lease.id as u64);
+        let mut keep_alive = self.client.keep_alive_for(lease.id).await
+            .context("Failed to keep the heartbeat lease alive")?;
+
+        let since = chrono::Utc::now().timestamp();
+        let mut renew = tokio::time::interval(Duration::from_secs(HEARTBEAT_INTERVAL));
+        loop {
+            renew.tick().await;
+            match keep_alive.keep_alive().await {
+                Ok(Some(response)) if response.ttl > 0 => {}
+                Ok(_) => anyhow::bail!("heartbeat lease {} expired", lease.id),
+                Err(e) => return Err(anyhow::Error::new(e).context("Failed to renew the heartbeat lease")),
+            }
+            let heartbeat = serde_json::to_string(&NodeHeartbeat {
+                node_id: self.node_id.clone(),
+                since,
+                last_seen: chrono::Utc::now().timestamp(),
+            })?;
+            self.client
+                .put(PutRequest::new(format!("{}/{}", NODE_HEARTBEAT_PREFIX, self.node_id), heartbeat).lease(lease.id))
+                .await
+                .context("Failed to write the node heartbeat")?;
+        }
     }
 
-    pub async fn update_service_packet_time(&self, service_ip: &str, packet_time: i64) -> Result<()> {
-        debug!("Would update service {} packet time to {} via etcd", service_ip, packet_time);
-        Ok(())
+    /// The nodes with a live heartbeat, recorded as the peers of this one.
+    async fn live_nodes(&self) -> Result<HashSet<String>> {
+        let response = self.client.get_by_prefix(format!("{}/", NODE_HEARTBEAT_PREFIX)).await
+            .context("Failed to read node heartbeats from etcd")?;
+        let mut live_nodes = HashSet::new();
+        let mut peers = Vec::new();
+        for kv in &response.kvs {
+            let Some((_, node)) = kv.key_str().rsplit_once('/') else {
+                continue;
+            };
+            if node != self.node_id {
+                let last_heartbeat = serde_json::from_slice::<NodeHeartbeat>(&kv.value)
+                    .map(|heartbeat| heartbeat.last_seen.max(heartbeat.since))
+                    .unwrap_or(0);
+                peers.push(PeerStatus { node_id: node.to_string(), last_heartbeat });
+            }
+            live_nodes.insert(node.to_string());
+        }
+        self.sync.observe_peers(peers);
+        Ok(live_nodes)
     }
 
-    pub async fn pull_service_data_from_etcd(&self) -> Result<()> {
-        debug!("Would pull service data from etcd");
+    /// Campaigns for `LEADER_KEY` under a fresh lease kept alive for as long as etcd can be
+    /// reached, and holds it or waits for it to become free. Only returns once the lease or the
+    /// watch on the key is lost.
+    async fn campaign(&self) -> Result<()> {
+        let lease = self.client.grant_lease(Duration::from_secs(LEADER_TTL)).await
+            .context("Failed to grant a leader lease")?;
+        *self.leader_lease_id.lock().unwrap() = Some(lease.id as u64);
+        let mut keep_alive = self.client.keep_alive_for(lease.id).await
+            .context("Failed to keep the leader lease alive")?;
+        let (mut watch, canceler) = self.client.watch(KeyRange::key(LEADER_KEY)).await
+            .context("Failed to watch the leader key")?;
+        let mut renew = tokio::time::interval(Duration::from_secs(LEADER_TTL / 3));
+
+        let result = loop {
+            if !self.is_leader() {
+                match self.try_acquire(lease.id).await {
+                    Ok(true) => self.set_leader(true),
+                    Ok(false) => {}
+                    Err(e) => break Err(e),
+                }
+            }
+            tokio::select! {
+                _ = renew.tick() => match keep_alive.keep_alive().await {
+                    Ok(Some(response)) if response.ttl > 0 => {}
+                    Ok(_) => break Err(anyhow::anyhow!("leader lease {} expired", lease.id)),
+                    Err(e) => break Err(anyhow::Error::new(e).context("Failed to renew the leader lease")),
+                },
+                inbound = watch.inbound() => match inbound {
+                    WatchInbound::Ready(response) => {
+                        for event in response.events {
+                            // Someone else holding the key means this node lost it
+                            let ours = event.event_type == EventType::Put && event.kv.lease == lease.id;
+                            if !ours {
+                                self.set_leader(false);
+                                let leader = serde_json::from_slice::<LeaderInfo>(&event.kv.value).ok()
+                                    .filter(|_| event.event_type == EventType::Put);
+                                self.sync.observe_leader(leader.map(|leader| leader.node_id));
+                            }
+                        }
+                    }
+                    WatchInbound::Interrupted(e) => break Err(anyhow::Error::new(e).context("Leader key watch interrupted")),
+                    WatchInbound::Closed => break Err(anyhow::anyhow!("leader key watch closed")),
+                },
+            }
+        };
+
+        let _ = canceler.cancel().await;
+        // Frees the key right away instead of after the TTL
+        let _ = self.client.revoke(LeaseRevokeRequest::new(lease.id)).await;
+        *self.leader_lease_id.lock().unwrap() = None;
+        result
+    }
+
+    /// Puts this node's `LeaderInfo` under `LEADER_KEY` bound to `lease_id` if no node holds it.
+    async fn try_acquire(&self, lease_id: i64) -> Result<bool> {
+        let leader_info = serde_json::to_string(&LeaderInfo {
+            node_id: self.node_id.clone(),
+            elected_at: chrono::Utc::now().timestamp(),
+            lease_id: lease_id as u64,
+        })?;
+        let txn = TxnRequest::new()
+            .when_create_revision(KeyRange::key(LEADER_KEY), TxnCmp::Equal, 0)
+            .and_then(PutRequest::new(LEADER_KEY, leader_info).lease(lease_id))
+            .or_else(RangeRequest::new(KeyRange::key(LEADER_KEY)));
+        let response = self.client.txn(txn).await.context("Failed to campaign for leadership")?;
+        if !response.succeeded {
+            let leader = response.responses.iter().find_map(|response| match response {
+                TxnOpResponse::Range(range) => range.kvs.first()
+                    .and_then(|kv| serde_json::from_slice::<LeaderInfo>(&kv.value).ok()),
+                _ => None,
+            });
+            if let Some(leader) = &leader {
+                debug!("Node {} is led by {}", self.node_id, leader.node_id);
+            }
+            self.sync.observe_leader(leader.map(|leader| leader.node_id));
+        }
+        Ok(response.succeeded)
+    }
+
+    /// Merges the packet times every live node pushed into `WATCHED_SERVICES`, keeping the latest
+    /// per service, so that a service is not scaled down while it receives traffic on another
+    /// node. Entries of nodes without a heartbeat are ignored, and the leader deletes them once
+    /// they have not been updated for `COORDINATION_DEAD_NODE_CLEANUP_SECONDS` (10 minutes by
+    /// default).
+    async fn pull_service_data_from_etcd(&self) -> Result<()> {
+        let live_nodes = self.live_nodes().await?;
+        let response = self.client.get_by_prefix(format!("{}/", SERVICE_DATA_PREFIX)).await
+            .context("Failed to read service data from etcd")?;
+        let now = chrono::Utc::now().timestamp();
+        let cleanup_after = coordination::dead_node_cleanup_after();
+
+        let mut latest: StdHashMap<String, i64> = StdHashMap::new();
+        let mut stale = Vec::new();
+        for kv in response.kvs {
+            // Keys are SERVICE_DATA_PREFIX/<service>/<node>
+            let Some((service_key, node)) = kv.key_str()[SERVICE_DATA_PREFIX.len() + 1..].rsplit_once('/') else {
+                continue;
+            };
+            let Ok(entry) = serde_json::from_slice::<EtcdServiceData>(&kv.value) else {
+                warn!("Ignoring unreadable service data under {}", kv.key_str());
+                continue;
+            };
+            if node != self.node_id && !live_nodes.contains(node) {
+                if now - entry.updated_at > cleanup_after {
+                    stale.push(kv.key_str().to_string());
+                }
+                continue;
+            }
+            let packet_time = latest.entry(service_key.to_string()).or_insert(0);
+            *packet_time = (*packet_time).max(entry.service_data.last_packet_time.get());
+        }
+
+        self.sync.merge_packet_times(latest);
+
+        if self.is_leader() {
+            for key in stale {
+                info!("Deleting service data {} of a dead node from etcd", key);
+                if let Err(e) = self.client.delete(KeyRange::key(key.as_str())).await {
+                    warn!("Failed to delete stale service data {}: {}", key, e);
+                }
+            }
+        }
         Ok(())
     }
 
-    pub async fn push_service_data_to_etcd(&self) -> Result<()> {
-        debug!("Would push service data to etcd");
+    /// Writes the packet times of services that received traffic on this node since the last
+    /// push under `SERVICE_DATA_PREFIX/<service>/<node>`.
+    async fn push_service_data_to_etcd(&self) -> Result<()> {
+        let changed = self.sync.unsynced_services();
+        let updated_at = chrono::Utc::now().timestamp();
+        for (key, service_data) in changed {
+            let packet_time = service_data.last_packet_time.get();
+            let value = serde_json::to_string(&EtcdServiceData { service_data, updated_at })?;
+            self.client.put(PutRequest::new(format!("{}/{}/{}", SERVICE_DATA_PREFIX, key, self.node_id), value)).await
+                .with_context(|| format!("Failed to write service data of {} to etcd", key))?;
+            self.sync.mark_synced(key, packet_time);
+        }
         Ok(())
     }
 
-    pub async fn pull_service_list_from_etcd(&self) -> Result<StdHashMap<u32, u32>> {
-        debug!("Would pull service list from etcd");
-        Ok(StdHashMap::new())
+    /// Applies the availability of every service published under `SERVICE_LIST_PREFIX`, and
+    /// returns the revision it was read at.
+    async fn pull_service_list_from_etcd(&self) -> Result<i64> {
+        let response = self.client.get_by_prefix(format!("{}/", SERVICE_LIST_PREFIX)).await
+            .context("Failed to read the service list from etcd")?;
+        for kv in &response.kvs {
+            self.apply_service_list_entry(kv.key_str(), &kv.value);
+        }
+        Ok(response.header.revision())
     }
 
-    pub async fn push_service_list_to_etcd(&self) -> Result<()> {
-        debug!("Would push service list to etcd");
+    /// Publishes the availability of services whose availability changed since the last push,
+    /// and removes services no longer watched. Only the leader publishes.
+    async fn push_service_list_to_etcd(&self) -> Result<()> {
+        if !self.is_leader() {
+            return Ok(());
+        }
+        let (changed, removed) = self.sync.availability_changes();
+
+        let updated_at = chrono::Utc::now().timestamp();
+        for (key, backend_available) in changed {
+            let value = serde_json::to_string(&EtcdServiceListEntry { backend_available, updated_at })?;
+            self.client.put(PutRequest::new(format!("{}/{}", SERVICE_LIST_PREFIX, key), value)).await
+                .with_context(|| format!("Failed to publish the availability of {} to etcd", key))?;
+            self.sync.mark_published(key, Some(backend_available));
+        }
+        for key in removed {
+            self.client.delete(KeyRange::key(format!("{}/{}", SERVICE_LIST_PREFIX, key))).await
+                .with_context(|| format!("Failed to remove {} from the service list in etcd", key))?;
+            self.sync.mark_published(key, None);
+        }
         Ok(())
     }
 
-    pub async fn cleanup(&self) {
-        info!("Cleaning up EtcdCoordinator for node: {}", self.node_id);
+    /// Follows `SERVICE_LIST_PREFIX` so that availability published by the leader reaches the
+    /// kernel maps of this node within a watch event. After every gap in the watch, whether a
+    /// lost connection or a compacted revision, the whole list is pulled again before watching
+    /// from where that pull left off.
+    async fn follow_service_list_with_backoff(&self) {
+        let mut backoff = MIN_WATCH_BACKOFF;
+        loop {
+            match self.follow_service_list().await {
+                Ok(()) => backoff = MIN_WATCH_BACKOFF,
+                Err(e) => {
+                    coordination::record_failure(&self.node_id, &format!("Service list watch interrupted, retrying in {:?}", backoff), &e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_WATCH_BACKOFF);
+                }
+            }
+        }
     }
-}
 
-pub async fn initialize_etcd_coordinator(etcd_endpoints: Vec<String>) -> Result<()> {
-    let coordinator = EtcdCoordinator::new(etcd_endpoints).await?;
-    coordinator.start().await?;
-    
-    *ETCD_COORDINATOR.lock().unwrap() = Some(coordinator);
-    Ok(())
-}
+    /// Pulls the service list and watches it until the watch ends.
+    async fn follow_service_list(&self) -> Result<()> {
+        let revision = self.pull_service_list_from_etcd().await?;
+        let request = WatchCreateRequest::create(KeyRange::prefix(format!("{}/", SERVICE_LIST_PREFIX)))
+            .start_revision(revision + 1);
+        let (mut watch, _canceler) = self.client.watch(request).await
+            .context("Failed to watch the service list")?;
+        loop {
+            match watch.inbound().await {
+                WatchInbound::Ready(response) => {
+                    for event in response.events {
+                        if event.event_type == EventType::Put {
+                            self.apply_service_list_entry(event.kv.key_str(), &event.kv.value);
+                        }
+                    }
+                    if response.canceled {
+                        debug!("Service list watch canceled by etcd, pulling it again");
+                        return Ok(());
+                    }
+                }
+                WatchInbound::Interrupted(e) => return Err(anyhow::Error::new(e)),
+                WatchInbound::Closed => return Ok(()),
+            }
+        }
+    }
 
-pub async fn update_packet_time_via_etcd(service_ip: &str, packet_time: i64) -> Result<()> {
-    let coordinator = {
-        ETCD_COORDINATOR.lock().unwrap().as_ref().cloned()
-    };
-    
-    if let Some(coordinator) = coordinator {
-        coordinator.update_service_packet_time(service_ip, packet_time).await?;
+    /// Applies the availability published under `key` to `WATCHED_SERVICES`, unless this node
+    /// is the leader publishing it.
+    fn apply_service_list_entry(&self, key: &str, value: &[u8]) {
+        if self.is_leader() {
+            return;
+        }
+        let Some(service_key) = key.strip_prefix(SERVICE_LIST_PREFIX).and_then(|key| key.strip_prefix('/')) else {
+            return;
+        };
+        let Ok(entry) = serde_json::from_slice::<EtcdServiceListEntry>(value) else {
+            warn!("Ignoring unreadable service list entry under {}", key);
+            return;
+        };
+        coordination::apply_availability(service_key, entry.backend_available);
     }
-    Ok(())
-}
 
-pub fn is_leader() -> bool {
-    ETCD_COORDINATOR.lock().unwrap()
-        .as_ref()
-        .map(|c| c.is_leader())
-        .unwrap_or(false)
+    /// Sends the wake requests under `WAKE_REQUEST_PREFIX` and then those put after them, until
+    /// the watch ends.
+    async fn follow_wake_requests(&self, requests: &mpsc::UnboundedSender<WakeRequest>) -> Result<()> {
+        let response = self.client.get_by_prefix(format!("{}/", WAKE_REQUEST_PREFIX)).await
+            .context("Failed to read wake requests from etcd")?;
+        for kv in &response.kvs {
+            send_wake_request(requests, kv.key_str(), &kv.value);
+        }
+        let request = WatchCreateRequest::create(KeyRange::prefix(format!("{}/", WAKE_REQUEST_PREFIX)))
+            .start_revision(response.header.revision() + 1);
+        let (mut watch, _canceler) = self.client.watch(request).await
+            .context("Failed to watch wake requests")?;
+        loop {
+            match watch.inbound().await {
+                WatchInbound::Ready(response) => {
+                    for event in response.events {
+                        if event.event_type == EventType::Put {
+                            send_wake_request(requests, event.kv.key_str(), &event.kv.value);
+                        }
+                    }
+                    if response.canceled {
+                        return Ok(());
+                    }
+                }
+                WatchInbound::Interrupted(e) => return Err(anyhow::Error::new(e)),
+                WatchInbound::Closed => return Ok(()),
+            }
+        }
+    }
 }
 
-pub async fn pull_service_data_from_etcd() -> Result<()> {
-    let coordinator = {
-        ETCD_COORDINATOR.lock().unwrap().as_ref().cloned()
-    };
-    
-    if let Some(coordinator) = coordinator {
-        coordinator.pull_service_data_from_etcd().await?;
+fn send_wake_request(requests: &mpsc::UnboundedSender<WakeRequest>, key: &str, value: &[u8]) {
+    match serde_json::from_slice::<WakeRequest>(value) {
+        Ok(request) => {
+            let _ = requests.send(request);
+        }
+        Err(_) => warn!("Ignoring unreadable wake request under {}", key),
     }
-    Ok(())
 }
 
-pub async fn push_service_data_to_etcd() -> Result<()> {
-    let coordinator = {
-        ETCD_COORDINATOR.lock().unwrap().as_ref().cloned()
-    };
-    
-    if let Some(coordinator) = coordinator {
-        coordinator.push_service_data_to_etcd().await?;
+#[async_trait]
+impl CoordinationBackend for EtcdCoordinator {
+    fn backend(&self) -> &'static str {
+        "etcd"
     }
-    Ok(())
-}
 
-pub async fn pull_service_list_from_etcd() -> Result<StdHashMap<u32, u32>> {
-    let coordinator = {
-        ETCD_COORDINATOR.lock().unwrap().as_ref().cloned()
-    };
-    
-    if let Some(coordinator) = coordinator {
-        return coordinator.pull_service_list_from_etcd().await;
+    fn node_id(&self) -> &str {
+        &self.node_id
     }
-    Ok(StdHashMap::new())
-}
 
-pub async fn push_service_list_to_etcd() -> Result<()> {
-    let coordinator = {
-        ETCD_COORDINATOR.lock().unwrap().as_ref().cloned()
-    };
-    
-    if let Some(coordinator) = coordinator {
-        coordinator.push_service_list_to_etcd().await?;
+    fn is_leader(&self) -> bool {
+        *self.is_leader.lock().unwrap()
+    }
+
+    fn membership(&self) -> Membership {
+        self.sync.membership()
+    }
+
+    async fn push_service_data(&self) -> Result<()> {
+        self.push_service_data_to_etcd().await
+    }
+
+    async fn pull_service_data(&self) -> Result<()> {
+        self.pull_service_data_from_etcd().await
+    }
+
+    async fn push_service_list(&self) -> Result<()> {
+        self.push_service_list_to_etcd().await
     }
-    Ok(())
-}
 
-pub async fn cleanup_etcd_coordinator() {
-    let coordinator = {
-        ETCD_COORDINATOR.lock().unwrap().as_ref().cloned()
-    };
-    
-    if let Some(coordinator) = coordinator {
-        coordinator.cleanup().await;
+    async fn watch_service_list(&self) {
+        self.follow_service_list_with_backoff().await
     }
-} 
\ No newline at end of file
+
+    /// Puts the request under `WAKE_REQUEST_PREFIX/<service>` on this node's heartbeat lease, so
+    /// it goes away with the node if the leader never serves it.
+    async fn request_wake(&self, request: &WakeRequest) -> Result<()> {
+        let value = serde_json::to_string(request)?;
+        let mut put = PutRequest::new(format!("{}/{}", WAKE_REQUEST_PREFIX, request.service), value);
+        if let Some(lease_id) = *self.heartbeat_lease_id.lock().unwrap() {
+            put = put.lease(lease_id as i64);
+        }
+        self.client.put(put).await
+            .with_context(|| format!("Failed to write the wake request for {} to etcd", request.service))?;
+        Ok(())
+    }
+
+    async fn watch_wake_requests(&self, requests: mpsc::UnboundedSender<WakeRequest>) {
+        let mut backoff = MIN_WATCH_BACKOFF;
+        loop {
+            match self.follow_wake_requests(&requests).await {
+                Ok(()) => backoff = MIN_WATCH_BACKOFF,
+                Err(e) => {
+                    coordination::record_failure(&self.node_id, &format!("Wake request watch interrupted, retrying in {:?}", backoff), &e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_WATCH_BACKOFF);
+                }
+            }
+        }
+    }
+
+    async fn acknowledge_wake(&self, service: &str) -> Result<()> {
+        self.client.delete(KeyRange::key(format!("{}/{}", WAKE_REQUEST_PREFIX, service))).await
+            .with_context(|| format!("Failed to delete the wake request for {} from etcd", service))?;
+        Ok(())
+    }
+
+    async fn cleanup(&self) {
+        info!("Cleaning up EtcdCoordinator for node: {}", self.node_id);
+        // Hands leadership over and leaves without waiting for the leases to expire
+        let leases = [
+            self.leader_lease_id.lock().unwrap().take(),
+            self.heartbeat_lease_id.lock().unwrap().take(),
+        ];
+        for lease_id in leases.into_iter().flatten() {
+            if let Err(e) = self.client.revoke(LeaseRevokeRequest::new(lease_id as i64)).await {
+                warn!("Failed to revoke lease {}: {}", lease_id, e);
+            }
+        }
+        self.set_leader(false);
+    }
+}
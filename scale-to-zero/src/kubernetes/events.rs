@@ -0,0 +1,64 @@
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use kube::Client;
+use log::warn;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::models::SERVICE_REFERENCES;
+
+/// When an event of a given reason was last published on an object, keyed by the object's
+/// UID (or namespace/name) and the reason.
+static LAST_PUBLISHED: Lazy<Mutex<HashMap<(String, String), Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Publishes an Event on `reference`, unless one with the same reason was published on it within
+/// `EVENT_INTERVAL_SECONDS`, so a flapping service shows up once rather than hundreds of times.
+/// Failures are only logged, as events are informational.
+pub async fn publish(client: &Client, reference: ObjectReference, type_: EventType, reason: &str, note: String) {
+    let object = reference.uid.clone().unwrap_or_else(|| {
+        format!(
+            "{}/{}",
+            reference.namespace.as_deref().unwrap_or_default(),
+            reference.name.as_deref().unwrap_or_default()
+        )
+    });
+    {
+        let now = Instant::now();
+        let interval = crate::config::get().event_interval;
+        let mut last_published = LAST_PUBLISHED.lock().unwrap();
+        last_published.retain(|_, published| now.duration_since(*published) < interval);
+        let throttle_key = (object, reason.to_string());
+        if last_published.contains_key(&throttle_key) {
+            return;
+        }
+        last_published.insert(throttle_key, now);
+    }
+
+    let reporter = Reporter {
+        controller: "scale-to-zero".to_string(),
+        instance: std::env::var("HOSTNAME").ok(),
+    };
+    let name = reference.name.clone().unwrap_or_default();
+    let recorder = Recorder::new(client.clone(), reporter, reference);
+    let event = Event {
+        type_,
+        reason: reason.to_string(),
+        note: Some(note),
+        action: reason.to_string(),
+        secondary: None,
+    };
+    if let Err(e) = recorder.publish(event).await {
+        warn!("Failed to publish {} event on {}: {}", reason, name, e);
+    }
+}
+
+/// Like `publish`, for the Service watched under `service_key` in `WATCHED_SERVICES`.
+pub async fn publish_for_service(client: &Client, service_key: &str, type_: EventType, reason: &str, note: String) {
+    let reference = SERVICE_REFERENCES.lock().unwrap().get(service_key).cloned();
+    if let Some(reference) = reference {
+        publish(client, reference, type_, reason, note).await;
+    }
+}
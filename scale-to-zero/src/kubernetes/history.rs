@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+
+use k8s_openapi::chrono;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use super::models::ServiceData;
+
+/// Scale actions kept in memory, up to `SCALE_HISTORY_SIZE`. Oldest entries are dropped first.
+static HISTORY: Lazy<Mutex<VecDeque<ScaleRecord>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// One scale action on a service, as served by the admin API.
+#[derive(Clone, Debug, Serialize)]
+pub struct ScaleRecord {
+    /// Unix time of the outcome.
+    pub time: i64,
+    pub key: String,
+    pub namespace: String,
+    pub service: String,
+    /// `scale-up`, `scale-down`, `hpa-suspend` or `hpa-restore`.
+    pub action: &'static str,
+    /// Source IP of the traffic that woke the service, `idle-timer`, `retry` or what else asked
+    /// for the action.
+    pub trigger: String,
+    /// `succeeded`, `failed`, `deferred`, `timed-out` or `dependency-not-ready`.
+    pub outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Appends an action on the service watched under `key`.
+pub fn record(
+    key: &str,
+    service: &ServiceData,
+    action: &'static str,
+    trigger: &str,
+    outcome: &'static str,
+    error: Option<String>,
+) {
+    let capacity = crate::config::get().scale_history_size;
+    if capacity == 0 {
+        return;
+    }
+    let record = ScaleRecord {
+        time: chrono::Utc::now().timestamp(),
+        key: key.to_string(),
+        namespace: service.namespace.clone(),
+        service: service.name.clone(),
+        action,
+        trigger: trigger.to_string(),
+        outcome,
+        error,
+    };
+    let mut history = HISTORY.lock().unwrap_or_else(PoisonError::into_inner);
+    while history.len() >= capacity {
+        history.pop_front();
+    }
+    history.push_back(record);
+}
+
+/// Recorded actions since Unix time `since`, oldest first, optionally only those of one
+/// namespace or of services of one name.
+pub fn query(namespace: Option<&str>, service: Option<&str>, since: Option<i64>) -> Vec<ScaleRecord> {
+    HISTORY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .filter(|record| namespace.is_none_or(|namespace| record.namespace == namespace))
+        .filter(|record| service.is_none_or(|service| record.service == service))
+        .filter(|record| since.is_none_or(|since| record.time >= since))
+        .cloned()
+        .collect()
+}
+
+/// The last `count` actions on the service watched under `key`, oldest first.
+pub fn recent(key: &str, count: usize) -> Vec<ScaleRecord> {
+    let history = HISTORY.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut recent: Vec<ScaleRecord> = history
+        .iter()
+        .rev()
+        .filter(|record| record.key == key)
+        .take(count)
+        .cloned()
+        .collect();
+    recent.reverse();
+    recent
+}
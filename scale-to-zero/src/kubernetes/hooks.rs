@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hyper::{Body, Client, Method, Request, Uri};
+use k8s_openapi::serde_json::json;
+use log::{info, warn};
+
+use super::models::ServiceData;
+
+/// Checks a `scale-to-zero/pre-scale-down-hook` URL. Only plain HTTP is supported, as hooks are
+/// expected to be in-cluster Services.
+pub fn parse_hook_url(value: &str) -> std::result::Result<String, String> {
+    let uri: Uri = value.trim().parse().map_err(|e| format!("'{}' is not a URL: {}", value, e))?;
+    if uri.scheme_str() != Some("http") || uri.host().is_none() {
+        return Err(format!("'{}' is not an http:// URL", value));
+    }
+    Ok(uri.to_string())
+}
+
+/// Whether a service is scaled down anyway when its hook fails, from
+/// `PRE_SCALE_DOWN_HOOK_FAIL_OPEN`. Off by default: the scale-down is retried later.
+pub fn fail_open() -> bool {
+    crate::config::get().pre_scale_down_hook_fail_open
+}
+
+/// POSTs the service's name, namespace and idle seconds to `url`, retrying up to
+/// `PRE_SCALE_DOWN_HOOK_ATTEMPTS` times (3 by default), each attempt given
+/// `PRE_SCALE_DOWN_HOOK_TIMEOUT_SECONDS` (10 by default). Succeeds on the first 2xx response.
+pub async fn call_pre_scale_down(url: &str, service: &ServiceData, idle_seconds: i64) -> Result<()> {
+    let config = crate::config::get();
+    let timeout = config.pre_scale_down_hook_timeout;
+    let attempts = config.pre_scale_down_hook_attempts;
+    let payload = json!({
+        "name": service.name,
+        "namespace": service.namespace,
+        "idleSeconds": idle_seconds,
+    })
+    .to_string();
+
+    let client = Client::new();
+    let mut last_error = None;
+    for attempt in 1..=attempts {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header("content-type", "application/json")
+            .body(Body::from(payload.clone()))
+            .context("invalid hook request")?;
+        let error = match tokio::time::timeout(timeout, client.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() => {
+                info!(target: "scale_down", "Pre-scale-down hook {} of {}/{} returned {}",
+                      url, service.namespace, service.name, response.status());
+                return Ok(());
+            }
+            Ok(Ok(response)) => anyhow::anyhow!("returned {}", response.status()),
+            Ok(Err(e)) => anyhow::Error::new(e),
+            Err(_) => anyhow::anyhow!("timed out after {:?}", timeout),
+        };
+        warn!(target: "scale_down", "Pre-scale-down hook {} of {}/{} failed (attempt {}/{}): {:#}",
+              url, service.namespace, service.name, attempt, attempts, error);
+        last_error = Some(error);
+        if attempt < attempts {
+            tokio::time::sleep(Duration::from_secs(1 << (attempt - 1).min(5))).await;
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("not called")))
+        .with_context(|| format!("pre-scale-down hook {} failed after {} attempts", url, attempts))
+}
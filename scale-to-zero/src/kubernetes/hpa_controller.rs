@@ -1,80 +1,170 @@
-use super::models::WATCHED_SERVICES;
+use super::history;
+use super::ops::{KubeClient, KubeOps};
+use super::models::{
+    read_watched_services, write_watched_services, HpaSuspendStrategy, ScaleTargetRef, ServiceData, WorkloadReference,
+    HPA_DELETIONS, HPA_RECREATIONS,
+};
 use anyhow::{Context, Result};
-use k8s_openapi::api::autoscaling::v2::HorizontalPodAutoscaler;
+use k8s_openapi::api::autoscaling::v2::{HorizontalPodAutoscaler, HorizontalPodAutoscalerBehavior, MetricSpec, MetricTarget, ResourceMetricSource};
 use k8s_openapi::serde_json;
-use kube::api::Api;
-use kube::Client;
+use kube::{Client, ResourceExt};
 use log::{info, warn, error};
 use std::collections::HashSet;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, OnceCell};
+
+/// Annotation on an HPA whose minReplicas the agent lowered, holding the original value.
+const ORIGINAL_MIN_REPLICAS_ANNOTATION: &str = "scale-to-zero/original-min-replicas";
+
+/// The strategy of services without a `scale-to-zero/hpa-suspend-strategy` annotation, from
+/// `HPA_SUSPEND_STRATEGY`.
+pub fn default_strategy() -> HpaSuspendStrategy {
+    crate::config::get().hpa_suspend_strategy
+}
+
+/// Parses `delete` or `min-replicas`.
+pub fn parse_strategy(value: &str) -> Option<HpaSuspendStrategy> {
+    match value.trim() {
+        "delete" => Some(HpaSuspendStrategy::Delete),
+        "min-replicas" => Some(HpaSuspendStrategy::MinReplicas),
+        _ => None,
+    }
+}
+
+/// The minReplicas a suspended HPA is lowered to, from `HPA_SUSPEND_MIN_REPLICAS`. 0 needs the
+/// HPAScaleToZero feature gate; with 1 the HPA idles as its target has no replicas.
+fn suspended_min_replicas() -> i32 {
+    crate::config::get().hpa_suspend_min_replicas
+}
+
+/// Compares the HPA of every HPA-enabled service with what the agent expects of it, every
+/// `HPA_RECONCILE_INTERVAL_SECONDS` (60 by default) until shutdown, and repairs at most
+/// `HPA_RECONCILE_MAX_REPAIRS` (5 by default) per pass: an HPA deleted by someone else is
+/// recreated, one recreated by someone else while the service is scaled down is suspended again.
+pub async fn reconcile(mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let controller = HPASuspensionController::shared().await?;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(crate::utils::with_jitter(crate::config::get().hpa_reconcile_interval)) => {}
+            _ = shutdown.changed() => {
+                info!("Shutting down, stopping HPA reconciliation");
+                return Ok(());
+            }
+        }
+
+        if !super::coordination::may_scale() || super::workload::dry_run() {
+            continue;
+        }
+
+        // Services being scaled are left to the scaler
+        let services: Vec<(String, ServiceData)> = read_watched_services()
+            .iter()
+            .filter(|(key, service)| {
+                service.hpa_enabled
+                    && !service.workload_missing
+                    && service.scale_up_started.is_none()
+                    && !super::retry::is_pending(key)
+            })
+            .map(|(key, service)| (key.clone(), service.clone()))
+            .collect();
+        let max_repairs = crate::config::get().hpa_reconcile_max_repairs;
+        let mut repairs = 0;
+        for (key, service) in services {
+            if repairs >= max_repairs {
+                info!(target: "hpa_reconcile", "Made {} HPA repairs, leaving the rest to the next pass", repairs);
+                break;
+            }
+            match controller.reconcile_service(&key, &service).await {
+                Ok(Some(repair)) => {
+                    repairs += 1;
+                    warn!(target: "hpa_reconcile", "Service {}/{}: {}", service.namespace, service.name, repair);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    repairs += 1;
+                    error!(target: "hpa_reconcile", "Failed to reconcile the HPA of {}/{}: {:#}", service.namespace, service.name, e);
+                }
+            }
+        }
+    }
+}
+
+static CONTROLLER: OnceCell<HPASuspensionController> = OnceCell::const_new();
 
 pub struct HPASuspensionController {
-    client: Client,
+    ops: Arc<dyn KubeOps>,
     suspended_hpas: Arc<Mutex<HashSet<String>>>,
 }
 
 impl HPASuspensionController {
-    pub async fn new() -> Result<Self> {
-        let client = Client::try_default().await?;
-        Ok(Self {
-            client,
+    /// The controller every HPA operation goes through, created on first use, so that all of
+    /// them share one client and one view of the suspended HPAs.
+    pub async fn shared() -> Result<&'static Self> {
+        CONTROLLER
+            .get_or_try_init(|| async {
+                let client = Client::try_default().await?;
+                Ok(Self::new(Arc::new(KubeClient::new(client))))
+            })
+            .await
+    }
+
+    /// Makes `shared` return a controller over `ops`, for tests of code that reaches the cluster
+    /// through it. Only the first call installs one.
+    #[cfg(test)]
+    pub fn install_shared(ops: Arc<dyn KubeOps>) {
+        let _ = CONTROLLER.set(Self::new(ops));
+    }
+
+    pub fn new(ops: Arc<dyn KubeOps>) -> Self {
+        Self {
+            ops,
             suspended_hpas: Arc::new(Mutex::new(HashSet::new())),
-        })
+        }
+    }
+
+    /// What the controller reads and changes the cluster through, shared with the scaler.
+    pub fn ops(&self) -> &Arc<dyn KubeOps> {
+        &self.ops
+    }
+
+    /// The replicas HPA `hpa_name` currently wants, the larger of its desired and current
+    /// replicas, or `None` if there is no such HPA.
+    pub async fn desired_replicas(&self, namespace: &str, hpa_name: &str) -> Result<Option<i32>> {
+        let hpa = self.ops.get_hpa(namespace, hpa_name).await?;
+        Ok(hpa.map(|hpa| {
+            hpa.status
+                .map(|status| status.desired_replicas.max(status.current_replicas.unwrap_or(0)))
+                .unwrap_or(0)
+        }))
+    }
+
+    /// The configuration of the existing HPA `hpa_name`, left as it is, or `None` if there is none.
+    pub async fn adopt_hpa(&self, namespace: &str, hpa_name: &str) -> Result<Option<super::models::HPAConfig>> {
+        let hpa = self.ops.get_hpa(namespace, hpa_name).await?;
+        Ok(hpa.as_ref().map(capture_config))
     }
 
     pub async fn delete_hpa(&self, namespace: &str, hpa_name: &str) -> Result<Option<super::models::HPAConfig>> {
-        let hpa_api: Api<HorizontalPodAutoscaler> = Api::namespaced(self.client.clone(), namespace);
-        
-        let hpa = match hpa_api.get(hpa_name).await {
-            Ok(hpa) => hpa,
+        let hpa = match self.ops.get_hpa(namespace, hpa_name).await {
+            Ok(Some(hpa)) => hpa,
+            Ok(None) => {
+                warn!("HPA {} not found in namespace {}, skipping deletion", hpa_name, namespace);
+                return Ok(None);
+            }
             Err(e) => {
-                warn!("HPA {} not found in namespace {}, skipping deletion: {}", hpa_name, namespace, e);
+                warn!("Failed to get HPA {}/{}, skipping deletion: {:#}", namespace, hpa_name, e);
                 return Ok(None);
             }
         };
 
-        let hpa_config = if let Some(spec) = &hpa.spec {
-            let min_replicas = spec.min_replicas;
-            let max_replicas = spec.max_replicas;
-            
-            let target_cpu_utilization_percentage = spec.metrics.as_ref()
-                .and_then(|metrics| metrics.iter().find(|m| {
-                    m.type_ == "Resource" && 
-                    m.resource.as_ref().map(|r| r.name == "cpu").unwrap_or(false)
-                }))
-                .and_then(|metric| metric.resource.as_ref())
-                .and_then(|resource| resource.target.average_utilization);
-
-            let metrics = spec.metrics.as_ref()
-                .map(|m| serde_json::to_string(m).ok())
-                .flatten();
-            
-            let behavior = spec.behavior.as_ref()
-                .map(|b| serde_json::to_string(b).ok())
-                .flatten();
-
-            super::models::HPAConfig {
-                min_replicas,
-                max_replicas,
-                target_cpu_utilization_percentage,
-                metrics,
-                behavior,
-            }
-        } else {
-            super::models::HPAConfig {
-                min_replicas: Some(1),
-                max_replicas: 5,
-                target_cpu_utilization_percentage: Some(80),
-                metrics: None,
-                behavior: None,
-            }
-        };
+        let hpa_config = capture_config(&hpa);
 
         info!("Deleting HPA {}/{}, storing config: min={:?}, max={}, cpu={:?}", 
               namespace, hpa_name, hpa_config.min_replicas, hpa_config.max_replicas, hpa_config.target_cpu_utilization_percentage);
 
-        hpa_api.delete(hpa_name, &Default::default()).await
-            .with_context(|| format!("Failed to delete HPA {}/{}", namespace, hpa_name))?;
+        self.ops.delete_hpa(namespace, hpa_name).await?;
 
         self.suspended_hpas.lock().unwrap().insert(format!("{}/{}", namespace, hpa_name));
         
@@ -82,53 +172,48 @@ impl HPASuspensionController {
         Ok(Some(hpa_config))
     }
 
-    pub async fn recreate_hpa(&self, namespace: &str, hpa_name: &str, deployment_name: &str, hpa_config: &super::models::HPAConfig) -> Result<()> {
-        let hpa_api: Api<HorizontalPodAutoscaler> = Api::namespaced(self.client.clone(), namespace);
-        
+    /// Creates the HPA `hpa_name` scaling `target`, or what the HPA it replaces scaled if that was
+    /// captured in `hpa_config`.
+    pub async fn recreate_hpa(&self, namespace: &str, hpa_name: &str, target: &WorkloadReference, hpa_config: &super::models::HPAConfig) -> Result<()> {
         info!("Recreating HPA {}/{} with config: min={:?}, max={}, cpu={:?}", 
               namespace, hpa_name, hpa_config.min_replicas, hpa_config.max_replicas, hpa_config.target_cpu_utilization_percentage);
 
-        if let Ok(_) = hpa_api.get(hpa_name).await {
+        if let Ok(Some(existing)) = self.ops.get_hpa(namespace, hpa_name).await {
             info!("HPA {}/{} already exists, deleting first", namespace, hpa_name);
-            hpa_api.delete(hpa_name, &Default::default()).await
+            self.ops.delete_hpa(namespace, hpa_name).await
                 .with_context(|| format!("Failed to delete existing HPA {}/{}", namespace, hpa_name))?;
 
-            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+            // Creating it again only succeeds once the old one is actually gone
+            let uid = existing.uid().unwrap_or_default();
+            if !self.ops.await_hpa_deleted(namespace, hpa_name, &uid, Duration::from_secs(30)).await {
+                anyhow::bail!("Timed out waiting for HPA {}/{} to be deleted", namespace, hpa_name);
+            }
         }
 
-        let mut hpa_spec = k8s_openapi::api::autoscaling::v2::HorizontalPodAutoscalerSpec {
-            scale_target_ref: k8s_openapi::api::autoscaling::v2::CrossVersionObjectReference {
-                api_version: Some("apps/v1".to_string()),
-                kind: "Deployment".to_string(),
-                name: deployment_name.to_string(),
+        let scale_target_ref = match &hpa_config.scale_target_ref {
+            Some(captured) => k8s_openapi::api::autoscaling::v2::CrossVersionObjectReference {
+                api_version: captured.api_version.clone(),
+                kind: captured.kind.clone(),
+                name: captured.name.clone(),
             },
+            None => {
+                let resource = super::workload::api_resource(&target.kind)?;
+                k8s_openapi::api::autoscaling::v2::CrossVersionObjectReference {
+                    api_version: Some(resource.api_version),
+                    kind: resource.kind,
+                    name: target.name.clone(),
+                }
+            }
+        };
+        let mut hpa_spec = k8s_openapi::api::autoscaling::v2::HorizontalPodAutoscalerSpec {
+            scale_target_ref,
             min_replicas: hpa_config.min_replicas,
             max_replicas: hpa_config.max_replicas,
             metrics: None,
             behavior: None,
         };
 
-        if let Some(cpu_target) = hpa_config.target_cpu_utilization_percentage {
-            let cpu_metric = k8s_openapi::api::autoscaling::v2::MetricSpec {
-                type_: "Resource".to_string(),
-                resource: Some(k8s_openapi::api::autoscaling::v2::ResourceMetricSource {
-                    name: "cpu".to_string(),
-                    target: k8s_openapi::api::autoscaling::v2::MetricTarget {
-                        type_: "Utilization".to_string(),
-                        average_utilization: Some(cpu_target),
-                        ..Default::default()
-                    },
-                }),
-                ..Default::default()
-            };
-            hpa_spec.metrics = Some(vec![cpu_metric]);
-        }
-
-        if let Some(metrics_json) = &hpa_config.metrics {
-            if let Ok(custom_metrics) = serde_json::from_str(metrics_json) {
-                hpa_spec.metrics = Some(custom_metrics);
-            }
-        }
+        hpa_spec.metrics = metric_specs(hpa_config);
 
         if let Some(behavior_json) = &hpa_config.behavior {
             if let Ok(behavior) = serde_json::from_str(behavior_json) {
@@ -149,38 +234,163 @@ impl HPASuspensionController {
             ..Default::default()
         };
 
-        hpa_api.create(&Default::default(), &hpa).await
+        self.ops.create_hpa(&hpa).await
             .with_context(|| format!("Failed to recreate HPA {}/{}", namespace, hpa_name))?;
 
+        if let Err(e) = self.ops.set_stored_hpa_config(target, None).await {
+            warn!("Failed to remove the stored config of HPA {}/{} from {}: {:#}", namespace, hpa_name, target, e);
+        }
+
         self.suspended_hpas.lock().unwrap().remove(&format!("{}/{}", namespace, hpa_name));
         
         info!("Successfully recreated HPA {}/{}", namespace, hpa_name);
         Ok(())
     }
 
-    pub async fn delete_hpa_for_service(&self, service_ip: &str) -> Result<()> {
+    /// Lowers the minReplicas of HPA `hpa_name` to `suspended_min_replicas()`, recording the
+    /// original value on the HPA. Returns false if the HPA does not exist.
+    pub async fn lower_min_replicas(&self, namespace: &str, hpa_name: &str) -> Result<bool> {
+        let Some(hpa) = self.ops.get_hpa(namespace, hpa_name).await? else {
+            warn!("HPA {} not found in namespace {}, skipping suspension", hpa_name, namespace);
+            return Ok(false);
+        };
+        // Lowered already, by an agent that restarted since
+        let original = match hpa.annotations().get(ORIGINAL_MIN_REPLICAS_ANNOTATION) {
+            Some(original) => original.clone(),
+            None => hpa.spec.as_ref().and_then(|spec| spec.min_replicas).unwrap_or(1).to_string(),
+        };
+        let min_replicas = suspended_min_replicas();
+        info!("Suspending HPA {}/{} by lowering its minReplicas from {} to {}", namespace, hpa_name, original, min_replicas);
+        let patch = serde_json::json!({
+            "metadata": {
+                "annotations": {
+                    ORIGINAL_MIN_REPLICAS_ANNOTATION: original
+                }
+            },
+            "spec": {
+                "minReplicas": min_replicas
+            }
+        });
+        self.ops.patch_hpa(namespace, hpa_name, patch).await
+            .with_context(|| format!("Failed to lower the minReplicas of HPA {}/{}", namespace, hpa_name))?;
+        self.suspended_hpas.lock().unwrap().insert(format!("{}/{}", namespace, hpa_name));
+        Ok(true)
+    }
+
+    /// Restores the minReplicas of HPA `hpa_name` lowered by `lower_min_replicas`, if it was.
+    /// Returns false if the HPA does not exist.
+    pub async fn restore_min_replicas(&self, namespace: &str, hpa_name: &str) -> Result<bool> {
+        let Some(hpa) = self.ops.get_hpa(namespace, hpa_name).await? else {
+            return Ok(false);
+        };
+        let Some(original) = hpa.annotations().get(ORIGINAL_MIN_REPLICAS_ANNOTATION).and_then(|value| value.parse::<i32>().ok()) else {
+            return Ok(true);
+        };
+        info!("Resuming HPA {}/{} by restoring its minReplicas to {}", namespace, hpa_name, original);
+        let patch = serde_json::json!({
+            "metadata": {
+                "annotations": {
+                    ORIGINAL_MIN_REPLICAS_ANNOTATION: null
+                }
+            },
+            "spec": {
+                "minReplicas": original
+            }
+        });
+        self.ops.patch_hpa(namespace, hpa_name, patch).await
+            .with_context(|| format!("Failed to restore the minReplicas of HPA {}/{}", namespace, hpa_name))?;
+        self.suspended_hpas.lock().unwrap().remove(&format!("{}/{}", namespace, hpa_name));
+        Ok(true)
+    }
+
+    /// Repairs the HPA of the service under `key` if it is not in the state the agent expects:
+    /// active while the service is up, suspended while it is scaled down. Returns what was
+    /// repaired, if anything.
+    async fn reconcile_service(&self, key: &str, service: &ServiceData) -> Result<Option<String>> {
+        let (Some(hpa_name), Some(target)) = (&service.hpa_name, service.hpa_target()) else {
+            return Ok(None);
+        };
+        let hpa = self.ops.get_hpa(&target.namespace, hpa_name).await?;
+        let lowered = hpa.as_ref().is_some_and(|hpa| hpa.annotations().contains_key(ORIGINAL_MIN_REPLICAS_ANNOTATION));
+        let active = hpa.is_some() && !lowered;
+
+        if service.backend_available {
+            if active && !service.hpa_deleted {
+                return Ok(None);
+            }
+            if active {
+                set_hpa_deleted(key, false);
+                return Ok(Some(format!("HPA {} was recreated by someone else, no longer treating it as suspended", hpa_name)));
+            }
+            self.recreate_hpa_for_service(key, "hpa-reconcile").await?;
+            return Ok(Some(if hpa.is_none() {
+                format!("HPA {} was missing although the service is up, recreated it", hpa_name)
+            } else {
+                format!("HPA {} was still suspended although the service is up, resumed it", hpa_name)
+            }));
+        }
+
+        if active {
+            // Suspended again the way scale-down does it, capturing the config it now has
+            set_hpa_deleted(key, false);
+            self.delete_hpa_for_service(key, "hpa-reconcile").await?;
+            return Ok(Some(format!("HPA {} was active although the service is scaled down, suspended it again", hpa_name)));
+        }
+        if !service.hpa_deleted {
+            set_hpa_deleted(key, true);
+            return Ok(Some(format!("HPA {} is already suspended or gone, treating it as suspended", hpa_name)));
+        }
+        Ok(None)
+    }
+
+    /// Suspends the HPA of the service under `service_ip`, recording it in the scale history as
+    /// asked for by `trigger`.
+    pub async fn delete_hpa_for_service(&self, service_ip: &str, trigger: &str) -> Result<()> {
+        if super::workload::dry_run() {
+            info!("Dry run, not suspending the HPA of service {}", service_ip);
+            return Ok(());
+        }
         let service_data = {
-            let watched_services = WATCHED_SERVICES.lock().unwrap();
+            let watched_services = read_watched_services();
             watched_services.get(service_ip).cloned()
         };
 
         if let Some(mut service_data) = service_data {
             if service_data.hpa_enabled && !service_data.hpa_deleted {
-                if let Some(hpa_name) = &service_data.hpa_name {
-                    match self.delete_hpa(&service_data.namespace, hpa_name).await {
+                if let (Some(hpa_name), Some(hpa_target)) = (&service_data.hpa_name, service_data.hpa_target()) {
+                    if service_data.hpa_suspend_strategy == HpaSuspendStrategy::MinReplicas {
+                        if let Err(e) = self.lower_min_replicas(&hpa_target.namespace, hpa_name).await {
+                            history::record(service_ip, &service_data, "hpa-suspend", trigger, "failed", Some(format!("{:#}", e)));
+                            return Err(e);
+                        }
+                        HPA_DELETIONS.fetch_add(1, Ordering::Relaxed);
+                        history::record(service_ip, &service_data, "hpa-suspend", trigger, "succeeded", None);
+                        if let Some(service) = write_watched_services().get_mut(service_ip) {
+                            service.hpa_deleted = true;
+                        }
+                        return Ok(());
+                    }
+                    match self.delete_hpa(&hpa_target.namespace, hpa_name).await {
                         Ok(Some(hpa_config)) => {
+                            HPA_DELETIONS.fetch_add(1, Ordering::Relaxed);
+                            history::record(service_ip, &service_data, "hpa-suspend", trigger, "succeeded", None);
+                            if let Err(e) = self.ops.set_stored_hpa_config(hpa_target, Some(&hpa_config)).await {
+                                warn!("Failed to store the config of HPA {} on {}, it is lost if the agent restarts: {:#}",
+                                      hpa_name, hpa_target, e);
+                            }
                             service_data.hpa_deleted = true;
                             service_data.hpa_config = Some(hpa_config);
-                            let mut watched_services = WATCHED_SERVICES.lock().unwrap();
+                            let mut watched_services = write_watched_services();
                             watched_services.insert(service_ip.to_string(), service_data);
                         }
                         Ok(None) => {
                             service_data.hpa_deleted = true;
-                            let mut watched_services = WATCHED_SERVICES.lock().unwrap();
+                            let mut watched_services = write_watched_services();
                             watched_services.insert(service_ip.to_string(), service_data);
                         }
                         Err(e) => {
                             error!("Failed to delete HPA for service {}: {}", service_ip, e);
+                            history::record(service_ip, &service_data, "hpa-suspend", trigger, "failed", Some(format!("{:#}", e)));
                             return Err(e);
                         }
                     }
@@ -191,29 +401,60 @@ impl HPASuspensionController {
         Ok(())
     }
     
-    pub async fn recreate_hpa_for_service(&self, service_ip: &str) -> Result<()> {
+    /// Restores the HPA of the service under `service_ip`, recording it in the scale history as
+    /// asked for by `trigger`.
+    pub async fn recreate_hpa_for_service(&self, service_ip: &str, trigger: &str) -> Result<()> {
+        if super::workload::dry_run() {
+            info!("Dry run, not restoring the HPA of service {}", service_ip);
+            return Ok(());
+        }
         let service_data = {
-            let watched_services = WATCHED_SERVICES.lock().unwrap();
+            let watched_services = read_watched_services();
             watched_services.get(service_ip).cloned()
         };
 
         if let Some(mut service_data) = service_data {
             if service_data.hpa_enabled {
-                if let (Some(hpa_name), Some(hpa_config)) = (service_data.hpa_name.clone(), service_data.hpa_config.clone()) {
-                    match self.recreate_hpa(&service_data.namespace, &hpa_name, &service_data.name, &hpa_config).await {
+                // An HPA that still exists only needs its minReplicas back; a missing one is recreated
+                if service_data.hpa_suspend_strategy == HpaSuspendStrategy::MinReplicas
+                    && let (Some(hpa_name), Some(hpa_target)) = (&service_data.hpa_name, service_data.hpa_target())
+                    && self.restore_min_replicas(&hpa_target.namespace, hpa_name).await?
+                {
+                    HPA_RECREATIONS.fetch_add(1, Ordering::Relaxed);
+                    history::record(service_ip, &service_data, "hpa-restore", trigger, "succeeded", None);
+                    if let Some(service) = write_watched_services().get_mut(service_ip) {
+                        service.hpa_deleted = false;
+                    }
+                    return Ok(());
+                }
+                // An HPA that was never suspended is left as it is
+                if !service_data.hpa_deleted
+                    && let Some(hpa_name) = &service_data.hpa_name
+                    && let Some(hpa_target) = service_data.hpa_target()
+                    && self.adopt_hpa(&hpa_target.namespace, hpa_name).await?.is_some()
+                {
+                    return Ok(());
+                }
+                if let (Some(hpa_name), Some(hpa_config), Some(hpa_target)) =
+                    (service_data.hpa_name.clone(), service_data.hpa_config.clone(), service_data.hpa_target().cloned())
+                {
+                    match self.recreate_hpa(&hpa_target.namespace, &hpa_name, &hpa_target, &hpa_config).await {
                         Ok(()) => {
+                            HPA_RECREATIONS.fetch_add(1, Ordering::Relaxed);
+                            history::record(service_ip, &service_data, "hpa-restore", trigger, "succeeded", None);
                             service_data.hpa_deleted = false;
-                            let mut watched_services = WATCHED_SERVICES.lock().unwrap();
+                            let mut watched_services = write_watched_services();
                             watched_services.insert(service_ip.to_string(), service_data);
                             info!("Successfully created/updated HPA {} for service {}", hpa_name, service_ip);
                         }
                         Err(e) => {
                             error!("Failed to create/recreate HPA for service {}: {}", service_ip, e);
+                            history::record(service_ip, &service_data, "hpa-restore", trigger, "failed", Some(format!("{:#}", e)));
                             return Err(e);
                         }
                     }
                 } else {
-                    warn!("Cannot create HPA for service {}: missing HPA name or config", service_ip);
+                    warn!("Cannot create HPA for service {}: missing HPA name, config or a Deployment or StatefulSet", service_ip);
                 }
             } else {
                 info!("Service {} is not HPA-enabled, skipping HPA creation", service_ip);
@@ -225,4 +466,234 @@ impl HPASuspensionController {
         Ok(())
     }
 
-} 
\ No newline at end of file
+}
+
+fn set_hpa_deleted(key: &str, deleted: bool) {
+    if let Some(service) = write_watched_services().get_mut(key) {
+        service.hpa_deleted = deleted;
+    }
+}
+
+/// The configuration of `hpa`, to recreate it from after it was deleted.
+fn capture_config(hpa: &HorizontalPodAutoscaler) -> super::models::HPAConfig {
+    if let Some(spec) = &hpa.spec {
+        let min_replicas = spec.min_replicas;
+        let max_replicas = spec.max_replicas;
+        
+        let utilization_target = |name: &str| spec.metrics.as_ref()
+            .and_then(|metrics| metrics.iter().find(|m| {
+                m.type_ == "Resource" && 
+                m.resource.as_ref().map(|r| r.name == name).unwrap_or(false)
+            }))
+            .and_then(|metric| metric.resource.as_ref())
+            .and_then(|resource| resource.target.average_utilization);
+        let target_cpu_utilization_percentage = utilization_target("cpu");
+        let target_memory_utilization_percentage = utilization_target("memory");
+
+        let metrics = spec.metrics.as_ref()
+            .map(|m| serde_json::to_string(m).ok())
+            .flatten();
+        
+        let behavior = spec.behavior.as_ref()
+            .map(|b| serde_json::to_string(b).ok())
+            .flatten();
+
+        super::models::HPAConfig {
+            min_replicas,
+            max_replicas,
+            target_cpu_utilization_percentage,
+            target_memory_utilization_percentage,
+            metrics,
+            behavior,
+            scale_target_ref: Some(ScaleTargetRef {
+                api_version: spec.scale_target_ref.api_version.clone(),
+                kind: spec.scale_target_ref.kind.clone(),
+                name: spec.scale_target_ref.name.clone(),
+            }),
+        }
+    } else {
+        super::models::HPAConfig {
+            min_replicas: Some(1),
+            max_replicas: 5,
+            target_cpu_utilization_percentage: Some(80),
+            target_memory_utilization_percentage: None,
+            metrics: None,
+            behavior: None,
+            scale_target_ref: None,
+        }
+    }
+}
+
+/// Parses a `scale-to-zero/hpa-metrics` annotation, a JSON list of HPA MetricSpecs.
+pub fn parse_metrics(value: &str) -> std::result::Result<Vec<MetricSpec>, String> {
+    serde_json::from_str(value).map_err(|e| format!("'{}' is not a JSON list of HPA metrics: {}", value, e))
+}
+
+/// Parses a `scale-to-zero/hpa-behavior` annotation, the JSON of an HPA's scaling behavior.
+pub fn parse_behavior(value: &str) -> std::result::Result<HorizontalPodAutoscalerBehavior, String> {
+    serde_json::from_str(value).map_err(|e| format!("'{}' is not a JSON HPA behavior: {}", value, e))
+}
+
+/// The metrics of an HPA created from `hpa_config`: its metric list, plus the CPU and memory
+/// utilization targets the list does not cover already.
+fn metric_specs(hpa_config: &super::models::HPAConfig) -> Option<Vec<MetricSpec>> {
+    let mut metrics: Vec<MetricSpec> = hpa_config
+        .metrics
+        .as_deref()
+        .and_then(|metrics| parse_metrics(metrics).ok())
+        .unwrap_or_default();
+    let targets = [
+        ("cpu", hpa_config.target_cpu_utilization_percentage),
+        ("memory", hpa_config.target_memory_utilization_percentage),
+    ];
+    for (name, target) in targets {
+        let Some(target) = target else { continue };
+        if metrics.iter().any(|metric| metric.resource.as_ref().is_some_and(|resource| resource.name == name)) {
+            continue;
+        }
+        metrics.push(MetricSpec {
+            type_: "Resource".to_string(),
+            resource: Some(ResourceMetricSource {
+                name: name.to_string(),
+                target: MetricTarget {
+                    type_: "Utilization".to_string(),
+                    average_utilization: Some(target),
+                    ..Default::default()
+                },
+            }),
+            ..Default::default()
+        });
+    }
+    (!metrics.is_empty()).then_some(metrics)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubernetes::models::HPAConfig;
+    use crate::kubernetes::ops::FakeKube;
+    use crate::kubernetes::workload::STORED_HPA_CONFIG_ANNOTATION;
+    use k8s_openapi::api::autoscaling::v2::{CrossVersionObjectReference, HorizontalPodAutoscalerSpec};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    const NAMESPACE: &str = "hpa-test";
+
+    fn deployment(name: &str) -> WorkloadReference {
+        WorkloadReference {
+            kind: "deployment".to_string(),
+            name: name.to_string(),
+            namespace: NAMESPACE.to_string(),
+        }
+    }
+
+    /// An HPA named like the Deployment it scales.
+    fn hpa(name: &str, min_replicas: i32, max_replicas: i32) -> HorizontalPodAutoscaler {
+        HorizontalPodAutoscaler {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some(NAMESPACE.to_string()),
+                ..Default::default()
+            },
+            spec: Some(HorizontalPodAutoscalerSpec {
+                scale_target_ref: CrossVersionObjectReference {
+                    api_version: Some("apps/v1".to_string()),
+                    kind: "Deployment".to_string(),
+                    name: name.to_string(),
+                },
+                min_replicas: Some(min_replicas),
+                max_replicas,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn spec(hpa: Option<HorizontalPodAutoscaler>) -> HorizontalPodAutoscalerSpec {
+        hpa.and_then(|hpa| hpa.spec).expect("HPA with a spec")
+    }
+
+    #[tokio::test]
+    async fn deleting_a_missing_hpa_is_skipped() {
+        let controller = HPASuspensionController::new(Arc::new(FakeKube::default()));
+
+        assert_eq!(controller.delete_hpa(NAMESPACE, "missing").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn suspending_deletes_the_hpa_and_keeps_its_config() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("web"), 2);
+        fake.add_hpa(hpa("web", 2, 5));
+        write_watched_services().insert("hpa-web".to_string(), ServiceData {
+            name: "web".to_string(),
+            namespace: NAMESPACE.to_string(),
+            workloads: vec![deployment("web")],
+            hpa_enabled: true,
+            hpa_name: Some("web".to_string()),
+            ..Default::default()
+        });
+        let controller = HPASuspensionController::new(fake.clone());
+
+        controller.delete_hpa_for_service("hpa-web", "test").await.unwrap();
+
+        assert!(fake.hpa(NAMESPACE, "web").is_none());
+        let annotations = fake.workload(&deployment("web")).unwrap().annotations;
+        let stored: HPAConfig = serde_json::from_str(&annotations[STORED_HPA_CONFIG_ANNOTATION]).unwrap();
+        assert_eq!((stored.min_replicas, stored.max_replicas), (Some(2), 5));
+        let watched = read_watched_services()["hpa-web"].clone();
+        assert!(watched.hpa_deleted);
+        assert_eq!(watched.hpa_config, Some(stored));
+    }
+
+    #[tokio::test]
+    async fn recreating_replaces_an_existing_hpa() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("api"), 1);
+        fake.add_hpa(hpa("api", 1, 3));
+        let config = HPAConfig {
+            min_replicas: Some(2),
+            max_replicas: 7,
+            target_cpu_utilization_percentage: Some(80),
+            target_memory_utilization_percentage: None,
+            metrics: None,
+            behavior: None,
+            scale_target_ref: None,
+        };
+        let controller = HPASuspensionController::new(fake.clone());
+
+        controller.recreate_hpa(NAMESPACE, "api", &deployment("api"), &config).await.unwrap();
+
+        let recreated = fake.hpa(NAMESPACE, "api").unwrap();
+        assert!(recreated.annotations().contains_key("scale-to-zero/recreated-at"));
+        let spec = spec(Some(recreated));
+        assert_eq!((spec.min_replicas, spec.max_replicas), (Some(2), 7));
+        assert_eq!(spec.scale_target_ref.kind, "Deployment");
+        let cpu = spec.metrics.unwrap().into_iter().find_map(|metric| metric.resource).unwrap();
+        assert_eq!((cpu.name.as_str(), cpu.target.average_utilization), ("cpu", Some(80)));
+    }
+
+    #[tokio::test]
+    async fn lowered_min_replicas_are_restored() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_hpa(hpa("batch", 3, 6));
+        let controller = HPASuspensionController::new(fake.clone());
+
+        assert!(controller.lower_min_replicas(NAMESPACE, "batch").await.unwrap());
+        let lowered = fake.hpa(NAMESPACE, "batch").unwrap();
+        assert_eq!(lowered.annotations().get(ORIGINAL_MIN_REPLICAS_ANNOTATION).map(String::as_str), Some("3"));
+        assert_eq!(spec(Some(lowered)).min_replicas, Some(suspended_min_replicas()));
+
+        assert!(controller.restore_min_replicas(NAMESPACE, "batch").await.unwrap());
+        let restored = fake.hpa(NAMESPACE, "batch").unwrap();
+        assert!(!restored.annotations().contains_key(ORIGINAL_MIN_REPLICAS_ANNOTATION));
+        assert_eq!(spec(Some(restored)).min_replicas, Some(3));
+    }
+
+    #[tokio::test]
+    async fn lowering_a_missing_hpa_reports_it() {
+        let controller = HPASuspensionController::new(Arc::new(FakeKube::default()));
+
+        assert!(!controller.lower_min_replicas(NAMESPACE, "missing").await.unwrap());
+    }
+}
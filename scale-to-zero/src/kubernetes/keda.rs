@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use k8s_openapi::serde_json::json;
+use kube::api::{Api, ApiResource, DynamicObject, GroupVersionKind, Patch, PatchParams};
+use kube::Client;
+use log::info;
+
+use super::workload::{self, FIELD_MANAGER};
+
+/// Annotation that makes KEDA hold a ScaledObject's workload at the given replicas.
+const PAUSED_REPLICAS_ANNOTATION: &str = "autoscaling.keda.sh/paused-replicas";
+
+fn api(client: &Client, namespace: &str) -> Api<DynamicObject> {
+    let resource = ApiResource::from_gvk(&GroupVersionKind::gvk("keda.sh", "v1alpha1", "ScaledObject"));
+    Api::namespaced_with(client.clone(), namespace, &resource)
+}
+
+/// Pauses ScaledObject `name` at `replicas`, so KEDA neither scales the workload back up nor
+/// touches it while it is scaled down.
+pub async fn pause(client: &Client, namespace: &str, name: &str, replicas: i32) -> Result<()> {
+    info!(target: "scale_down", "Pausing KEDA ScaledObject {}/{} at {} replicas", namespace, name, replicas);
+    set_paused_replicas(client, namespace, name, Some(replicas.to_string())).await
+}
+
+/// Hands the workload of ScaledObject `name` back to KEDA.
+pub async fn resume(client: &Client, namespace: &str, name: &str) -> Result<()> {
+    info!(target: "scale_up", "Resuming KEDA ScaledObject {}/{}", namespace, name);
+    set_paused_replicas(client, namespace, name, None).await
+}
+
+async fn set_paused_replicas(client: &Client, namespace: &str, name: &str, replicas: Option<String>) -> Result<()> {
+    if workload::dry_run() {
+        info!("Dry run, leaving KEDA ScaledObject {}/{} as it is", namespace, name);
+        return Ok(());
+    }
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "annotations": {
+                PAUSED_REPLICAS_ANNOTATION: replicas
+            }
+        }
+    }));
+    let params = PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    };
+    api(client, namespace)
+        .patch(name, &params, &patch)
+        .await
+        .with_context(|| format!("Failed to annotate KEDA ScaledObject {}/{}", namespace, name))?;
+    Ok(())
+}
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::api::core::v1::ConfigMap;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use k8s_openapi::chrono;
+use k8s_openapi::serde_json::{self, json};
+use kube::api::{Api, ObjectMeta, Patch, PatchParams, PostParams};
+use kube::runtime::{watcher, WatchStreamExt};
+use kube::Client;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::coordination::{self, CoordinationBackend, Membership, PeerStatus, SyncState, WakeRequest};
+use super::workload::FIELD_MANAGER;
+
+/// Lease the leader holds, and ConfigMap the nodes share their state through.
+const LEASE_NAME: &str = "scale-to-zero-leader";
+const STATE_CONFIG_MAP: &str = "scale-to-zero-coordination";
+/// ConfigMap key the leader publishes availability under; each node writes `node.<node>`, and
+/// followers `wake.<service>`.
+const AVAILABILITY_KEY: &str = "availability";
+const NODE_KEY_PREFIX: &str = "node.";
+const WAKE_KEY_PREFIX: &str = "wake.";
+const LEASE_DURATION_SECONDS: i32 = 30;
+const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(10);
+/// A node rewrites its state at least this often, so the others know it is alive.
+const HEARTBEAT_INTERVAL_SECONDS: i64 = 30;
+/// The leader publishes availability at most this often, to spare the apiserver.
+const MIN_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The packet times a node has seen, as stored under `node.<node>` in the state ConfigMap.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct NodeState {
+    updated_at: i64,
+    packet_times: HashMap<String, i64>,
+}
+
+/// Coordinates through the Kubernetes API instead of etcd: the leader holds a
+/// `coordination.k8s.io/v1` Lease, and packet times and availability are shared through a
+/// ConfigMap, both in `COORDINATION_NAMESPACE` (the agent's `POD_NAMESPACE` by default).
+#[derive(Clone)]
+pub struct KubeCoordinator {
+    client: Client,
+    namespace: String,
+    node_id: String,
+    is_leader: Arc<Mutex<bool>>,
+    /// The packet times of this node, written as a whole under its key.
+    own_state: Arc<Mutex<NodeState>>,
+    last_published: Arc<Mutex<Option<tokio::time::Instant>>>,
+    sync: SyncState,
+}
+
+impl KubeCoordinator {
+    /// Connects to the cluster and starts the leader election of this node.
+    pub async fn start() -> Result<Self> {
+        // The agent's own namespace from the downward API, unless configured
+        let namespace = crate::config::get()
+            .coordination_namespace
+            .clone()
+            .or_else(|| std::env::var("POD_NAMESPACE").ok().filter(|value| !value.is_empty()))
+            .unwrap_or_else(|| "default".to_string());
+        let coordinator = Self {
+            client: Client::try_default().await?,
+            namespace,
+            node_id: coordination::node_id().await?,
+            is_leader: Arc::new(Mutex::new(false)),
+            own_state: Arc::new(Mutex::new(NodeState::default())),
+            last_published: Arc::new(Mutex::new(None)),
+            sync: SyncState::default(),
+        };
+        info!("Starting KubeCoordinator for node {} in namespace {}", coordinator.node_id, coordinator.namespace);
+
+        let elector = coordinator.clone();
+        tokio::spawn(async move {
+            loop {
+                match elector.try_lead().await {
+                    Ok(leader) => elector.set_leader(leader),
+                    Err(e) => {
+                        coordination::record_failure(&elector.node_id, "Leader election failed", &e);
+                        elector.set_leader(false);
+                    }
+                }
+                tokio::time::sleep(LEASE_RENEW_INTERVAL).await;
+            }
+        });
+        Ok(coordinator)
+    }
+
+    fn set_leader(&self, leader: bool) {
+        let was_leader = std::mem::replace(&mut *self.is_leader.lock().unwrap(), leader);
+        if was_leader != leader {
+            if leader {
+                self.sync.forget_published();
+                self.sync.observe_leader(Some(self.node_id.clone()));
+            }
+            coordination::leadership_changed(&self.node_id, leader);
+        }
+    }
+
+    fn leases(&self) -> Api<Lease> {
+        Api::namespaced(self.client.clone(), &self.namespace)
+    }
+
+    fn config_maps(&self) -> Api<ConfigMap> {
+        Api::namespaced(self.client.clone(), &self.namespace)
+    }
+
+    /// The state ConfigMap as it changes, relisted after every gap in the watch.
+    fn watch_state(&self) -> BoxStream<'static, Result<ConfigMap, watcher::Error>> {
+        let config = watcher::Config::default().fields(&format!("metadata.name={}", STATE_CONFIG_MAP));
+        watcher(self.config_maps(), config).default_backoff().applied_objects().boxed()
+    }
+
+    /// Acquires or renews `LEASE_NAME` if it is free, expired or already held by this node.
+    /// Conflicting writes are settled by the apiserver through the Lease's resourceVersion.
+    async fn try_lead(&self) -> Result<bool> {
+        let now = chrono::Utc::now();
+        let Some(mut lease) = self.leases().get_opt(LEASE_NAME).await.context("Failed to get the leader Lease")? else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(LEASE_NAME.to_string()),
+                    ..Default::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(self.node_id.clone()),
+                    acquire_time: Some(MicroTime(now)),
+                    renew_time: Some(MicroTime(now)),
+                    lease_duration_seconds: Some(LEASE_DURATION_SECONDS),
+                    lease_transitions: Some(0),
+                }),
+            };
+            return match self.leases().create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+                Err(e) => Err(e).context("Failed to create the leader Lease"),
+            };
+        };
+
+        let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+        let held = spec.holder_identity.as_deref() == Some(self.node_id.as_str());
+        let expired = spec.renew_time.as_ref().is_none_or(|renewed| {
+            let duration = spec.lease_duration_seconds.unwrap_or(LEASE_DURATION_SECONDS);
+            renewed.0 + chrono::Duration::seconds(duration as i64) < now
+        });
+        if !held && !expired {
+            debug!("Node {} is led by {}", self.node_id, spec.holder_identity.as_deref().unwrap_or("nobody"));
+            self.sync.observe_leader(spec.holder_identity.clone());
+            return Ok(false);
+        }
+        if !held {
+            spec.holder_identity = Some(self.node_id.clone());
+            spec.acquire_time = Some(MicroTime(now));
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+        }
+        spec.renew_time = Some(MicroTime(now));
+        spec.lease_duration_seconds = Some(LEASE_DURATION_SECONDS);
+        match self.leases().replace(LEASE_NAME, &PostParams::default(), &lease).await {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e).context("Failed to renew the leader Lease"),
+        }
+    }
+
+    /// Sets `data` keys of the state ConfigMap in one write, creating it if needed, and removes
+    /// those set to `None`. Nodes only ever write their own keys, so a merge patch leaves those
+    /// of the others alone.
+    async fn write_state(&self, data: HashMap<String, Option<String>>) -> Result<()> {
+        let params = PatchParams {
+            field_manager: Some(FIELD_MANAGER.to_string()),
+            ..Default::default()
+        };
+        let patch = Patch::Merge(json!({ "data": data }));
+        match self.config_maps().patch(STATE_CONFIG_MAP, &params, &patch).await {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                let config_map = ConfigMap {
+                    metadata: ObjectMeta {
+                        name: Some(STATE_CONFIG_MAP.to_string()),
+                        ..Default::default()
+                    },
+                    data: Some(data.into_iter().filter_map(|(key, value)| Some((key, value?))).collect()),
+                    ..Default::default()
+                };
+                match self.config_maps().create(&PostParams::default(), &config_map).await {
+                    Ok(_) => Ok(()),
+                    // Another node created it meanwhile
+                    Err(kube::Error::Api(e)) if e.code == 409 => {
+                        self.config_maps().patch(STATE_CONFIG_MAP, &params, &patch).await
+                            .context("Failed to write the coordination ConfigMap")?;
+                        Ok(())
+                    }
+                    Err(e) => Err(e).context("Failed to create the coordination ConfigMap"),
+                }
+            }
+            Err(e) => Err(e).context("Failed to write the coordination ConfigMap"),
+        }
+    }
+}
+
+/// ConfigMap key of the wake request for `service`. Service keys are ClusterIPs or
+/// "namespace/name", and neither `/` nor `:` may appear in ConfigMap keys.
+fn wake_key(service: &str) -> String {
+    format!("{}{}", WAKE_KEY_PREFIX, service.replace('/', "_").replace(':', "-"))
+}
+
+#[async_trait]
+impl CoordinationBackend for KubeCoordinator {
+    fn backend(&self) -> &'static str {
+        "kube"
+    }
+
+    fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    fn is_leader(&self) -> bool {
+        *self.is_leader.lock().unwrap()
+    }
+
+    fn membership(&self) -> Membership {
+        self.sync.membership()
+    }
+
+    /// Rewrites this node's packet times as one ConfigMap key, only when they changed or its
+    /// heartbeat is due.
+    async fn push_service_data(&self) -> Result<()> {
+        let changed = self.sync.unsynced_services();
+        let now = chrono::Utc::now().timestamp();
+        let state = {
+            let mut state = self.own_state.lock().unwrap();
+            if changed.is_empty() && now - state.updated_at < HEARTBEAT_INTERVAL_SECONDS {
+                return Ok(());
+            }
+            for (key, service) in &changed {
+                state.packet_times.insert(key.clone(), service.last_packet_time.get());
+            }
+            let watched = super::models::read_watched_services();
+            state.packet_times.retain(|key, _| watched.contains_key(key));
+            state.updated_at = now;
+            state.clone()
+        };
+
+        let key = format!("{}{}", NODE_KEY_PREFIX, self.node_id);
+        let value = serde_json::to_string(&state)?;
+        self.write_state(HashMap::from([(key, Some(value))])).await?;
+        for (key, service) in changed {
+            self.sync.mark_synced(key, service.last_packet_time.get());
+        }
+        Ok(())
+    }
+
+    /// Merges the packet times of nodes that wrote their state within three heartbeats. The
+    /// leader removes the state of nodes silent for `COORDINATION_DEAD_NODE_CLEANUP_SECONDS`.
+    async fn pull_service_data(&self) -> Result<()> {
+        let Some(config_map) = self.config_maps().get_opt(STATE_CONFIG_MAP).await
+            .context("Failed to read the coordination ConfigMap")?
+        else {
+            return Ok(());
+        };
+        let now = chrono::Utc::now().timestamp();
+        let cleanup_after = coordination::dead_node_cleanup_after();
+
+        let mut latest: HashMap<String, i64> = HashMap::new();
+        let mut dead = HashMap::new();
+        let mut peers = Vec::new();
+        for (key, value) in config_map.data.unwrap_or_default() {
+            let Some(node) = key.strip_prefix(NODE_KEY_PREFIX) else {
+                continue;
+            };
+            let Ok(state) = serde_json::from_str::<NodeState>(&value) else {
+                warn!("Ignoring unreadable node state under {}", key);
+                continue;
+            };
+            let silent_for = now - state.updated_at;
+            if node != self.node_id && silent_for > 3 * HEARTBEAT_INTERVAL_SECONDS {
+                if silent_for > cleanup_after {
+                    dead.insert(key, None);
+                }
+                continue;
+            }
+            if node != self.node_id {
+                peers.push(PeerStatus { node_id: node.to_string(), last_heartbeat: state.updated_at });
+            }
+            for (service_key, packet_time) in state.packet_times {
+                let latest = latest.entry(service_key).or_insert(0);
+                *latest = (*latest).max(packet_time);
+            }
+        }
+        self.sync.merge_packet_times(latest);
+        self.sync.observe_peers(peers);
+
+        if self.is_leader() && !dead.is_empty() {
+            info!("Removing the state of dead nodes {:?} from the coordination ConfigMap", dead.keys().collect::<Vec<_>>());
+            self.write_state(dead).await?;
+        }
+        Ok(())
+    }
+
+    /// Publishes the availability of all services as one ConfigMap key when any of it changed,
+    /// at most every `MIN_PUBLISH_INTERVAL`.
+    async fn push_service_list(&self) -> Result<()> {
+        if !self.is_leader() {
+            return Ok(());
+        }
+        let (changed, removed) = self.sync.availability_changes();
+        if changed.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+        if self.last_published.lock().unwrap().is_some_and(|last| last.elapsed() < MIN_PUBLISH_INTERVAL) {
+            return Ok(());
+        }
+
+        let mut availability = self.sync.published();
+        for key in &removed {
+            availability.remove(key);
+        }
+        availability.extend(changed.iter().cloned());
+        let value = serde_json::to_string(&availability)?;
+        self.write_state(HashMap::from([(AVAILABILITY_KEY.to_string(), Some(value))])).await?;
+        *self.last_published.lock().unwrap() = Some(tokio::time::Instant::now());
+        for (key, backend_available) in changed {
+            self.sync.mark_published(key, Some(backend_available));
+        }
+        for key in removed {
+            self.sync.mark_published(key, None);
+        }
+        Ok(())
+    }
+
+    async fn watch_service_list(&self) {
+        let mut config_maps = self.watch_state();
+        loop {
+            match config_maps.try_next().await {
+                Ok(Some(config_map)) => {
+                    if self.is_leader() {
+                        continue;
+                    }
+                    let availability = config_map
+                        .data
+                        .as_ref()
+                        .and_then(|data| data.get(AVAILABILITY_KEY))
+                        .and_then(|value| serde_json::from_str::<HashMap<String, bool>>(value).ok());
+                    for (key, backend_available) in availability.unwrap_or_default() {
+                        coordination::apply_availability(&key, backend_available);
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => coordination::record_failure(&self.node_id, "Coordination ConfigMap watch failed",
+                                                      &anyhow::Error::new(e)),
+            }
+        }
+    }
+
+    async fn request_wake(&self, request: &WakeRequest) -> Result<()> {
+        let value = serde_json::to_string(request)?;
+        self.write_state(HashMap::from([(wake_key(&request.service), Some(value))])).await
+    }
+
+    /// Sends the wake requests in the state ConfigMap on every change of it. Requests already
+    /// being served are coalesced by the caller.
+    async fn watch_wake_requests(&self, requests: mpsc::UnboundedSender<WakeRequest>) {
+        let mut config_maps = self.watch_state();
+        loop {
+            match config_maps.try_next().await {
+                Ok(Some(config_map)) => {
+                    let data = config_map.data.unwrap_or_default();
+                    for (key, value) in data.iter().filter(|(key, _)| key.starts_with(WAKE_KEY_PREFIX)) {
+                        match serde_json::from_str::<WakeRequest>(value) {
+                            Ok(request) => {
+                                let _ = requests.send(request);
+                            }
+                            Err(_) => warn!("Ignoring unreadable wake request under {}", key),
+                        }
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => coordination::record_failure(&self.node_id, "Wake request watch failed",
+                                                      &anyhow::Error::new(e)),
+            }
+        }
+    }
+
+    async fn acknowledge_wake(&self, service: &str) -> Result<()> {
+        self.write_state(HashMap::from([(wake_key(service), None)])).await
+    }
+
+    async fn cleanup(&self) {
+        info!("Cleaning up KubeCoordinator for node: {}", self.node_id);
+        if !self.is_leader() {
+            return;
+        }
+        // Releases the Lease so that another node takes over right away
+        let released = async {
+            let mut lease = self.leases().get(LEASE_NAME).await?;
+            if let Some(spec) = lease.spec.as_mut() {
+                spec.holder_identity = None;
+                spec.renew_time = None;
+            }
+            self.leases().replace(LEASE_NAME, &PostParams::default(), &lease).await
+        };
+        if let Err(e) = released.await {
+            warn!("Failed to release the leader Lease: {}", e);
+        }
+        self.set_leader(false);
+    }
+}
@@ -1,5 +1,20 @@
+pub mod access;
+pub mod connections;
 pub mod controller;
+pub mod dependency_graph;
+pub mod events;
+pub mod history;
+pub mod hooks;
+pub mod keda;
 pub mod models;
+pub mod ops;
+pub mod policy;
+pub mod retry;
+pub mod schedule;
 pub mod scaler;
+pub mod service_status;
+pub mod workload;
 pub mod hpa_controller;
+pub mod coordination;
 pub mod etcd_coordinator;
+pub mod lease_coordinator;
@@ -1,43 +1,616 @@
+use k8s_openapi::api::core::v1::ObjectReference;
 use once_cell::sync::Lazy;
+use scale_to_zero_common::node_port_from_key;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::net::Ipv6Addr;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
 
-pub static WATCHED_SERVICES: Lazy<Arc<Mutex<HashMap<String, ServiceData>>>> =
-    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+use super::connections::ConnectionsEndpoint;
+use super::schedule::AllowedWindow;
 
-pub static LAST_CALLED: Lazy<Mutex<HashMap<String, SystemTime>>> =
+/// Every watched service, keyed by ClusterIP or "namespace/name" for headless services. Only
+/// accessed through `read_watched_services` and `write_watched_services`.
+pub static WATCHED_SERVICES: Lazy<ServiceMap> = Lazy::new(ServiceMap::default);
+
+/// Set once the first full listing of services has been processed. Until then
+/// `WATCHED_SERVICES` may be incomplete, so entries in pinned maps must not be pruned.
+pub static SERVICES_LISTED: AtomicBool = AtomicBool::new(false);
+
+/// Notified when service availability changed outside of the map sync loop, so that it syncs
+/// the kernel maps right away instead of at its next tick.
+pub static SERVICE_LIST_CHANGED: Lazy<tokio::sync::Notify> = Lazy::new(tokio::sync::Notify::new);
+
+/// Errors the Kubernetes watcher recovered from by reconnecting.
+pub static WATCHER_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Scale-downs that failed, each retried with backoff by the scale-down loop.
+pub static SCALE_DOWN_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Services the scale-down loop started scaling down, and the Unix time it last finished a pass.
+pub static SCALE_DOWN_ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+pub static LAST_SCALE_DOWN_PASS: AtomicI64 = AtomicI64::new(0);
+
+/// Services scaled up, including dependencies, and the scale-ups that failed to patch a workload
+/// or timed out before the service became available.
+pub static SCALE_UP_ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+pub static SCALE_UP_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// HPAs suspended before scaling a service to zero and restored after scaling it up, whether
+/// deleted and recreated or only their minReplicas changed.
+pub static HPA_DELETIONS: AtomicU64 = AtomicU64::new(0);
+pub static HPA_RECREATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Retries of failed scale operations, and operations given up after their last retry.
+pub static SCALE_RETRIES: AtomicU64 = AtomicU64::new(0);
+pub static SCALE_RETRIES_EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+
+/// Shares `WATCHED_SERVICES` with the other readers: the scaler's scans, the map sync, and
+/// every packet, as recording traffic only updates the atomic `last_packet_time`.
+pub fn read_watched_services() -> RwLockReadGuard<'static, HashMap<String, ServiceData>> {
+    WATCHED_SERVICES.read()
+}
+
+/// Locks `WATCHED_SERVICES` to add, remove or change entries, blocking every reader until the
+/// guard is dropped, so it should not be held across anything slow.
+pub fn write_watched_services() -> WatchedServicesWriteGuard<'static> {
+    WATCHED_SERVICES.write()
+}
+
+/// Bumped whenever a write guard of `WATCHED_SERVICES` that was written through is dropped, so
+/// the map sync can tell that nothing changed since its last pass without scanning the services.
+pub fn services_generation() -> u64 {
+    WATCHED_SERVICES.generation.load(Ordering::Acquire)
+}
+
+/// Contention on `WATCHED_SERVICES` since the last call.
+pub fn take_lock_times() -> LockTimes {
+    WATCHED_SERVICES.take_lock_times()
+}
+
+/// Services behind a read-write lock that records how long readers wait for it and writers
+/// hold it.
+#[derive(Default)]
+pub struct ServiceMap {
+    services: RwLock<HashMap<String, ServiceData>>,
+    /// Longest wait for a read lock and longest hold of the write lock in microseconds, the
+    /// write locks taken and how long they were held in total in nanoseconds, since
+    /// `take_lock_times` was last called.
+    max_read_wait_us: AtomicU64,
+    max_write_hold_us: AtomicU64,
+    write_locks: AtomicU64,
+    write_hold_ns: AtomicU64,
+    generation: AtomicU64,
+}
+
+impl ServiceMap {
+    /// Both locks recover the services if a panic poisoned them. Entries are only ever replaced
+    /// or updated field by field, so a panicking holder cannot leave one half-written.
+    pub fn read(&self) -> RwLockReadGuard<'_, HashMap<String, ServiceData>> {
+        let started = Instant::now();
+        let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+        record_max(&self.max_read_wait_us, started.elapsed());
+        services
+    }
+
+    pub fn write(&self) -> WatchedServicesWriteGuard<'_> {
+        WatchedServicesWriteGuard {
+            services: self.services.write().unwrap_or_else(PoisonError::into_inner),
+            map: self,
+            acquired: Instant::now(),
+            modified: false,
+        }
+    }
+
+    pub fn take_lock_times(&self) -> LockTimes {
+        let write_locks = self.write_locks.swap(0, Ordering::Relaxed);
+        let write_hold_ns = self.write_hold_ns.swap(0, Ordering::Relaxed);
+        LockTimes {
+            max_read_wait: Duration::from_micros(self.max_read_wait_us.swap(0, Ordering::Relaxed)),
+            max_write_hold: Duration::from_micros(self.max_write_hold_us.swap(0, Ordering::Relaxed)),
+            mean_write_hold: write_hold_ns.checked_div(write_locks).map(Duration::from_nanos).unwrap_or_default(),
+            write_locks,
+        }
+    }
+}
+
+/// Write guard of a `ServiceMap` recording how long it was held.
+pub struct WatchedServicesWriteGuard<'a> {
+    services: RwLockWriteGuard<'a, HashMap<String, ServiceData>>,
+    map: &'a ServiceMap,
+    acquired: Instant,
+    modified: bool,
+}
+
+impl Deref for WatchedServicesWriteGuard<'_> {
+    type Target = HashMap<String, ServiceData>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.services
+    }
+}
+
+impl DerefMut for WatchedServicesWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.modified = true;
+        &mut self.services
+    }
+}
+
+impl Drop for WatchedServicesWriteGuard<'_> {
+    fn drop(&mut self) {
+        let held = self.acquired.elapsed();
+        self.map.write_locks.fetch_add(1, Ordering::Relaxed);
+        self.map.write_hold_ns.fetch_add(held.as_nanos().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+        record_max(&self.map.max_write_hold_us, held);
+        // Still under the lock, so a sync reading the new generation also sees the change
+        if self.modified {
+            self.map.generation.fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
+fn record_max(max: &AtomicU64, elapsed: Duration) {
+    max.fetch_max(elapsed.as_micros().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+}
+
+/// Contention on a `ServiceMap` since its lock times were last taken.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LockTimes {
+    pub max_read_wait: Duration,
+    pub max_write_hold: Duration,
+    pub mean_write_hold: Duration,
+    pub write_locks: u64,
+}
+
+/// Unix time of the last packet to a service. Atomic, so that traffic is recorded under a read
+/// lock of `WATCHED_SERVICES` without waiting for the scaler's scans; a clone is a snapshot.
+#[derive(Debug, Default)]
+pub struct PacketTime(AtomicI64);
+
+impl PacketTime {
+    pub fn new(time: i64) -> Self {
+        Self(AtomicI64::new(time))
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Moves the packet time forward to `time`, and returns whether it did. Packets recorded
+    /// concurrently never move it back.
+    pub fn touch(&self, time: i64) -> bool {
+        self.0.fetch_max(time, Ordering::Relaxed) < time
+    }
+}
+
+impl Clone for PacketTime {
+    fn clone(&self) -> Self {
+        Self::new(self.get())
+    }
+}
+
+impl PartialEq for PacketTime {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl Eq for PacketTime {}
+
+impl serde::Serialize for PacketTime {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.get())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PacketTime {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <i64 as serde::Deserialize>::deserialize(deserializer).map(Self::new)
+    }
+}
+
+/// Replicas the agent last scaled each workload to, to notice when someone else scaled it since.
+pub static LAST_SCALED: Lazy<Mutex<HashMap<WorkloadReference, i32>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Packet counters aggregated from the kernel's `SERVICE_COUNTERS` map and scaling history,
+/// keyed like `WATCHED_SERVICES`. Kept out of `ServiceData` so that updating them never takes the
+/// watched services lock.
+pub static SERVICE_STATS: Lazy<Mutex<HashMap<String, ServiceStats>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The Service object behind each `WATCHED_SERVICES` entry, which Events are published on.
+pub static SERVICE_REFERENCES: Lazy<Mutex<HashMap<String, ObjectReference>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
-#[derive(Eq, Hash, PartialEq)]
+/// Namespace and name of the ScaleToZeroPolicy configuring each `WATCHED_SERVICES` entry, if any.
+pub static SERVICE_POLICIES: Lazy<Mutex<HashMap<String, (String, String)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct WorkloadReference {
     pub kind: String,
     pub name: String,
     pub namespace: String,
 }
 
+impl std::fmt::Display for WorkloadReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}/{}", self.kind, self.namespace, self.name)
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct HPAConfig {
     pub min_replicas: Option<i32>,
     pub max_replicas: i32,
     pub target_cpu_utilization_percentage: Option<i32>,
+    pub target_memory_utilization_percentage: Option<i32>,
+    /// HPA MetricSpecs as JSON, rendered alongside the CPU and memory targets.
     pub metrics: Option<String>,
     pub behavior: Option<String>,
+    /// What a deleted HPA scaled, so it is recreated pointing at the same object.
+    pub scale_target_ref: Option<ScaleTargetRef>,
 }
 
+/// How an HPA is kept from scaling a workload back up while it is scaled to zero.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum HpaSuspendStrategy {
+    /// Delete the HPA and recreate it from its captured configuration on scale-up.
+    #[default]
+    Delete,
+    /// Lower the HPA's minReplicas and restore it on scale-up, keeping the object.
+    MinReplicas,
+}
+
+/// The `scaleTargetRef` of an HPA.
 #[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ScaleTargetRef {
+    pub api_version: Option<String>,
+    pub kind: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct ServiceData {
     pub scale_down_time: i64,
-    pub last_packet_time: i64,
-    pub kind: String,
+    /// Seconds after a scale-up during which further traffic does not trigger another one.
+    pub scale_up_cooldown: i64,
+    /// Seconds after a scale-up before the service may be scaled down again, however idle.
+    pub min_uptime: i64,
+    /// Unix time the agent last scaled the service up.
+    pub last_scale_up_time: Option<i64>,
+    /// Unix time in milliseconds traffic last triggered a scale-up, which `scale_up_cooldown`
+    /// counts from.
+    pub last_scale_up_request: Option<i64>,
+    pub last_packet_time: PacketTime,
+    /// Name and namespace of the Service itself.
     pub name: String,
     pub namespace: String,
+    /// Workloads behind the service, scaled down and up together.
+    pub workloads: Vec<WorkloadReference>,
     pub backend_available: bool,
     pub dependencies: Vec<String>,
     pub dependents: Vec<String>,
     pub hpa_enabled: bool,
     pub hpa_name: Option<String>,
+    /// Set while the HPA is suspended, whether deleted or with its minReplicas lowered.
     pub hpa_deleted: bool,
     pub hpa_config: Option<HPAConfig>,
+    pub hpa_suspend_strategy: HpaSuspendStrategy,
+    /// KEDA ScaledObject scaling the workload, from `scale-to-zero/keda-scaledobject`. It is
+    /// paused while the service is scaled down, and its HPA never touched.
+    pub keda_scaled_object: Option<String>,
+    /// Set once an HPA that already existed was adopted, whose spec is then kept in `hpa_config`
+    /// rather than derived from the annotations.
+    pub hpa_adopted: bool,
     pub scaling_priority: i32,
+    pub secondary_ips: Vec<String>,
+    pub ports: Vec<u16>,
+    pub wake_sources: Vec<WakeSource>,
+    /// Endpoint addresses of a headless service, watched in place of a ClusterIP.
+    pub pod_ips: Vec<String>,
+    /// One of the `UNAVAILABLE_*` actions from scale-to-zero-common.
+    pub unavailable_action: u8,
+    /// Source CIDRs whose traffic never keeps this service awake.
+    pub excluded_sources: Vec<String>,
+    /// Node ports the service is exposed on; traffic to them on a node address wakes it too.
+    pub node_ports: Vec<u16>,
+    /// LoadBalancer ingress IPs and `spec.externalIPs`, watched like the ClusterIP.
+    pub external_ips: Vec<String>,
+    /// Ready endpoints across the service's EndpointSlices, once any has been seen. Without one
+    /// the service stays unavailable even with replicas, as its pods are still starting.
+    pub ready_endpoints: Option<usize>,
+    /// Addresses of the ready endpoints across the service's EndpointSlices.
+    pub endpoint_addresses: Vec<String>,
+    /// Where its pods report their active connections, if the service is only scaled down
+    /// without any, from `scale-to-zero/check-active-connections`.
+    pub active_connections_endpoint: Option<ConnectionsEndpoint>,
+    /// URL POSTed to before the service is scaled down, from `scale-to-zero/pre-scale-down-hook`.
+    pub pre_scale_down_hook: Option<String>,
+    /// Times the service may be scaled down in, from `scale-to-zero/allowed-window`; any time if
+    /// empty.
+    pub allowed_windows: Vec<AllowedWindow>,
+    /// Unix time until which the service is not scaled down, because someone scaled one of its
+    /// workloads by hand. Cleared early by the `scale-to-zero/resume` annotation.
+    pub manual_override_until: Option<i64>,
+    /// Unix time until which the service is neither scaled up nor down automatically, set
+    /// through the admin API.
+    pub paused_until: Option<i64>,
+    /// Set while one of the workloads does not exist; the service is then never scaled.
+    pub workload_missing: bool,
+    /// Keys of the other services in a dependency cycle with this one. Traffic to a service is
+    /// not propagated to these, or they would keep each other awake forever.
+    pub dependency_cycle: Vec<String>,
+    /// Unix time of a scale-up still waiting for the service to become available.
+    pub scale_up_started: Option<i64>,
+    /// Set when the last scale-up gave up before the service became available.
+    pub scale_up_failed: bool,
+    /// Scale-ups in a row that failed or timed out, reset once the service becomes available.
+    pub scale_up_failures: u32,
+    /// Unix time until which the service is quarantined after too many failed scale-ups, and
+    /// traffic to it no longer scales it up.
+    pub circuit_open_until: Option<i64>,
+    /// Replicas to scale each workload up to, from `scale-to-zero/scale-up-replicas`.
+    pub scale_up_replicas: Option<i32>,
+    /// Replicas each workload had when it was last scaled to zero, restored on scale-up.
+    pub previous_replicas: HashMap<WorkloadReference, i32>,
+    /// Replicas each workload is scaled down to when idle, from `scale-to-zero/idle-replicas`.
+    /// With one or more the service stays available and traffic reaches it as usual.
+    pub idle_replicas: i32,
+    /// Set while the service is scaled down to a non-zero `idle_replicas`, until traffic scales
+    /// it back up.
+    pub at_idle_floor: bool,
+    /// Whether the service may be scaled down while services depending on it are still up, from
+    /// `scale-to-zero/scale-down-independently`.
+    pub scale_down_independently: bool,
+}
+
+impl ServiceData {
+    /// The referenced workloads, for log messages and events.
+    pub fn describe_workloads(&self) -> String {
+        self.workloads.iter().map(|workload| workload.to_string()).collect::<Vec<_>>().join(", ")
+    }
+
+    /// Whether an HPA, the agent's own or KEDA's, changes the service's replicas.
+    pub fn autoscaled(&self) -> bool {
+        self.hpa_enabled || self.keda_scaled_object.is_some()
+    }
+
+    /// The Deployment or StatefulSet an HPA created for this service scales.
+    pub fn hpa_target(&self) -> Option<&WorkloadReference> {
+        self.workloads.iter().find(|workload| is_hpa_target(workload))
+    }
+}
+
+/// Whether an HPA created for a service may scale `workload`.
+pub fn is_hpa_target(workload: &WorkloadReference) -> bool {
+    workload.kind == "deployment" || workload.kind == "statefulset"
+}
+
+/// Number of recent wake sources kept per service.
+pub const MAX_WAKE_SOURCES: usize = 10;
+
+/// Client whose traffic asked for a scaled-down service to be scaled up.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WakeSource {
+    pub source: String,
+    pub port: u16,
+    pub time: i64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ServiceStats {
+    pub passed_packets: u64,
+    pub dropped_packets: u64,
+    /// Held connections that asked for the service to be woken up.
+    pub wake_events: u64,
+    pub scale_ups: u64,
+    pub scale_downs: u64,
+    /// Milliseconds from patching the replicas of the last scale-up until the service was
+    /// available.
+    pub last_scale_up_duration_ms: Option<u64>,
+    /// Seconds spent at zero replicas, not counting the current stretch.
+    pub seconds_at_zero: u64,
+    /// Unix time the service was scaled to zero, while it still is.
+    pub at_zero_since: Option<i64>,
+    /// Unix time the service was last scaled down, to zero or its idle replicas.
+    pub last_scale_down: Option<i64>,
+    /// Unix time in milliseconds the replicas of a scale-up still waiting to be available were
+    /// patched.
+    pub scale_up_patched_at: Option<i64>,
+    /// Unix time in milliseconds traffic, or a dependent's scale-up, asked for that scale-up.
+    pub scale_up_requested_at: Option<i64>,
+}
+
+impl ServiceStats {
+    /// Seconds spent at zero replicas since the agent started, up to `now`.
+    pub fn time_at_zero(&self, now: i64) -> u64 {
+        self.seconds_at_zero + self.at_zero_since.map_or(0, |since| (now - since).max(0) as u64)
+    }
+
+    /// Records that the replicas of a scale-up were patched at `now_ms`, ending a stretch at zero.
+    pub fn record_scale_up(&mut self, now_ms: i64) {
+        self.scale_ups += 1;
+        self.seconds_at_zero = self.time_at_zero(now_ms / 1000);
+        self.at_zero_since = None;
+        self.scale_up_patched_at = Some(now_ms);
+    }
+
+    /// Records that a scaled-up service became available at `now_ms`, returning the time since
+    /// the scale-up was requested.
+    pub fn record_available(&mut self, now_ms: i64) -> Option<Duration> {
+        if let Some(patched_at) = self.scale_up_patched_at.take() {
+            self.last_scale_up_duration_ms = Some((now_ms - patched_at).max(0) as u64);
+        }
+        self.scale_up_requested_at
+            .take()
+            .map(|requested_at| Duration::from_millis((now_ms - requested_at).max(0) as u64))
+    }
+
+    /// Forgets a scale-up that gave up before the service became available.
+    pub fn clear_scale_up(&mut self) {
+        self.scale_up_patched_at = None;
+        self.scale_up_requested_at = None;
+    }
+
+    pub fn record_scale_down(&mut self, now: i64, to_zero: bool) {
+        self.scale_downs += 1;
+        self.last_scale_down = Some(now);
+        if to_zero {
+            self.at_zero_since.get_or_insert(now);
+        }
+    }
+}
+
+/// Updates the `SERVICE_STATS` entry of the service under `key`.
+pub fn update_service_stats(key: &str, update: impl FnOnce(&mut ServiceStats)) {
+    let mut service_stats = SERVICE_STATS.lock().unwrap_or_else(PoisonError::into_inner);
+    update(service_stats.entry(key.to_string()).or_default());
+}
+
+/// Returns the `WATCHED_SERVICES` key of the service that owns `ip`, either because `ip` is the
+/// key itself, one of the service's secondary ClusterIPs or external IPs, or a pod IP of a headless
+/// service.
+pub fn resolve_service_key(services: &HashMap<String, ServiceData>, ip: &str) -> Option<String> {
+    if services.contains_key(ip) {
+        return Some(ip.to_string());
+    }
+    services
+        .iter()
+        .find(|(_, service)| {
+            service.secondary_ips.iter().any(|secondary| secondary == ip)
+                || service.external_ips.iter().any(|external| external == ip)
+                || service.pod_ips.iter().any(|pod_ip| pod_ip == ip)
+        })
+        .map(|(key, _)| key.clone())
+}
+
+/// Like `resolve_service_key`, for a 16-byte address from a kernel map: IPv4 addresses are
+/// IPv4-mapped and node port traffic is keyed as built by `node_port_key`.
+pub fn resolve_address_key(services: &HashMap<String, ServiceData>, address: [u8; 16]) -> Option<String> {
+    if let Some(port) = node_port_from_key(&address) {
+        return services
+            .iter()
+            .find(|(_, service)| service.node_ports.contains(&port))
+            .map(|(key, _)| key.clone());
+    }
+    resolve_service_key(services, &Ipv6Addr::from(address).to_canonical().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+
+    const SERVICES: usize = 1_000;
+    const PACKET_THREADS: usize = 4;
+    const PACKETS_PER_THREAD: i64 = 20_000;
+    const WRITES: usize = 2_000;
+
+    // Each test locks a map of its own, so the tests touching WATCHED_SERVICES do not skew it
+
+    fn service_map() -> Arc<ServiceMap> {
+        let map = Arc::new(ServiceMap::default());
+        let mut services = map.write();
+        for index in 0..SERVICES {
+            services.insert(key(index), ServiceData { name: key(index), ..Default::default() });
+        }
+        drop(services);
+        map.take_lock_times();
+        map
+    }
+
+    fn key(index: usize) -> String {
+        format!("service-{}", index % SERVICES)
+    }
+
+    /// Toggles the availability of the services one write lock at a time, as the watcher does.
+    fn write(map: &ServiceMap) {
+        for index in 0..WRITES {
+            if let Some(service) = map.write().get_mut(&key(index)) {
+                service.backend_available = !service.backend_available;
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn packets_are_recorded_while_a_scan_holds_the_read_lock() {
+        let map = service_map();
+        let scan = map.read();
+
+        let (recorded, packets) = mpsc::channel();
+        let packet_thread = {
+            let map = map.clone();
+            std::thread::spawn(move || {
+                for time in 1..=PACKETS_PER_THREAD {
+                    map.read()[&key(time as usize)].last_packet_time.touch(time);
+                }
+                recorded.send(()).unwrap();
+            })
+        };
+        let finished = packets.recv_timeout(Duration::from_secs(60));
+        drop(scan);
+        packet_thread.join().unwrap();
+
+        assert!(finished.is_ok(), "packets waited for the scan to release the read lock");
+        assert_eq!(map.read()[&key(PACKETS_PER_THREAD as usize)].last_packet_time.get(), PACKETS_PER_THREAD);
+    }
+
+    /// Packets are recorded under the read lock while the scaler scans and the watcher writes,
+    /// so none of them is lost, and the write lock is held on average no more than 20 times as long
+    /// as by a writer alone, a bound that does not depend on the speed or load of the machine.
+    #[test]
+    fn packets_scans_and_writes_keep_write_holds_as_short_as_a_lone_writer() {
+        let alone = service_map();
+        write(&alone);
+        let baseline = alone.take_lock_times();
+
+        let map = service_map();
+        let done = Arc::new(AtomicBool::new(false));
+        let scanner = {
+            let (map, done) = (map.clone(), done.clone());
+            std::thread::spawn(move || {
+                let mut scans = 0;
+                while !done.load(Ordering::Relaxed) {
+                    // As the scale-down loop does, snapshotting the services to check
+                    let snapshot: Vec<ServiceData> = map.read().values().cloned().collect();
+                    assert_eq!(snapshot.len(), SERVICES);
+                    scans += 1;
+                }
+                scans
+            })
+        };
+        let packets: Vec<_> = (0..PACKET_THREADS)
+            .map(|thread| {
+                let map = map.clone();
+                std::thread::spawn(move || {
+                    for time in 1..=PACKETS_PER_THREAD {
+                        map.read()[&key(thread * 7919 + time as usize)].last_packet_time.touch(time);
+                    }
+                })
+            })
+            .collect();
+        write(&map);
+        for packet_thread in packets {
+            packet_thread.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        let scans = scanner.join().unwrap();
+        let contended = map.take_lock_times();
+
+        let latest = map.read().values().map(|service| service.last_packet_time.get()).max();
+        assert!(scans > 0);
+        assert_eq!(latest, Some(PACKETS_PER_THREAD));
+        assert_eq!((baseline.write_locks, contended.write_locks), (WRITES as u64, WRITES as u64));
+        assert!(contended.mean_write_hold <= baseline.mean_write_hold * 20,
+                "write lock held for {:?} on average, {:?} by a lone writer",
+                contended.mean_write_hold, baseline.mean_write_hold);
+    }
 }
@@ -0,0 +1,502 @@
+//! Everything the scaler and the HPA controller read from and change in the cluster, behind
+//! `KubeOps`, so their logic runs the same against the API server and against the in-memory fake
+//! the tests use. The tests of coordination get an in-memory `CoordinationBackend` here too.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use k8s_openapi::api::autoscaling::v2::HorizontalPodAutoscaler;
+use k8s_openapi::serde_json;
+use kube::api::{Api, Patch, PatchParams};
+use kube::runtime::events::EventType;
+use kube::runtime::wait::{await_condition, conditions};
+use kube::Client;
+
+use super::models::{HPAConfig, WorkloadReference};
+use super::workload::{self, Scale};
+use super::{events, keda, policy};
+
+#[async_trait]
+pub trait KubeOps: Send + Sync {
+    /// The `scale` subresource of `workload`, or `None` if it does not exist.
+    async fn get_scale(&self, workload: &WorkloadReference) -> Result<Option<Scale>>;
+
+    /// Desired replicas of `workload`, or `None` if it does not exist.
+    async fn get_replicas(&self, workload: &WorkloadReference) -> Result<Option<i32>> {
+        Ok(self.get_scale(workload).await?.map(|scale| scale.replicas))
+    }
+
+    /// Sets the replicas of `workload`, failing if it was scaled by someone else since
+    /// `observed` was read.
+    async fn set_replicas(&self, workload: &WorkloadReference, replicas: i32, observed: &Scale) -> Result<()>;
+
+    async fn ready_replicas(&self, workload: &WorkloadReference) -> Result<Option<i64>>;
+
+    async fn get_previous_replicas(&self, workload: &WorkloadReference) -> Result<Option<i32>>;
+
+    async fn set_previous_replicas(&self, workload: &WorkloadReference, replicas: i32) -> Result<()>;
+
+    async fn set_stored_hpa_config(&self, workload: &WorkloadReference, config: Option<&HPAConfig>) -> Result<()>;
+
+    async fn get_hpa(&self, namespace: &str, name: &str) -> Result<Option<HorizontalPodAutoscaler>>;
+
+    /// Creates `hpa` in the namespace of its metadata.
+    async fn create_hpa(&self, hpa: &HorizontalPodAutoscaler) -> Result<()>;
+
+    /// Applies `patch` to an HPA as a JSON merge patch.
+    async fn patch_hpa(&self, namespace: &str, name: &str, patch: serde_json::Value) -> Result<()>;
+
+    async fn delete_hpa(&self, namespace: &str, name: &str) -> Result<()>;
+
+    /// Waits up to `timeout` for the HPA with `uid` to be gone, returning whether it is.
+    async fn await_hpa_deleted(&self, namespace: &str, name: &str, uid: &str, timeout: Duration) -> bool;
+
+    /// Pauses KEDA ScaledObject `name` at `replicas`.
+    async fn pause_scaled_object(&self, namespace: &str, name: &str, replicas: i32) -> Result<()>;
+
+    async fn resume_scaled_object(&self, namespace: &str, name: &str) -> Result<()>;
+
+    /// Publishes an Event on the Service watched under `service_key`; failures are only logged.
+    async fn publish_event(&self, service_key: &str, type_: EventType, reason: &str, note: String);
+
+    /// Records a scale event in the status of the ScaleToZeroPolicy of `service_key`, if it has one.
+    async fn record_policy_event(&self, service_key: &str, reason: &str);
+}
+
+/// `KubeOps` against the API server.
+pub struct KubeClient {
+    client: Client,
+}
+
+impl KubeClient {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    fn hpas(&self, namespace: &str) -> Api<HorizontalPodAutoscaler> {
+        Api::namespaced(self.client.clone(), namespace)
+    }
+}
+
+#[async_trait]
+impl KubeOps for KubeClient {
+    async fn get_scale(&self, w: &WorkloadReference) -> Result<Option<Scale>> {
+        workload::get_scale(&self.client, &w.kind, &w.namespace, &w.name).await
+    }
+
+    async fn set_replicas(&self, w: &WorkloadReference, replicas: i32, observed: &Scale) -> Result<()> {
+        workload::set_replicas(&self.client, &w.kind, &w.namespace, &w.name, replicas, observed).await
+    }
+
+    async fn ready_replicas(&self, w: &WorkloadReference) -> Result<Option<i64>> {
+        workload::ready_replicas(&self.client, &w.kind, &w.namespace, &w.name).await
+    }
+
+    async fn get_previous_replicas(&self, w: &WorkloadReference) -> Result<Option<i32>> {
+        workload::get_previous_replicas(&self.client, &w.kind, &w.namespace, &w.name).await
+    }
+
+    async fn set_previous_replicas(&self, w: &WorkloadReference, replicas: i32) -> Result<()> {
+        workload::set_previous_replicas(&self.client, &w.kind, &w.namespace, &w.name, replicas).await
+    }
+
+    async fn set_stored_hpa_config(&self, w: &WorkloadReference, config: Option<&HPAConfig>) -> Result<()> {
+        workload::set_stored_hpa_config(&self.client, &w.kind, &w.namespace, &w.name, config).await
+    }
+
+    async fn get_hpa(&self, namespace: &str, name: &str) -> Result<Option<HorizontalPodAutoscaler>> {
+        self.hpas(namespace)
+            .get_opt(name)
+            .await
+            .with_context(|| format!("Failed to get HPA {}/{}", namespace, name))
+    }
+
+    async fn create_hpa(&self, hpa: &HorizontalPodAutoscaler) -> Result<()> {
+        let namespace = hpa.metadata.namespace.as_deref().unwrap_or_default();
+        let name = hpa.metadata.name.as_deref().unwrap_or_default();
+        self.hpas(namespace)
+            .create(&Default::default(), hpa)
+            .await
+            .with_context(|| format!("Failed to create HPA {}/{}", namespace, name))?;
+        Ok(())
+    }
+
+    async fn patch_hpa(&self, namespace: &str, name: &str, patch: serde_json::Value) -> Result<()> {
+        let params = PatchParams {
+            field_manager: Some(workload::FIELD_MANAGER.to_string()),
+            ..Default::default()
+        };
+        self.hpas(namespace)
+            .patch(name, &params, &Patch::Merge(patch))
+            .await
+            .with_context(|| format!("Failed to patch HPA {}/{}", namespace, name))?;
+        Ok(())
+    }
+
+    async fn delete_hpa(&self, namespace: &str, name: &str) -> Result<()> {
+        self.hpas(namespace)
+            .delete(name, &Default::default())
+            .await
+            .with_context(|| format!("Failed to delete HPA {}/{}", namespace, name))?;
+        Ok(())
+    }
+
+    async fn await_hpa_deleted(&self, namespace: &str, name: &str, uid: &str, timeout: Duration) -> bool {
+        let deleted = await_condition(self.hpas(namespace), name, conditions::is_deleted(uid));
+        matches!(tokio::time::timeout(timeout, deleted).await, Ok(Ok(_)))
+    }
+
+    async fn pause_scaled_object(&self, namespace: &str, name: &str, replicas: i32) -> Result<()> {
+        keda::pause(&self.client, namespace, name, replicas).await
+    }
+
+    async fn resume_scaled_object(&self, namespace: &str, name: &str) -> Result<()> {
+        keda::resume(&self.client, namespace, name).await
+    }
+
+    async fn publish_event(&self, service_key: &str, type_: EventType, reason: &str, note: String) {
+        events::publish_for_service(&self.client, service_key, type_, reason, note).await;
+    }
+
+    async fn record_policy_event(&self, service_key: &str, reason: &str) {
+        policy::record_scale_event(&self.client, service_key, reason).await;
+    }
+}
+
+#[cfg(test)]
+pub use fake::{FakeCoordinator, FakeKube};
+
+#[cfg(test)]
+mod fake {
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::Mutex;
+
+    use tokio::sync::{mpsc, Notify};
+
+    use super::*;
+    use crate::kubernetes::coordination::{CoordinationBackend, Membership, SyncState, WakeRequest};
+
+    /// A workload in `FakeKube`, whose pods are ready as soon as it is scaled.
+    #[derive(Debug, Clone, Default)]
+    pub struct FakeWorkload {
+        pub replicas: i32,
+        pub annotations: BTreeMap<String, String>,
+    }
+
+    /// `KubeOps` over workloads and HPAs kept in memory.
+    #[derive(Default)]
+    pub struct FakeKube {
+        workloads: Mutex<HashMap<WorkloadReference, FakeWorkload>>,
+        hpas: Mutex<HashMap<(String, String), HorizontalPodAutoscaler>>,
+        /// Reasons of the Events published, by service key.
+        events: Mutex<Vec<(String, String)>>,
+    }
+
+    impl FakeKube {
+        pub fn add_workload(&self, reference: &WorkloadReference, replicas: i32) {
+            self.workloads.lock().unwrap().insert(reference.clone(), FakeWorkload {
+                replicas,
+                ..Default::default()
+            });
+        }
+
+        pub fn workload(&self, reference: &WorkloadReference) -> Option<FakeWorkload> {
+            self.workloads.lock().unwrap().get(reference).cloned()
+        }
+
+        pub fn add_hpa(&self, hpa: HorizontalPodAutoscaler) {
+            let key = (hpa.metadata.namespace.clone().unwrap_or_default(), hpa.metadata.name.clone().unwrap_or_default());
+            self.hpas.lock().unwrap().insert(key, hpa);
+        }
+
+        pub fn hpa(&self, namespace: &str, name: &str) -> Option<HorizontalPodAutoscaler> {
+            self.hpas.lock().unwrap().get(&(namespace.to_string(), name.to_string())).cloned()
+        }
+
+        pub fn events(&self, service_key: &str) -> Vec<String> {
+            self.events
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(key, _)| key == service_key)
+                .map(|(_, reason)| reason.clone())
+                .collect()
+        }
+
+        fn update<T>(&self, w: &WorkloadReference, change: impl FnOnce(&mut FakeWorkload) -> T) -> Result<T> {
+            match self.workloads.lock().unwrap().get_mut(w) {
+                Some(workload) => Ok(change(workload)),
+                None => anyhow::bail!("{} not found", w),
+            }
+        }
+    }
+
+    /// Applies `patch` to `target` as RFC 7386 describes.
+    fn merge(target: &mut serde_json::Value, patch: &serde_json::Value) {
+        let serde_json::Value::Object(fields) = patch else {
+            *target = patch.clone();
+            return;
+        };
+        if !target.is_object() {
+            *target = serde_json::json!({});
+        }
+        let object = target.as_object_mut().unwrap();
+        for (name, value) in fields {
+            if value.is_null() {
+                object.remove(name);
+            } else {
+                merge(object.entry(name.clone()).or_insert(serde_json::Value::Null), value);
+            }
+        }
+    }
+
+    #[async_trait]
+    impl KubeOps for FakeKube {
+        async fn get_scale(&self, w: &WorkloadReference) -> Result<Option<Scale>> {
+            Ok(self.workload(w).map(|workload| Scale {
+                replicas: workload.replicas,
+                resource_version: None,
+            }))
+        }
+
+        async fn set_replicas(&self, w: &WorkloadReference, replicas: i32, _observed: &Scale) -> Result<()> {
+            self.update(w, |workload| workload.replicas = replicas)
+        }
+
+        async fn ready_replicas(&self, w: &WorkloadReference) -> Result<Option<i64>> {
+            Ok(self.workload(w).map(|workload| workload.replicas.into()))
+        }
+
+        async fn get_previous_replicas(&self, w: &WorkloadReference) -> Result<Option<i32>> {
+            Ok(self
+                .workload(w)
+                .and_then(|workload| workload.annotations.get(workload::PREVIOUS_REPLICAS_ANNOTATION).cloned())
+                .and_then(|value| value.parse().ok()))
+        }
+
+        async fn set_previous_replicas(&self, w: &WorkloadReference, replicas: i32) -> Result<()> {
+            self.update(w, |workload| {
+                workload.annotations.insert(workload::PREVIOUS_REPLICAS_ANNOTATION.to_string(), replicas.to_string());
+            })
+        }
+
+        async fn set_stored_hpa_config(&self, w: &WorkloadReference, config: Option<&HPAConfig>) -> Result<()> {
+            let value = config.map(serde_json::to_string).transpose()?;
+            self.update(w, |workload| match value {
+                Some(value) => workload.annotations.insert(workload::STORED_HPA_CONFIG_ANNOTATION.to_string(), value),
+                None => workload.annotations.remove(workload::STORED_HPA_CONFIG_ANNOTATION),
+            })?;
+            Ok(())
+        }
+
+        async fn get_hpa(&self, namespace: &str, name: &str) -> Result<Option<HorizontalPodAutoscaler>> {
+            Ok(self.hpa(namespace, name))
+        }
+
+        async fn create_hpa(&self, hpa: &HorizontalPodAutoscaler) -> Result<()> {
+            let namespace = hpa.metadata.namespace.as_deref().unwrap_or_default();
+            let name = hpa.metadata.name.as_deref().unwrap_or_default();
+            if self.hpa(namespace, name).is_some() {
+                anyhow::bail!("HPA {}/{} already exists", namespace, name);
+            }
+            self.add_hpa(hpa.clone());
+            Ok(())
+        }
+
+        async fn patch_hpa(&self, namespace: &str, name: &str, patch: serde_json::Value) -> Result<()> {
+            let mut hpas = self.hpas.lock().unwrap();
+            let Some(hpa) = hpas.get_mut(&(namespace.to_string(), name.to_string())) else {
+                anyhow::bail!("HPA {}/{} not found", namespace, name);
+            };
+            let mut value = serde_json::to_value(&*hpa)?;
+            merge(&mut value, &patch);
+            *hpa = serde_json::from_value(value)?;
+            Ok(())
+        }
+
+        async fn delete_hpa(&self, namespace: &str, name: &str) -> Result<()> {
+            match self.hpas.lock().unwrap().remove(&(namespace.to_string(), name.to_string())) {
+                Some(_) => Ok(()),
+                None => anyhow::bail!("HPA {}/{} not found", namespace, name),
+            }
+        }
+
+        async fn await_hpa_deleted(&self, namespace: &str, name: &str, _uid: &str, _timeout: Duration) -> bool {
+            self.hpa(namespace, name).is_none()
+        }
+
+        async fn pause_scaled_object(&self, _namespace: &str, _name: &str, _replicas: i32) -> Result<()> {
+            Ok(())
+        }
+
+        async fn resume_scaled_object(&self, _namespace: &str, _name: &str) -> Result<()> {
+            Ok(())
+        }
+
+        async fn publish_event(&self, service_key: &str, _type: EventType, reason: &str, _note: String) {
+            self.events.lock().unwrap().push((service_key.to_string(), reason.to_string()));
+        }
+
+        async fn record_policy_event(&self, _service_key: &str, _reason: &str) {}
+    }
+
+    /// `CoordinationBackend` of one node, whose leadership the test sets, over node states and
+    /// wake requests kept in memory. The states of other nodes are written by the test.
+    #[derive(Default)]
+    pub struct FakeCoordinator {
+        node_id: String,
+        leader: AtomicBool,
+        sync: SyncState,
+        /// Packet times per service, by the node that pushed them.
+        node_states: Mutex<HashMap<String, HashMap<String, i64>>>,
+        pulls: AtomicU32,
+        /// Availability published by the leader.
+        availability: Mutex<HashMap<String, bool>>,
+        wakes: Mutex<HashMap<String, WakeRequest>>,
+        wakes_changed: Notify,
+        wake_requests: AtomicU32,
+        acknowledged: Mutex<Vec<String>>,
+    }
+
+    impl FakeCoordinator {
+        pub fn new(node_id: &str, leader: bool) -> Self {
+            Self {
+                node_id: node_id.to_string(),
+                leader: AtomicBool::new(leader),
+                ..Default::default()
+            }
+        }
+
+        pub fn set_leader(&self, leader: bool) {
+            self.leader.store(leader, Ordering::SeqCst);
+        }
+
+        /// Writes the packet times of `node_id` as if that node pushed them.
+        pub fn set_node_state(&self, node_id: &str, packet_times: HashMap<String, i64>) {
+            self.node_states.lock().unwrap().insert(node_id.to_string(), packet_times);
+        }
+
+        pub fn node_state(&self, node_id: &str) -> HashMap<String, i64> {
+            self.node_states.lock().unwrap().get(node_id).cloned().unwrap_or_default()
+        }
+
+        pub fn pulls(&self) -> u32 {
+            self.pulls.load(Ordering::SeqCst)
+        }
+
+        pub fn availability(&self) -> HashMap<String, bool> {
+            self.availability.lock().unwrap().clone()
+        }
+
+        /// Wake requests written and not yet acknowledged.
+        pub fn pending_wakes(&self) -> Vec<WakeRequest> {
+            self.wakes.lock().unwrap().values().cloned().collect()
+        }
+
+        /// Times a wake was requested, coalesced or not.
+        pub fn wake_requests(&self) -> u32 {
+            self.wake_requests.load(Ordering::SeqCst)
+        }
+
+        pub fn acknowledged(&self) -> Vec<String> {
+            self.acknowledged.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl CoordinationBackend for FakeCoordinator {
+        fn backend(&self) -> &'static str {
+            "fake"
+        }
+
+        fn node_id(&self) -> &str {
+            &self.node_id
+        }
+
+        fn is_leader(&self) -> bool {
+            self.leader.load(Ordering::SeqCst)
+        }
+
+        fn membership(&self) -> Membership {
+            self.sync.membership()
+        }
+
+        async fn push_service_data(&self) -> Result<()> {
+            let changed = self.sync.unsynced_services();
+            let mut node_states = self.node_states.lock().unwrap();
+            let state = node_states.entry(self.node_id.clone()).or_default();
+            for (key, service) in changed {
+                state.insert(key.clone(), service.last_packet_time.get());
+                self.sync.mark_synced(key, service.last_packet_time.get());
+            }
+            Ok(())
+        }
+
+        async fn pull_service_data(&self) -> Result<()> {
+            let mut latest: HashMap<String, i64> = HashMap::new();
+            for (node, state) in self.node_states.lock().unwrap().iter() {
+                if *node == self.node_id {
+                    continue;
+                }
+                for (key, packet_time) in state {
+                    let time = latest.entry(key.clone()).or_default();
+                    *time = (*time).max(*packet_time);
+                }
+            }
+            self.sync.merge_packet_times(latest);
+            self.pulls.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn push_service_list(&self) -> Result<()> {
+            if !self.is_leader() {
+                return Ok(());
+            }
+            let (changed, removed) = self.sync.availability_changes();
+            let mut availability = self.availability.lock().unwrap();
+            for key in removed {
+                availability.remove(&key);
+                self.sync.mark_published(key, None);
+            }
+            for (key, backend_available) in changed {
+                availability.insert(key.clone(), backend_available);
+                self.sync.mark_published(key, Some(backend_available));
+            }
+            Ok(())
+        }
+
+        async fn watch_service_list(&self) {
+            std::future::pending().await
+        }
+
+        async fn request_wake(&self, request: &WakeRequest) -> Result<()> {
+            self.wake_requests.fetch_add(1, Ordering::SeqCst);
+            self.wakes.lock().unwrap().insert(request.service.clone(), request.clone());
+            self.wakes_changed.notify_waiters();
+            Ok(())
+        }
+
+        async fn watch_wake_requests(&self, requests: mpsc::UnboundedSender<WakeRequest>) {
+            loop {
+                // Registered before reading, so a request written meanwhile is not missed
+                let changed = self.wakes_changed.notified();
+                for request in self.pending_wakes() {
+                    if requests.send(request).is_err() {
+                        return;
+                    }
+                }
+                changed.await;
+            }
+        }
+
+        async fn acknowledge_wake(&self, service: &str) -> Result<()> {
+            self.wakes.lock().unwrap().remove(service);
+            self.acknowledged.lock().unwrap().push(service.to_string());
+            Ok(())
+        }
+
+        async fn cleanup(&self) {}
+    }
+}
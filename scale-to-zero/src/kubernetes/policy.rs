@@ -0,0 +1,171 @@
+use anyhow::Result;
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::chrono::{self, SecondsFormat};
+use k8s_openapi::serde_json::json;
+use kube::api::{Api, ListParams, Patch, PatchParams};
+use kube::{Client, CustomResource, ResourceExt};
+use log::warn;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::models::{read_watched_services, SERVICE_POLICIES};
+
+/// Scale-to-zero settings for one Service in the policy's namespace, as an alternative to its
+/// `scale-to-zero/*` annotations. Fields that are set take precedence over the annotations.
+#[derive(CustomResource, Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[kube(
+    group = "scale-to-zero.io",
+    version = "v1alpha1",
+    kind = "ScaleToZeroPolicy",
+    namespaced,
+    status = "ScaleToZeroPolicyStatus",
+    shortname = "stzpolicy",
+    printcolumn = r#"{"name":"Service","type":"string","jsonPath":".spec.targetService"}"#,
+    printcolumn = r#"{"name":"Available","type":"boolean","jsonPath":".status.backendAvailable"}"#,
+    printcolumn = r#"{"name":"Last Packet","type":"date","jsonPath":".status.lastPacketTime"}"#,
+    printcolumn = r#"{"name":"Last Scale","type":"string","jsonPath":".status.lastScaleEvent"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct ScaleToZeroPolicySpec {
+    pub target_service: String,
+    /// Workloads behind the service, in the syntax of the `scale-to-zero/reference` annotation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workload_ref: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_seconds: Option<i64>,
+    /// `namespace/service` of the services this one depends on; an empty list clears the annotation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dependencies: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dependents: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hpa: Option<PolicyHpa>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyHpa {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_replicas: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_replicas: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_cpu_utilization: Option<i32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScaleToZeroPolicyStatus {
+    pub backend_available: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_packet_time: Option<String>,
+    /// Reason of the last scale Event, such as `ScaledToZero`, `ScaledUp` or `ScaleFailed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_scale_event: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_scale_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_scale_up_time: Option<String>,
+    /// Until when traffic no longer scales the service up, after it failed to scale up too often.
+    #[serde(default)]
+    pub circuit_open_until: Option<String>,
+    /// Until when scale-down is paused because a workload was scaled by hand.
+    #[serde(default)]
+    pub manual_override_until: Option<String>,
+}
+
+/// "namespace/name" of the Service `policy` targets.
+pub fn target_key(policy: &ScaleToZeroPolicy) -> String {
+    format!("{}/{}", policy.namespace().unwrap_or_default(), policy.spec.target_service)
+}
+
+/// Writes the fields set in `policy` over the annotations of `service`, so they are parsed and
+/// validated exactly like the annotations they stand in for.
+pub fn overlay(service: &mut Service, policy: &ScaleToZeroPolicy) {
+    let spec = &policy.spec;
+    let annotations = service.annotations_mut();
+    let mut set = |key: &str, value: String| {
+        annotations.insert(format!("scale-to-zero/{}", key), value);
+    };
+    if let Some(workload_ref) = &spec.workload_ref {
+        set("reference", workload_ref.clone());
+    }
+    if let Some(idle_seconds) = spec.idle_seconds {
+        set("scale-down-time", idle_seconds.to_string());
+    }
+    if let Some(dependencies) = &spec.dependencies {
+        set("dependencies", dependencies.join(","));
+    }
+    if let Some(dependents) = &spec.dependents {
+        set("dependents", dependents.join(","));
+    }
+    if let Some(priority) = spec.priority {
+        set("scaling-priority", priority.to_string());
+    }
+    if let Some(hpa) = &spec.hpa {
+        set("hpa-enabled", hpa.enabled.to_string());
+        if let Some(name) = &hpa.name {
+            set("hpa-name", name.clone());
+        }
+        if let Some(min_replicas) = hpa.min_replicas {
+            set("min-replicas", min_replicas.to_string());
+        }
+        if let Some(max_replicas) = hpa.max_replicas {
+            set("max-replicas", max_replicas.to_string());
+        }
+        if let Some(target_cpu_utilization) = hpa.target_cpu_utilization {
+            set("target-cpu-utilization", target_cpu_utilization.to_string());
+        }
+    }
+}
+
+/// Whether the ScaleToZeroPolicy CRD is installed and readable. It is optional; without it
+/// services are configured from their annotations only.
+pub async fn crd_installed(policies: &Api<ScaleToZeroPolicy>) -> Result<bool> {
+    match policies.list(&ListParams::default().limit(1)).await {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(response)) if response.code == 404 || response.code == 403 => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes the state of the service stored under `service_key` to the status of the policy
+/// targeting it, if there is one. Failures are only logged, as for Events.
+pub async fn record_scale_event(client: &Client, service_key: &str, reason: &str) {
+    let Some((namespace, name)) = SERVICE_POLICIES.lock().unwrap().get(service_key).cloned() else {
+        return;
+    };
+    let Some(service) = read_watched_services().get(service_key).cloned() else {
+        return;
+    };
+    let status = ScaleToZeroPolicyStatus {
+        backend_available: service.backend_available,
+        last_packet_time: chrono::DateTime::from_timestamp(service.last_packet_time.get(), 0)
+            .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        last_scale_event: Some(reason.to_string()),
+        last_scale_time: Some(chrono::Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)),
+        last_scale_up_time: service
+            .last_scale_up_time
+            .and_then(|time| chrono::DateTime::from_timestamp(time, 0))
+            .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        circuit_open_until: service
+            .circuit_open_until
+            .and_then(|until| chrono::DateTime::from_timestamp(until, 0))
+            .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        manual_override_until: service
+            .manual_override_until
+            .and_then(|until| chrono::DateTime::from_timestamp(until, 0))
+            .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true)),
+    };
+    let policies: Api<ScaleToZeroPolicy> = Api::namespaced(client.clone(), &namespace);
+    let patch = Patch::Merge(json!({ "status": status }));
+    if let Err(e) = policies.patch_status(&name, &PatchParams::default(), &patch).await {
+        warn!("Failed to update status of ScaleToZeroPolicy {}/{}: {}", namespace, name, e);
+    }
+}
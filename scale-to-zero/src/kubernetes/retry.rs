@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::FutureExt;
+use kube::runtime::events::EventType;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+
+use super::hpa_controller::HPASuspensionController;
+use super::models::{read_watched_services, write_watched_services, SCALE_RETRIES, SCALE_RETRIES_EXHAUSTED};
+use super::{history, scaler};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleOperation {
+    Up,
+    Down,
+}
+
+impl std::fmt::Display for ScaleOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ScaleOperation::Up => "scale-up",
+            ScaleOperation::Down => "scale-down",
+        })
+    }
+}
+
+struct PendingRetry {
+    operation: ScaleOperation,
+    /// Failed attempts so far, including the one that queued it.
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Failed scale operations waiting to be retried, keyed like `WATCHED_SERVICES`.
+static QUEUE: Lazy<Mutex<HashMap<String, PendingRetry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Attempts of a scale operation before it is given up, from `SCALE_RETRY_MAX_ATTEMPTS`.
+fn max_attempts() -> u32 {
+    crate::config::get().scale_retry_max_attempts
+}
+
+/// Queues `operation` on the service under `key` to be retried after it failed `attempts` times,
+/// replacing whatever was queued for it.
+pub fn schedule(key: &str, operation: ScaleOperation, attempts: u32) {
+    let backoff = MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_BACKOFF);
+    info!("Retrying {} of {} in {:?}", operation, key, backoff);
+    QUEUE.lock().unwrap().insert(
+        key.to_string(),
+        PendingRetry {
+            operation,
+            attempts,
+            next_attempt: Instant::now() + backoff,
+        },
+    );
+}
+
+/// Whether a failed operation on the service under `key` is waiting to be retried.
+pub fn is_pending(key: &str) -> bool {
+    QUEUE.lock().unwrap().contains_key(key)
+}
+
+/// Drops a queued retry for the service under `key`, as it has been scaled successfully since.
+pub fn clear(key: &str) {
+    QUEUE.lock().unwrap().remove(key);
+}
+
+/// Retries queued scale operations as they become due until shutdown, independently of new
+/// traffic to the services.
+pub async fn run(mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let hpa_controller = HPASuspensionController::shared().await?;
+    loop {
+        // Non-leaders keep their retries queued in case they become the leader
+        let due: Vec<(String, PendingRetry)> = if !super::coordination::may_scale() {
+            Vec::new()
+        } else {
+            let mut queue = QUEUE.lock().unwrap();
+            let now = Instant::now();
+            let keys: Vec<String> = queue
+                .iter()
+                .filter(|(_, retry)| retry.next_attempt <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.into_iter()
+                .filter_map(|key| queue.remove(&key).map(|retry| (key, retry)))
+                .collect()
+        };
+        for (key, retry) in due {
+            retry_operation(hpa_controller, &key, retry).await;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+            _ = shutdown.changed() => {
+                info!("Shutting down, stopping scale retries");
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn retry_operation(hpa_controller: &HPASuspensionController, key: &str, retry: PendingRetry) {
+    let ops = hpa_controller.ops();
+    let Some(service) = read_watched_services().get(key).cloned() else {
+        return;
+    };
+    if retry.operation == ScaleOperation::Up && scaler::circuit_open(&service) {
+        return;
+    }
+    let attempt = retry.attempts + 1;
+    let max_attempts = max_attempts();
+    SCALE_RETRIES.fetch_add(1, Ordering::Relaxed);
+    info!("Retrying {} of {} (attempt {}/{})", retry.operation, key, attempt, max_attempts);
+
+    let result = match retry.operation {
+        ScaleOperation::Up => scaler::guarded(scaler::scale_service_by_ip(ops.clone(), key.to_string()).boxed()).await,
+        ScaleOperation::Down => scaler::guarded(scaler::scale_down_service(hpa_controller, key, service.clone()).boxed()).await,
+    };
+    match result {
+        Ok(()) => {
+            info!("{} of {} succeeded on attempt {}", retry.operation, key, attempt);
+            // Scale-down publishes its own Event
+            if retry.operation == ScaleOperation::Up {
+                ops.publish_event(key, EventType::Normal, "ScaledUp",
+                    format!("Scaled up {} on attempt {}", service.describe_workloads(), attempt)).await;
+                ops.record_policy_event(key, "ScaledUp").await;
+                history::record(key, &service, "scale-up", "retry", "succeeded", None);
+            }
+        }
+        Err(e) if attempt >= max_attempts => {
+            SCALE_RETRIES_EXHAUSTED.fetch_add(1, Ordering::Relaxed);
+            error!("Giving up {} of {} after {} attempts: {:#}", retry.operation, key, attempt, e);
+            if retry.operation == ScaleOperation::Up {
+                if let Some(service) = write_watched_services().get_mut(key) {
+                    service.scale_up_failed = true;
+                }
+                scaler::record_scale_up_failure(ops.as_ref(), key).await;
+                history::record(key, &service, "scale-up", "retry", "failed", Some(format!("{:#}", e)));
+            }
+            ops.publish_event(key, EventType::Warning, "ScaleRetriesExhausted",
+                format!("Gave up the {} of {} after {} attempts: {:#}", retry.operation, service.describe_workloads(), attempt, e)).await;
+            ops.record_policy_event(key, "ScaleRetriesExhausted").await;
+        }
+        Err(e) => {
+            warn!("Attempt {}/{} of the {} of {} failed: {:#}", attempt, max_attempts, retry.operation, key, e);
+            if retry.operation == ScaleOperation::Up {
+                scaler::record_scale_up_failure(ops.as_ref(), key).await;
+                history::record(key, &service, "scale-up", "retry", "failed", Some(format!("{:#}", e)));
+            }
+            ops.publish_event(key, EventType::Warning, "ScaleFailed",
+                format!("Attempt {}/{} of the {} of {} failed: {:#}", attempt, max_attempts, retry.operation, service.describe_workloads(), e)).await;
+            // Tripping the breaker drops the retry
+            let quarantined = read_watched_services().get(key).is_some_and(scaler::circuit_open);
+            if !quarantined {
+                schedule(key, retry.operation, attempt);
+            }
+        }
+    }
+}
@@ -1,237 +1,508 @@
-use super::models::{ServiceData, WATCHED_SERVICES};
+use super::retry::{self, ScaleOperation};
+use super::{connections, coordination, dependency_graph, history, hooks, workload};
+use super::ops::KubeOps;
+use super::models::{
+    read_watched_services, update_service_stats, write_watched_services, ServiceData, WorkloadReference, LAST_SCALED,
+    LAST_SCALE_DOWN_PASS, SCALE_DOWN_ATTEMPTS, SCALE_DOWN_FAILURES, SCALE_UP_ATTEMPTS, SCALE_UP_FAILURES,
+};
 use super::hpa_controller::HPASuspensionController;
-use crate::kubernetes::models::LAST_CALLED;
+use crate::metrics;
 use anyhow::Result;
-use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
-
+use futures::FutureExt;
 use k8s_openapi::chrono;
-use k8s_openapi::serde_json::json;
-use kube::api::Api;
-use kube::api::{Patch, PatchParams};
-use kube::Client;
-use log::{info, error};
+use kube::runtime::events::EventType;
+use log::{debug, info, warn, error};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 
-pub async fn scale_down() -> Result<()> {
-    // Initialize HPA suspension controller for enhanced scaling
-    let hpa_controller = Arc::new(HPASuspensionController::new().await?);
-    
-    let client = Client::try_default().await?;
+pub async fn scale_down(mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let hpa_controller = HPASuspensionController::shared().await?;
+    info!(target: "scale_down", "Checking services for scale down every {:?}",
+          crate::config::get().scale_check_interval);
     loop {
-        // Get all services and sort by scaling priority (lower priority scales down first)
-        let mut services_to_check: Vec<_>;
-        {
-            let watched_services = WATCHED_SERVICES.lock().unwrap();
-            services_to_check = watched_services.iter()
-                .map(|(key, service)| (key.clone(), service.clone()))
-                .collect();
-        }
+        let services_to_check = scale_down_order(&read_watched_services());
+        debug!(target: "scale_down", "Checking {} services for scale down in priority order", services_to_check.len());
         
-        // Sort by scaling priority (lower numbers = parents, scale down first)
-        services_to_check.sort_by_key(|(_, service)| service.scaling_priority);
-        
-        info!(target: "scale_down", "Checking {} services for scale down in priority order", services_to_check.len());
-        
-        for (key, mut service) in services_to_check {
-            let idle_minutes = service.scale_down_time;
-            let last_packet_time = service.last_packet_time;
-            let now = chrono::Utc::now().timestamp();
-            
-            // Check if HPA-enabled service is already scaled down but HPA not deleted
-            if service.hpa_enabled && !service.backend_available && !service.hpa_deleted {
-                info!(target: "scale_down", "Service {} is already scaled down but HPA not deleted, deleting HPA now", service.name);
-                if let Err(e) = hpa_controller.delete_hpa_for_service(&key).await {
-                    error!("Failed to delete HPA for already scaled service {}: {}", key, e);
-                } else {
-                    info!(target: "scale_down", "Successfully deleted HPA for already scaled service {}", service.name);
-                    // The delete_hpa_for_service method already updates WATCHED_SERVICES
+        for (key, service) in services_to_check {
+            // Services with a failed operation are left to the retry queue
+            if retry::is_pending(&key) {
+                continue;
+            }
+            // Leadership can be lost in the middle of a pass
+            if !coordination::may_scale() {
+                debug!(target: "scale_down", "Not the leader, leaving scale-down to the leader");
+                break;
+            }
+            if let Err(e) = guarded(scale_down_service(hpa_controller, &key, service)).await {
+                SCALE_DOWN_FAILURES.fetch_add(1, Ordering::Relaxed);
+                error!(target: "scale_down", "Failed to scale down {}: {:#}", key, e);
+                retry::schedule(&key, ScaleOperation::Down, 1);
+            }
+        }
+        LAST_SCALE_DOWN_PASS.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+        // Only stop between passes so in-flight patches are never cut off
+        tokio::select! {
+            _ = tokio::time::sleep(crate::utils::with_jitter(crate::config::get().scale_check_interval)) => {}
+            _ = shutdown.changed() => {
+                info!("Shutting down, stopping scale-down loop");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Every watched service, by scaling priority: lower numbers are parents, scaled down first.
+fn scale_down_order(watched_services: &HashMap<String, ServiceData>) -> Vec<(String, ServiceData)> {
+    let mut services: Vec<(String, ServiceData)> = watched_services
+        .iter()
+        .map(|(key, service)| (key.clone(), service.clone()))
+        .collect();
+    services.sort_by_key(|(_, service)| service.scaling_priority);
+    services
+}
+
+/// Runs a scale operation, turning a panic into an error so one service cannot take down the
+/// loop scaling all the others.
+pub(super) async fn guarded(operation: impl Future<Output = Result<()>>) -> Result<()> {
+    AssertUnwindSafe(operation)
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("panicked while scaling")))
+}
+
+/// Scales the service watched under `key` to zero, or to its `idle_replicas`, if it has been idle
+/// for its scale-down time.
+pub(super) async fn scale_down_service(
+    hpa_controller: &HPASuspensionController,
+    key: &str,
+    service: ServiceData,
+) -> Result<()> {
+    if service.workload_missing || paused(&service) {
+        return Ok(());
+    }
+    let idle_minutes = service.scale_down_time;
+    let last_packet_time = service.last_packet_time.get();
+    let now = chrono::Utc::now().timestamp();
+    
+    // Check if HPA-enabled service is already scaled down but HPA not deleted
+    if service.hpa_enabled && !service.backend_available && !service.hpa_deleted {
+        info!(target: "scale_down", "Service {} is already scaled down but HPA not deleted, deleting HPA now", service.name);
+        if let Err(e) = hpa_controller.delete_hpa_for_service(key, "scale-down").await {
+            error!("Failed to delete HPA for already scaled service {}: {}", key, e);
+        } else {
+            info!(target: "scale_down", "Successfully deleted HPA for already scaled service {}", service.name);
+            // The delete_hpa_for_service method already updates WATCHED_SERVICES
+        }
+    }
+    
+    if let Some(until) = service.manual_override_until {
+        if now < until {
+            return Ok(());
+        }
+        info!(target: "scale_down", "Manual scale of {}/{} has expired, resuming scale-down", service.namespace, service.name);
+        if let Some(watched) = write_watched_services().get_mut(key) {
+            watched.manual_override_until = None;
+        }
+        hpa_controller.ops().record_policy_event(key, "ManualOverrideExpired").await;
+    }
+
+    // A service just woken up is kept up a while, or a single probe would wake it every time
+    if let Some(last_scale_up_time) = service.last_scale_up_time
+        && now - last_scale_up_time < service.min_uptime
+    {
+        return Ok(());
+    }
+
+    // Idle time keeps being tracked outside the allowed windows, so the service is scaled down
+    // as soon as the next one opens
+    let in_window = service.allowed_windows.is_empty()
+        || service.allowed_windows.iter().any(|window| window.contains(chrono::Utc::now()));
+    let scaled_down = !service.backend_available || service.at_idle_floor;
+    if now - last_packet_time > idle_minutes && !scaled_down && !in_window {
+        debug!(target: "scale_down", "Not scaling down {}/{}: outside its allowed windows", service.namespace, service.name);
+        return Ok(());
+    }
+
+    // A service is still called by dependents that are up, whatever its own idle timer says.
+    // Within a dependency cycle, waiting for each other would keep every member up forever.
+    if now - last_packet_time > idle_minutes && !scaled_down && !service.scale_down_independently {
+        let watched_services = read_watched_services();
+        let busy_dependent = dependency_graph::direct_dependents(&watched_services, key)
+            .into_iter()
+            .filter(|dependent| !service.dependency_cycle.contains(dependent))
+            .find_map(|dependent| watched_services.get(&dependent).filter(|dependent| dependent.backend_available));
+        if let Some(dependent) = busy_dependent {
+            debug!(target: "scale_down", "Not scaling down {}/{}: its dependent {}/{} is still up",
+                   service.namespace, service.name, dependent.namespace, dependent.name);
+            return Ok(());
+        }
+    }
+
+    if now - last_packet_time > idle_minutes && !scaled_down {
+        // Long-lived connections can be busy without sending the packets the idle timer sees;
+        // while a pod reports any, they count as traffic
+        if let Some(endpoint) = &service.active_connections_endpoint {
+            let active = connections::active_connections(&service, endpoint).await;
+            if active > 0 {
+                info!(target: "scale_down", "Not scaling down {}/{}: its pods report {} active connections",
+                      service.namespace, service.name, active);
+                if let Some(service) = write_watched_services().get_mut(key) {
+                    service.last_packet_time.touch(now);
                 }
+                return Ok(());
             }
-            
-            if now - last_packet_time > idle_minutes as i64 && service.backend_available {
-                info!(target: "scale_down", "Scaling down backends of {} in namespace {} (priority: {} - {})", 
-                      service.name, service.namespace, service.scaling_priority,
-                      if service.scaling_priority <= 50 { "parent" } else { "child" });
-                
-                service.backend_available = false;
-                
-                // Delete HPA for HPA-enabled services before scaling to zero
-                if service.hpa_enabled && !service.hpa_deleted {
-                    info!(target: "scale_down", "Service {} is HPA-enabled and not deleted, deleting HPA before scaling to zero", service.name);
-                    if let Err(e) = hpa_controller.delete_hpa_for_service(&key).await {
-                        error!("Failed to delete HPA for service {}: {}", key, e);
-                        // Continue with direct scaling as fallback
-                    } else {
-                        info!(target: "scale_down", "Successfully deleted HPA for service {}", service.name);
-                        // The delete_hpa_for_service method already updates the service data
+        }
+
+        // An HPA holding more replicas is scaling on load that does not reach the service as
+        // packets, such as batch work; if it cannot be read, the service is left up as well
+        if service.hpa_enabled
+            && !service.hpa_deleted
+            && let (Some(hpa_name), Some(target)) = (&service.hpa_name, service.hpa_target())
+        {
+            match hpa_controller.desired_replicas(&target.namespace, hpa_name).await {
+                Ok(Some(desired)) if desired > service.idle_replicas.max(1) => {
+                    info!(target: "scale_down", "Not scaling down {}/{}: its HPA {} wants {} replicas",
+                          service.namespace, service.name, hpa_name, desired);
+                    if let Some(service) = write_watched_services().get_mut(key) {
+                        service.last_packet_time.touch(now);
                     }
-                } else if service.hpa_enabled && service.hpa_deleted {
-                    info!(target: "scale_down", "Service {} HPA is already deleted", service.name);
-                }
-                
-                // Perform direct scaling to zero
-                if service.kind == "deployment" {
-                    let deployments: Api<Deployment> = Api::namespaced(client.clone(), &service.namespace);
-                    deployments
-                        .patch(
-                            service.name.as_str(),
-                            &PatchParams::default(),
-                            &Patch::Merge(json!({
-                                "spec": {
-                                    "replicas": 0
-                                }
-                            })),
-                        )
-                        .await?;
-                } else if service.kind == "statefulset" {
-                    let statefulsets: Api<StatefulSet> = Api::namespaced(client.clone(), &service.namespace);
-                    statefulsets
-                        .patch(
-                            service.name.as_str(),
-                            &PatchParams::default(),
-                            &Patch::Merge(json!({
-                                "spec": {
-                                    "replicas": 0
-                                }
-                            })),
-                        )
-                        .await?;
+                    return Ok(());
                 }
-                {
-                    let mut watched_services = WATCHED_SERVICES.lock().unwrap();
-                    let service_to_update = watched_services.get_mut(&key).unwrap();
-                    *service_to_update = service;
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(target: "scale_down", "Not scaling down {}/{}: failed to read its HPA: {:#}",
+                          service.namespace, service.name, e);
+                    return Ok(());
                 }
             }
         }
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+        scale_down_now(hpa_controller, key, service, "idle-timer",
+                       format!("No traffic for {}s", now - last_packet_time)).await?;
     }
+    Ok(())
 }
 
-pub async fn scale_up(service_ip: String) -> Result<()> {
-    let now = SystemTime::now();
-    {
-        let mut last_called = LAST_CALLED.lock().unwrap();
-        if let Some(time) = last_called.get(&service_ip) {
-            if now.duration_since(*time)? < Duration::from_secs(5) {
-                return Err(anyhow::anyhow!(
-                    "Rate Limited: Function can only be called once every 5 seconds per service_ip"
-                ));
+/// Scales the service under `key` down to its idle replicas, zero by default, after calling its
+/// pre-scale-down hook. `cause` starts the message of the Event published for it.
+pub(super) async fn scale_down_now(
+    hpa_controller: &HPASuspensionController,
+    key: &str,
+    mut service: ServiceData,
+    trigger: &str,
+    cause: String,
+) -> Result<()> {
+    let ops = hpa_controller.ops().as_ref();
+    let now = chrono::Utc::now().timestamp();
+    let last_packet_time = service.last_packet_time.get();
+    if let Some(url) = &service.pre_scale_down_hook {
+        let idle_seconds = now - last_packet_time;
+        match hooks::call_pre_scale_down(url, &service, idle_seconds).await {
+            Ok(()) => {
+                ops.publish_event(key, EventType::Normal, "PreScaleDownHookSucceeded",
+                    format!("Pre-scale-down hook {} succeeded", url)).await;
+            }
+            Err(e) if hooks::fail_open() => {
+                ops.publish_event(key, EventType::Warning, "PreScaleDownHookFailed",
+                    format!("{:#}, scaling down anyway", e)).await;
+            }
+            Err(e) => {
+                ops.publish_event(key, EventType::Warning, "PreScaleDownHookFailed",
+                    format!("{:#}, scale-down deferred", e)).await;
+                history::record(key, &service, "scale-down", trigger, "deferred", Some(format!("{:#}", e)));
+                return Err(e);
             }
         }
-        last_called.insert(service_ip.clone(), now);
     }
-    info!(target: "scale_up", "Scaling up backends of {}", service_ip);
 
-    let client = Client::try_default().await?;
+    SCALE_DOWN_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+    info!(target: "scale_down", "Scaling down backends of {} in namespace {} (priority: {} - {})", 
+          service.name, service.namespace, service.scaling_priority,
+          if service.scaling_priority <= 50 { "parent" } else { "child" });
+    
+    // With idle replicas left the service stays available, and its HPA is left alone
+    if service.idle_replicas > 0 {
+        service.at_idle_floor = true;
+    } else {
+        service.backend_available = false;
+    }
+
+    // Delete HPA for HPA-enabled services before scaling to zero
+    if service.hpa_enabled && !service.hpa_deleted && service.idle_replicas == 0 {
+        info!(target: "scale_down", "Service {} is HPA-enabled and not deleted, deleting HPA before scaling to zero", service.name);
+        if let Err(e) = hpa_controller.delete_hpa_for_service(key, "scale-down").await {
+            error!("Failed to delete HPA for service {}: {}", key, e);
+            // Continue with direct scaling as fallback
+        } else {
+            info!(target: "scale_down", "Successfully deleted HPA for service {}", service.name);
+            // Recorded in WATCHED_SERVICES by the HPA controller
+            if let Some(watched) = read_watched_services().get(key) {
+                service.hpa_deleted = watched.hpa_deleted;
+                service.hpa_config = watched.hpa_config.clone();
+            }
+        }
+    } else if service.hpa_enabled && service.hpa_deleted && service.idle_replicas == 0 {
+        info!(target: "scale_down", "Service {} HPA is already deleted", service.name);
+    }
     
+    remember_replicas(ops, key, &mut service).await;
+
+    // KEDA owns the HPA of a ScaledObject, so it is paused rather than the HPA touched
+    if let Some(scaled_object) = &service.keda_scaled_object
+        && let Err(e) = ops.pause_scaled_object(&service.namespace, scaled_object, service.idle_replicas).await
+    {
+        history::record(key, &service, "scale-down", trigger, "failed", Some(format!("{:#}", e)));
+        return Err(e);
+    }
+
+    // Perform direct scaling to zero
+    let target = if service.idle_replicas > 0 {
+        format!("{} replicas", service.idle_replicas)
+    } else {
+        "zero".to_string()
+    };
+    if let Err(e) = set_replicas(ops, key, &service, service.idle_replicas).await {
+        ops.publish_event(key, EventType::Warning, "ScaleFailed",
+            format!("Failed to scale {} to {}: {:#}", service.describe_workloads(), target, e)).await;
+        ops.record_policy_event(key, "ScaleFailed").await;
+        history::record(key, &service, "scale-down", trigger, "failed", Some(format!("{:#}", e)));
+        return Err(e);
+    }
+    history::record(key, &service, "scale-down", trigger, "succeeded", None);
+    let reason = if service.idle_replicas > 0 { "ScaledToIdleReplicas" } else { "ScaledToZero" };
+    update_service_stats(key, |stats| stats.record_scale_down(now, service.idle_replicas == 0));
+    ops.publish_event(key, EventType::Normal, reason,
+        format!("{}, scaled {} to {}", cause, service.describe_workloads(), target)).await;
+    // `service` was taken before the hook and the API calls above, while packets, the watcher and
+    // scale-ups may have changed the live entry, so only what the scale-down changed is written
+    if let Some(watched) = write_watched_services().get_mut(key) {
+        watched.backend_available = service.backend_available;
+        watched.at_idle_floor = service.at_idle_floor;
+        watched.hpa_deleted = service.hpa_deleted;
+        watched.hpa_config = service.hpa_config;
+        watched.previous_replicas = service.previous_replicas;
+    }
+    ops.record_policy_event(key, reason).await;
+    Ok(())
+}
+
+/// What `scale_up` did with a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleUpOutcome {
+    ScaledUp,
+    /// The service was scaled up less than its `scale_up_cooldown` ago.
+    RateLimited,
+    /// The service failed to scale up too often and its circuit breaker is open.
+    Quarantined,
+    /// Another node is the leader and scales the service up.
+    NotLeader,
+    /// Automatic scaling of the service is paused through the admin API.
+    Paused,
+}
+
+/// Scales up the service watched under `service_ip` together with its dependencies and
+/// dependents, because of traffic from `source`.
+pub async fn scale_up(service_ip: String, source: String) -> Result<ScaleUpOutcome> {
     // Get the service that received traffic
-    let service: ServiceData;
+    let Some(service) = read_watched_services().get(&service_ip).cloned() else {
+        anyhow::bail!("service {} is not watched", service_ip);
+    };
+
+    if !coordination::may_scale() {
+        return Ok(ScaleUpOutcome::NotLeader);
+    }
+    if circuit_open(&service) {
+        return Ok(ScaleUpOutcome::Quarantined);
+    }
+    if paused(&service) {
+        return Ok(ScaleUpOutcome::Paused);
+    }
+
     {
-        let watched_services = WATCHED_SERVICES.lock().unwrap();
-        service = watched_services.get(&service_ip).unwrap().clone();
+        let mut watched_services = write_watched_services();
+        let Some(service) = watched_services.get_mut(&service_ip) else {
+            anyhow::bail!("service {} is no longer watched", service_ip);
+        };
+        if !claim_scale_up(service, chrono::Utc::now().timestamp_millis()) {
+            return Ok(ScaleUpOutcome::RateLimited);
+        }
     }
+    // Latency is measured from the packet, not from when its dependencies are ready
+    update_service_stats(&service_ip, |stats| {
+        stats.scale_up_requested_at.get_or_insert(chrono::Utc::now().timestamp_millis());
+    });
+    info!(target: "scale_up", "Scaling up backends of {}", service_ip);
+
+    let ops = HPASuspensionController::shared().await?.ops().clone();
 
     info!(target: "scale_up", "Initiating ordered scale up for {} (priority: {})", service.name, service.scaling_priority);
     
-    // Step 1: Identify all services that need to be scaled up based on dependencies
+    // Step 1: Identify all services that need to be scaled up: the service's direct dependents,
+    // and everything the service and those dependents depend on, directly or transitively
+    let max_depth = max_dependency_depth();
+    let keys: Vec<String> = {
+        let watched_services = read_watched_services();
+        let dependents: Vec<String> = service
+            .dependents
+            .iter()
+            .flat_map(|target| dependency_graph::resolve_target(&watched_services, target))
+            .collect();
+        let mut keys = vec![service_ip.clone()];
+        keys.extend(dependency_graph::dependency_closure(&watched_services, &service_ip, max_depth));
+        for dependent in dependents {
+            keys.push(dependent.clone());
+            keys.extend(dependency_graph::dependency_closure(&watched_services, &dependent, max_depth));
+        }
+        keys
+    };
+    let mut seen = HashSet::new();
     let mut services_to_scale = Vec::new();
-    services_to_scale.push((service_ip.clone(), service.clone()));
-    
-    // Add children (dependencies) to scale up list
-    for dependency_target in &service.dependencies {
-        if let Some(dep_ip) = find_service_ip_by_target(dependency_target).await {
-            let dep_service = {
-                let watched_services = WATCHED_SERVICES.lock().unwrap();
-                watched_services.get(&dep_ip).cloned()
-            };
-            
-            if let Some(dep_service) = dep_service {
-                if !dep_service.backend_available {
-                    info!(target: "scale_up", "Adding dependency {} to scale up list", dep_service.name);
-                    services_to_scale.push((dep_ip, dep_service));
-                }
-            }
+    for key in keys {
+        if !seen.insert(key.clone()) {
+            continue;
+        }
+        let Some(svc) = read_watched_services().get(&key).cloned() else {
+            continue;
+        };
+        if key == service_ip {
+            services_to_scale.push((key, svc));
+        } else if !svc.backend_available || svc.at_idle_floor {
+            info!(target: "scale_up", "Adding {} to scale up list", svc.name);
+            services_to_scale.push((key, svc));
         }
     }
     
-    // Add parents (dependents) to scale up list
-    for dependent_target in &service.dependents {
-        if let Some(dep_ip) = find_service_ip_by_target(dependent_target).await {
-            let dep_service = {
-                let watched_services = WATCHED_SERVICES.lock().unwrap();
-                watched_services.get(&dep_ip).cloned()
-            };
+    // Step 2: Group into tiers by scaling priority
+    let count = services_to_scale.len();
+    let tiers = scale_up_tiers(&mut services_to_scale);
+    
+    info!(target: "scale_up", "Scaling up {} services in {} dependency tiers", count, tiers.len());
+    
+    // Step 3: Scale up one tier at a time (children first, parents last), each once the tier
+    // before it is ready, so no service is available before the services it calls
+    let timeout = Duration::from_secs(scale_up_timeout().max(0) as u64);
+    for (index, tier) in tiers.iter().enumerate() {
+        let mut scaled = Vec::new();
+        for (ip, svc) in tier.iter() {
+            if svc.workload_missing {
+                info!(target: "scale_up", "Not scaling up {}: its workload is missing", svc.name);
+                continue;
+            }
+            if circuit_open(svc) {
+                info!(target: "scale_up", "Not scaling up {}: it is quarantined after failing to scale up", svc.name);
+                continue;
+            }
+            info!(target: "scale_up", "Scaling up {} (priority: {} - {})", 
+                  svc.name, svc.scaling_priority,
+                  if svc.scaling_priority <= 50 { "parent" } else { "child" });
             
-            if let Some(dep_service) = dep_service {
-                if !dep_service.backend_available {
-                    info!(target: "scale_up", "Adding dependent {} to scale up list", dep_service.name);
-                    services_to_scale.push((dep_ip, dep_service));
+            if let Err(e) = scale_service_by_ip(ops.clone(), ip.clone()).await {
+                error!("Failed to scale up service {}: {}", svc.name, e);
+                ops.publish_event(ip, EventType::Warning, "ScaleFailed",
+                    format!("Failed to scale up {}: {:#}", svc.describe_workloads(), e)).await;
+                ops.record_policy_event(ip, "ScaleFailed").await;
+                history::record(ip, svc, "scale-up", &source, "failed", Some(format!("{:#}", e)));
+                record_scale_up_failure(ops.as_ref(), ip).await;
+                // Clients keep waiting on it whether or not more packets arrive
+                if !retry::is_pending(ip) {
+                    retry::schedule(ip, ScaleOperation::Up, 1);
                 }
+            } else {
+                retry::clear(ip);
+                let note = if *ip == service_ip {
+                    format!("Scaled up {} on traffic from {}", svc.describe_workloads(), source)
+                } else {
+                    format!("Scaled up {} along with {} on traffic from {}", svc.describe_workloads(), service.name, source)
+                };
+                ops.publish_event(ip, EventType::Normal, "ScaledUp", note).await;
+                ops.record_policy_event(ip, "ScaledUp").await;
+                history::record(ip, svc, "scale-up", &source, "succeeded", None);
             }
+            scaled.push(ip.clone());
         }
-    }
-    
-    // Step 2: Sort by scaling priority (higher numbers = children, scale up first)
-    services_to_scale.sort_by_key(|(_, service)| std::cmp::Reverse(service.scaling_priority));
-    
-    info!(target: "scale_up", "Scaling up {} services in dependency order", services_to_scale.len());
-    
-    // Step 3: Scale up services in priority order (children first, parents last)
-    for (ip, svc) in services_to_scale {
-        info!(target: "scale_up", "Scaling up {} (priority: {} - {})", 
-              svc.name, svc.scaling_priority,
-              if svc.scaling_priority <= 50 { "parent" } else { "child" });
-        
-        if let Err(e) = scale_service_by_ip(client.clone(), ip).await {
-            error!("Failed to scale up service {}: {}", svc.name, e);
-        } else {
-            // Add a small delay between scaling operations to ensure proper ordering
-            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let Some(later_tiers) = tiers.get(index + 1..).filter(|later| !later.is_empty()) else {
+            break;
+        };
+        if let Some(stuck) = await_tier(&scaled, timeout).await {
+            let stuck_name = read_watched_services()
+                .get(&stuck)
+                .map(|stuck| format!("{}/{}", stuck.namespace, stuck.name))
+                .unwrap_or(stuck);
+            let waiting: Vec<String> = later_tiers
+                .iter()
+                .flat_map(|tier| tier.iter())
+                .map(|(_, svc)| format!("{}/{}", svc.namespace, svc.name))
+                .collect();
+            warn!(target: "scale_up", "Dependency {} of {} did not become ready within {:?}, not scaling up {}",
+                  stuck_name, service.name, timeout, waiting.join(", "));
+            ops.publish_event(&service_ip, EventType::Warning, "DependencyNotReady",
+                format!("Dependency {} did not become ready within {}s, not scaling up {}",
+                        stuck_name, timeout.as_secs(), waiting.join(", "))).await;
+            history::record(&service_ip, &service, "scale-up", &source, "dependency-not-ready",
+                Some(format!("dependency {} did not become ready within {}s", stuck_name, timeout.as_secs())));
+            anyhow::bail!("dependency {} of {} did not become ready", stuck_name, service_ip);
         }
     }
     
-    Ok(())
+    Ok(ScaleUpOutcome::ScaledUp)
 }
 
-async fn find_service_ip_by_target(target: &str) -> Option<String> {
-    let watched_services = WATCHED_SERVICES.lock().unwrap();
-    
-    // Try to find by IP first
-    if watched_services.contains_key(target) {
-        return Some(target.to_string());
+/// Records a scale-up request for `service` at `now`, in Unix milliseconds, unless the last one
+/// was within its `scale_up_cooldown`. Returns whether it did, false meaning rate limited.
+fn claim_scale_up(service: &mut ServiceData, now: i64) -> bool {
+    if service
+        .last_scale_up_request
+        .is_some_and(|last| now - last < service.scale_up_cooldown.max(0) * 1000)
+    {
+        return false;
     }
-    
-    // Try to find by service name
-    for (ip, service_data) in watched_services.iter() {
-        let is_match = if target.contains('/') {
-            // namespace/service-name format
-            let parts: Vec<&str> = target.split('/').collect();
-            if parts.len() == 2 {
-                let target_namespace = parts[0];
-                let target_name = parts[1];
-                service_data.name == target_name && service_data.namespace == target_namespace
-            } else {
-                false
-            }
-        } else {
-            // Just service name, look in all namespaces
-            service_data.name == target
+    service.last_scale_up_request = Some(now);
+    true
+}
+
+/// Sorts `services` into tiers of equal scaling priority, highest first: higher numbers are
+/// children, scaled up first.
+fn scale_up_tiers(services: &mut [(String, ServiceData)]) -> Vec<&[(String, ServiceData)]> {
+    services.sort_by_key(|(_, service)| std::cmp::Reverse(service.scaling_priority));
+    services
+        .chunk_by(|(_, a), (_, b)| a.scaling_priority == b.scaling_priority)
+        .collect()
+}
+
+/// Levels of dependencies scaled up along with a service, from `MAX_DEPENDENCY_DEPTH`.
+fn max_dependency_depth() -> usize {
+    crate::config::get().max_dependency_depth
+}
+
+/// Waits until every service under `keys` is available. Returns the first one that is not if
+/// `timeout` passes or its scale-up gives up first.
+async fn await_tier(keys: &[String], timeout: Duration) -> Option<String> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let (stuck, failed) = {
+            let watched_services = read_watched_services();
+            let stuck = keys
+                .iter()
+                .find(|key| watched_services.get(*key).is_some_and(|service| !service.backend_available))?;
+            (stuck.clone(), watched_services[stuck].scale_up_failed)
         };
-        
-        if is_match {
-            return Some(ip.clone());
+        if failed || Instant::now() >= deadline {
+            return Some(stuck);
         }
+        tokio::time::sleep(Duration::from_millis(500)).await;
     }
-    
-    None
 }
 
-async fn scale_service_by_ip(client: Client, service_ip: String) -> Result<()> {
-    let mut service: ServiceData;
+pub(super) async fn scale_service_by_ip(ops: Arc<dyn KubeOps>, service_ip: String) -> Result<()> {
+    let service: ServiceData;
     {
-        let mut watched_services = WATCHED_SERVICES.lock().unwrap();
+        let mut watched_services = write_watched_services();
         service = match watched_services.get_mut(&service_ip) {
             Some(s) => s.clone(),
             None => {
@@ -240,39 +511,68 @@ async fn scale_service_by_ip(client: Client, service_ip: String) -> Result<()> {
             }
         };
     }
-    service.backend_available = true;
+    info!(target: "scale_up", "Scaling up {} for service {}/{}", service.describe_workloads(), service.namespace, service.name);
+    SCALE_UP_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
 
-    info!(target: "scale_up", "Scaling up {} {} in namespace {}", service.kind, service.name, service.namespace);
-    
-    // Perform direct scaling to 1 replica (immediate response)
-    if service.kind == "deployment" {
-        let deployments: Api<Deployment> = Api::namespaced(client.clone(), &service.namespace);
-        deployments
-            .patch(
-                service.name.as_str(),
-                &PatchParams::default(),
-                &Patch::Merge(json!({
-                    "spec": {
-                        "replicas": 1
-                    }
-                })),
-            )
-            .await?;
-    } else if service.kind == "statefulset" {
-        let statefulsets: Api<StatefulSet> = Api::namespaced(client.clone(), &service.namespace);
-        statefulsets
-            .patch(
-                service.name.as_str(),
-                &PatchParams::default(),
-                &Patch::Merge(json!({
-                    "spec": {
-                        "replicas": 1
-                    }
-                })),
-            )
-            .await?;
+    // Traffic already reaches a service at its idle replicas, so nothing is held while it grows
+    if service.at_idle_floor {
+        restore_replicas(ops.as_ref(), &service_ip, &service).await?;
+        if let Some(service) = write_watched_services().get_mut(&service_ip) {
+            service.at_idle_floor = false;
+            service.last_scale_up_time = Some(chrono::Utc::now().timestamp());
+        }
+        // Available all along, so there is no wait to measure
+        update_service_stats(&service_ip, |stats| {
+            stats.record_scale_up(chrono::Utc::now().timestamp_millis());
+            stats.clear_scale_up();
+        });
+        return Ok(());
     }
-    
+
+    // Held until a replica is ready, so flows are not released to a backend that cannot answer
+    let already_waiting = {
+        let mut watched_services = write_watched_services();
+        match watched_services.get_mut(&service_ip) {
+            Some(service) => {
+                let waiting = service.scale_up_started.is_some();
+                service.scale_up_started.get_or_insert_with(|| chrono::Utc::now().timestamp());
+                service.scale_up_failed = false;
+                waiting
+            }
+            None => false,
+        }
+    };
+    // Dependencies are requested by the scale-up of the service they serve
+    update_service_stats(&service_ip, |stats| {
+        stats.scale_up_requested_at.get_or_insert_with(|| chrono::Utc::now().timestamp_millis());
+    });
+
+    let restored = async {
+        // Resumed first, as KEDA holds a paused ScaledObject's workload at its paused replicas
+        if let Some(scaled_object) = &service.keda_scaled_object {
+            ops.resume_scaled_object(&service.namespace, scaled_object).await?;
+        }
+        restore_replicas(ops.as_ref(), &service_ip, &service).await
+    }
+    .await;
+    if let Err(e) = restored {
+        if let Some(service) = write_watched_services().get_mut(&service_ip).filter(|_| !already_waiting) {
+            service.scale_up_started = None;
+        }
+        if !already_waiting {
+            update_service_stats(&service_ip, |stats| stats.clear_scale_up());
+        }
+        SCALE_UP_FAILURES.fetch_add(1, Ordering::Relaxed);
+        return Err(e);
+    }
+    if let Some(service) = write_watched_services().get_mut(&service_ip) {
+        service.last_scale_up_time = Some(chrono::Utc::now().timestamp());
+    }
+    // A scale-up joining one still waiting keeps timing from the first patch
+    if !already_waiting {
+        update_service_stats(&service_ip, |stats| stats.record_scale_up(chrono::Utc::now().timestamp_millis()));
+    }
+
     // Create/recreate HPA if service is HPA-enabled
     if service.hpa_enabled {
         if service.hpa_deleted {
@@ -287,7 +587,7 @@ async fn scale_service_by_ip(client: Client, service_ip: String) -> Result<()> {
             async move {
                 tokio::time::sleep(std::time::Duration::from_secs(5)).await;
                 
-                let hpa_controller = match HPASuspensionController::new().await {
+                let hpa_controller = match HPASuspensionController::shared().await {
                     Ok(controller) => controller,
                     Err(e) => {
                         error!("Failed to create HPA controller for HPA creation: {}", e);
@@ -295,7 +595,7 @@ async fn scale_service_by_ip(client: Client, service_ip: String) -> Result<()> {
                     }
                 };
                 
-                if let Err(e) = hpa_controller.recreate_hpa_for_service(&service_ip_clone).await {
+                if let Err(e) = hpa_controller.recreate_hpa_for_service(&service_ip_clone, "scale-up").await {
                     error!("Failed to create/recreate HPA for service {} after delay: {}", service_ip_clone, e);
                 } else {
                     info!(target: "scale_up", "Successfully created/recreated HPA for service {} after delay", service_ip_clone);
@@ -303,14 +603,562 @@ async fn scale_service_by_ip(client: Client, service_ip: String) -> Result<()> {
             }
         });
     }
-    
-    // Update the service in WATCHED_SERVICES to ensure consistency
-    {
-        let mut watched_services = WATCHED_SERVICES.lock().unwrap();
-        if let Some(service_to_update) = watched_services.get_mut(&service_ip) {
-            *service_to_update = service;
+
+    if !already_waiting {
+        tokio::spawn(await_availability(ops, service_ip));
+    }
+
+    Ok(())
+}
+
+/// Seconds a scaled-up service gets to become available, from `SCALE_UP_TIMEOUT_SECONDS`.
+fn scale_up_timeout() -> i64 {
+    crate::config::get().scale_up_timeout.as_secs() as i64
+}
+
+/// Waits for the service under `service_ip` to become available after a scale-up. With
+/// EndpointSlices the watcher marks it available once an endpoint is ready; without, this does
+/// once every workload has a ready replica. Gives up after `scale_up_timeout()`, marking the
+/// service failed so the next packet tries again.
+async fn await_availability(ops: Arc<dyn KubeOps>, service_ip: String) {
+    loop {
+        let Some(service) = read_watched_services().get(&service_ip).cloned() else {
+            return;
+        };
+        let Some(started) = service.scale_up_started else {
+            return;
+        };
+        let ready = service.backend_available
+            || (service.ready_endpoints.is_none() && workloads_ready(ops.as_ref(), &service).await);
+        let elapsed = chrono::Utc::now().timestamp() - started;
+        if ready {
+            info!(target: "scale_up", "Service {}/{} became available {}s after scaling up", service.namespace, service.name, elapsed);
+            if let Some(service) = write_watched_services().get_mut(&service_ip) {
+                service.scale_up_started = None;
+                service.backend_available = true;
+                service.scale_up_failures = 0;
+                service.circuit_open_until = None;
+            }
+            let mut latency = None;
+            update_service_stats(&service_ip, |stats| latency = stats.record_available(chrono::Utc::now().timestamp_millis()));
+            if let Some(latency) = latency {
+                metrics::SCALE_UP_LATENCY.observe(latency);
+            }
+            ops.record_policy_event(&service_ip, "ScaledUp").await;
+            return;
         }
+        if elapsed >= scale_up_timeout() {
+            warn!(target: "scale_up", "Service {}/{} did not become available within {}s of scaling up {}",
+                  service.namespace, service.name, elapsed, service.describe_workloads());
+            if let Some(service) = write_watched_services().get_mut(&service_ip) {
+                service.scale_up_started = None;
+                service.scale_up_failed = true;
+            }
+            update_service_stats(&service_ip, |stats| stats.clear_scale_up());
+            SCALE_UP_FAILURES.fetch_add(1, Ordering::Relaxed);
+            ops.publish_event(&service_ip, EventType::Warning, "ScaleUpTimedOut",
+                format!("No ready replica of {} within {}s of scaling up", service.describe_workloads(), elapsed)).await;
+            ops.record_policy_event(&service_ip, "ScaleUpTimedOut").await;
+            history::record(&service_ip, &service, "scale-up", "scale-up-timeout", "timed-out",
+                Some(format!("no ready replica within {}s", elapsed)));
+            record_scale_up_failure(ops.as_ref(), &service_ip).await;
+            return;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Whether automatic scaling of `service` is paused.
+pub(crate) fn paused(service: &ServiceData) -> bool {
+    service.paused_until.is_some_and(|until| chrono::Utc::now().timestamp() < until)
+}
+
+/// Pauses automatic scaling of the service under `key` until `until`, or resumes it without.
+/// Returns whether the service is watched.
+pub async fn set_paused(key: &str, until: Option<i64>) -> bool {
+    let service = {
+        let mut watched_services = write_watched_services();
+        let Some(service) = watched_services.get_mut(key) else {
+            return false;
+        };
+        service.paused_until = until;
+        service.clone()
+    };
+    let ops = match HPASuspensionController::shared().await {
+        Ok(controller) => controller.ops(),
+        Err(e) => {
+            warn!("Failed to publish the pause of {}/{}: {:#}", service.namespace, service.name, e);
+            return true;
+        }
+    };
+    match until {
+        Some(until) => {
+            info!("Pausing automatic scaling of {}/{} for {}s", service.namespace, service.name,
+                  until - chrono::Utc::now().timestamp());
+            ops.publish_event(key, EventType::Normal, "ScalingPaused",
+                format!("Automatic scaling paused until {}",
+                        chrono::DateTime::from_timestamp(until, 0).map(|time| time.to_rfc3339()).unwrap_or_default())).await;
+        }
+        None => {
+            info!("Resuming automatic scaling of {}/{}", service.namespace, service.name);
+            ops.publish_event(key, EventType::Normal, "ScalingResumed",
+                "Automatic scaling resumed".to_string()).await;
+        }
+    }
+    true
+}
+
+/// What `force_scale_down` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForcedScaleDown {
+    ScaledDown,
+    /// The service is already at its idle replicas or zero.
+    AlreadyScaledDown,
+    /// Another node is the leader and scales services.
+    NotLeader,
+}
+
+/// Scales the service under `key` down right away, however recently it received traffic, on
+/// request of `source`.
+pub async fn force_scale_down(key: &str, source: &str) -> Result<ForcedScaleDown> {
+    let Some(service) = read_watched_services().get(key).cloned() else {
+        anyhow::bail!("service {} is not watched", key);
+    };
+    if !coordination::may_scale() {
+        return Ok(ForcedScaleDown::NotLeader);
+    }
+    if service.workload_missing {
+        anyhow::bail!("a workload of {}/{} does not exist", service.namespace, service.name);
+    }
+    if !service.backend_available || service.at_idle_floor {
+        return Ok(ForcedScaleDown::AlreadyScaledDown);
+    }
+    let hpa_controller = HPASuspensionController::shared().await?;
+    let cause = format!("Forced through the {}", source);
+    guarded(scale_down_now(hpa_controller, key, service, source, cause)).await?;
+    Ok(ForcedScaleDown::ScaledDown)
+}
+
+/// Whether `service` is quarantined after failing to scale up too often.
+pub(super) fn circuit_open(service: &ServiceData) -> bool {
+    service.circuit_open_until.is_some_and(|until| chrono::Utc::now().timestamp() < until)
+}
+
+/// Counts a failed scale-up of the service under `key`. After `CIRCUIT_BREAKER_THRESHOLD` (3 by
+/// default) in a row, it is quarantined for `CIRCUIT_BREAKER_COOLDOWN_SECONDS` (300 by default);
+/// the first scale-up after that failing too quarantines it again.
+pub(super) async fn record_scale_up_failure(ops: &dyn KubeOps, key: &str) {
+    let config = crate::config::get();
+    let threshold = config.circuit_breaker_threshold;
+    let cooldown = config.circuit_breaker_cooldown.as_secs() as i64;
+    let tripped = {
+        let mut watched_services = write_watched_services();
+        let Some(service) = watched_services.get_mut(key) else {
+            return;
+        };
+        service.scale_up_failures += 1;
+        if service.scale_up_failures >= threshold && !circuit_open(service) {
+            service.circuit_open_until = Some(chrono::Utc::now().timestamp() + cooldown);
+            Some(service.clone())
+        } else {
+            None
+        }
+    };
+    if let Some(service) = tripped {
+        warn!(target: "scale_up", "Service {}/{} failed to scale up {} times in a row, quarantining it for {}s",
+              service.namespace, service.name, service.scale_up_failures, cooldown);
+        retry::clear(key);
+        ops.publish_event(key, EventType::Warning, "CircuitBreakerOpen",
+            format!("{} failed to scale up {} times in a row, traffic no longer scales it up for {}s",
+                    service.describe_workloads(), service.scale_up_failures, cooldown)).await;
+        ops.record_policy_event(key, "CircuitBreakerOpen").await;
+    }
+}
+
+/// Whether every workload of `service` has a ready replica.
+async fn workloads_ready(ops: &dyn KubeOps, service: &ServiceData) -> bool {
+    for reference in &service.workloads {
+        match ops.ready_replicas(reference).await {
+            Ok(Some(ready)) if ready > 0 => {}
+            Ok(_) => return false,
+            Err(e) => {
+                warn!(target: "scale_up", "Failed to read ready replicas of {}: {:#}", reference, e);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Records the current replicas of each workload of the service under `key` before it is scaled
+/// down, in `ServiceData::previous_replicas` and on the workload itself.
+async fn remember_replicas(ops: &dyn KubeOps, key: &str, service: &mut ServiceData) {
+    for reference in &service.workloads {
+        let replicas = match ops.get_replicas(reference).await {
+            Ok(Some(replicas)) if replicas > service.idle_replicas => replicas,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!(target: "scale_down", "Failed to read replicas of {} before scaling it down: {:#}", reference, e);
+                continue;
+            }
+        };
+        service.previous_replicas.insert(reference.clone(), replicas);
+        if let Err(e) = ops.set_previous_replicas(reference, replicas).await {
+            warn!(target: "scale_down", "Failed to record the replicas of {}: {:#}", reference, e);
+        }
+    }
+    if let Some(watched) = write_watched_services().get_mut(key) {
+        watched.previous_replicas = service.previous_replicas.clone();
+    }
+}
+
+/// Scales every workload of `service` back up. HPA-enabled services get their HPA's minimum and
+/// leave the rest to it; others get `scale-to-zero/scale-up-replicas`, else the replicas they had
+/// before the scale-down, else 1.
+async fn restore_replicas(ops: &dyn KubeOps, key: &str, service: &ServiceData) -> Result<()> {
+    let mut result = Ok(());
+    for reference in &service.workloads {
+        let replicas = if service.hpa_enabled {
+            service.hpa_config.as_ref().and_then(|config| config.min_replicas).unwrap_or(1)
+        } else if let Some(replicas) = service.scale_up_replicas {
+            replicas
+        } else if let Some(replicas) = service.previous_replicas.get(reference) {
+            *replicas
+        } else {
+            // Remembered only on the workload if the agent restarted since the scale-down
+            ops.get_previous_replicas(reference)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!(target: "scale_up", "Failed to read the previous replicas of {}: {:#}", reference, e);
+                    None
+                })
+                .unwrap_or(1)
+        }
+        .max(service.idle_replicas)
+        .max(1);
+        if let Err(e) = scale_workload(ops, key, service, reference, replicas).await {
+            error!("Failed to scale {} to {} replicas: {:#}", reference, replicas, e);
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
+
+/// Sets the replicas of every workload of `service`, carrying on past failures so one broken
+/// workload does not hold back the others.
+async fn set_replicas(ops: &dyn KubeOps, key: &str, service: &ServiceData, replicas: i32) -> Result<()> {
+    let mut result = Ok(());
+    for reference in &service.workloads {
+        if let Err(e) = scale_workload(ops, key, service, reference, replicas).await {
+            error!("Failed to scale {} to {} replicas: {:#}", reference, replicas, e);
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
+
+/// Scales one workload of the service under `key` to `replicas`, reporting it if someone else
+/// changed its replicas since the agent last scaled it. A workload that already has more than its
+/// idle replicas is left alone on scale-up rather than cut back.
+async fn scale_workload(
+    ops: &dyn KubeOps,
+    key: &str,
+    service: &ServiceData,
+    reference: &WorkloadReference,
+    replicas: i32,
+) -> Result<()> {
+    let Some(scale) = ops.get_scale(reference).await? else {
+        anyhow::bail!("{} does not exist", reference);
+    };
+    let last_scaled = LAST_SCALED.lock().unwrap().get(reference).copied();
+    // An HPA changing the replicas is expected
+    if let Some(last_scaled) = last_scaled
+        && last_scaled != scale.replicas
+        && !service.autoscaled()
+    {
+        warn!("{} was scaled from {} to {} by someone else", reference, last_scaled, scale.replicas);
+        ops.publish_event(key, EventType::Warning, "ReplicasChangedExternally",
+            format!("{} was scaled from {} to {} by someone else since scale-to-zero last scaled it",
+                    reference, last_scaled, scale.replicas)).await;
+    }
+    let floor = service.idle_replicas;
+    if scale.replicas == replicas || (replicas > floor && scale.replicas > floor) {
+        info!("{} already has {} replicas, not scaling it to {}", reference, scale.replicas, replicas);
+        LAST_SCALED.lock().unwrap().insert(reference.clone(), scale.replicas);
+        return Ok(());
+    }
+    if workload::dry_run() {
+        info!("Dry run, not scaling {} from {} to {} replicas", reference, scale.replicas, replicas);
+        return Ok(());
+    }
+    info!("Scaling {} from {} to {} replicas", reference, scale.replicas, replicas);
+    // Recorded first, so the watcher never mistakes this change for a manual one
+    LAST_SCALED.lock().unwrap().insert(reference.clone(), replicas);
+    if let Err(e) = ops.set_replicas(reference, replicas, &scale).await {
+        LAST_SCALED.lock().unwrap().insert(reference.clone(), scale.replicas);
+        return Err(e);
     }
-    
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubernetes::models::PacketTime;
+    use crate::kubernetes::ops::FakeKube;
+
+    // WATCHED_SERVICES is shared by every test, so each one watches services of its own names
+
+    fn deployment(name: &str) -> WorkloadReference {
+        WorkloadReference {
+            kind: "deployment".to_string(),
+            name: name.to_string(),
+            namespace: "scaler-test".to_string(),
+        }
+    }
+
+    /// A service behind deployment `name` that is up and received its last packet `idle` seconds
+    /// ago, with a scale-down time of 60s.
+    fn service(name: &str, idle: i64) -> ServiceData {
+        ServiceData {
+            name: name.to_string(),
+            namespace: "scaler-test".to_string(),
+            workloads: vec![deployment(name)],
+            scale_down_time: 60,
+            last_packet_time: PacketTime::new(chrono::Utc::now().timestamp() - idle),
+            backend_available: true,
+            ..Default::default()
+        }
+    }
+
+    fn watch(service: ServiceData) -> ServiceData {
+        write_watched_services().insert(service.name.clone(), service.clone());
+        service
+    }
+
+    fn with_priorities(priorities: &[i32]) -> Vec<(String, ServiceData)> {
+        priorities
+            .iter()
+            .enumerate()
+            .map(|(index, priority)| {
+                let mut service = service(&format!("svc-{}", index), 0);
+                service.scaling_priority = *priority;
+                (service.name.clone(), service)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn idle_service_is_scaled_to_zero() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("idle"), 3);
+        let service = watch(service("idle", 120));
+
+        scale_down_service(&HPASuspensionController::new(fake.clone()), "idle", service).await.unwrap();
+
+        let scaled = fake.workload(&deployment("idle")).unwrap();
+        assert_eq!(scaled.replicas, 0);
+        assert_eq!(scaled.annotations.get(workload::PREVIOUS_REPLICAS_ANNOTATION).map(String::as_str), Some("3"));
+        let watched = read_watched_services()["idle"].clone();
+        assert!(!watched.backend_available);
+        assert_eq!(watched.previous_replicas.get(&deployment("idle")), Some(&3));
+        assert_eq!(fake.events("idle"), ["ScaledToZero"]);
+    }
+
+    #[tokio::test]
+    async fn scale_down_keeps_changes_made_to_the_service_meanwhile() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("changed"), 2);
+        let snapshot = service("changed", 120);
+        // A packet and an annotation change arrive while the hook and API calls are awaited
+        let live = watch(ServiceData { scale_down_time: 300, ..service("changed", 0) });
+
+        scale_down_now(&HPASuspensionController::new(fake.clone()), "changed", snapshot, "idle-timer",
+                       "No traffic".to_string()).await.unwrap();
+
+        let watched = read_watched_services()["changed"].clone();
+        assert!(!watched.backend_available);
+        assert_eq!(watched.previous_replicas.get(&deployment("changed")), Some(&2));
+        assert_eq!(watched.last_packet_time.get(), live.last_packet_time.get());
+        assert_eq!(watched.scale_down_time, 300);
+    }
+
+    /// An idle service behind deployment `parent` depending on `child`, up or already scaled down.
+    fn parent_of(parent: &str, child: &str, up: bool) -> ServiceData {
+        ServiceData {
+            dependencies: vec![child.to_string()],
+            backend_available: up,
+            ..service(parent, 120)
+        }
+    }
+
+    #[tokio::test]
+    async fn dependency_of_an_idle_parent_still_up_is_left_up() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("called-child"), 1);
+        watch(parent_of("idle-parent", "called-child", true));
+        let child = watch(service("called-child", 120));
+
+        scale_down_service(&HPASuspensionController::new(fake.clone()), "called-child", child).await.unwrap();
+
+        assert_eq!(fake.workload(&deployment("called-child")).unwrap().replicas, 1);
+        assert!(read_watched_services()["called-child"].backend_available);
+    }
+
+    #[tokio::test]
+    async fn dependency_of_a_scaled_down_parent_is_scaled_down() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("released-child"), 1);
+        watch(parent_of("scaled-parent", "released-child", false));
+        let child = watch(service("released-child", 120));
+
+        scale_down_service(&HPASuspensionController::new(fake.clone()), "released-child", child).await.unwrap();
+
+        assert_eq!(fake.workload(&deployment("released-child")).unwrap().replicas, 0);
+        assert!(!read_watched_services()["released-child"].backend_available);
+    }
+
+    #[tokio::test]
+    async fn dependency_scaling_down_independently_ignores_its_parent() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("independent-child"), 1);
+        watch(parent_of("busy-parent", "independent-child", true));
+        let child = watch(ServiceData { scale_down_independently: true, ..service("independent-child", 120) });
+
+        scale_down_service(&HPASuspensionController::new(fake.clone()), "independent-child", child).await.unwrap();
+
+        assert_eq!(fake.workload(&deployment("independent-child")).unwrap().replicas, 0);
+        assert!(!read_watched_services()["independent-child"].backend_available);
+    }
+
+    #[tokio::test]
+    async fn service_within_its_scale_down_time_is_left_up() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("busy"), 2);
+        let service = watch(service("busy", 30));
+
+        scale_down_service(&HPASuspensionController::new(fake.clone()), "busy", service).await.unwrap();
+
+        assert_eq!(fake.workload(&deployment("busy")).unwrap().replicas, 2);
+        assert!(read_watched_services()["busy"].backend_available);
+        assert!(fake.events("busy").is_empty());
+    }
+
+    #[tokio::test]
+    async fn woken_service_is_kept_up_for_its_min_uptime() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("woken"), 1);
+        let mut woken = service("woken", 120);
+        woken.min_uptime = 300;
+        woken.last_scale_up_time = Some(chrono::Utc::now().timestamp() - 100);
+        let service = watch(woken);
+
+        scale_down_service(&HPASuspensionController::new(fake.clone()), "woken", service).await.unwrap();
+
+        assert_eq!(fake.workload(&deployment("woken")).unwrap().replicas, 1);
+    }
+
+    #[tokio::test]
+    async fn idle_service_keeps_its_idle_replicas() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("floor"), 4);
+        let mut floor = service("floor", 120);
+        floor.idle_replicas = 1;
+        let service = watch(floor);
+
+        scale_down_service(&HPASuspensionController::new(fake.clone()), "floor", service).await.unwrap();
+
+        assert_eq!(fake.workload(&deployment("floor")).unwrap().replicas, 1);
+        let watched = read_watched_services()["floor"].clone();
+        assert!(watched.backend_available);
+        assert!(watched.at_idle_floor);
+        assert_eq!(fake.events("floor"), ["ScaledToIdleReplicas"]);
+    }
+
+    #[tokio::test]
+    async fn service_with_its_hpa_already_deleted_is_scaled_to_zero() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("hpa-gone"), 2);
+        let mut hpa_gone = service("hpa-gone", 120);
+        hpa_gone.hpa_enabled = true;
+        hpa_gone.hpa_name = Some("hpa-gone".to_string());
+        hpa_gone.hpa_deleted = true;
+        let service = watch(hpa_gone);
+
+        scale_down_service(&HPASuspensionController::new(fake.clone()), "hpa-gone", service).await.unwrap();
+
+        assert_eq!(fake.workload(&deployment("hpa-gone")).unwrap().replicas, 0);
+        assert!(fake.hpa("scaler-test", "hpa-gone").is_none());
+        let watched = read_watched_services()["hpa-gone"].clone();
+        assert!(!watched.backend_available);
+        assert!(watched.hpa_deleted);
+    }
+
+    #[tokio::test]
+    async fn hpa_deleted_by_someone_else_is_treated_as_suspended() {
+        let fake = Arc::new(FakeKube::default());
+        fake.add_workload(&deployment("hpa-missing"), 2);
+        let mut hpa_missing = service("hpa-missing", 120);
+        hpa_missing.hpa_enabled = true;
+        hpa_missing.hpa_name = Some("hpa-missing".to_string());
+        let service = watch(hpa_missing);
+
+        scale_down_service(&HPASuspensionController::new(fake.clone()), "hpa-missing", service).await.unwrap();
+
+        assert_eq!(fake.workload(&deployment("hpa-missing")).unwrap().replicas, 0);
+        let watched = read_watched_services()["hpa-missing"].clone();
+        assert!(!watched.backend_available);
+        assert!(watched.hpa_deleted);
+    }
+
+    #[test]
+    fn parents_are_scaled_down_first() {
+        let watched: HashMap<String, ServiceData> = with_priorities(&[100, 10, 50]).into_iter().collect();
+
+        let priorities: Vec<i32> = scale_down_order(&watched)
+            .iter()
+            .map(|(_, service)| service.scaling_priority)
+            .collect();
+
+        assert_eq!(priorities, [10, 50, 100]);
+    }
+
+    #[test]
+    fn children_are_scaled_up_first_in_tiers_of_equal_priority() {
+        let mut services = with_priorities(&[10, 100, 50, 100]);
+
+        let tiers: Vec<(i32, usize)> = scale_up_tiers(&mut services)
+            .iter()
+            .map(|tier| (tier[0].1.scaling_priority, tier.len()))
+            .collect();
+
+        assert_eq!(tiers, [(100, 2), (50, 1), (10, 1)]);
+    }
+
+    #[test]
+    fn scale_ups_within_the_cooldown_are_rate_limited() {
+        let mut service = service("cooldown", 0);
+        service.scale_up_cooldown = 30;
+        let start = 1_700_000_000_000;
+
+        assert!(claim_scale_up(&mut service, start));
+        assert!(!claim_scale_up(&mut service, start + 29_999));
+        // A refused request does not extend the cooldown
+        assert_eq!(service.last_scale_up_request, Some(start));
+        assert!(claim_scale_up(&mut service, start + 30_000));
+        assert_eq!(service.last_scale_up_request, Some(start + 30_000));
+    }
+
+    #[test]
+    fn scale_ups_without_a_cooldown_are_never_rate_limited() {
+        let mut service = service("no-cooldown", 0);
+        let now = 1_700_000_000_000;
+
+        assert!(claim_scale_up(&mut service, now));
+        assert!(claim_scale_up(&mut service, now));
+    }
+}
@@ -0,0 +1,93 @@
+use chrono_tz::Tz;
+use k8s_openapi::chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+
+/// A daily time range in which a service may be scaled down, as written in the
+/// `scale-to-zero/allowed-window` annotation: `HH:MM-HH:MM[,days][,TZ]`, e.g.
+/// `19:00-07:00,mon-fri,Europe/Berlin`. A range past midnight belongs to the day it starts on.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AllowedWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    /// First and last day, inclusive and possibly wrapping around the week; every day if unset.
+    days: Option<(Weekday, Weekday)>,
+    timezone: Tz,
+}
+
+impl AllowedWindow {
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.timezone);
+        let time = local.time();
+        let (in_range, day) = if self.start < self.end {
+            (self.start <= time && time < self.end, local.weekday())
+        } else if time >= self.start {
+            (true, local.weekday())
+        } else {
+            // Early hours of a window that started the day before
+            (time < self.end, (local - Duration::days(1)).weekday())
+        };
+        in_range && self.days.is_none_or(|(first, last)| day_in_range(day, first, last))
+    }
+}
+
+fn day_in_range(day: Weekday, first: Weekday, last: Weekday) -> bool {
+    let (day, first, last) = (day.num_days_from_monday(), first.num_days_from_monday(), last.num_days_from_monday());
+    if first <= last {
+        first <= day && day <= last
+    } else {
+        day >= first || day <= last
+    }
+}
+
+/// Parses one or more windows separated by `;`.
+pub fn parse_windows(value: &str) -> Result<Vec<AllowedWindow>, String> {
+    let windows = value
+        .split(';')
+        .map(str::trim)
+        .filter(|window| !window.is_empty())
+        .map(parse_window)
+        .collect::<Result<Vec<_>, _>>()?;
+    if windows.is_empty() {
+        return Err("no window given".to_string());
+    }
+    Ok(windows)
+}
+
+fn parse_window(window: &str) -> Result<AllowedWindow, String> {
+    let mut parts = window.split(',').map(str::trim);
+    let range = parts.next().unwrap_or_default();
+    let (start, end) = range
+        .split_once('-')
+        .and_then(|(start, end)| Some((parse_time(start)?, parse_time(end)?)))
+        .ok_or_else(|| format!("'{}' is not a HH:MM-HH:MM range", range))?;
+    if start == end {
+        return Err(format!("window '{}' is empty", range));
+    }
+
+    let mut days = None;
+    let mut timezone = Tz::UTC;
+    for part in parts {
+        if let Some(parsed) = parse_days(part) {
+            days = Some(parsed);
+        } else if let Ok(parsed) = part.parse::<Tz>() {
+            timezone = parsed;
+        } else {
+            return Err(format!("'{}' in window '{}' is neither days like mon-fri nor a time zone", part, window));
+        }
+    }
+    Ok(AllowedWindow { start, end, days, timezone })
+}
+
+fn parse_time(value: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(value.trim(), "%H:%M").ok()
+}
+
+/// `mon-fri` or a single day such as `sat`.
+fn parse_days(value: &str) -> Option<(Weekday, Weekday)> {
+    match value.split_once('-') {
+        Some((first, last)) => Some((first.trim().parse().ok()?, last.trim().parse().ok()?)),
+        None => {
+            let day = value.parse().ok()?;
+            Some((day, day))
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::chrono::{self, SecondsFormat};
+use k8s_openapi::serde_json::json;
+use kube::api::{Api, Patch, PatchParams};
+use kube::{Client, ResourceExt};
+use log::{debug, info, warn};
+use tokio::sync::watch;
+
+use super::models::{read_watched_services, ServiceData, SERVICES_LISTED, SERVICE_STATS};
+use super::{coordination, scaler};
+
+pub const STATUS: &str = "scale-to-zero/status";
+pub const LAST_SCALE_DOWN: &str = "scale-to-zero/last-scale-down";
+pub const LAST_SCALE_UP: &str = "scale-to-zero/last-scale-up";
+pub const LAST_WAKE_SOURCE: &str = "scale-to-zero/last-wake-source";
+
+/// Annotations the agent keeps on watched Services, which the watcher ignores changes of.
+pub const STATUS_ANNOTATIONS: [&str; 4] = [STATUS, LAST_SCALE_DOWN, LAST_SCALE_UP, LAST_WAKE_SOURCE];
+
+/// Field manager of the status annotations, so they are owned apart from the Service's spec.
+const FIELD_MANAGER: &str = "scale-to-zero-status";
+
+type Annotations = BTreeMap<&'static str, String>;
+
+/// Writes the status annotations of watched Services while leading, at most once per
+/// `SERVICE_STATUS_INTERVAL_MS` (10s by default) and only for services whose status changed.
+/// Disabled with `SERVICE_STATUS_ANNOTATIONS=false`, and in a dry run.
+pub async fn run(mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    if !crate::config::get().service_status_annotations {
+        info!("Status annotations on services are disabled");
+        return Ok(());
+    }
+    let client = Client::try_default().await?;
+    // Annotations last applied to each service, by service key
+    let mut applied: HashMap<String, Annotations> = HashMap::new();
+    loop {
+        // In a dry run nothing is actually scaled, so the status would be made up
+        if !coordination::may_scale() || super::workload::dry_run() {
+            // Another node writes them meanwhile, so ours are stale once we lead again
+            applied.clear();
+        } else if SERVICES_LISTED.load(Ordering::SeqCst) {
+            publish(&client, &mut applied).await;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(crate::utils::with_jitter(crate::config::get().service_status_interval)) => {}
+            _ = shutdown.changed() => {
+                info!("Shutting down, no longer updating service status annotations");
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn publish(client: &Client, applied: &mut HashMap<String, Annotations>) {
+    let services: Vec<(String, ServiceData)> = read_watched_services()
+        .iter()
+        .map(|(key, service)| (key.clone(), service.clone()))
+        .collect();
+    let last_scale_downs: HashMap<String, i64> = SERVICE_STATS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(key, stats)| stats.last_scale_down.map(|time| (key.clone(), time)))
+        .collect();
+    applied.retain(|key, _| services.iter().any(|(watched, _)| watched == key));
+
+    for (key, service) in services {
+        let services_api: Api<Service> = Api::namespaced(client.clone(), &service.namespace);
+        // Times from before a restart are only known from the annotations themselves
+        let previous = match applied.get(&key) {
+            Some(previous) => previous.clone(),
+            None => match services_api.get_opt(&service.name).await {
+                Ok(Some(existing)) => current_annotations(&existing),
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Failed to get service {}/{} to update its status: {}", service.namespace, service.name, e);
+                    continue;
+                }
+            },
+        };
+        let mut annotations = previous.clone();
+        annotations.insert(STATUS, status(&service).to_string());
+        if let Some(time) = last_scale_downs.get(&key).copied().and_then(timestamp) {
+            annotations.insert(LAST_SCALE_DOWN, time);
+        }
+        if let Some(time) = service.last_scale_up_time.and_then(timestamp) {
+            annotations.insert(LAST_SCALE_UP, time);
+        }
+        if let Some(wake_source) = service.wake_sources.last() {
+            annotations.insert(LAST_WAKE_SOURCE, format!("{} to port {}", wake_source.source, wake_source.port));
+        }
+        if annotations == previous {
+            applied.insert(key, annotations);
+            continue;
+        }
+
+        let patch = json!({
+            "apiVersion": "v1",
+            "kind": "Service",
+            "metadata": {
+                "name": service.name,
+                "namespace": service.namespace,
+                "annotations": annotations,
+            },
+        });
+        match services_api
+            .patch(&service.name, &PatchParams::apply(FIELD_MANAGER).force(), &Patch::Apply(&patch))
+            .await
+        {
+            Ok(_) => {
+                debug!("Service {}/{} is {}", service.namespace, service.name, annotations[STATUS]);
+                applied.insert(key, annotations);
+            }
+            Err(e) => {
+                warn!("Failed to update the status annotations of service {}/{}: {}", service.namespace, service.name, e);
+            }
+        }
+    }
+}
+
+fn status(service: &ServiceData) -> &'static str {
+    if service.workload_missing || service.scale_up_failed || scaler::circuit_open(service) {
+        "error"
+    } else if service.scale_up_started.is_some() {
+        "scaling-up"
+    } else if !service.backend_available {
+        "scaled-to-zero"
+    } else {
+        "available"
+    }
+}
+
+fn current_annotations(service: &Service) -> Annotations {
+    STATUS_ANNOTATIONS
+        .into_iter()
+        .filter_map(|name| service.annotations().get(name).map(|value| (name, value.clone())))
+        .collect()
+}
+
+fn timestamp(time: i64) -> Option<String> {
+    chrono::DateTime::from_timestamp(time, 0).map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+/// Hash of `service` without the status annotations and what the apiserver changes on every
+/// write, such as the resourceVersion. Versions with the same fingerprint only differ in their
+/// status, which the watcher need not process again.
+pub fn fingerprint(service: &Service) -> u64 {
+    let mut service = service.clone();
+    service.metadata.resource_version = None;
+    service.metadata.managed_fields = None;
+    if let Some(annotations) = service.metadata.annotations.as_mut() {
+        for name in STATUS_ANNOTATIONS {
+            annotations.remove(name);
+        }
+    }
+    let mut hasher = DefaultHasher::new();
+    k8s_openapi::serde_json::to_string(&service).unwrap_or_default().hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use k8s_openapi::serde_json::{self, json};
+use kube::api::{Api, ApiResource, DynamicObject, GroupVersionKind, ListParams, Patch, PatchParams};
+use kube::{Client, ResourceExt};
+
+use super::models::HPAConfig;
+
+/// Workload kinds that can be referenced by a short name, as `(name, group, version, kind)`.
+/// Anything else with a `scale` subresource is referenced as `group/version/Kind`.
+const KNOWN_KINDS: &[(&str, &str, &str, &str)] = &[
+    ("deployment", "apps", "v1", "Deployment"),
+    ("statefulset", "apps", "v1", "StatefulSet"),
+    ("rollout", "argoproj.io", "v1alpha1", "Rollout"),
+];
+
+/// A `scale-to-zero/reference` annotation, split into the workload kind (a short name from
+/// `KNOWN_KINDS` or `group/version/Kind`), its namespace if given, and its name.
+pub struct WorkloadRef {
+    pub kind: String,
+    pub namespace: Option<String>,
+    pub name: String,
+}
+
+/// Parses `kind/name`, `kind/namespace/name`, `group/version/Kind/name` or
+/// `group/version/Kind/namespace/name`.
+pub fn parse_reference(reference: &str) -> Option<WorkloadRef> {
+    let parts: Vec<&str> = reference.split('/').collect();
+    if parts.iter().any(|part| part.is_empty()) {
+        return None;
+    }
+    let (kind, namespace, name) = match parts.as_slice() {
+        [kind, name] => (kind.to_lowercase(), None, name),
+        [kind, namespace, name] => (kind.to_lowercase(), Some(namespace), name),
+        [group, version, kind, name] => (format!("{}/{}/{}", group, version, kind), None, name),
+        [group, version, kind, namespace, name] => (format!("{}/{}/{}", group, version, kind), Some(namespace), name),
+        _ => return None,
+    };
+    Some(WorkloadRef {
+        kind,
+        namespace: namespace.map(|namespace| namespace.to_string()),
+        name: name.to_string(),
+    })
+}
+
+/// Resolves a workload kind as stored in `ServiceData::kind`.
+pub fn api_resource(kind: &str) -> Result<ApiResource> {
+    let gvk = match KNOWN_KINDS.iter().find(|(name, ..)| *name == kind) {
+        Some((_, group, version, kind)) => GroupVersionKind::gvk(group, version, kind),
+        None => match kind.split('/').collect::<Vec<_>>().as_slice() {
+            [group, version, kind] => GroupVersionKind::gvk(group, version, kind),
+            _ => anyhow::bail!(
+                "unknown workload type {} (expected one of {} or group/version/Kind)",
+                kind,
+                KNOWN_KINDS.iter().map(|(name, ..)| *name).collect::<Vec<_>>().join(", ")
+            ),
+        },
+    };
+    Ok(ApiResource::from_gvk(&gvk))
+}
+
+pub fn api(client: &Client, kind: &str, namespace: &str) -> Result<Api<DynamicObject>> {
+    Ok(Api::namespaced_with(client.clone(), namespace, &api_resource(kind)?))
+}
+
+/// Field manager of every change the agent makes to a workload, so tools comparing managed
+/// fields, such as GitOps controllers, can tell it apart.
+pub const FIELD_MANAGER: &str = "scale-to-zero";
+
+/// Whether `--dry-run` is set, in which case the agent only logs what it would change on
+/// workloads, their HPAs and KEDA ScaledObjects.
+pub fn dry_run() -> bool {
+    crate::config::get().dry_run
+}
+
+/// The `scale` subresource of a workload: its desired replicas and the resourceVersion they were
+/// read at.
+pub struct Scale {
+    pub replicas: i32,
+    pub resource_version: Option<String>,
+}
+
+/// The `scale` subresource of a workload, or `None` if the workload does not exist.
+pub async fn get_scale(client: &Client, kind: &str, namespace: &str, name: &str) -> Result<Option<Scale>> {
+    let scale = match api(client, kind, namespace)?.get_scale(name).await {
+        Ok(scale) => scale,
+        Err(kube::Error::Api(response)) if response.code == 404 => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to get {} {} in namespace {}", kind, name, namespace));
+        }
+    };
+    Ok(Some(Scale {
+        replicas: scale.spec.and_then(|spec| spec.replicas).unwrap_or(0),
+        resource_version: scale.metadata.resource_version,
+    }))
+}
+
+/// Ready replicas of a workload from its `status.readyReplicas`, or `None` if the workload does
+/// not exist.
+pub async fn ready_replicas(client: &Client, kind: &str, namespace: &str, name: &str) -> Result<Option<i64>> {
+    let workload = api(client, kind, namespace)?
+        .get_opt(name)
+        .await
+        .with_context(|| format!("Failed to get {} {} in namespace {}", kind, name, namespace))?;
+    Ok(workload.map(|workload| workload.data["status"]["readyReplicas"].as_i64().unwrap_or(0)))
+}
+
+/// Annotation on a workload recording its replicas before it was scaled to zero, so they are
+/// restored on scale-up even after the agent restarted.
+pub const PREVIOUS_REPLICAS_ANNOTATION: &str = "scale-to-zero/previous-replicas";
+
+/// The `PREVIOUS_REPLICAS_ANNOTATION` of a workload, if it exists and has a valid one.
+pub async fn get_previous_replicas(client: &Client, kind: &str, namespace: &str, name: &str) -> Result<Option<i32>> {
+    let workload = api(client, kind, namespace)?
+        .get_metadata_opt(name)
+        .await
+        .with_context(|| format!("Failed to get {} {} in namespace {}", kind, name, namespace))?;
+    Ok(workload
+        .and_then(|workload| workload.annotations().get(PREVIOUS_REPLICAS_ANNOTATION).cloned())
+        .and_then(|value| value.parse().ok()))
+}
+
+pub async fn set_previous_replicas(client: &Client, kind: &str, namespace: &str, name: &str, replicas: i32) -> Result<()> {
+    if dry_run() {
+        return Ok(());
+    }
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "annotations": {
+                PREVIOUS_REPLICAS_ANNOTATION: replicas.to_string()
+            }
+        }
+    }));
+    api(client, kind, namespace)?
+        .patch_metadata(name, &patch_params(), &patch)
+        .await
+        .with_context(|| format!("Failed to annotate {} {} in namespace {}", kind, name, namespace))?;
+    Ok(())
+}
+
+/// Annotation on the workload an HPA scaled, holding the HPA's configuration while the agent has
+/// it deleted, so it is recreated faithfully even after the agent restarted.
+pub const STORED_HPA_CONFIG_ANNOTATION: &str = "scale-to-zero/stored-hpa-config";
+
+/// The `STORED_HPA_CONFIG_ANNOTATION` of a workload, if it exists and has a valid one.
+pub async fn get_stored_hpa_config(client: &Client, kind: &str, namespace: &str, name: &str) -> Result<Option<HPAConfig>> {
+    let workload = api(client, kind, namespace)?
+        .get_metadata_opt(name)
+        .await
+        .with_context(|| format!("Failed to get {} {} in namespace {}", kind, name, namespace))?;
+    Ok(workload
+        .and_then(|workload| workload.annotations().get(STORED_HPA_CONFIG_ANNOTATION).cloned())
+        .and_then(|value| serde_json::from_str(&value).ok()))
+}
+
+/// Stores `config` in the `STORED_HPA_CONFIG_ANNOTATION` of a workload, or removes it if `None`.
+pub async fn set_stored_hpa_config(client: &Client, kind: &str, namespace: &str, name: &str, config: Option<&HPAConfig>) -> Result<()> {
+    if dry_run() {
+        return Ok(());
+    }
+    let value = config.map(serde_json::to_string).transpose()?;
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "annotations": {
+                STORED_HPA_CONFIG_ANNOTATION: value
+            }
+        }
+    }));
+    api(client, kind, namespace)?
+        .patch_metadata(name, &patch_params(), &patch)
+        .await
+        .with_context(|| format!("Failed to annotate {} {} in namespace {}", kind, name, namespace))?;
+    Ok(())
+}
+
+/// Sets the replicas of a workload through its `scale` subresource. Fails with a conflict
+/// instead if the workload was scaled by someone else since `observed` was read.
+pub async fn set_replicas(
+    client: &Client,
+    kind: &str,
+    namespace: &str,
+    name: &str,
+    replicas: i32,
+    observed: &Scale,
+) -> Result<()> {
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "resourceVersion": observed.resource_version
+        },
+        "spec": {
+            "replicas": replicas
+        }
+    }));
+    match api(client, kind, namespace)?.patch_scale(name, &patch_params(), &patch).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(response)) if response.code == 409 => anyhow::bail!(
+            "{} {} in namespace {} was scaled by someone else while being scaled to {}",
+            kind, name, namespace, replicas
+        ),
+        Err(e) => Err(e).with_context(|| format!("Failed to scale {} {} in namespace {}", kind, name, namespace)),
+    }
+}
+
+fn patch_params() -> PatchParams {
+    PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    }
+}
+
+/// Names of the workloads of `kind` in `namespace` whose labels match `selector`.
+pub async fn select(client: &Client, kind: &str, namespace: &str, selector: &LabelSelector) -> Result<Vec<String>> {
+    let mut names: Vec<String> = api(client, kind, namespace)?
+        .list(&ListParams::default().labels(&selector.to_string()))
+        .await
+        .with_context(|| format!("Failed to list {} matching {} in namespace {}", kind, selector, namespace))?
+        .items
+        .iter()
+        .map(|workload| workload.name_any())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// An equality-based label selector such as `app=myapp,tier!=canary,!legacy`, evaluated both by
+/// the API server when listing and locally against workloads seen by the watcher.
+#[derive(Clone, Debug)]
+pub struct LabelSelector {
+    raw: String,
+    requirements: Vec<Requirement>,
+}
+
+#[derive(Clone, Debug)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String),
+}
+
+impl LabelSelector {
+    pub fn parse(raw: &str) -> std::result::Result<Self, String> {
+        let mut requirements = Vec::new();
+        for term in raw.split(',').map(str::trim).filter(|term| !term.is_empty()) {
+            let requirement = if let Some((key, value)) = term.split_once("!=") {
+                Requirement::NotEquals(key.trim().to_string(), value.trim().to_string())
+            } else if let Some((key, value)) = term.split_once("==").or_else(|| term.split_once('=')) {
+                Requirement::Equals(key.trim().to_string(), value.trim().to_string())
+            } else if let Some(key) = term.strip_prefix('!') {
+                Requirement::NotExists(key.trim().to_string())
+            } else if term.contains(char::is_whitespace) || term.contains('(') {
+                return Err(format!("set-based selector term '{}' is not supported, use key=value terms", term));
+            } else {
+                Requirement::Exists(term.to_string())
+            };
+            let key = match &requirement {
+                Requirement::Equals(key, _)
+                | Requirement::NotEquals(key, _)
+                | Requirement::Exists(key)
+                | Requirement::NotExists(key) => key,
+            };
+            if key.is_empty() {
+                return Err(format!("selector term '{}' has no label key", term));
+            }
+            requirements.push(requirement);
+        }
+        if requirements.is_empty() {
+            return Err("selector is empty".to_string());
+        }
+        Ok(LabelSelector {
+            raw: raw.trim().to_string(),
+            requirements,
+        })
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.requirements.iter().all(|requirement| match requirement {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::NotExists(key) => !labels.contains_key(key),
+        })
+    }
+}
+
+impl std::fmt::Display for LabelSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
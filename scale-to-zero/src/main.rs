@@ -1,25 +1,60 @@
 
-use aya::{
-    maps::{HashMap, perf::AsyncPerfEventArray},
-    programs::{Xdp, XdpFlags},
-    util::online_cpus,
-};
+use anyhow::Context;
+use aya::util::KernelVersion;
+use clap::Parser;
 
 #[rustfmt::skip]
 use log::{debug, warn, info, error};
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use std::sync::atomic::Ordering;
 use tokio::task;
-use bytes::BytesMut;
-use scale_to_zero_common::PacketLog;
 
+mod admin;
+mod cli;
+mod compat;
+mod config;
+#[cfg(test)]
+mod e2e;
+mod health;
 mod kubernetes;
+mod metrics;
+mod program;
+mod reject;
+mod stats;
 mod utils;
+#[cfg(test)]
+mod veth;
+mod xdp;
     
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // Initialize logger with custom timestamp format
-    env_logger::Builder::from_default_env()
-        .format(|buf, record| {
+fn main() -> anyhow::Result<()> {
+    let cli = cli::Cli::parse();
+    let mut config = config::Config::load(&cli)?;
+    // The checks report what passed at info level, which would not show at the default level
+    if cli.validate_config {
+        config.log_level = Some(config.log_level.unwrap_or(log::LevelFilter::Info).max(log::LevelFilter::Info));
+    }
+    init_logger(&config);
+    config::set(config);
+
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    if cli.validate_config {
+        return runtime.block_on(cli::validate_config(&config::get()));
+    }
+    runtime.block_on(run(cli))
+}
+
+fn init_logger(config: &config::Config) {
+    let mut builder = match config.log_level {
+        // Filtered by the max level alone, which a reloaded config file may change
+        Some(_) => {
+            let mut builder = env_logger::Builder::new();
+            builder.filter_level(log::LevelFilter::Trace);
+            builder
+        }
+        None => env_logger::Builder::from_default_env(),
+    };
+    match config.log_format {
+        cli::LogFormat::Text => builder.format(|buf, record| {
             use std::io::Write;
             let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
             writeln!(buf, "[{}] [{}] [{}:{}] {}",
@@ -29,9 +64,27 @@ async fn main() -> anyhow::Result<()> {
                 record.line().unwrap_or(0),
                 record.args()
             )
-        })
-        .init();
+        }),
+        cli::LogFormat::Json => builder.format(|buf, record| {
+            use std::io::Write;
+            let line = serde_json::json!({
+                "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "file": record.file(),
+                "line": record.line(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        }),
+    };
+    builder.init();
+    if let Some(level) = config.log_level {
+        log::set_max_level(level);
+    }
+}
 
+async fn run(cli: cli::Cli) -> anyhow::Result<()> {
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
     let rlim = libc::rlimit {
@@ -43,59 +96,146 @@ async fn main() -> anyhow::Result<()> {
         debug!("remove limit on locked memory failed, ret is: {ret}");
     }
 
-    // // Initialize etcd coordination if configured
-    let use_etcd = std::env::var("USE_ETCD_COORDINATION")
-        .unwrap_or_else(|_| "false".to_string())
-        .parse::<bool>()
-        .unwrap_or(false);
-    
-    if use_etcd {
-        let etcd_endpoints = std::env::var("ETCD_ENDPOINTS")
-            .unwrap_or_else(|_| "http://etcd:2379".to_string())
-            .split(',')
-            .map(|s| s.trim().to_string())
-            .collect::<Vec<String>>();
-        
-        info!("Initializing etcd coordination with endpoints: {:?}", etcd_endpoints);
-        
-        match kubernetes::etcd_coordinator::initialize_etcd_coordinator(etcd_endpoints).await {
-            Ok(_) => {
-                info!("Successfully initialized etcd coordination");
-            }
-            Err(e) => {
-                error!("Failed to initialize etcd coordination: {}", e);
-                return Err(e);
-            }
-        }
-    } else {
-        info!("Running in single-node mode (no etcd coordination)");
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+
+    // Serve Prometheus metrics and health probes if METRICS_PORT is set, from the start so that
+    // probes can tell a slow startup from a stuck one
+    let metrics_shutdown = shutdown_rx.clone();
+    let metrics_task = task::spawn(health::supervised(
+        "Metrics endpoint",
+        move || metrics::serve(metrics_shutdown.clone()),
+    ));
+
+    // Coordinate with the agents on other nodes if configured
+    if let Err(e) = kubernetes::coordination::initialize().await {
+        error!("Failed to initialize coordination: {:#}", e);
+        return Err(e);
+    }
+
+    // Permission problems, e.g. a Role missing in one of WATCH_NAMESPACES, are reported up front
+    match kubernetes::controller::verify_permissions().await {
+        Ok(true) => info!("Kubernetes permissions verified"),
+        Ok(false) => error!("Some Kubernetes permissions are missing, services in the affected namespaces will not be scaled"),
+        Err(e) => warn!("Could not verify Kubernetes permissions: {:#}", e),
     }
 
     // Start kubernetes event watcher in background
-    task::spawn(async move {
-        kubernetes::controller::kube_event_watcher().await.unwrap();
-    });
+    let watcher_shutdown = shutdown_rx.clone();
+    let watcher_task = task::spawn(health::supervised(
+        "Kubernetes event watcher",
+        move || kubernetes::controller::kube_event_watcher(watcher_shutdown.clone()),
+    ));
+
+    // Learn every annotated service before scaling or filtering anything, so none is left
+    // unmanaged after a restart. Services already at zero replicas are registered as unavailable.
+    let startup_sync_timeout = config::get().startup_sync_timeout;
+    if kubernetes::controller::wait_for_initial_listing(startup_sync_timeout).await {
+        info!("Registered {} annotated services",
+              kubernetes::models::read_watched_services().len());
+    } else {
+        warn!("Services were not fully listed within {:?}, starting anyway; the rest are picked up as they are listed",
+              startup_sync_timeout);
+    }
 
     // Start kubernetes scaler in background
-    task::spawn(async move {
-        kubernetes::scaler::scale_down().await.unwrap();
-    });
+    let scaler_shutdown = shutdown_rx.clone();
+    let scaler_task = task::spawn(health::supervised(
+        "Kubernetes scaler",
+        move || kubernetes::scaler::scale_down(scaler_shutdown.clone()),
+    ));
+
+    // Retry failed scale operations in background
+    let retry_shutdown = shutdown_rx.clone();
+    let retry_task = task::spawn(health::supervised(
+        "Scale retries",
+        move || kubernetes::retry::run(retry_shutdown.clone()),
+    ));
+
+    // Share packet times with the other nodes when coordinating
+    let coordination_sync_shutdown = shutdown_rx.clone();
+    let coordination_sync_task = task::spawn(health::supervised(
+        "Service data sync",
+        move || kubernetes::coordination::sync_service_data(coordination_sync_shutdown.clone()),
+    ));
+
+    // Follow service availability published by the leader when coordinating
+    let service_list_shutdown = shutdown_rx.clone();
+    let service_list_task = task::spawn(health::supervised(
+        "Service list sync",
+        move || kubernetes::coordination::sync_service_list(service_list_shutdown.clone()),
+    ));
+
+    // Scale up the services woken on followers when leading
+    let wake_shutdown = shutdown_rx.clone();
+    let wake_task = task::spawn(health::supervised(
+        "Serving wake requests",
+        move || kubernetes::coordination::serve_wake_requests(wake_shutdown.clone()),
+    ));
+
+    // Repair HPAs that drifted from what the agent expects in background
+    let reconcile_shutdown = shutdown_rx.clone();
+    let reconcile_task = task::spawn(health::supervised(
+        "HPA reconciliation",
+        move || kubernetes::hpa_controller::reconcile(reconcile_shutdown.clone()),
+    ));
+
+    // Show the state of each service in its annotations when leading
+    let status_shutdown = shutdown_rx.clone();
+    let status_task = task::spawn(health::supervised(
+        "Service status annotations",
+        move || kubernetes::service_status::run(status_shutdown.clone()),
+    ));
+
+    // Apply changes to the config file without a restart
+    let config_shutdown = shutdown_rx.clone();
+    let config_task = task::spawn(health::supervised(
+        "Config reload",
+        move || config::watch(cli.clone(), config_shutdown.clone()),
+    ));
+
+    // Start per-service traffic rate collection in background
+    let stats_task = task::spawn(health::supervised("Traffic rate collection", || async {
+        stats::collect_rates().await;
+        Ok(())
+    }));
 
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
     // reach for `Bpf::load_file` instead.
-    let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/scale-to-zero"
-    )))?;
-    if let Err(e) = aya_log::EbpfLogger::init(&mut ebpf) {
-        // This can happen if you remove all log statements from your eBPF program.
-        warn!("failed to initialize eBPF logger: {e}");
+    let kernel_version = KernelVersion::current()?;
+    if kernel_version < KernelVersion::new(5, 8, 0) {
+        anyhow::bail!(
+            "kernel {} does not support BPF ring buffers, Linux 5.8 or newer is required",
+            kernel_version
+        );
     }
+    let config = config::get();
+    let ring_buf_size = ring_buf_size(&config)?;
+
+    // Service and stats maps are pinned so a restarted agent keeps dropping traffic to
+    // scaled-down services while it relearns them
+    let pin_path = config.bpf_pin_path.clone();
+    if config.fresh_start && pin_path.exists() {
+        info!("Removing pinned maps in {}", pin_path.display());
+        std::fs::remove_dir_all(&pin_path)
+            .with_context(|| format!("failed to remove pinned maps in {}", pin_path.display()))?;
+    }
+    std::fs::create_dir_all(&pin_path)
+        .with_context(|| format!("failed to create pin path {}", pin_path.display()))?;
+
+    let mut loaded = program::LoadedProgram::load(
+        program::ObjectSource::Embedded(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/scale-to-zero"
+        ))),
+        &pin_path,
+        ring_buf_size,
+    )?;
 
-    let program: &mut Xdp = ebpf.program_mut("scale_to_zero").unwrap().try_into()?;
-    program.load()?;
+    // Fill the service maps before the program sees any traffic
+    utils::sync_data(&mut loaded.service_maps).await?;
+    let program = loaded.program()?;
     
     let network_interfaces = NetworkInterface::show().unwrap();
     let network_interfaces = network_interfaces
@@ -103,50 +243,199 @@ async fn main() -> anyhow::Result<()> {
         .map(|itf| itf.name.clone())
         .collect::<Vec<_>>();
 
-    // let attach_modes = [XdpFlags::default(), XdpFlags::SKB_MODE, XdpFlags::HW_MODE];
+    let interface_filter = xdp::InterfaceFilter::new(&config);
+    let missing = interface_filter.missing(&network_interfaces);
+    if !missing.is_empty() {
+        anyhow::bail!("ATTACH_INTERFACES names unknown interfaces: {}", missing.join(", "));
+    }
+
+    let attach_mode = config.xdp_mode;
+    let conflict_policy = config.xdp_conflict_policy;
+    let mut attached_interfaces = Vec::new();
     for itf in network_interfaces.iter() {
-        info!("Attach to interface {} with {:?}", itf, XdpFlags::SKB_MODE);
-        match program.attach(&itf, XdpFlags::SKB_MODE) {
-            Ok(_) => {}
+        if !interface_filter.allows(itf) {
+            debug!("Skipping interface {}", itf);
+            continue;
+        }
+        info!("Attach to interface {} with {:?}", itf, attach_mode);
+        match xdp::attach_with_mode(program, itf, attach_mode, conflict_policy) {
+            Ok(attached) => attached_interfaces.push(attached),
+            Err(err) if interface_filter.is_included(itf) => {
+                anyhow::bail!("Failed to attach to interface {} listed in ATTACH_INTERFACES: {}", itf, err);
+            }
             Err(err) => {
-                warn!("Failed to detach from interface {}: {}", itf, err);
+                warn!("Failed to attach to interface {}: {}", itf, err);
             }
         }
     }
+    if attached_interfaces.is_empty() {
+        anyhow::bail!("Failed to attach XDP program to any interface");
+    }
+    health::record_interfaces(&attached_interfaces);
 
-    let mut perf_array = AsyncPerfEventArray::try_from(ebpf.take_map("SCALE_REQUESTS").unwrap())?;
-
+    info!("Watching up to {} service entries per address family", config.max_watched_services);
+    info!("Reading scale requests from a {} byte ring buffer", ring_buf_size);
+    // The ring buffer reader only queues scale requests for a single task handling them
+    let (packet_queue, packets) = utils::packet_queue();
+    // Shared with the task restarted in place of one that failed
+    let packets = std::sync::Arc::new(tokio::sync::Mutex::new(packets));
+    let packet_task = task::spawn(health::supervised("Scale request processing", move || {
+        let packets = packets.clone();
+        async move {
+            utils::process_packets(&mut *packets.lock().await).await;
+            Ok(())
+        }
+    }));
+    loaded.start(packet_queue)?;
 
-    for cpu_id in online_cpus().map_err(|e| anyhow::anyhow!("Failed to get online CPUs: {}", e.1))? {
-        info!("Opening perf array for CPU {}", cpu_id);
-        let mut buf = perf_array.open(cpu_id, None)?;
+    // SIGHUP loads a new program from EBPF_RELOAD_PATH in place of the running one
+    let reload_path = config.ebpf_reload_path.clone();
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let mut reload_requested = false;
 
-        task::spawn(async move {
-            let mut buffers = (0..10)
-                .map(|_| BytesMut::with_capacity(1024))
-                .collect::<Vec<_>>();
+    info!("Syncing changes to the service maps every {:?}, fully every {:?}",
+          config.map_sync_interval, config.map_full_sync_interval);
 
-            loop {
-                let events = buf.read_events(&mut buffers).await.unwrap();
-                for buf in buffers.iter_mut().take(events.read) {
-                    let ptr = buf.as_ptr() as *const PacketLog;
-                    let data = unsafe { ptr.read_unaligned() };
-                    utils::process_packet(data).await;
-                }
-            }
-        });
-    }
+    let mut suppressed_total = 0u64;
+    let mut last_suppressed_check = std::time::Instant::now();
 
-    // sync scalable_service_list with SCALABLE_PODS
-    let mut scalable_service_list: HashMap<_, u32, u32> =
-        HashMap::try_from(ebpf.map_mut("SERVICE_LIST").unwrap()).unwrap();
+    let mut interface_watcher =
+        xdp::InterfaceWatcher::new(interface_filter, attach_mode, conflict_policy, &network_interfaces);
+    let mut last_interface_scan = std::time::Instant::now();
+    let mut last_verified = std::time::Instant::now();
     
+    let clear_on_shutdown = config.clear_service_list_on_shutdown;
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    let mut given_up = None;
+
     // Start the sync loop
     loop {
-        if let Err(e) = utils::sync_data(&mut scalable_service_list).await {
+        if reload_requested {
+            reload_requested = false;
+            match reload_path.as_deref() {
+                Some(path) => {
+                    if let Err(e) = program::reload(&mut loaded, path, &pin_path, ring_buf_size, &mut attached_interfaces).await {
+                        error!("Failed to reload XDP program from {}, keeping the running one: {:#}", path.display(), e);
+                    }
+                }
+                None => warn!("Received SIGHUP but EBPF_RELOAD_PATH is not set, ignoring it"),
+            }
+        }
+
+        health::LAST_MAP_SYNC.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+        if let Err(e) = utils::sync_data(&mut loaded.service_maps).await {
             error!("Failed to sync data: {}", e);
         }
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // Pick up interfaces created or removed since startup
+        if last_interface_scan.elapsed() >= config::get().interface_scan_interval {
+            interface_watcher.reconcile(loaded.program()?, &mut attached_interfaces);
+            if let Err(e) = utils::sync_local_addresses(&mut loaded.local_addresses) {
+                warn!("Failed to sync local addresses: {}", e);
+            }
+            last_interface_scan = std::time::Instant::now();
+        }
+
+        // Make sure nobody has replaced or detached our XDP program since we attached it
+        if last_verified.elapsed() >= config::get().xdp_verify_interval {
+            xdp::verify_attachments(loaded.program()?, &mut attached_interfaces, conflict_policy);
+            last_verified = std::time::Instant::now();
+        }
+        health::record_interfaces(&attached_interfaces);
+
+        if last_suppressed_check.elapsed() >= std::time::Duration::from_secs(60) {
+            match loaded.suppressed_scale_requests.get(&0, 0) {
+                Ok(values) => {
+                    let total: u64 = values.iter().sum();
+                    if total > suppressed_total {
+                        info!("Rate limited {} scale requests in the kernel in the last minute",
+                              total - suppressed_total);
+                    }
+                    suppressed_total = total;
+                }
+                Err(e) => warn!("Failed to read suppressed scale request counter: {}", e),
+            }
+            last_suppressed_check = std::time::Instant::now();
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(utils::with_jitter(config::get().map_sync_interval)) => {}
+            _ = kubernetes::models::SERVICE_LIST_CHANGED.notified() => {}
+            _ = sighup.recv() => {
+                info!("Received SIGHUP, reloading XDP program");
+                reload_requested = true;
+            }
+            _ = sigterm.recv() => {
+                info!("Received SIGTERM, shutting down");
+                break;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Received SIGINT, shutting down");
+                break;
+            }
+            task = health::task_given_up() => {
+                error!("{} is down for good, shutting down", task);
+                given_up = Some(task);
+                break;
+            }
+        }
+    }
+
+    health::shutting_down();
+    let _ = shutdown_tx.send(true);
+
+    // Clearing lets traffic to scaled-down services pass untouched until the next instance is up
+    if clear_on_shutdown
+        && let Err(e) = utils::clear_service_maps(&mut loaded.service_maps)
+    {
+        error!("Failed to clear the service maps: {:#}", e);
+    }
+
+    xdp::detach_all(loaded.program()?, &mut attached_interfaces);
+
+    // Give the background tasks a chance to finish their current pass
+    let tasks = [
+        ("watcher", watcher_task),
+        ("scaler", scaler_task),
+        ("scale retries", retry_task),
+        ("HPA reconciliation", reconcile_task),
+        ("service status annotations", status_task),
+        ("config reload", config_task),
+        ("service data sync", coordination_sync_task),
+        ("service list sync", service_list_task),
+        ("wake request server", wake_task),
+        ("metrics endpoint", metrics_task),
+    ];
+    for (name, handle) in tasks {
+        let abort = handle.abort_handle();
+        if tokio::time::timeout(std::time::Duration::from_secs(10), handle).await.is_err() {
+            warn!("Timed out waiting for the {} to stop, aborting it", name);
+            abort.abort();
+        }
     }
+    stats_task.abort();
+    packet_task.abort();
+    kubernetes::coordination::cleanup().await;
+    loaded.stop();
 
+    if let Some(task) = given_up {
+        anyhow::bail!("{} stopped", task);
+    }
+    info!("Shutdown complete");
+    Ok(())
+}
+
+/// Size in bytes of the `SCALE_REQUESTS` ring buffer, checked against what the kernel requires:
+/// a power of two that is a multiple of the page size.
+fn ring_buf_size(config: &config::Config) -> anyhow::Result<u32> {
+    let size = config.ring_buffer_size;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+    if !size.is_power_of_two() || !size.is_multiple_of(page_size) {
+        anyhow::bail!(
+            "ring-buffer-size (SCALE_REQUESTS_RING_BUFFER_SIZE) must be a power of two and a multiple of the page size ({}), got {}",
+            page_size,
+            size
+        );
+    }
+    Ok(size)
 }
@@ -0,0 +1,284 @@
+//! Prometheus metrics, served at `/metrics` on `METRICS_PORT` when it is set, along with the
+//! probes of `health` and the API of `admin`.
+//!
+//! Counters are the atomics incremented where things happen; everything else is read from
+//! `WATCHED_SERVICES`, `SERVICE_STATS` and the coordinator when scraped. Names are stable:
+//!
+//! | Metric | Type | Labels |
+//! |---|---|---|
+//! | `scale_to_zero_watched_services` | gauge | |
+//! | `scale_to_zero_services_at_zero` | gauge | |
+//! | `scale_to_zero_services_available` | gauge | |
+//! | `scale_to_zero_scale_up_attempts_total` | counter | |
+//! | `scale_to_zero_scale_up_failures_total` | counter | |
+//! | `scale_to_zero_scale_up_latency_seconds` | histogram | |
+//! | `scale_to_zero_scale_down_attempts_total` | counter | |
+//! | `scale_to_zero_scale_down_failures_total` | counter | |
+//! | `scale_to_zero_scale_down_last_pass_timestamp_seconds` | gauge | |
+//! | `scale_to_zero_scale_retries_total` | counter | |
+//! | `scale_to_zero_scale_retries_exhausted_total` | counter | |
+//! | `scale_to_zero_hpa_deletions_total` | counter | |
+//! | `scale_to_zero_hpa_recreations_total` | counter | |
+//! | `scale_to_zero_packets_total` | counter | `action` (`passed`, `dropped`) |
+//! | `scale_to_zero_scale_request_events_processed_total` | counter | |
+//! | `scale_to_zero_scale_request_events_lost_total` | counter | `stage` (`kernel`, `userspace`) |
+//! | `scale_to_zero_scale_request_reader_restarts_total` | counter | |
+//! | `scale_to_zero_task_restarts_total` | counter | `task` |
+//! | `scale_to_zero_coordination_info` | gauge | `backend`, `node`, `leader` |
+//! | `scale_to_zero_is_leader` | gauge | |
+//! | `scale_to_zero_leadership_changes_total` | counter | |
+//! | `scale_to_zero_coordination_outages_total` | counter | |
+//! | `scale_to_zero_coordination_failures_total` | counter | |
+//! | `scale_to_zero_service_available` | gauge | `namespace`, `name` |
+//! | `scale_to_zero_service_packets_total` | counter | `namespace`, `name`, `action` |
+//! | `scale_to_zero_service_wake_events_total` | counter | `namespace`, `name` |
+//! | `scale_to_zero_service_scale_ups_total` | counter | `namespace`, `name` |
+//! | `scale_to_zero_service_scale_downs_total` | counter | `namespace`, `name` |
+//! | `scale_to_zero_service_zero_seconds_total` | counter | `namespace`, `name` |
+//! | `scale_to_zero_service_last_scale_up_duration_seconds` | gauge | `namespace`, `name` |
+//!
+//! Per-service counters start from zero when the agent starts or the service is first watched.
+
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use k8s_openapi::chrono;
+use log::info;
+use tokio::sync::watch;
+
+use crate::{admin, health};
+use crate::kubernetes::coordination::{self, COORDINATION_OUTAGES, LEADERSHIP_CHANGES};
+use crate::kubernetes::models::{
+    read_watched_services, ServiceStats, HPA_DELETIONS, HPA_RECREATIONS, LAST_SCALE_DOWN_PASS, SCALE_DOWN_ATTEMPTS,
+    SCALE_DOWN_FAILURES, SCALE_RETRIES, SCALE_RETRIES_EXHAUSTED, SCALE_UP_ATTEMPTS, SCALE_UP_FAILURES, SERVICE_STATS,
+};
+use crate::stats::{LOST_EVENTS, SCALE_REQUEST_READER_RESTARTS};
+use crate::utils::{DROPPED_PACKET_EVENTS, PROCESSED_PACKET_EVENTS};
+
+/// Upper bounds in seconds of the `scale_to_zero_scale_up_latency_seconds` buckets.
+const LATENCY_BUCKETS: [f64; 12] = [0.5, 1.0, 2.0, 3.0, 5.0, 10.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+/// Time from the packet, or the dependent's scale-up, asking for a scale-up until the service
+/// was available.
+pub static SCALE_UP_LATENCY: Histogram = Histogram::new();
+
+/// A Prometheus histogram over `LATENCY_BUCKETS`.
+pub struct Histogram {
+    /// Observations per bucket, not cumulative; the last one is `+Inf`.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_ms: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+            sum_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(value.as_millis().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        let mut count = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let bound = LATENCY_BUCKETS.get(index).map_or("+Inf".to_string(), |bound| bound.to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let sum = self.sum_ms.load(Ordering::Relaxed) as f64 / 1000.0;
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, sum, name, count);
+    }
+}
+
+/// Serves the metrics and health probes until shutdown.
+pub async fn serve(mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    // Not served without a port
+    let Some(port) = crate::config::get().metrics_port else {
+        return Ok(());
+    };
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+    let server = Server::try_bind(&address)
+        .with_context(|| format!("failed to listen on {}", address))?
+        .serve(make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) }));
+    info!("Serving metrics on http://{}/metrics", address);
+    server
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.changed().await;
+        })
+        .await?;
+    Ok(())
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.uri().path().starts_with("/admin/") {
+        return Ok(admin::handle(request).await);
+    }
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(render())),
+        (&Method::GET, "/healthz") => probe(health::liveness()),
+        (&Method::GET, "/readyz") => probe(health::readiness()),
+        _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()),
+    };
+    Ok(response.expect("static response parts are valid"))
+}
+
+/// 200 without problems, else 503 listing them.
+fn probe(problems: Vec<String>) -> hyper::http::Result<Response<Body>> {
+    if problems.is_empty() {
+        return Response::builder().body(Body::from("ok\n"));
+    }
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(Body::from(problems.join("\n") + "\n"))
+}
+
+/// Every metric in the Prometheus text format.
+fn render() -> String {
+    let mut out = String::new();
+    let now = chrono::Utc::now().timestamp();
+
+    let counter = |out: &mut String, name: &str, help: &str, value: &AtomicU64| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}",
+                         name, help, name, name, value.load(Ordering::Relaxed));
+    };
+    let gauge = |out: &mut String, name: &str, help: &str, value: i64| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+    };
+
+    // Cloned so that the lock is not held while formatting
+    let services: Vec<(String, String, String, bool)> = read_watched_services()
+        .iter()
+        .map(|(key, service)| (key.clone(), service.namespace.clone(), service.name.clone(), service.backend_available))
+        .collect();
+    let service_stats = SERVICE_STATS.lock().unwrap().clone();
+
+    let available = services.iter().filter(|(_, _, _, available)| *available).count() as i64;
+    gauge(&mut out, "scale_to_zero_watched_services", "Services annotated for scale-to-zero.", services.len() as i64);
+    gauge(&mut out, "scale_to_zero_services_at_zero", "Watched services without an available backend.",
+          services.len() as i64 - available);
+    gauge(&mut out, "scale_to_zero_services_available", "Watched services with an available backend.", available);
+
+    counter(&mut out, "scale_to_zero_scale_up_attempts_total", "Services scaled up, including dependencies.",
+            &SCALE_UP_ATTEMPTS);
+    counter(&mut out, "scale_to_zero_scale_up_failures_total",
+            "Scale-ups that failed to patch a workload or timed out before the service was available.",
+            &SCALE_UP_FAILURES);
+    SCALE_UP_LATENCY.render(&mut out, "scale_to_zero_scale_up_latency_seconds",
+                            "Time from the request to scale a service up until it was available.");
+    counter(&mut out, "scale_to_zero_scale_down_attempts_total", "Services the scale-down loop started scaling down.",
+            &SCALE_DOWN_ATTEMPTS);
+    counter(&mut out, "scale_to_zero_scale_down_failures_total", "Scale-downs that failed.", &SCALE_DOWN_FAILURES);
+    gauge(&mut out, "scale_to_zero_scale_down_last_pass_timestamp_seconds",
+          "Unix time the scale-down loop last finished checking every service.",
+          LAST_SCALE_DOWN_PASS.load(Ordering::Relaxed));
+    counter(&mut out, "scale_to_zero_scale_retries_total", "Retries of failed scale operations.", &SCALE_RETRIES);
+    counter(&mut out, "scale_to_zero_scale_retries_exhausted_total", "Scale operations given up after their last retry.",
+            &SCALE_RETRIES_EXHAUSTED);
+    counter(&mut out, "scale_to_zero_hpa_deletions_total", "HPAs suspended before scaling a service to zero.",
+            &HPA_DELETIONS);
+    counter(&mut out, "scale_to_zero_hpa_recreations_total", "HPAs restored after scaling a service up.",
+            &HPA_RECREATIONS);
+
+    let (passed, dropped) = service_stats
+        .values()
+        .fold((0, 0), |(passed, dropped), stats| (passed + stats.passed_packets, dropped + stats.dropped_packets));
+    let _ = writeln!(out, "# HELP scale_to_zero_packets_total Packets to watched services seen by the XDP program.\n\
+                           # TYPE scale_to_zero_packets_total counter\n\
+                           scale_to_zero_packets_total{{action=\"passed\"}} {}\n\
+                           scale_to_zero_packets_total{{action=\"dropped\"}} {}", passed, dropped);
+    counter(&mut out, "scale_to_zero_scale_request_events_processed_total",
+            "Scale request events from the XDP program handled in userspace.", &PROCESSED_PACKET_EVENTS);
+    let _ = writeln!(out, "# HELP scale_to_zero_scale_request_events_lost_total Scale request events lost because the ring buffer or the queue behind it was full.\n\
+                           # TYPE scale_to_zero_scale_request_events_lost_total counter\n\
+                           scale_to_zero_scale_request_events_lost_total{{stage=\"kernel\"}} {}\n\
+                           scale_to_zero_scale_request_events_lost_total{{stage=\"userspace\"}} {}",
+                     LOST_EVENTS.load(Ordering::Relaxed), DROPPED_PACKET_EVENTS.load(Ordering::Relaxed));
+    counter(&mut out, "scale_to_zero_scale_request_reader_restarts_total",
+            "Times the ring buffer reader was reopened or restarted after failing.", &SCALE_REQUEST_READER_RESTARTS);
+    let _ = writeln!(out, "# HELP scale_to_zero_task_restarts_total Times a background task was restarted after failing.\n\
+                           # TYPE scale_to_zero_task_restarts_total counter");
+    for (task, restarts) in health::TASK_RESTARTS.lock().unwrap().iter() {
+        let _ = writeln!(out, "scale_to_zero_task_restarts_total{{task=\"{}\"}} {}", escape(task), restarts);
+    }
+
+    if let Some(status) = coordination::status() {
+        let _ = writeln!(out, "# HELP scale_to_zero_coordination_info The coordination backend, this node and the leader it knows of.\n\
+                               # TYPE scale_to_zero_coordination_info gauge\n\
+                               scale_to_zero_coordination_info{{backend=\"{}\",node=\"{}\",leader=\"{}\"}} 1",
+                         escape(status.backend), escape(&status.node_id), escape(status.leader_id.as_deref().unwrap_or("")));
+        gauge(&mut out, "scale_to_zero_is_leader", "Whether this node is the leader that scales services.",
+              status.is_leader as i64);
+        let _ = writeln!(out, "# HELP scale_to_zero_coordination_failures_total Failed operations on the coordination backend.\n\
+                               # TYPE scale_to_zero_coordination_failures_total counter\n\
+                               scale_to_zero_coordination_failures_total {}", status.failures);
+    } else {
+        // Without coordination every node scales its own services
+        gauge(&mut out, "scale_to_zero_is_leader", "Whether this node is the leader that scales services.", 1);
+    }
+    counter(&mut out, "scale_to_zero_leadership_changes_total", "Times leadership moved between nodes.",
+            &LEADERSHIP_CHANGES);
+    counter(&mut out, "scale_to_zero_coordination_outages_total",
+            "Times coordination became unavailable and the agent fell back to single-node mode.", &COORDINATION_OUTAGES);
+
+    let _ = writeln!(out, "# HELP scale_to_zero_service_packets_total Packets to the service seen by the XDP program.\n\
+                           # TYPE scale_to_zero_service_packets_total counter");
+    for (key, namespace, name, _) in services.iter() {
+        let stats = service_stats.get(key).copied().unwrap_or_default();
+        for (action, packets) in [("passed", stats.passed_packets), ("dropped", stats.dropped_packets)] {
+            let _ = writeln!(out, "scale_to_zero_service_packets_total{{namespace=\"{}\",name=\"{}\",action=\"{}\"}} {}",
+                             escape(namespace), escape(name), action, packets);
+        }
+    }
+
+    type ServiceValue = fn(&ServiceStats, bool, i64) -> Option<f64>;
+    let per_service: [(&str, &str, &str, ServiceValue); 6] = [
+        ("scale_to_zero_service_available", "gauge", "Whether the service has an available backend.",
+         |_, available, _| Some(available as u8 as f64)),
+        ("scale_to_zero_service_wake_events_total", "counter", "Held connections that asked for the service to be woken up.",
+         |stats, _, _| Some(stats.wake_events as f64)),
+        ("scale_to_zero_service_scale_ups_total", "counter", "Times the service was scaled up.",
+         |stats, _, _| Some(stats.scale_ups as f64)),
+        ("scale_to_zero_service_scale_downs_total", "counter", "Times the service was scaled down.",
+         |stats, _, _| Some(stats.scale_downs as f64)),
+        ("scale_to_zero_service_zero_seconds_total", "counter", "Seconds the service spent at zero replicas.",
+         |stats, _, now| Some(stats.time_at_zero(now) as f64)),
+        ("scale_to_zero_service_last_scale_up_duration_seconds", "gauge",
+         "Time from patching the replicas of the last scale-up until the service was available.",
+         |stats, _, _| stats.last_scale_up_duration_ms.map(|ms| ms as f64 / 1000.0)),
+    ];
+    for (metric, kind, help, value) in per_service {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", metric, help, metric, kind);
+        for (key, namespace, name, available) in services.iter() {
+            let stats = service_stats.get(key).copied().unwrap_or_default();
+            if let Some(value) = value(&stats, *available, now) {
+                let _ = writeln!(out, "{}{{namespace=\"{}\",name=\"{}\"}} {}", metric, escape(namespace), escape(name), value);
+            }
+        }
+    }
+
+    out
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
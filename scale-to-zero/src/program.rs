@@ -0,0 +1,278 @@
+use std::io;
+use std::os::fd::{AsFd, OwnedFd};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use aya::{
+    maps::{Array, HashMap, LpmTrie, Map, MapData, PerCpuArray, PerCpuHashMap, RingBuf},
+    programs::Xdp,
+    Ebpf, EbpfLoader,
+};
+use log::{error, info, warn};
+use scale_to_zero_common::{
+    PacketLog, CONFIG_DECAP_OVERLAY, CONFIG_PASS_EVENT_INTERVAL_NS, CONFIG_SCALE_REQUEST_INTERVAL_NS,
+};
+use tokio::{
+    io::unix::AsyncFd,
+    sync::mpsc,
+    task::{self, JoinHandle, JoinSet},
+};
+
+use crate::{compat, stats, utils, xdp};
+
+/// Bounds of the backoff between attempts to reopen the scale request ring buffer.
+const MIN_READER_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_READER_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Where the eBPF object is read from.
+pub enum ObjectSource<'a> {
+    /// The object built together with this binary.
+    Embedded(&'a [u8]),
+    /// An object file on disk, used to upgrade the program without restarting the agent.
+    File(&'a Path),
+}
+
+/// A loaded, not necessarily attached, XDP program together with the maps and background tasks
+/// the agent drives it through.
+pub struct LoadedProgram {
+    ebpf: Ebpf,
+    pub service_maps: utils::ServiceMaps,
+    pub local_addresses: HashMap<MapData, [u8; 16], u8>,
+    pub suppressed_scale_requests: PerCpuArray<MapData, u64>,
+    /// Where scale requests read from the ring buffer go, once started.
+    packet_queue: Option<mpsc::Sender<PacketLog>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl LoadedProgram {
+    /// Loads the object and fills its configuration maps. Maps pinned under `pin_path` are
+    /// reused, so a second load shares the service list with the first.
+    pub fn load(source: ObjectSource, pin_path: &Path, ring_buf_size: u32) -> Result<Self> {
+        compat::verify_pinned_maps(pin_path)?;
+
+        let settings = crate::config::get();
+        let capacity = settings.max_watched_services;
+        let mut loader = EbpfLoader::new();
+        loader
+            .map_pin_path(pin_path)
+            .set_max_entries("SCALE_REQUESTS", ring_buf_size)
+            .set_max_entries("SERVICE_LIST", capacity)
+            .set_max_entries("SERVICE_LIST_V6", capacity)
+            .set_max_entries("LAST_SCALE_REQUEST", capacity)
+            .set_max_entries("LAST_PASS_EVENT", capacity)
+            .set_max_entries("LAST_SEEN", capacity)
+            .set_max_entries("SERVICE_COUNTERS", capacity)
+            .set_max_entries("POD_LIST", capacity)
+            .set_max_entries("POD_LIST_V6", capacity)
+            .set_max_entries("SERVICE_SOURCE_EXCLUDE", capacity)
+            .set_max_entries("NODEPORT_LIST", capacity);
+        let mut ebpf = match source {
+            ObjectSource::Embedded(data) => loader.load(data),
+            ObjectSource::File(path) => loader.load_file(path),
+        }
+        .context("failed to load eBPF object (is BPF_MAP_TYPE_RINGBUF supported by this kernel? \
+                  If the map layout changed since the maps were pinned, restart with FRESH_START=true)")?;
+        compat::verify_packet_log_layout(&ebpf)?;
+        if let Err(e) = aya_log::EbpfLogger::init(&mut ebpf) {
+            // This can happen if you remove all log statements from your eBPF program.
+            warn!("failed to initialize eBPF logger: {e}");
+        }
+
+        // Rate limit scale requests per destination IP in the kernel, before they reach userspace
+        let mut config: Array<_, u64> = Array::try_from(ebpf.map_mut("CONFIG").unwrap())?;
+        config.set(CONFIG_SCALE_REQUEST_INTERVAL_NS, settings.scale_request_interval.as_nanos() as u64, 0)?;
+        info!("Emitting at most one scale request per service every {:?}", settings.scale_request_interval);
+
+        // Traffic to available services only produces an event this often per service; activity in
+        // between is picked up from the LAST_SEEN map
+        config.set(CONFIG_PASS_EVENT_INTERVAL_NS, settings.pass_event_interval.as_nanos() as u64, 0)?;
+
+        // Overlay CNIs (Flannel VXLAN, Geneve) hide the ClusterIP inside the encapsulated packet
+        config.set(CONFIG_DECAP_OVERLAY, settings.decap_overlay as u64, 0)?;
+        if settings.decap_overlay {
+            info!("Inspecting VXLAN and Geneve encapsulated traffic");
+        }
+
+        let program: &mut Xdp = ebpf.program_mut("scale_to_zero").unwrap().try_into()?;
+        program.load()?;
+
+        // sync the service and pod lists with WATCHED_SERVICES
+        let service_maps = utils::ServiceMaps {
+            services: HashMap::try_from(ebpf.take_map("SERVICE_LIST").unwrap())?,
+            services_v6: HashMap::try_from(ebpf.take_map("SERVICE_LIST_V6").unwrap())?,
+            pods: LpmTrie::try_from(ebpf.take_map("POD_LIST").unwrap())?,
+            pods_v6: LpmTrie::try_from(ebpf.take_map("POD_LIST_V6").unwrap())?,
+            source_excludes: LpmTrie::try_from(ebpf.take_map("SERVICE_SOURCE_EXCLUDE").unwrap())?,
+            node_ports: HashMap::try_from(ebpf.take_map("NODEPORT_LIST").unwrap())?,
+            applied: Default::default(),
+        };
+
+        // Node ports only match traffic addressed to this node
+        let mut local_addresses = HashMap::try_from(ebpf.take_map("LOCAL_ADDRESSES").unwrap())?;
+        utils::sync_local_addresses(&mut local_addresses)?;
+
+        let mut source_excludes = LpmTrie::try_from(ebpf.take_map("SOURCE_EXCLUDE").unwrap())?;
+        utils::load_source_excludes(&mut source_excludes)?;
+
+        let suppressed_scale_requests =
+            PerCpuArray::try_from(ebpf.take_map("SUPPRESSED_SCALE_REQUESTS").unwrap())?;
+
+        Ok(LoadedProgram {
+            ebpf,
+            service_maps,
+            local_addresses,
+            suppressed_scale_requests,
+            packet_queue: None,
+            tasks: Vec::new(),
+        })
+    }
+
+    pub fn program(&mut self) -> Result<&mut Xdp> {
+        Ok(self.ebpf.program_mut("scale_to_zero").unwrap().try_into()?)
+    }
+
+    /// Starts the background tasks reading the program's event and statistics maps, queueing
+    /// scale requests on `packet_queue`. Only one program may be started at a time, as the tasks
+    /// share the pinned counters.
+    pub fn start(&mut self, packet_queue: mpsc::Sender<PacketLog>) -> Result<()> {
+        // The reader reopens the ring buffer from its own copy of the map's descriptor
+        let Map::RingBuf(scale_requests) = self.ebpf.take_map("SCALE_REQUESTS").unwrap() else {
+            anyhow::bail!("SCALE_REQUESTS is not a ring buffer");
+        };
+        let scale_requests = scale_requests.fd().as_fd().try_clone_to_owned()?;
+        self.packet_queue = Some(packet_queue.clone());
+        self.tasks.push(task::spawn(supervise_scale_request_reader(scale_requests, packet_queue)));
+
+        // Aggregate kernel-side per-service packet counters in background
+        let service_counters = PerCpuHashMap::try_from(self.ebpf.take_map("SERVICE_COUNTERS").unwrap())?;
+        self.tasks.push(task::spawn(async move {
+            stats::collect_kernel_counters(service_counters).await;
+        }));
+
+        // Refresh last_packet_time from the kernel in background
+        let last_seen = HashMap::try_from(self.ebpf.take_map("LAST_SEEN").unwrap())?;
+        self.tasks.push(task::spawn(async move {
+            utils::sync_last_seen(last_seen).await;
+        }));
+
+        // Report events the kernel could not queue on the ring buffer
+        let lost_events = PerCpuArray::try_from(self.ebpf.take_map("LOST_EVENTS").unwrap())?;
+        self.tasks.push(task::spawn(async move {
+            stats::collect_lost_events(lost_events).await;
+        }));
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// Runs `read_scale_requests`, restarting it whenever it panics. Aborting the supervisor aborts
+/// the reader too.
+async fn supervise_scale_request_reader(scale_requests: OwnedFd, packet_queue: mpsc::Sender<PacketLog>) {
+    let mut backoff = MIN_READER_BACKOFF;
+    loop {
+        let mut reader = JoinSet::new();
+        let started = Instant::now();
+        reader.spawn(read_scale_requests(scale_requests.try_clone(), packet_queue.clone()));
+        match reader.join_next().await {
+            Some(Err(err)) if err.is_panic() => error!("Scale request reader panicked, restarting it in {:?}", backoff),
+            _ => error!("Scale request reader stopped, restarting it in {:?}", backoff),
+        }
+        stats::SCALE_REQUEST_READER_RESTARTS.fetch_add(1, Ordering::Relaxed);
+        // A reader that ran for a while failed afresh rather than kept failing
+        if started.elapsed() > MAX_READER_BACKOFF {
+            backoff = MIN_READER_BACKOFF;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_READER_BACKOFF);
+    }
+}
+
+/// Queues every scale request the kernel puts on the `SCALE_REQUESTS` ring buffer behind
+/// `scale_requests` on `packet_queue`. A buffer that cannot be opened or polled is reopened with
+/// backoff, so one failed read does not leave scale requests unread for good.
+async fn read_scale_requests(scale_requests: io::Result<OwnedFd>, packet_queue: mpsc::Sender<PacketLog>) {
+    let scale_requests = match scale_requests {
+        Ok(scale_requests) => scale_requests,
+        Err(err) => {
+            error!("Failed to duplicate the scale request ring buffer descriptor: {}", err);
+            return;
+        }
+    };
+    let mut backoff = MIN_READER_BACKOFF;
+    loop {
+        let mut ring = match open_ring_buf(&scale_requests) {
+            Ok(ring) => ring,
+            Err(err) => {
+                error!("Failed to open the scale request ring buffer, retrying in {:?}: {:#}", backoff, err);
+                stats::SCALE_REQUEST_READER_RESTARTS.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_READER_BACKOFF);
+                continue;
+            }
+        };
+
+        loop {
+            let mut guard = match ring.readable_mut().await {
+                Ok(guard) => guard,
+                Err(err) => {
+                    error!("Failed to poll the scale request ring buffer, reopening it in {:?}: {}", backoff, err);
+                    break;
+                }
+            };
+            backoff = MIN_READER_BACKOFF;
+
+            // Only decodes events, which `utils::process_packets` handles, so the ring buffer
+            // is drained as fast as the kernel fills it
+            let ring = guard.get_inner_mut();
+            while let Some(item) = ring.next() {
+                if item.len() < std::mem::size_of::<PacketLog>() {
+                    warn!("Dropping scale request of {} bytes, expected {}",
+                          item.len(), std::mem::size_of::<PacketLog>());
+                    continue;
+                }
+                let ptr = item.as_ptr() as *const PacketLog;
+                utils::queue_packet(&packet_queue, unsafe { ptr.read_unaligned() });
+            }
+            guard.clear_ready();
+        }
+
+        stats::SCALE_REQUEST_READER_RESTARTS.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_READER_BACKOFF);
+    }
+}
+
+fn open_ring_buf(fd: &OwnedFd) -> Result<AsyncFd<RingBuf<MapData>>> {
+    let map = MapData::from_fd(fd.try_clone()?)?;
+    Ok(AsyncFd::new(RingBuf::try_from(Map::RingBuf(map))?)?)
+}
+
+/// Loads the eBPF object at `path` and moves every attachment of `current` over to it. The new
+/// program takes over only once it is loaded, verified and its service maps are filled, and the
+/// hand-over replaces each interface's program atomically. On failure `current` stays in place.
+pub async fn reload(
+    current: &mut LoadedProgram,
+    path: &Path,
+    pin_path: &Path,
+    ring_buf_size: u32,
+    attached: &mut [xdp::AttachedInterface],
+) -> Result<()> {
+    let mut next = LoadedProgram::load(ObjectSource::File(path), pin_path, ring_buf_size)?;
+    utils::sync_data(&mut next.service_maps).await?;
+
+    xdp::replace_all(current.program()?, next.program()?, attached)?;
+
+    let packet_queue = current.packet_queue.clone().context("the current program was never started")?;
+    let mut previous = std::mem::replace(current, next);
+    previous.stop();
+    current.start(packet_queue)?;
+    info!("Reloaded XDP program from {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::{
+    io,
+    mem,
+    net::{Ipv4Addr, Ipv6Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use anyhow::Result;
+use scale_to_zero_common::{PacketLog, IP_VERSION_6, UNAVAILABLE_ICMP, UNAVAILABLE_RESET};
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_ICMPV6: u8 = 58;
+
+const TCP_FLAG_RST: u8 = 0x04;
+const TCP_FLAG_ACK: u8 = 0x10;
+
+const IPV4_HDR_LEN: usize = 20;
+const IPV6_HDR_LEN: usize = 40;
+const TCP_HDR_LEN: usize = 20;
+const UDP_HDR_LEN: usize = 8;
+const ICMP_HDR_LEN: usize = 8;
+
+/// Answers the packet described by `log` on behalf of an unavailable service, so the client
+/// fails fast instead of retrying into a silent drop. The XDP program has already dropped the
+/// original packet.
+pub fn send(log: &PacketLog) -> Result<()> {
+    let flow = Flow::from_log(log);
+    let l4 = match (log.unavailable_action, log.protocol) {
+        (UNAVAILABLE_RESET, IPPROTO_TCP) => Reply::TcpReset,
+        (UNAVAILABLE_ICMP, IPPROTO_TCP | IPPROTO_UDP) => Reply::PortUnreachable,
+        // Nothing to answer, e.g. UDP to a service in reset mode
+        _ => return Ok(()),
+    };
+
+    match flow {
+        Flow::V4 { client, service } => {
+            let packet = match l4 {
+                Reply::TcpReset => {
+                    let tcp = tcp_reset(log, |segment| pseudo_header_sum_v4(service, client, IPPROTO_TCP, segment));
+                    ipv4_packet(service, client, IPPROTO_TCP, &tcp)
+                }
+                Reply::PortUnreachable => {
+                    // ICMP quotes the original IP header and the first 8 bytes of its payload
+                    let quoted_l4 = quoted_l4_header(log);
+                    let quoted = ipv4_packet(client, service, log.protocol, &quoted_l4);
+                    let icmp = icmp_message(3, 3, &quoted, |_| 0);
+                    ipv4_packet(service, client, IPPROTO_ICMP, &icmp)
+                }
+            };
+            send_raw(libc::AF_INET, &packet, client.into())
+        }
+        Flow::V6 { client, service } => {
+            let packet = match l4 {
+                Reply::TcpReset => {
+                    let tcp = tcp_reset(log, |segment| pseudo_header_sum_v6(&service, &client, IPPROTO_TCP, segment));
+                    ipv6_packet(&service, &client, IPPROTO_TCP, &tcp)
+                }
+                Reply::PortUnreachable => {
+                    let quoted_l4 = quoted_l4_header(log);
+                    let quoted = ipv6_packet(&client, &service, log.protocol, &quoted_l4);
+                    let icmp = icmp_message(1, 4, &quoted, |message| {
+                        pseudo_header_sum_v6(&service, &client, IPPROTO_ICMPV6, message)
+                    });
+                    ipv6_packet(&service, &client, IPPROTO_ICMPV6, &icmp)
+                }
+            };
+            send_raw(libc::AF_INET6, &packet, client.into())
+        }
+    }
+}
+
+enum Reply {
+    TcpReset,
+    PortUnreachable,
+}
+
+enum Flow {
+    V4 { client: Ipv4Addr, service: Ipv4Addr },
+    V6 { client: Ipv6Addr, service: Ipv6Addr },
+}
+
+impl Flow {
+    fn from_log(log: &PacketLog) -> Self {
+        if log.ip_version == IP_VERSION_6 {
+            Flow::V6 {
+                client: Ipv6Addr::from(log.src_ipv6_address),
+                service: Ipv6Addr::from(log.ipv6_address),
+            }
+        } else {
+            Flow::V4 {
+                client: Ipv4Addr::from(log.src_ipv4_address),
+                service: Ipv4Addr::from(log.ipv4_address),
+            }
+        }
+    }
+}
+
+/// RST+ACK answering the SYN in `log`. `pseudo_sum` supplies the checksum contribution of the
+/// IP pseudo header for the finished segment.
+fn tcp_reset(log: &PacketLog, pseudo_sum: impl Fn(&[u8]) -> u32) -> Vec<u8> {
+    let mut tcp = vec![0u8; TCP_HDR_LEN];
+    tcp[0..2].copy_from_slice(&log.port.to_be_bytes());
+    tcp[2..4].copy_from_slice(&log.src_port.to_be_bytes());
+    // seq stays 0; acknowledge the SYN
+    tcp[8..12].copy_from_slice(&log.tcp_seq.wrapping_add(1).to_be_bytes());
+    tcp[12] = ((TCP_HDR_LEN / 4) as u8) << 4;
+    tcp[13] = TCP_FLAG_RST | TCP_FLAG_ACK;
+
+    let checksum = fold_checksum(pseudo_sum(&tcp) + sum_words(&tcp));
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+    tcp
+}
+
+/// First 8 bytes of the client's original TCP or UDP header, as quoted in ICMP errors.
+fn quoted_l4_header(log: &PacketLog) -> Vec<u8> {
+    let mut header = vec![0u8; 8];
+    header[0..2].copy_from_slice(&log.src_port.to_be_bytes());
+    header[2..4].copy_from_slice(&log.port.to_be_bytes());
+    if log.protocol == IPPROTO_TCP {
+        header[4..8].copy_from_slice(&log.tcp_seq.to_be_bytes());
+    } else {
+        header[4..6].copy_from_slice(&(UDP_HDR_LEN as u16).to_be_bytes());
+    }
+    header
+}
+
+fn icmp_message(icmp_type: u8, code: u8, quoted: &[u8], pseudo_sum: impl Fn(&[u8]) -> u32) -> Vec<u8> {
+    let mut icmp = vec![0u8; ICMP_HDR_LEN];
+    icmp[0] = icmp_type;
+    icmp[1] = code;
+    icmp.extend_from_slice(quoted);
+
+    let checksum = fold_checksum(pseudo_sum(&icmp) + sum_words(&icmp));
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+    icmp
+}
+
+fn ipv4_packet(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0u8; IPV4_HDR_LEN];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&((IPV4_HDR_LEN + payload.len()) as u16).to_be_bytes());
+    // Don't fragment
+    packet[6] = 0x40;
+    packet[8] = 64;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&src.octets());
+    packet[16..20].copy_from_slice(&dst.octets());
+
+    let checksum = fold_checksum(sum_words(&packet));
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv6_packet(src: &Ipv6Addr, dst: &Ipv6Addr, next_header: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0u8; IPV6_HDR_LEN];
+    packet[0] = 0x60;
+    packet[4..6].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet[6] = next_header;
+    packet[7] = 64;
+    packet[8..24].copy_from_slice(&src.octets());
+    packet[24..40].copy_from_slice(&dst.octets());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn pseudo_header_sum_v4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, segment: &[u8]) -> u32 {
+    sum_words(&src.octets()) + sum_words(&dst.octets()) + protocol as u32 + segment.len() as u32
+}
+
+fn pseudo_header_sum_v6(src: &Ipv6Addr, dst: &Ipv6Addr, next_header: u8, segment: &[u8]) -> u32 {
+    sum_words(&src.octets()) + sum_words(&dst.octets()) + next_header as u32 + segment.len() as u32
+}
+
+fn sum_words(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32)
+        .sum()
+}
+
+fn fold_checksum(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Sends a complete IP packet through a raw socket, bypassing the kernel's own header so the
+/// reply can carry the service address as its source.
+fn send_raw(family: libc::c_int, packet: &[u8], dst: std::net::IpAddr) -> Result<()> {
+    let fd = unsafe { libc::socket(family, libc::SOCK_RAW, libc::IPPROTO_RAW) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let sent = match dst {
+        std::net::IpAddr::V4(dst) => {
+            let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+            addr.sin_family = libc::AF_INET as libc::sa_family_t;
+            addr.sin_addr.s_addr = u32::from(dst).to_be();
+            unsafe {
+                libc::sendto(
+                    socket.as_raw_fd(),
+                    packet.as_ptr() as *const libc::c_void,
+                    packet.len(),
+                    0,
+                    &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        std::net::IpAddr::V6(dst) => {
+            let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            addr.sin6_addr.s6_addr = dst.octets();
+            unsafe {
+                libc::sendto(
+                    socket.as_raw_fd(),
+                    packet.as_ptr() as *const libc::c_void,
+                    packet.len(),
+                    0,
+                    &addr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
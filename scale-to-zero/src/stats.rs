@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use aya::maps::{MapData, PerCpuArray, PerCpuHashMap};
+use k8s_openapi::chrono;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use scale_to_zero_common::ServiceCounters;
+
+use crate::utils::DROPPED_PACKET_EVENTS;
+use crate::kubernetes::coordination::{self, COORDINATION_OUTAGES, LEADERSHIP_CHANGES};
+use crate::kubernetes::models::{
+    read_watched_services, resolve_address_key, take_lock_times, ServiceData, SCALE_DOWN_FAILURES, SCALE_RETRIES,
+    SCALE_RETRIES_EXHAUSTED, SERVICES_LISTED, SERVICE_STATS,
+};
+
+const COLLECT_INTERVAL: Duration = Duration::from_secs(5);
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+const LOST_EVENTS_INTERVAL: Duration = Duration::from_secs(10);
+
+const WINDOW_1M: f64 = 60.0;
+const WINDOW_10M: f64 = 600.0;
+const WINDOW_1H: f64 = 3600.0;
+
+/// Events the XDP program could not send because the ring buffer was full, since startup.
+pub static LOST_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Times the ring buffer reader was reopened or restarted after failing, since startup.
+pub static SCALE_REQUEST_READER_RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+pub static SERVICE_RATES: Lazy<Mutex<HashMap<String, RateEstimator>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Packets-per-second estimates for a service, as exponentially weighted moving averages over
+/// 1 minute, 10 minutes and 1 hour.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rates {
+    pub pps_1m: f64,
+    pub pps_10m: f64,
+    pub pps_1h: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RateEstimator {
+    rates: Rates,
+    last_total: Option<u64>,
+}
+
+impl RateEstimator {
+    /// Feeds the current value of a monotonic counter observed `elapsed` after the previous
+    /// sample. A counter that went backwards (program reload, map recreation) is treated as
+    /// having restarted from zero.
+    pub fn update(&mut self, total: u64, elapsed: Duration) {
+        let previous = match self.last_total.replace(total) {
+            Some(previous) => previous,
+            // The first sample only establishes the baseline.
+            None => return,
+        };
+
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return;
+        }
+
+        let delta = if total >= previous { total - previous } else { total };
+        let sample = delta as f64 / secs;
+
+        self.rates.pps_1m = ewma(self.rates.pps_1m, sample, secs, WINDOW_1M);
+        self.rates.pps_10m = ewma(self.rates.pps_10m, sample, secs, WINDOW_10M);
+        self.rates.pps_1h = ewma(self.rates.pps_1h, sample, secs, WINDOW_1H);
+    }
+
+    pub fn rates(&self) -> Rates {
+        self.rates
+    }
+}
+
+fn ewma(current: f64, sample: f64, elapsed: f64, window: f64) -> f64 {
+    let alpha = 1.0 - (-elapsed / window).exp();
+    current + alpha * (sample - current)
+}
+
+pub async fn collect_rates() {
+    let mut last_collected = Instant::now();
+    let mut last_summary = Instant::now();
+
+    loop {
+        tokio::time::sleep(COLLECT_INTERVAL).await;
+
+        let elapsed = last_collected.elapsed();
+        last_collected = Instant::now();
+
+        let watched_ips: Vec<String> = read_watched_services().keys().cloned().collect();
+        // Rates are derived from the kernel counters aggregated by `collect_kernel_counters`
+        let totals: HashMap<String, u64> = SERVICE_STATS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ip, stats)| (ip.clone(), stats.passed_packets + stats.dropped_packets))
+            .collect();
+        {
+            let mut rates = SERVICE_RATES.lock().unwrap();
+            rates.retain(|ip, _| watched_ips.contains(ip));
+            for ip in watched_ips.iter() {
+                let total = totals.get(ip).copied().unwrap_or(0);
+                rates.entry(ip.clone()).or_default().update(total, elapsed);
+            }
+        }
+
+        if last_summary.elapsed() >= SUMMARY_INTERVAL {
+            log_service_summary();
+            last_summary = Instant::now();
+        }
+    }
+}
+
+/// Folds the kernel's per-CPU `SERVICE_COUNTERS` into `SERVICE_STATS`. Counters of addresses no
+/// longer watched are deleted from the map so a re-added service starts from zero.
+pub async fn collect_kernel_counters(mut counters: PerCpuHashMap<MapData, [u8; 16], ServiceCounters>) {
+    // Last raw totals seen per address, to turn the kernel's cumulative counters into deltas
+    let mut last_raw: HashMap<[u8; 16], ServiceCounters> = HashMap::new();
+
+    loop {
+        tokio::time::sleep(COLLECT_INTERVAL).await;
+
+        let mut deltas: HashMap<String, ServiceCounters> = HashMap::new();
+        let mut stale = Vec::new();
+        {
+            let watched_services = read_watched_services();
+            for entry in counters.iter() {
+                let (address, values) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        warn!("Failed to read service counters: {}", e);
+                        continue;
+                    }
+                };
+                let Some(key) = resolve_address_key(&watched_services, address) else {
+                    stale.push(address);
+                    continue;
+                };
+
+                let raw = values.iter().fold(ServiceCounters::default(), |total, cpu| ServiceCounters {
+                    passed: total.passed + cpu.passed,
+                    dropped: total.dropped + cpu.dropped,
+                });
+                let previous = last_raw.insert(address, raw).unwrap_or_default();
+                let delta = deltas.entry(key).or_default();
+                delta.passed += counter_delta(previous.passed, raw.passed);
+                delta.dropped += counter_delta(previous.dropped, raw.dropped);
+            }
+        }
+
+        // Counters restored from pinned maps may belong to services not listed yet
+        if !SERVICES_LISTED.load(Ordering::SeqCst) {
+            stale.clear();
+        }
+        for address in stale {
+            let _ = counters.remove(&address);
+            last_raw.remove(&address);
+        }
+
+        let watched_keys: Vec<String> = read_watched_services().keys().cloned().collect();
+        let mut service_stats = SERVICE_STATS.lock().unwrap();
+        service_stats.retain(|key, _| watched_keys.contains(key));
+        for (key, delta) in deltas {
+            let stats = service_stats.entry(key).or_default();
+            stats.passed_packets += delta.passed;
+            stats.dropped_packets += delta.dropped;
+        }
+    }
+}
+
+/// Polls the kernel's per-CPU `LOST_EVENTS` counter into `LOST_EVENTS`, warning at most once per
+/// interval when events were lost so the ring buffer can be sized.
+pub async fn collect_lost_events(lost_events: PerCpuArray<MapData, u64>) {
+    let mut last_per_cpu: Vec<u64> = Vec::new();
+
+    loop {
+        tokio::time::sleep(LOST_EVENTS_INTERVAL).await;
+
+        let per_cpu: Vec<u64> = match lost_events.get(&0, 0) {
+            Ok(values) => values.iter().copied().collect(),
+            Err(e) => {
+                warn!("Failed to read lost event counter: {}", e);
+                continue;
+            }
+        };
+
+        let lost: Vec<(usize, u64)> = per_cpu
+            .iter()
+            .enumerate()
+            .map(|(cpu, total)| (cpu, counter_delta(last_per_cpu.get(cpu).copied().unwrap_or(0), *total)))
+            .filter(|(_, delta)| *delta > 0)
+            .collect();
+        last_per_cpu = per_cpu;
+
+        if lost.is_empty() {
+            continue;
+        }
+        let total: u64 = lost.iter().map(|(_, delta)| delta).sum();
+        LOST_EVENTS.fetch_add(total, Ordering::Relaxed);
+        let breakdown: Vec<String> = lost.iter().map(|(cpu, delta)| format!("cpu{}={}", cpu, delta)).collect();
+        warn!("Lost {} scale request events in the last {}s ({}); consider raising SCALE_REQUESTS_RING_BUFFER_SIZE",
+              total, LOST_EVENTS_INTERVAL.as_secs(), breakdown.join(", "));
+    }
+}
+
+fn counter_delta(previous: u64, current: u64) -> u64 {
+    // The entry was recreated since the last read
+    if current >= previous { current - previous } else { current }
+}
+
+fn log_service_summary() {
+    let now = chrono::Utc::now().timestamp();
+    let watched_services = read_watched_services();
+    let rates = SERVICE_RATES.lock().unwrap();
+    let service_stats = SERVICE_STATS.lock().unwrap();
+
+    info!(target: "service_stats", "{} services watched, {} scale request events lost ({} dropped in userspace), {} scale request reader restarts, {} scale-downs failed, {} scale retries ({} given up), {} leadership changes, {} coordination outages since startup; scaling {}{}",
+          watched_services.len(), LOST_EVENTS.load(Ordering::Relaxed), DROPPED_PACKET_EVENTS.load(Ordering::Relaxed),
+          SCALE_REQUEST_READER_RESTARTS.load(Ordering::Relaxed), SCALE_DOWN_FAILURES.load(Ordering::Relaxed),
+          SCALE_RETRIES.load(Ordering::Relaxed), SCALE_RETRIES_EXHAUSTED.load(Ordering::Relaxed),
+          LEADERSHIP_CHANGES.load(Ordering::Relaxed), COORDINATION_OUTAGES.load(Ordering::Relaxed),
+          if coordination::may_scale() { "enabled" } else { "left to the leader" },
+          if coordination::is_degraded() { " (coordination unavailable, single-node mode)" } else { "" });
+    let lock_times = take_lock_times();
+    info!(target: "service_stats", "Watched services lock: longest read wait {:?}, longest write hold {:?} ({:?} on average), {} writes since the last summary",
+          lock_times.max_read_wait, lock_times.max_write_hold, lock_times.mean_write_hold, lock_times.write_locks);
+    if let Some(status) = coordination::status() {
+        let ago = |time: Option<i64>| time.map(|time| format!("{}s ago", now - time)).unwrap_or_else(|| "never".to_string());
+        let peers: Vec<String> = status.peers.iter()
+            .map(|peer| format!("{} ({}s ago)", peer.node_id, now - peer.last_heartbeat))
+            .collect();
+        info!(target: "service_stats", "Coordination through {} on node {}: {}, leader {}, peers [{}], last push {}, last pull {}, sync lag {}s, {} failures ({} in a row)",
+              status.backend, status.node_id,
+              match status.leader_since {
+                  Some(since) if status.is_leader => format!("leading for {}s", now - since),
+                  _ => "following".to_string(),
+              },
+              status.leader_id.as_deref().unwrap_or("unknown"), peers.join(", "),
+              ago(status.last_push), ago(status.last_pull), status.sync_lag_seconds,
+              status.failures, status.consecutive_failures);
+    }
+    for (ip, service) in watched_services.iter() {
+        let service_rates = rates.get(ip).map(|r| r.rates()).unwrap_or_default();
+        let counters = service_stats.get(ip).copied().unwrap_or_default();
+        let last_scale_up = service
+            .last_scale_up_time
+            .map(|time| format!("{}s ago", now - time))
+            .unwrap_or_else(|| "never".to_string());
+        let scale_up_duration = counters
+            .last_scale_up_duration_ms
+            .map(|ms| format!("{}ms", ms))
+            .unwrap_or_else(|| "unknown".to_string());
+        info!(target: "service_stats", "{}/{} ({}) state: {}, idle: {}s, last scale-up: {} (took {}), pps 1m/10m/1h: {:.2}/{:.2}/{:.2}, packets passed/dropped: {}/{}, wakes: {}, scale-ups/downs: {}/{}, at zero for {}s",
+              service.namespace, service.name, ip,
+              service_state(service, now),
+              now - service.last_packet_time.get(),
+              last_scale_up, scale_up_duration,
+              service_rates.pps_1m, service_rates.pps_10m, service_rates.pps_1h,
+              counters.passed_packets, counters.dropped_packets,
+              counters.wake_events, counters.scale_ups, counters.scale_downs, counters.time_at_zero(now));
+    }
+}
+
+/// What the service is doing, as shown in the summary and by the admin API.
+pub fn service_state(service: &ServiceData, now: i64) -> &'static str {
+    if service.workload_missing {
+        "workload-missing"
+    } else if service.paused_until.is_some_and(|until| now < until) {
+        "paused"
+    } else if service.manual_override_until.is_some() {
+        "manually-overridden"
+    } else if service.at_idle_floor {
+        "idle-replicas"
+    } else if service.backend_available {
+        "available"
+    } else if service.scale_up_started.is_some() {
+        "scaling-up"
+    } else if service.circuit_open_until.is_some_and(|until| now < until) {
+        "quarantined"
+    } else if service.scale_up_failed {
+        "scale-up-failed"
+    } else {
+        "scaled-to-zero"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEP: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn first_sample_only_sets_the_baseline() {
+        let mut estimator = RateEstimator::default();
+        estimator.update(1_000_000, STEP);
+        assert_eq!(estimator.rates(), Rates::default());
+
+        estimator.update(1_000_500, STEP);
+        assert!(estimator.rates().pps_1m > 0.0);
+    }
+
+    #[test]
+    fn steady_rate_is_converged_to() {
+        let mut estimator = RateEstimator::default();
+        // 100 packets per second for over 10 hours
+        for step in 0..8_000u64 {
+            estimator.update(step * 500, STEP);
+        }
+        let rates = estimator.rates();
+        for rate in [rates.pps_1m, rates.pps_10m, rates.pps_1h] {
+            assert!((rate - 100.0).abs() < 0.01, "rate {} did not converge to 100", rate);
+        }
+    }
+
+    #[test]
+    fn counter_reset_counts_from_zero() {
+        let mut estimator = RateEstimator::default();
+        estimator.update(1_000_000, STEP);
+        estimator.update(10, STEP);
+
+        let rates = estimator.rates();
+        assert!(rates.pps_1m > 0.0 && rates.pps_1m <= 2.0, "rate {} after a reset", rates.pps_1m);
+        assert!(rates.pps_1h > 0.0 && rates.pps_1h <= 2.0);
+    }
+
+    #[test]
+    fn zero_elapsed_leaves_the_rates_alone() {
+        let mut estimator = RateEstimator::default();
+        estimator.update(0, STEP);
+        estimator.update(500, STEP);
+        let before = estimator.rates();
+
+        estimator.update(1_000, Duration::ZERO);
+
+        assert_eq!(estimator.rates(), before);
+        assert!(before.pps_1m.is_finite());
+    }
+}
@@ -1,94 +1,235 @@
 use aya::{
-  maps::{HashMap, MapData},
+  maps::{lpm_trie::{Key, LpmTrie}, HashMap, MapData, MapError},
+  sys::SyscallError,
 };
 use k8s_openapi::chrono;
 use log::{error, info, warn};
-use scale_to_zero_common::PacketLog;
-use std::net::Ipv4Addr;
-use std::collections::HashMap as StdHashMap;
-use anyhow::Result;
+use scale_to_zero_common::{
+  node_port_key, PacketLog, ServiceKeyV4, ServiceKeyV6, ANY_PORT, IP_VERSION_6, SERVICE_AVAILABLE,
+  UNAVAILABLE_ACTION_SHIFT, UNAVAILABLE_DROP,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::collections::{HashMap as StdHashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use anyhow::{anyhow, bail, Context, Result};
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use once_cell::sync::Lazy;
+use tokio::sync::mpsc;
 
 use crate::kubernetes;
+use crate::kubernetes::scaler::ScaleUpOutcome;
+use crate::reject;
+
+/// Per-map state of the last sync, so capacity problems are logged when they change rather than
+/// on every sync.
+#[derive(Default)]
+struct MapSyncState {
+  failed_inserts: usize,
+  near_capacity: bool,
+}
+
+/// `interval` plus up to the configured loop jitter (none by default), so loops of agents on
+/// different nodes drift apart instead of hitting the apiserver together.
+pub fn with_jitter(interval: std::time::Duration) -> std::time::Duration {
+  let jitter_ms = crate::config::get().loop_jitter.as_millis() as u64;
+  let jitter = u64::from(chrono::Utc::now().timestamp_subsec_nanos()) % (jitter_ms + 1);
+  interval + std::time::Duration::from_millis(jitter)
+}
+
+static MAP_SYNC_STATE: Lazy<Mutex<StdHashMap<&'static str, MapSyncState>>> =
+    Lazy::new(|| Mutex::new(StdHashMap::new()));
+
+/// Scale request events dropped because `process_packets` fell behind, and events it handled,
+/// since startup.
+pub static DROPPED_PACKET_EVENTS: AtomicU64 = AtomicU64::new(0);
+pub static PROCESSED_PACKET_EVENTS: AtomicU64 = AtomicU64::new(0);
 
-pub async fn process_packet(packet_log: PacketLog) {
-  let dist_addr = Ipv4Addr::from(packet_log.ipv4_address);
-  if dist_addr.is_loopback() {
-    return;
+/// Most events handled as one batch.
+const MAX_PACKET_BATCH: usize = 1024;
+
+/// The queue between the ring buffer reader and `process_packets`, holding up to
+/// `PACKET_QUEUE_SIZE` events (4096 by default).
+pub fn packet_queue() -> (mpsc::Sender<PacketLog>, mpsc::Receiver<PacketLog>) {
+  mpsc::channel(crate::config::get().packet_queue_size)
+}
+
+/// Queues `packet_log` for `process_packets` without waiting, dropping it if the queue is full.
+/// The XDP program repeats scale requests of a service while it stays unavailable, so a dropped
+/// one only delays the wake.
+pub fn queue_packet(queue: &mpsc::Sender<PacketLog>, packet_log: PacketLog) {
+  match queue.try_send(packet_log) {
+    Ok(()) => {}
+    Err(mpsc::error::TrySendError::Full(_)) => {
+      if DROPPED_PACKET_EVENTS.fetch_add(1, Ordering::Relaxed) == 0 {
+        warn!("Packet event queue is full, dropping events; raise PACKET_QUEUE_SIZE if this persists");
+      }
+    }
+    Err(mpsc::error::TrySendError::Closed(_)) => {}
   }
+}
 
+/// Handles the events queued by the ring buffer reader until the queue closes. Events arriving
+/// within `PACKET_COALESCE_WINDOW_MS` (5 by default) of each other are handled as one batch, so
+/// the packet times of a service are updated once per batch and it is scaled up at most once.
+pub async fn process_packets(queue: &mut mpsc::Receiver<PacketLog>) {
+  while let Some(first) = queue.recv().await {
+    let mut batch = vec![first];
+    let deadline = tokio::time::Instant::now() + crate::config::get().packet_coalesce_window;
+    while batch.len() < MAX_PACKET_BATCH {
+      match tokio::time::timeout_at(deadline, queue.recv()).await {
+        Ok(Some(packet_log)) => batch.push(packet_log),
+        Ok(None) | Err(_) => break,
+      }
+    }
+    PROCESSED_PACKET_EVENTS.fetch_add(batch.len() as u64, Ordering::Relaxed);
+    process_batch(batch);
+  }
+}
+
+/// Traffic to one service within a batch.
+#[derive(Default)]
+struct ServiceTraffic {
+  port: u16,
+  /// Sources of the packets held while the service is scaled down, which wake it.
+  wake_sources: Vec<(String, u16)>,
+}
+
+fn process_batch(batch: Vec<PacketLog>) {
   let current_time = chrono::Utc::now().timestamp();
-  let dist_addr_str = dist_addr.to_string();
-
-  // Get the service dependencies and update the packet time
-  let (service_dependencies, service_dependents) = {
-    let mut services = kubernetes::models::WATCHED_SERVICES.lock().unwrap();
-
-    // Get the service data first, then update it and its dependencies
-    if let Some(service) = services.get_mut(&dist_addr_str) {
-        service.last_packet_time = current_time;
-        let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
-        info!("[{}] Updated last_packet_time for {} ({}/{}) to {}",
-              timestamp, service.name, service.namespace, service.kind, current_time);
-        
-        // Clone the dependencies and dependents to avoid borrowing issues
-        (service.dependencies.clone(), service.dependents.clone())
-    } else {
-        (Vec::new(), Vec::new())
+
+  let mut traffic: StdHashMap<String, ServiceTraffic> = StdHashMap::new();
+  {
+    let services = kubernetes::models::read_watched_services();
+    for packet_log in &batch {
+      let (dist_addr, src_addr) = if packet_log.ip_version == IP_VERSION_6 {
+        (IpAddr::V6(Ipv6Addr::from(packet_log.ipv6_address)), IpAddr::V6(Ipv6Addr::from(packet_log.src_ipv6_address)))
+      } else {
+        (IpAddr::V4(Ipv4Addr::from(packet_log.ipv4_address)), IpAddr::V4(Ipv4Addr::from(packet_log.src_ipv4_address)))
+      };
+      if dist_addr.is_loopback() {
+        continue;
+      }
+
+      // Every held connection is rejected on its own
+      if packet_log.unavailable_action != UNAVAILABLE_DROP
+          && let Err(e) = reject::send(packet_log)
+      {
+        warn!("Failed to reject connection to {} from {}: {}", dist_addr, src_addr, e);
+      }
+
+      // Excluded sources never count as activity
+      if packet_log.excluded != 0 {
+        continue;
+      }
+
+      // Traffic to a secondary ClusterIP or a node port is accounted to the service's primary key
+      let resolved = if packet_log.node_port != 0 {
+          kubernetes::models::resolve_address_key(&services, node_port_key(packet_log.port))
+      } else {
+          kubernetes::models::resolve_service_key(&services, &dist_addr.to_string())
+      };
+      let entry = traffic.entry(resolved.unwrap_or_else(|| dist_addr.to_string())).or_default();
+      entry.port = packet_log.port;
+      if packet_log.action == 1 {
+        let source = std::net::SocketAddr::new(src_addr, packet_log.src_port).to_string();
+        entry.wake_sources.push((source, packet_log.port));
+      }
     }
-  }; // services lock is released here
-    
-    // For etcd coordination, also update via etcd if available
-    if let Err(e) = kubernetes::etcd_coordinator::update_packet_time_via_etcd(&dist_addr_str, current_time).await {
-        warn!("Failed to update packet time via etcd: {}", e);
+
+    // Packet times are atomic, so this only shares the lock with the scaler's scans
+    for (key, service_traffic) in &traffic {
+      let Some(service) = services.get(key) else {
+        continue;
+      };
+      service.last_packet_time.touch(current_time);
+      let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+      info!("[{}] Updated last_packet_time for {}/{} to {} (port {})",
+            timestamp, service.namespace, service.name, current_time, service_traffic.port);
+
+      // Update children (dependencies) - services this service depends on
+      for dependency_target in &service.dependencies {
+          update_service_by_target(&services, dependency_target, current_time, key, "dependency", &service.dependency_cycle);
+      }
+
+      // Update parents (dependents) - services that depend on this service
+      for dependent_target in &service.dependents {
+          update_service_by_target(&services, dependent_target, current_time, key, "dependent", &service.dependency_cycle);
+      }
     }
-    
-    // Update dependent services (children) and parent services when this service gets traffic
-    if !service_dependencies.is_empty() || !service_dependents.is_empty() {
-        // info!("Service {} received traffic, updating {} dependencies (children) and {} dependents (parents)", 
-      //       dist_addr_str, service_dependencies.len(), service_dependents.len());
-      
-      // Reacquire the lock for updating dependencies
-      let mut services = kubernetes::models::WATCHED_SERVICES.lock().unwrap();
-        
-        // Update children (dependencies) - services this service depends on
-        for dependency_target in &service_dependencies {
-            update_service_by_target(&mut services, dependency_target, current_time, &dist_addr_str, "dependency");
-        }
-        
-        // Update parents (dependents) - services that depend on this service
-        for dependent_target in &service_dependents {
-            update_service_by_target(&mut services, dependent_target, current_time, &dist_addr_str, "dependent");
+  } // services lock is released here
+
+  // Only packets held while a service is scaled down record where they came from
+  if traffic.values().any(|service_traffic| !service_traffic.wake_sources.is_empty()) {
+    let mut services = kubernetes::models::write_watched_services();
+    for (key, service_traffic) in &traffic {
+      let Some(service) = services.get_mut(key) else {
+        continue;
+      };
+      for (source, port) in &service_traffic.wake_sources {
+        service.wake_sources.push(kubernetes::models::WakeSource {
+            source: source.clone(),
+            port: *port,
+            time: current_time,
+        });
+      }
+      let excess = service.wake_sources.len().saturating_sub(kubernetes::models::MAX_WAKE_SOURCES);
+      service.wake_sources.drain(..excess);
+    }
+    drop(services);
+
+    let mut service_stats = kubernetes::models::SERVICE_STATS.lock().unwrap();
+    for (key, service_traffic) in traffic.iter().filter(|(_, traffic)| !traffic.wake_sources.is_empty()) {
+      service_stats.entry(key.clone()).or_default().wake_events += service_traffic.wake_sources.len() as u64;
     }
   }
 
-  if packet_log.action == 1 {
-    match kubernetes::scaler::scale_up(dist_addr_str).await {
-      Ok(_) => {
-          info!("Scaled up {}", dist_addr);
-      }
-      Err(err) => {
-          if !err.to_string().starts_with("Rate Limited: Function ") {
-              error!("Failed to scale up {}: {}", dist_addr, err);
-          }
+  // A scale-up waits for dependencies to become ready, which must not hold up other packets
+  for (key, service_traffic) in traffic {
+    let Some((source, port)) = service_traffic.wake_sources.into_iter().next() else {
+      continue;
+    };
+    tokio::spawn(async move {
+      match kubernetes::scaler::scale_up(key.clone(), source.clone()).await {
+        Ok(ScaleUpOutcome::ScaledUp) => {
+            info!("Scaled up {} (woken by {} on port {})", key, source, port);
+        }
+        Ok(ScaleUpOutcome::NotLeader) => {
+            if let Err(err) = kubernetes::coordination::forward_wake(&key, &source).await {
+                error!("Failed to forward the wake of {} to the leader: {:#}", key, err);
+            }
+        }
+        Ok(ScaleUpOutcome::RateLimited | ScaleUpOutcome::Quarantined | ScaleUpOutcome::Paused) => {}
+        Err(err) => {
+            error!("Failed to scale up {}: {}", key, err);
+        }
       }
-    }
+    });
   }
 }
 
 
+/// Refreshes `last_packet_time` of the services matching `dependency_target`, except those in
+/// `dependency_cycle` with the triggering service: within a cycle only direct traffic counts.
 fn update_service_by_target(
-    services: &mut StdHashMap<String, kubernetes::models::ServiceData>,
+    services: &StdHashMap<String, kubernetes::models::ServiceData>,
     dependency_target: &str,
     current_time: i64,
     _triggering_service_ip: &str,
     relationship_type: &str,
+    dependency_cycle: &[String],
 ) {
+    if dependency_cycle.iter().any(|key| key == dependency_target) {
+        return;
+    }
     // Try to find by IP first (most direct)
-    if let Some(service) = services.get_mut(dependency_target) {
+    if let Some(service) = services.get(dependency_target) {
         // For dependency and dependent relationships, ALWAYS update last_packet_time
         // regardless of current state to maintain proper parent-child lifecycle
         if relationship_type == "dependency" || relationship_type == "dependent" {
-            service.last_packet_time = current_time;
+            service.last_packet_time.touch(current_time);
             // info!("Updated {} service {} ({}/{}) last_packet_time to {} (triggered by {} via {}) - forced update for dependency relationship", 
             //       relationship_type, dependency_target, service.namespace, service.name, current_time, triggering_service_ip, relationship_type);
             return;
@@ -102,7 +243,7 @@ fn update_service_by_target(
             return;
         }
         
-        service.last_packet_time = current_time;
+        service.last_packet_time.touch(current_time);
         // info!("Updated {} service {} ({}/{}) last_packet_time to {} (triggered by {} via {})", 
         //       relationship_type, dependency_target, service.namespace, service.name, current_time, triggering_service_ip, relationship_type);
         return;
@@ -128,7 +269,7 @@ fn update_service_by_target(
             service_data.name == dependency_target
         };
         
-        if is_match {
+        if is_match && !dependency_cycle.contains(service_ip) {
             matching_service_ips.push(service_ip.clone());
         }
     }
@@ -138,11 +279,11 @@ fn update_service_by_target(
         // info!("{} service '{}' not found in watched services", relationship_type, dependency_target);
     } else {
         for service_ip in matching_service_ips {
-            if let Some(service) = services.get_mut(&service_ip) {
+            if let Some(service) = services.get(&service_ip) {
                 // For dependency and dependent relationships, ALWAYS update last_packet_time
                 // regardless of current state to maintain proper parent-child lifecycle
                 if relationship_type == "dependency" || relationship_type == "dependent" {
-                    service.last_packet_time = current_time;
+                    service.last_packet_time.touch(current_time);
                     // info!("Updated {} service {} ({}/{}) last_packet_time to {} (triggered by {} via {}) - forced update for dependency relationship", 
                     //       relationship_type, service_ip, service.namespace, service.name, current_time, triggering_service_ip, relationship_type);
                     continue;
@@ -156,7 +297,7 @@ fn update_service_by_target(
                     continue;
                 }
                 
-                service.last_packet_time = current_time;
+                service.last_packet_time.touch(current_time);
                 // info!("Updated {} service {} ({}/{}) last_packet_time to {} (triggered by {} via {})", 
                 //       relationship_type, service_ip, service.namespace, service.name, current_time, triggering_service_ip, relationship_type);
             }
@@ -164,83 +305,648 @@ fn update_service_by_target(
     }
 }
 
-pub async fn sync_data(scalable_service_list: &mut HashMap<&mut MapData, u32, u32>) -> Result<()> {
-  // Try to get service list from etcd if coordination is enabled
-  let pod_ips: std::collections::HashMap<u32, u32> = {
-    // Check if etcd coordinator is available
-    // let etcd_available = {
-    //   let coordinator_guard = kubernetes::etcd_coordinator::ETCD_COORDINATOR.lock().unwrap();
-    //   coordinator_guard.is_some()
-    // };
-    
-    // if etcd_available {
-    //   // Try to get from etcd first (for multi-node coordination)
-    //   let coordinator_guard = kubernetes::etcd_coordinator::ETCD_COORDINATOR.lock().unwrap();
-    //   if let Some(coordinator) = coordinator_guard.as_ref() {
-    //     match coordinator.pull_service_list_from_etcd().await {
-    //       Ok(etcd_service_list) => {
-    //         // info!("Using service list from etcd with {} entries", etcd_service_list.len());
-    //         etcd_service_list
-    //       }
-    //       Err(e) => {
-    //         // warn!("Failed to get service list from etcd, falling back to local: {}", e);
-    //         // Fallback to local data
-    //         get_local_service_list()
-    //       }
-    //     }
-    //   } else {
-    //     get_local_service_list()
-    //   }
-    // } else {
-    //   // Single-node mode: use local data
-    //   get_local_service_list()
-    // }
-    get_local_service_list()
-  };
+/// The eBPF maps userspace keeps in sync with `WATCHED_SERVICES`.
+pub struct ServiceMaps {
+  pub services: HashMap<MapData, ServiceKeyV4, u32>,
+  pub services_v6: HashMap<MapData, ServiceKeyV6, u32>,
+  pub pods: LpmTrie<MapData, [u8; 4], u32>,
+  pub pods_v6: LpmTrie<MapData, [u8; 16], u32>,
+  pub source_excludes: LpmTrie<MapData, [u8; 32], u32>,
+  pub node_ports: HashMap<MapData, u16, u32>,
+  /// What the last `sync_data` wrote to these maps.
+  pub applied: AppliedServiceList,
+}
+
+/// State of the last `sync_data` on a `ServiceMaps`.
+#[derive(Default)]
+pub struct AppliedServiceList {
+  list: ServiceList,
+  /// `WATCHED_SERVICES` generation `list` was built from, `None` to make the next sync a full one.
+  generation: Option<u64>,
+  pruned: bool,
+  last_full_sync: Option<std::time::Instant>,
+}
+
+/// Desired contents of `ServiceMaps`.
+#[derive(Default)]
+struct ServiceList {
+  services: StdHashMap<ServiceKeyV4, u32>,
+  services_v6: StdHashMap<ServiceKeyV6, u32>,
+  pods: StdHashMap<([u8; 4], u32), u32>,
+  pods_v6: StdHashMap<([u8; 16], u32), u32>,
+  source_excludes: StdHashMap<([u8; 32], u32), u32>,
+  node_ports: StdHashMap<u16, u32>,
+}
+
+/// Refreshes `last_packet_time` from the kernel's `LAST_SEEN` map, which records the monotonic
+/// time of the last packet to every watched address.
+pub async fn sync_last_seen(mut last_seen: HashMap<MapData, [u8; 16], u64>) {
+  loop {
+      tokio::time::sleep(std::time::Duration::from_secs(1)).await;
 
-  for (key, value) in pod_ips.clone() {
-      match scalable_service_list.get(&key, 0) {
-          Ok(old_value) => {
-              if old_value != value {
-                  let _ = scalable_service_list.insert(key, value, 0);
-                  info!("Update service list: {:?} {}", key, value)
+      let now_wall = chrono::Utc::now().timestamp();
+      let now_mono = monotonic_now_ns();
+      let mut stale = Vec::new();
+      let mut woken = Vec::new();
+      {
+          let services = kubernetes::models::read_watched_services();
+          for entry in last_seen.iter() {
+              let (address, seen_ns) = match entry {
+                  Ok(entry) => entry,
+                  Err(e) => {
+                      warn!("Failed to read last seen times: {}", e);
+                      continue;
+                  }
+              };
+              let Some(key) = kubernetes::models::resolve_address_key(&services, address) else {
+                  stale.push(address);
+                  continue;
+              };
+
+              let seen = now_wall - (now_mono.saturating_sub(seen_ns) / 1_000_000_000) as i64;
+              // Traffic to a service at its idle replicas is never held, so this is the only
+              // place that notices it
+              if let Some(service) = services.get(&key)
+                  && service.last_packet_time.touch(seen)
+                  && service.at_idle_floor
+              {
+                  woken.push(key.clone());
               }
           }
-          Err(_) => {
-              let _ = scalable_service_list.insert(key, value, 0);
-              info!("Add service list: {:?} {}", key, value)
+      }
+
+      woken.sort();
+      woken.dedup();
+      for key in woken {
+          tokio::spawn(async move {
+              if let Err(err) = kubernetes::scaler::scale_up(key.clone(), "idle replicas".to_string()).await {
+                  error!("Failed to scale up {} from its idle replicas: {}", key, err);
+              }
+          });
+      }
+
+      if kubernetes::models::SERVICES_LISTED.load(std::sync::atomic::Ordering::SeqCst) {
+          for address in stale {
+              let _ = last_seen.remove(&address);
+          }
+      }
+  }
+}
+
+/// Current `CLOCK_MONOTONIC` time in nanoseconds, the clock behind `bpf_ktime_get_ns`.
+fn monotonic_now_ns() -> u64 {
+  let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+  unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+  ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Skips the sync when `WATCHED_SERVICES` is unchanged since the last one. Otherwise only the
+/// entries that differ from what the last sync wrote are touched, unless a full reconcile is due
+/// or the last sync left entries unwritten.
+pub async fn sync_data(maps: &mut ServiceMaps) -> Result<()> {
+  // Read before the services, so a change made while building the list is synced next time
+  let generation = kubernetes::models::services_generation();
+
+  // Entries restored from pinned maps are kept until the watcher has listed every service
+  let prune = kubernetes::models::SERVICES_LISTED.load(std::sync::atomic::Ordering::SeqCst);
+
+  let mut applied = std::mem::take(&mut maps.applied);
+  let full = applied.generation.is_none()
+      || applied.pruned != prune
+      || applied.last_full_sync.is_none_or(|at| at.elapsed() >= crate::config::get().map_full_sync_interval);
+  if !full && applied.generation == Some(generation) {
+      maps.applied = applied;
+      return Ok(());
+  }
+
+  // With coordination, availability published by the leader is already in WATCHED_SERVICES
+  let service_list = get_local_service_list();
+  let previous = if full { None } else { Some(&applied.list) };
+  let result = apply_service_list(maps, previous, &service_list, prune);
+
+  if full {
+      applied.last_full_sync = Some(std::time::Instant::now());
+  }
+  // Entries left unwritten are retried by full syncs until every one is in
+  applied.generation = matches!(result, Ok(0)).then_some(generation);
+  applied.pruned = prune;
+  applied.list = service_list;
+  maps.applied = applied;
+  result.map(|_| ())
+}
+
+/// Syncs every map even when an earlier one fails, and returns all failures as one error, or
+/// else the number of entries that didn't fit. Only entries that differ from `previous`, what
+/// the last sync wrote, are touched; without it every kernel entry is read back and compared.
+fn apply_service_list(
+  maps: &mut ServiceMaps,
+  previous: Option<&ServiceList>,
+  service_list: &ServiceList,
+  prune: bool,
+) -> Result<usize> {
+  let results = [
+      sync_service_map("SERVICE_LIST", &mut maps.services, previous.map(|p| &p.services), &service_list.services, prune),
+      sync_service_map("SERVICE_LIST_V6", &mut maps.services_v6, previous.map(|p| &p.services_v6), &service_list.services_v6, prune),
+      sync_service_map("NODEPORT_LIST", &mut maps.node_ports, previous.map(|p| &p.node_ports), &service_list.node_ports, prune),
+      sync_lpm_map("POD_LIST", &mut maps.pods, previous.map(|p| &p.pods), &service_list.pods, prune),
+      sync_lpm_map("POD_LIST_V6", &mut maps.pods_v6, previous.map(|p| &p.pods_v6), &service_list.pods_v6, prune),
+      sync_lpm_map("SERVICE_SOURCE_EXCLUDE", &mut maps.source_excludes, previous.map(|p| &p.source_excludes), &service_list.source_excludes, prune),
+  ];
+  let mut unwritten = 0;
+  let mut errors = Vec::new();
+  for result in results {
+      match result {
+          Ok(count) => unwritten += count,
+          Err(e) => errors.push(format!("{:#}", e)),
+      }
+  }
+  if !errors.is_empty() {
+      bail!("{}", errors.join("; "));
+  }
+  Ok(unwritten)
+}
+
+/// The map operations `sync_map` diffs with. Implemented for the eBPF hash maps, and for the
+/// LPM tries keyed by `(data, prefix length)`, so the diffing can also run on an in-memory map.
+trait SyncMap<K> {
+  fn get(&self, key: &K) -> Option<u32>;
+  fn insert(&mut self, key: &K, value: u32) -> Result<(), MapError>;
+  fn remove(&mut self, key: &K) -> Result<(), MapError>;
+  fn keys(&self) -> Vec<Result<K, MapError>>;
+}
+
+impl<K: aya::Pod> SyncMap<K> for HashMap<MapData, K, u32> {
+  fn get(&self, key: &K) -> Option<u32> {
+      HashMap::get(self, key, 0).ok()
+  }
+
+  fn insert(&mut self, key: &K, value: u32) -> Result<(), MapError> {
+      HashMap::insert(self, key, value, 0)
+  }
+
+  fn remove(&mut self, key: &K) -> Result<(), MapError> {
+      HashMap::remove(self, key)
+  }
+
+  fn keys(&self) -> Vec<Result<K, MapError>> {
+      HashMap::keys(self).collect()
+  }
+}
+
+impl<K: aya::Pod> SyncMap<(K, u32)> for LpmTrie<MapData, K, u32> {
+  fn get(&self, (data, prefix_len): &(K, u32)) -> Option<u32> {
+      LpmTrie::get(self, &Key::new(*prefix_len, *data), 0).ok()
+  }
+
+  fn insert(&mut self, (data, prefix_len): &(K, u32), value: u32) -> Result<(), MapError> {
+      LpmTrie::insert(self, &Key::new(*prefix_len, *data), value, 0)
+  }
+
+  fn remove(&mut self, (data, prefix_len): &(K, u32)) -> Result<(), MapError> {
+      LpmTrie::remove(self, &Key::new(*prefix_len, *data))
+  }
+
+  fn keys(&self) -> Vec<Result<(K, u32), MapError>> {
+      LpmTrie::keys(self)
+          .map(|key| key.map(|key| (key.data(), key.prefix_len())))
+          .collect()
+  }
+}
+
+/// What a `sync_map` pass could not apply.
+struct SyncFailures<K> {
+  /// Inserts rejected because the map is full, reported through `report_capacity`.
+  full: Vec<(K, MapError)>,
+  errors: Vec<String>,
+}
+
+/// Makes `map` hold exactly `desired`: missing and changed entries are inserted and, with
+/// `prune`, entries no longer desired are removed. A failed operation doesn't stop the pass.
+///
+/// With `previous`, the contents of `map` after the last pass, entries are compared against it
+/// rather than read from `map`, so only the changed ones cost a syscall.
+fn sync_map<K, M>(
+  name: &str,
+  map: &mut M,
+  previous: Option<&StdHashMap<K, u32>>,
+  desired: &StdHashMap<K, u32>,
+  prune: bool,
+) -> SyncFailures<K>
+where
+  K: Copy + Eq + Hash + Debug,
+  M: SyncMap<K>,
+{
+  let mut failures = SyncFailures { full: Vec::new(), errors: Vec::new() };
+  for (key, value) in desired.iter() {
+      let old_value = match previous {
+          Some(previous) => previous.get(key).copied(),
+          None => map.get(key),
+      };
+      if old_value == Some(*value) {
+          continue;
+      }
+      match map.insert(key, *value) {
+          Ok(()) if old_value.is_some() => info!("Update {}: {:?} {}", name, key, value),
+          Ok(()) => info!("Add {}: {:?} {}", name, key, value),
+          Err(e) if syscall_errno(&e) == Some(libc::E2BIG) || syscall_errno(&e) == Some(libc::ENOSPC) => {
+              failures.full.push((*key, e))
           }
+          Err(e) => failures.errors.push(format!("failed to insert {:?}: {}", key, e)),
       }
   }
 
-  let keys: Vec<_> = scalable_service_list.keys().collect();
+  if !prune {
+      return failures;
+  }
+
+  let keys = match previous {
+      Some(previous) => previous.keys().filter(|key| !desired.contains_key(key)).map(|key| Ok(*key)).collect(),
+      None => map.keys(),
+  };
   for key in keys {
       match key {
-          Ok(ip) => {
-              if !pod_ips.contains_key(&ip) {
-                  let _ = scalable_service_list.remove(&ip);
-                  info!("Remove service list: {:?}", ip)
+          Ok(key) if !desired.contains_key(&key) => match map.remove(&key) {
+              Ok(()) => info!("Remove {}: {:?}", name, key),
+              // Already gone
+              Err(e) if syscall_errno(&e) == Some(libc::ENOENT) => {}
+              Err(e) => failures.errors.push(format!("failed to remove {:?}: {}", key, e)),
+          },
+          Ok(_) => {}
+          Err(e) => failures.errors.push(format!("failed to list keys: {}", e)),
+      }
+  }
+  failures
+}
+
+fn syscall_errno(e: &MapError) -> Option<i32> {
+  match e {
+      MapError::SyscallError(SyscallError { io_error, .. }) => io_error.raw_os_error(),
+      _ => None,
+  }
+}
+
+fn sync_service_map<K>(
+  name: &'static str,
+  scalable_service_list: &mut HashMap<MapData, K, u32>,
+  previous: Option<&StdHashMap<K, u32>>,
+  pod_ips: &StdHashMap<K, u32>,
+  prune: bool,
+) -> Result<usize>
+where
+  K: aya::Pod + Eq + Hash + Debug,
+{
+  let failures = sync_map(name, scalable_service_list, previous, pod_ips, prune);
+  // Only a full pass sees every entry that doesn't fit
+  if previous.is_none() {
+      report_capacity(name, &failures.full, pod_ips.len(), Some(crate::config::get().max_watched_services as usize));
+  }
+  into_result(name, failures)
+}
+
+/// Syncs an LPM trie with the desired `(data, prefix length)` entries.
+fn sync_lpm_map<K>(
+  name: &'static str,
+  trie: &mut LpmTrie<MapData, K, u32>,
+  previous: Option<&StdHashMap<(K, u32), u32>>,
+  entries: &StdHashMap<(K, u32), u32>,
+  prune: bool,
+) -> Result<usize>
+where
+  K: aya::Pod + Eq + Hash + Debug,
+{
+  let failures = sync_map(name, trie, previous, entries, prune);
+  if previous.is_none() {
+      report_capacity(name, &failures.full, entries.len(), None);
+  }
+  into_result(name, failures)
+}
+
+fn into_result<K>(name: &str, failures: SyncFailures<K>) -> Result<usize> {
+  if failures.errors.is_empty() {
+      return Ok(failures.full.len());
+  }
+  Err(anyhow!("{}", failures.errors.join(", "))).with_context(|| format!("failed to sync {}", name))
+}
+
+/// Logs entries that didn't fit in `name` when they change, and warns once `len` passes 80% of
+/// `capacity` when it is known.
+fn report_capacity<K: Debug>(name: &'static str, full: &[(K, MapError)], len: usize, capacity: Option<usize>) {
+  let mut sync_state = MAP_SYNC_STATE.lock().unwrap();
+  let state = sync_state.entry(name).or_default();
+  if full.len() != state.failed_inserts {
+      if full.is_empty() {
+          info!("All entries fit in {} again", name);
+      } else {
+          let capacity = capacity.map(|c| format!(" (capacity {})", c)).unwrap_or_default();
+          error!("Failed to insert {} of {} entries into {}{}, these services are not protected:",
+                 full.len(), len, name, capacity);
+          for (key, e) in full.iter() {
+              error!("  {:?}: {}", key, e);
+          }
+      }
+      state.failed_inserts = full.len();
+  }
+  let Some(capacity) = capacity else {
+      return;
+  };
+  let near_capacity = len * 10 > capacity * 8;
+  if near_capacity && !state.near_capacity {
+      warn!("{} holds {} entries, over 80% of its capacity of {}; raise MAX_WATCHED_SERVICES",
+            name, len, capacity);
+  }
+  state.near_capacity = near_capacity;
+}
+
+/// Parses an IPv4 or IPv6 CIDR (a bare address is a single host) into 16 address bytes and a
+/// prefix length, IPv4 prefixes IPv4-mapped as the XDP program compares them.
+pub fn parse_cidr(cidr: &str) -> Option<([u8; 16], u32)> {
+  let (address, prefix_len) = match cidr.trim().split_once('/') {
+      Some((address, prefix_len)) => (address, Some(prefix_len.parse::<u32>().ok()?)),
+      None => (cidr.trim(), None),
+  };
+  match address.parse::<IpAddr>().ok()? {
+      IpAddr::V4(v4) => {
+          let prefix_len = prefix_len.unwrap_or(32);
+          (prefix_len <= 32).then(|| (v4.to_ipv6_mapped().octets(), 96 + prefix_len))
+      }
+      IpAddr::V6(v6) => {
+          let prefix_len = prefix_len.unwrap_or(128);
+          (prefix_len <= 128).then(|| (v6.octets(), prefix_len))
+      }
+  }
+}
+
+/// Syncs the `LOCAL_ADDRESSES` map with the addresses of this node's interfaces, the only
+/// destinations on which node ports are matched.
+pub fn sync_local_addresses(local_addresses: &mut HashMap<MapData, [u8; 16], u8>) -> Result<()> {
+  let desired: Vec<[u8; 16]> = NetworkInterface::show()?
+      .iter()
+      .flat_map(|itf| itf.addr.iter())
+      .map(|addr| match addr.ip() {
+          IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+          IpAddr::V6(v6) => v6.octets(),
+      })
+      .collect();
+
+  for address in desired.iter() {
+      if local_addresses.get(address, 0).is_err() {
+          local_addresses.insert(address, 1, 0)?;
+          info!("Matching node ports on local address {}", Ipv6Addr::from(*address).to_canonical());
+      }
+  }
+  let stale: Vec<[u8; 16]> = local_addresses
+      .keys()
+      .filter_map(|key| key.ok())
+      .filter(|key| !desired.contains(key))
+      .collect();
+  for address in stale {
+      let _ = local_addresses.remove(&address);
+  }
+  Ok(())
+}
+
+/// Fills the `SOURCE_EXCLUDE` map from the configured source CIDRs.
+pub fn load_source_excludes(source_excludes: &mut LpmTrie<MapData, [u8; 16], u32>) -> Result<()> {
+  for cidr in &crate::config::get().exclude_source_cidrs {
+      let (address, prefix_len) = parse_cidr(cidr)
+          .ok_or_else(|| anyhow::anyhow!("invalid CIDR in EXCLUDE_SOURCE_CIDRS: {}", cidr))?;
+      source_excludes.insert(&Key::new(prefix_len, address), 1, 0)?;
+      info!("Traffic from {} will not wake services", cidr);
+  }
+  Ok(())
+}
+
+pub fn clear_service_maps(maps: &mut ServiceMaps) -> Result<()> {
+  apply_service_list(maps, None, &ServiceList::default(), true)?;
+  info!("Cleared service list maps");
+  Ok(())
+}
+
+/// Addresses of watched services that failed to parse in the last sync, so each is warned about
+/// once rather than on every sync.
+static UNPARSEABLE_ADDRESSES: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Parses `addresses` of a service, collecting the ones that fail into `unparseable`.
+fn parse_service_addresses<'a>(
+  service: &kubernetes::models::ServiceData,
+  addresses: impl Iterator<Item = &'a String>,
+  unparseable: &mut HashSet<String>,
+) -> Vec<IpAddr> {
+  addresses
+      .filter_map(|address| match address.parse::<IpAddr>() {
+          Ok(address) => Some(address),
+          Err(_) => {
+              unparseable.insert(format!("{}/{}: {:?}", service.namespace, service.name, address));
+              None
+          }
+      })
+      .collect()
+}
+
+fn get_local_service_list() -> ServiceList {
+  let mut service_list = ServiceList::default();
+  let mut unparseable = HashSet::new();
+
+  let watched_services = kubernetes::models::read_watched_services();
+  for (ip, service) in watched_services.iter() {
+      let mut value = (service.unavailable_action as u32) << UNAVAILABLE_ACTION_SHIFT;
+      if service.backend_available {
+          value |= SERVICE_AVAILABLE;
+      }
+
+      // Headless services are keyed by namespace/name and only reached on their pod IPs
+      let cluster_ip = Some(ip).filter(|ip| !ip.contains('/'));
+      let service_addresses = parse_service_addresses(
+          service,
+          cluster_ip
+              .into_iter()
+              .chain(service.secondary_ips.iter())
+              .chain(service.external_ips.iter()),
+          &mut unparseable,
+      );
+      let pod_addresses = parse_service_addresses(service, service.pod_ips.iter(), &mut unparseable);
+
+      // Services without known ports match any destination port
+      let ports = if service.ports.is_empty() { vec![ANY_PORT] } else { service.ports.clone() };
+      for address in service_addresses.iter() {
+          for port in ports.iter() {
+              match address {
+                  IpAddr::V4(v4) => {
+                      service_list.services.insert(ServiceKeyV4::new((*v4).into(), *port), value);
+                  }
+                  IpAddr::V6(v6) => {
+                      service_list.services_v6.insert(ServiceKeyV6::new(v6.octets(), *port), value);
+                  }
+              }
+          }
+      }
+
+      for pod_address in pod_addresses.iter() {
+          match pod_address {
+              IpAddr::V4(v4) => {
+                  service_list.pods.insert((v4.octets(), 32), value);
+              }
+              IpAddr::V6(v6) => {
+                  service_list.pods_v6.insert((v6.octets(), 128), value);
               }
           }
-          Err(err) => {
-              info!("Error: {:?}", err);
+      }
+
+      for node_port in service.node_ports.iter() {
+          service_list.node_ports.insert(*node_port, value);
+      }
+
+      // Per-service exclusions are keyed by every address the service is reached on
+      let addresses = service_addresses
+          .iter()
+          .chain(pod_addresses.iter())
+          .map(|address| match address {
+              IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+              IpAddr::V6(v6) => v6.octets(),
+          })
+          .chain(service.node_ports.iter().map(|node_port| node_port_key(*node_port)));
+      for dst in addresses {
+          for (src, prefix_len) in service.excluded_sources.iter().filter_map(|cidr| parse_cidr(cidr)) {
+              let mut data = [0u8; 32];
+              data[..16].copy_from_slice(&dst);
+              data[16..].copy_from_slice(&src);
+              service_list.source_excludes.insert((data, 128 + prefix_len), 1);
           }
       }
   }
-  
-  Ok(())
+  drop(watched_services);
+
+  let mut previous = UNPARSEABLE_ADDRESSES.lock().unwrap();
+  for address in unparseable.difference(&previous) {
+      warn!("Skipping unparseable address of {}, traffic to it is not matched", address);
+  }
+  *previous = unparseable;
+
+  service_list
 }
 
-fn get_local_service_list() -> std::collections::HashMap<u32, u32> {
-  kubernetes::models::WATCHED_SERVICES
-    .lock()
-    .unwrap()
-    .iter()
-    .map(|(k, v)| {
-        (
-            k.parse::<Ipv4Addr>().unwrap().into(),
-            v.backend_available as u32,
-        )
-    })
-    .collect()
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kubernetes::models::{write_watched_services, ServiceData};
+
+  /// A `SyncMap` in memory, counting the writes a pass makes.
+  #[derive(Default)]
+  struct MemoryMap {
+    entries: StdHashMap<u16, u32>,
+    writes: usize,
+  }
+
+  impl SyncMap<u16> for MemoryMap {
+    fn get(&self, key: &u16) -> Option<u32> {
+      self.entries.get(key).copied()
+    }
+
+    fn insert(&mut self, key: &u16, value: u32) -> Result<(), MapError> {
+      self.writes += 1;
+      self.entries.insert(*key, value);
+      Ok(())
+    }
+
+    fn remove(&mut self, key: &u16) -> Result<(), MapError> {
+      self.writes += 1;
+      self.entries.remove(key).map(|_| ()).ok_or(MapError::KeyNotFound)
+    }
+
+    fn keys(&self) -> Vec<Result<u16, MapError>> {
+      self.entries.keys().map(|key| Ok(*key)).collect()
+    }
+  }
+
+  fn entries(pairs: &[(u16, u32)]) -> StdHashMap<u16, u32> {
+    pairs.iter().copied().collect()
+  }
+
+  fn map(pairs: &[(u16, u32)]) -> MemoryMap {
+    MemoryMap { entries: entries(pairs), writes: 0 }
+  }
+
+  #[test]
+  fn full_pass_adds_updates_and_removes() {
+    let mut map = map(&[(1, 1), (2, 2), (3, 3)]);
+    let desired = entries(&[(1, 1), (2, 20), (4, 4)]);
+
+    let failures = sync_map("TEST", &mut map, None, &desired, true);
+
+    assert!(failures.full.is_empty() && failures.errors.is_empty());
+    assert_eq!(map.entries, desired);
+    // The unchanged entry is left alone
+    assert_eq!(map.writes, 3);
+  }
+
+  #[test]
+  fn full_pass_without_pruning_keeps_stale_entries() {
+    let mut map = map(&[(1, 1), (2, 2), (3, 3)]);
+    let desired = entries(&[(1, 1), (2, 20), (4, 4)]);
+
+    sync_map("TEST", &mut map, None, &desired, false);
+
+    assert_eq!(map.entries, entries(&[(1, 1), (2, 20), (3, 3), (4, 4)]));
+    assert_eq!(map.writes, 2);
+  }
+
+  #[test]
+  fn incremental_pass_only_writes_changes_since_previous() {
+    let previous = entries(&[(1, 1), (2, 2), (3, 3)]);
+    // Entry 5 was not written by the last pass, so only a full pass removes it
+    let mut map = map(&[(1, 1), (2, 2), (3, 3), (5, 5)]);
+    let desired = entries(&[(1, 1), (2, 20), (4, 4)]);
+
+    let failures = sync_map("TEST", &mut map, Some(&previous), &desired, true);
+
+    assert!(failures.errors.is_empty());
+    assert_eq!(map.entries, entries(&[(1, 1), (2, 20), (4, 4), (5, 5)]));
+    assert_eq!(map.writes, 3);
+  }
+
+  #[test]
+  fn incremental_pass_without_pruning_keeps_removed_entries() {
+    let previous = entries(&[(1, 1), (3, 3)]);
+    let mut map = map(&[(1, 1), (3, 3)]);
+    let desired = entries(&[(1, 1)]);
+
+    sync_map("TEST", &mut map, Some(&previous), &desired, false);
+
+    assert_eq!(map.entries, entries(&[(1, 1), (3, 3)]));
+    assert_eq!(map.writes, 0);
+  }
+
+  #[test]
+  fn unparseable_and_headless_service_keys_are_skipped() {
+    let service = |name: &str| ServiceData {
+      name: name.to_string(),
+      namespace: "utils-test".to_string(),
+      backend_available: true,
+      ..Default::default()
+    };
+    let keys = ["None", "fd00::10", "utils-test/headless", "10.99.0.1"];
+    {
+      let mut services = write_watched_services();
+      services.insert("None".to_string(), service("no-cluster-ip"));
+      services.insert("fd00::10".to_string(), ServiceData { ports: vec![80], ..service("ipv6") });
+      services.insert(
+        "utils-test/headless".to_string(),
+        ServiceData { pod_ips: vec!["10.99.0.7".to_string()], ..service("headless") },
+      );
+      services.insert(
+        "10.99.0.1".to_string(),
+        ServiceData { secondary_ips: vec!["not-an-ip".to_string()], ..service("bad-secondary") },
+      );
+    }
+
+    let service_list = get_local_service_list();
+    write_watched_services().retain(|key, _| !keys.contains(&key.as_str()));
+
+    let available = SERVICE_AVAILABLE | ((UNAVAILABLE_DROP as u32) << UNAVAILABLE_ACTION_SHIFT);
+    let v6: Ipv6Addr = "fd00::10".parse().unwrap();
+    assert_eq!(service_list.services_v6.get(&ServiceKeyV6::new(v6.octets(), 80)), Some(&available));
+    assert_eq!(service_list.pods.get(&([10, 99, 0, 7], 32)), Some(&available));
+    let v4 = ServiceKeyV4::new(Ipv4Addr::new(10, 99, 0, 1).into(), ANY_PORT);
+    assert_eq!(service_list.services.get(&v4), Some(&available));
+  }
+}
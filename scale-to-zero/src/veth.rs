@@ -0,0 +1,200 @@
+//! Tests of two agents contending for the XDP hook of the same interface, one end of a veth
+//! pair created for the test. They load the real eBPF program, so they need root (or
+//! CAP_NET_ADMIN and CAP_BPF) and bpffs mounted at /sys/fs/bpf. They are ignored by default and
+//! share the interface names, so run them one at a time:
+//!
+//! ```shell
+//! sudo -E cargo test -p scale-to-zero veth -- --ignored --test-threads=1
+//! ```
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+use crate::program::{LoadedProgram, ObjectSource};
+use crate::xdp::{self, AttachMode, AttachedInterface, ConflictPolicy};
+
+const INTERFACE: &str = "stz-veth0";
+const PEER: &str = "stz-veth1";
+const RING_BUF_SIZE: u32 = 256 * 1024;
+
+/// A veth pair, deleted on drop.
+struct Veth;
+
+impl Veth {
+    fn create() -> Result<Self> {
+        // A failed run may have left the pair behind
+        let _ = ip(&["link", "del", INTERFACE]);
+        ip(&["link", "add", INTERFACE, "type", "veth", "peer", "name", PEER])?;
+        let veth = Veth;
+        ip(&["link", "set", INTERFACE, "up"])?;
+        ip(&["link", "set", PEER, "up"])?;
+        Ok(veth)
+    }
+}
+
+impl Drop for Veth {
+    fn drop(&mut self) {
+        let _ = ip(&["link", "del", INTERFACE]);
+    }
+}
+
+fn ip(args: &[&str]) -> Result<()> {
+    let status = Command::new("ip").args(args).status().context("failed to run ip")?;
+    anyhow::ensure!(status.success(), "ip {} failed with {}", args.join(" "), status);
+    Ok(())
+}
+
+/// An agent's program, loaded with maps pinned apart from the other agent's, as two DaemonSets
+/// would have them.
+struct Agent {
+    loaded: LoadedProgram,
+    pin_path: PathBuf,
+    attached: Vec<AttachedInterface>,
+}
+
+impl Agent {
+    fn load(name: &str) -> Result<Self> {
+        let pin_path = PathBuf::from(format!("/sys/fs/bpf/stz-veth-test-{}", name));
+        let _ = std::fs::remove_dir_all(&pin_path);
+        std::fs::create_dir_all(&pin_path)?;
+        let loaded = LoadedProgram::load(
+            ObjectSource::Embedded(aya::include_bytes_aligned!(concat!(env!("OUT_DIR"), "/scale-to-zero"))),
+            &pin_path,
+            RING_BUF_SIZE,
+        )?;
+        Ok(Agent { loaded, pin_path, attached: Vec::new() })
+    }
+
+    fn id(&mut self) -> Result<u32> {
+        Ok(self.loaded.program()?.info()?.id())
+    }
+
+    fn attach(&mut self, policy: ConflictPolicy) -> Result<()> {
+        let attached = xdp::attach_with_mode(self.loaded.program()?, INTERFACE, AttachMode::Skb, policy)?;
+        self.attached.push(attached);
+        Ok(())
+    }
+
+    fn verify(&mut self, policy: ConflictPolicy) -> Result<()> {
+        xdp::verify_attachments(self.loaded.program()?, &mut self.attached, policy);
+        Ok(())
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        if let Ok(program) = self.loaded.program() {
+            xdp::detach_all(program, &mut self.attached);
+        }
+        let _ = std::fs::remove_dir_all(&self.pin_path);
+    }
+}
+
+/// Id of the program the kernel runs on the test interface.
+fn attached_program() -> Result<Option<u32>> {
+    let if_index = xdp::interface_index(INTERFACE)?;
+    Ok(xdp::query_xdp_attachment(if_index)?.map(|attachment| attachment.prog_id))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs root and bpffs"]
+async fn second_agent_refuses_an_interface_taken_by_the_first() -> Result<()> {
+    let _veth = Veth::create()?;
+    let mut first = Agent::load("first")?;
+    let mut second = Agent::load("second")?;
+
+    first.attach(ConflictPolicy::Refuse)?;
+    let err = second.attach(ConflictPolicy::Refuse).unwrap_err();
+
+    assert!(err.to_string().contains("another scale-to-zero agent"), "{}", err);
+    assert_eq!(attached_program()?, Some(first.id()?));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs root and bpffs"]
+async fn second_agent_replaces_the_first_when_told_to() -> Result<()> {
+    let _veth = Veth::create()?;
+    let mut first = Agent::load("first")?;
+    let mut second = Agent::load("second")?;
+
+    first.attach(ConflictPolicy::Refuse)?;
+    second.attach(ConflictPolicy::Replace)?;
+
+    assert_eq!(attached_program()?, Some(second.id()?));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs root and bpffs"]
+async fn replaced_agent_leaves_the_interface_to_another_agent_and_marks_it_down() -> Result<()> {
+    let _veth = Veth::create()?;
+    let mut first = Agent::load("first")?;
+    let mut second = Agent::load("second")?;
+
+    first.attach(ConflictPolicy::Replace)?;
+    second.attach(ConflictPolicy::Replace)?;
+    // Taking it back would only have the second agent take it again on its next verify
+    first.verify(ConflictPolicy::Replace)?;
+    second.verify(ConflictPolicy::Replace)?;
+
+    assert_eq!(attached_program()?, Some(second.id()?));
+    assert!(first.attached[0].down);
+    assert!(!second.attached[0].down);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs root and bpffs"]
+async fn interface_marked_down_is_taken_back_once_the_other_agent_leaves() -> Result<()> {
+    let _veth = Veth::create()?;
+    let mut first = Agent::load("first")?;
+    let mut second = Agent::load("second")?;
+
+    first.attach(ConflictPolicy::Replace)?;
+    second.attach(ConflictPolicy::Replace)?;
+    first.verify(ConflictPolicy::Replace)?;
+    assert!(first.attached[0].down);
+    drop(second);
+    first.verify(ConflictPolicy::Replace)?;
+
+    assert_eq!(attached_program()?, Some(first.id()?));
+    assert!(!first.attached[0].down);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs root and bpffs"]
+async fn replaced_agent_refusing_conflicts_leaves_the_interface_alone() -> Result<()> {
+    let _veth = Veth::create()?;
+    let mut first = Agent::load("first")?;
+    let mut second = Agent::load("second")?;
+
+    first.attach(ConflictPolicy::Refuse)?;
+    second.attach(ConflictPolicy::Replace)?;
+    first.verify(ConflictPolicy::Refuse)?;
+
+    assert_eq!(attached_program()?, Some(second.id()?));
+    assert!(first.attached[0].down);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs root and bpffs"]
+async fn detached_agent_reattaches_on_verify() -> Result<()> {
+    let _veth = Veth::create()?;
+    let mut first = Agent::load("first")?;
+    let mut second = Agent::load("second")?;
+
+    first.attach(ConflictPolicy::Refuse)?;
+    // The second agent taking over and going away leaves the interface without a program
+    second.attach(ConflictPolicy::Replace)?;
+    drop(second);
+    assert_eq!(attached_program()?, None);
+    first.verify(ConflictPolicy::Refuse)?;
+
+    assert_eq!(attached_program()?, Some(first.id()?));
+    Ok(())
+}
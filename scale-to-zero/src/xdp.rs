@@ -0,0 +1,683 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use aya::programs::{loaded_links, loaded_programs, xdp::XdpLinkId, Xdp, XdpFlags};
+use log::{debug, error, info, warn};
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+
+use crate::config::Config;
+
+// Name of our XDP entry point, used to recognise other scale-to-zero agents.
+const PROGRAM_NAME: &str = "scale_to_zero";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Detach the foreign program and attach ours in its place. Once attached, an interface is
+    /// taken back from foreign programs at most `MAX_TAKEOVERS` times in a row, and never from
+    /// another agent, as the two would replace each other forever.
+    Replace,
+    /// Leave the foreign program in place and skip the interface.
+    Refuse,
+}
+
+impl ConflictPolicy {
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy.trim().to_lowercase().as_str() {
+            "replace" => Some(ConflictPolicy::Replace),
+            "refuse" => Some(ConflictPolicy::Refuse),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachMode {
+    Driver,
+    Skb,
+    Hw,
+    /// Native driver mode where the NIC supports it, SKB mode otherwise.
+    Auto,
+}
+
+impl AttachMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode.trim().to_lowercase().as_str() {
+            "driver" | "drv" | "native" => Some(AttachMode::Driver),
+            "skb" | "generic" => Some(AttachMode::Skb),
+            "hw" | "offload" => Some(AttachMode::Hw),
+            "auto" => Some(AttachMode::Auto),
+            _ => None,
+        }
+    }
+
+    /// Flags to try, in order, when attaching in this mode.
+    fn candidates(self) -> Vec<XdpFlags> {
+        match self {
+            AttachMode::Driver => vec![XdpFlags::DRV_MODE],
+            AttachMode::Skb => vec![XdpFlags::SKB_MODE],
+            AttachMode::Hw => vec![XdpFlags::HW_MODE],
+            AttachMode::Auto => vec![XdpFlags::DRV_MODE, XdpFlags::SKB_MODE],
+        }
+    }
+}
+
+pub fn mode_name(flags: XdpFlags) -> &'static str {
+    if flags.contains(XdpFlags::HW_MODE) {
+        "hw"
+    } else if flags.contains(XdpFlags::DRV_MODE) {
+        "driver"
+    } else if flags.contains(XdpFlags::SKB_MODE) {
+        "skb"
+    } else {
+        "default"
+    }
+}
+
+/// Interface selection from `ATTACH_INTERFACES` and `EXCLUDE_INTERFACES`, comma-separated names
+/// or glob patterns (`*` and `?`). Exclusions win over inclusions.
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl InterfaceFilter {
+    pub fn new(config: &Config) -> Self {
+        InterfaceFilter {
+            include: config.interfaces.clone(),
+            exclude: config.exclude_interfaces.clone(),
+        }
+    }
+
+    pub fn is_excluded(&self, interface: &str) -> bool {
+        self.exclude.iter().any(|pattern| glob_match(pattern, interface))
+    }
+
+    /// Whether `interface` was asked for in `ATTACH_INTERFACES`; failing to attach to such an
+    /// interface is a configuration error.
+    pub fn is_included(&self, interface: &str) -> bool {
+        self.include.iter().any(|pattern| glob_match(pattern, interface))
+    }
+
+    pub fn allows(&self, interface: &str) -> bool {
+        !self.is_excluded(interface) && (self.include.is_empty() || self.is_included(interface))
+    }
+
+    /// Plain interface names in `ATTACH_INTERFACES` that match none of `interfaces`.
+    pub fn missing<'a>(&'a self, interfaces: &[String]) -> Vec<&'a str> {
+        self.include
+            .iter()
+            .filter(|pattern| !pattern.contains(['*', '?']))
+            .filter(|name| !interfaces.iter().any(|itf| itf == *name))
+            .map(|name| name.as_str())
+            .collect()
+    }
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+const HOTPLUG_RETRY_INITIAL: Duration = Duration::from_secs(5);
+const HOTPLUG_RETRY_MAX: Duration = Duration::from_secs(300);
+const HOTPLUG_MAX_ATTEMPTS: u32 = 10;
+
+struct PendingAttach {
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Follows interfaces appearing and disappearing after startup, attaching to new ones that pass
+/// the interface filter and retrying failed attaches with exponential backoff.
+pub struct InterfaceWatcher {
+    filter: InterfaceFilter,
+    mode: AttachMode,
+    policy: ConflictPolicy,
+    known: HashSet<String>,
+    pending: HashMap<String, PendingAttach>,
+}
+
+impl InterfaceWatcher {
+    /// `known` are the interfaces handled at startup; only interfaces appearing later are
+    /// attached by the watcher.
+    pub fn new(filter: InterfaceFilter, mode: AttachMode, policy: ConflictPolicy, known: &[String]) -> Self {
+        InterfaceWatcher {
+            filter,
+            mode,
+            policy,
+            known: known.iter().cloned().collect(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn reconcile(&mut self, program: &mut Xdp, attached: &mut Vec<AttachedInterface>) {
+        let current: HashSet<String> = match NetworkInterface::show() {
+            Ok(interfaces) => interfaces.into_iter().map(|itf| itf.name).collect(),
+            Err(e) => {
+                warn!("Failed to list network interfaces: {}", e);
+                return;
+            }
+        };
+
+        // The kernel drops the XDP link together with a removed interface
+        attached.retain(|itf| {
+            let present = current.contains(&itf.name);
+            if !present {
+                info!("Interface {} disappeared, forgetting its XDP attachment", itf.name);
+            }
+            present
+        });
+        self.known.retain(|name| current.contains(name));
+        self.pending.retain(|name, _| current.contains(name));
+
+        let now = Instant::now();
+        for name in current.iter() {
+            if self.known.contains(name) && !self.pending.contains_key(name) {
+                continue;
+            }
+            if self.known.insert(name.clone()) {
+                if !self.filter.allows(name) {
+                    continue;
+                }
+                info!("Interface {} appeared, attaching XDP program", name);
+            } else if self.pending.get(name).is_some_and(|retry| retry.next_attempt > now) {
+                continue;
+            }
+
+            match attach_with_mode(program, name, self.mode, self.policy) {
+                Ok(itf) => {
+                    attached.push(itf);
+                    self.pending.remove(name);
+                }
+                Err(e) => {
+                    let attempts = self.pending.get(name).map_or(0, |retry| retry.attempts) + 1;
+                    if attempts >= HOTPLUG_MAX_ATTEMPTS {
+                        error!("Giving up attaching to interface {} after {} attempts: {}", name, attempts, e);
+                        self.pending.remove(name);
+                        continue;
+                    }
+                    let backoff = (HOTPLUG_RETRY_INITIAL * 2u32.pow(attempts - 1)).min(HOTPLUG_RETRY_MAX);
+                    warn!("Failed to attach to interface {}, retrying in {:?}: {}", name, backoff, e);
+                    self.pending.insert(name.clone(), PendingAttach { attempts, next_attempt: now + backoff });
+                }
+            }
+        }
+    }
+}
+
+pub struct AttachedInterface {
+    pub name: String,
+    pub if_index: u32,
+    pub flags: XdpFlags,
+    link_id: Option<XdpLinkId>,
+    /// Set while the interface is left to a foreign program that took it over, until that
+    /// program goes away.
+    pub down: bool,
+    /// Takeovers from foreign programs, each within `TAKEOVER_WINDOW` of the one before, and
+    /// when the last one happened.
+    takeovers: u32,
+    last_takeover: Option<Instant>,
+}
+
+/// After this many takeovers of an interface in a row, it is left to the foreign program.
+const MAX_TAKEOVERS: u32 = 3;
+const TAKEOVER_WINDOW: Duration = Duration::from_secs(600);
+
+/// Program currently attached to an interface as reported by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XdpAttachment {
+    pub prog_id: u32,
+    pub mode: u8,
+}
+
+pub fn interface_index(interface: &str) -> Result<u32> {
+    let c_interface = CString::new(interface)?;
+    let if_index = unsafe { libc::if_nametoindex(c_interface.as_ptr()) };
+    if if_index == 0 {
+        return Err(anyhow!("Unknown interface {}", interface));
+    }
+    Ok(if_index)
+}
+
+pub fn attach(
+    program: &mut Xdp,
+    interface: &str,
+    flags: XdpFlags,
+    policy: ConflictPolicy,
+) -> Result<AttachedInterface> {
+    let if_index = interface_index(interface)?;
+    let own_id = program.info()?.id();
+
+    if let Some(existing) = query_xdp_attachment(if_index)? {
+        if existing.prog_id == own_id {
+            return Err(anyhow!("program {} is already attached to {}", own_id, interface));
+        }
+        resolve_conflict(interface, if_index, existing, policy)?;
+    }
+
+    let link_id = program.attach_to_if_index(if_index, flags)?;
+    Ok(AttachedInterface {
+        name: interface.to_string(),
+        if_index,
+        flags,
+        link_id: Some(link_id),
+        down: false,
+        takeovers: 0,
+        last_takeover: None,
+    })
+}
+
+/// Attaches to `interface` with the first flags of `mode` the interface accepts.
+pub fn attach_with_mode(
+    program: &mut Xdp,
+    interface: &str,
+    mode: AttachMode,
+    policy: ConflictPolicy,
+) -> Result<AttachedInterface> {
+    let mut last_err = None;
+    for flags in mode.candidates() {
+        match attach(program, interface, flags, policy) {
+            Ok(attached) => {
+                info!("Attached XDP program to interface {} in {} mode", interface, mode_name(flags));
+                return Ok(attached);
+            }
+            Err(e) => {
+                debug!("Attaching to interface {} in {} mode failed: {}", interface, mode_name(flags), e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow!("no attach mode to try for {}", interface)))
+}
+
+/// Detaches our program from every interface in `attached`.
+pub fn detach_all(program: &mut Xdp, attached: &mut Vec<AttachedInterface>) {
+    for itf in attached.drain(..) {
+        let Some(link_id) = itf.link_id else { continue };
+        match program.detach(link_id) {
+            Ok(()) => info!("Detached XDP program from interface {}", itf.name),
+            Err(e) => warn!("Failed to detach XDP program from interface {}: {}", itf.name, e),
+        }
+    }
+}
+
+/// Moves every attachment in `attached` from `old` to `new`, atomically replacing the program on
+/// each interface so none of them goes unfiltered. If an interface cannot be moved, the ones
+/// already moved are handed back to `old` and an error is returned.
+pub fn replace_all(old: &mut Xdp, new: &mut Xdp, attached: &mut [AttachedInterface]) -> Result<()> {
+    for i in 0..attached.len() {
+        let itf = &mut attached[i];
+        let Some(link_id) = itf.link_id.take() else { continue };
+        let err = match move_link(old, new, link_id) {
+            Ok(link_id) => {
+                itf.link_id = Some(link_id);
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        // A failed update consumes the link, so the old program is attached again from scratch
+        match old.attach_to_if_index(itf.if_index, itf.flags) {
+            Ok(link_id) => itf.link_id = Some(link_id),
+            Err(e) => error!("Failed to restore XDP program on interface {}: {}", itf.name, e),
+        }
+        let name = itf.name.clone();
+
+        for moved in attached[..i].iter_mut() {
+            let Some(link_id) = moved.link_id.take() else { continue };
+            match move_link(new, old, link_id) {
+                Ok(link_id) => moved.link_id = Some(link_id),
+                Err(e) => error!("Failed to hand interface {} back to the previous XDP program: {}", moved.name, e),
+            }
+        }
+        return Err(anyhow!("failed to move interface {} to the new XDP program: {}", name, err));
+    }
+    Ok(())
+}
+
+fn move_link(from: &mut Xdp, to: &mut Xdp, link_id: XdpLinkId) -> Result<XdpLinkId> {
+    let link = from.take_link(link_id)?;
+    Ok(to.attach_to_link(link)?)
+}
+
+/// Checks that our program is still the one attached to every interface we attached to, and
+/// re-attaches it where something else has taken its place, unless the interface is to be left
+/// to that program, in which case it is marked down until the program goes away.
+pub fn verify_attachments(program: &mut Xdp, attached: &mut [AttachedInterface], policy: ConflictPolicy) {
+    let own_id = match program.info() {
+        Ok(info) => info.id(),
+        Err(e) => {
+            error!("Failed to read XDP program info for verification: {}", e);
+            return;
+        }
+    };
+
+    for itf in attached.iter_mut() {
+        let current = match query_xdp_attachment(itf.if_index) {
+            Ok(current) => current,
+            Err(e) => {
+                warn!("Failed to query XDP program on interface {}: {}", itf.name, e);
+                continue;
+            }
+        };
+
+        if current.map(|a| a.prog_id) == Some(own_id) {
+            continue;
+        }
+
+        match current {
+            // Left to the program that took it over
+            Some(_) if itf.down => continue,
+            None if itf.down => info!("Interface {} no longer has a foreign XDP program, re-attaching", itf.name),
+            Some(foreign) => warn!(
+                "XDP program on interface {} was replaced by {}, re-attaching",
+                itf.name,
+                describe_program(foreign.prog_id)
+            ),
+            None => warn!("XDP program on interface {} was detached, re-attaching", itf.name),
+        }
+
+        // Our link no longer owns the hook; release it before attaching again.
+        if let Some(link_id) = itf.link_id.take() {
+            if let Err(e) = program.detach(link_id) {
+                debug!("Failed to release stale XDP link on {}: {}", itf.name, e);
+            }
+        }
+
+        if let Some(foreign) = current {
+            if let Err(e) = take_back(itf, foreign, policy) {
+                error!("Cannot re-attach XDP program to interface {}, marking it down: {}", itf.name, e);
+                itf.down = true;
+                continue;
+            }
+        }
+
+        match program.attach_to_if_index(itf.if_index, itf.flags) {
+            Ok(link_id) => {
+                itf.link_id = Some(link_id);
+                itf.down = false;
+                info!("Re-attached XDP program to interface {}", itf.name);
+            }
+            Err(e) => error!("Failed to re-attach XDP program to interface {}: {}", itf.name, e),
+        }
+    }
+}
+
+/// Detaches `foreign`, which took `itf` over, so that our program can be attached again. Fails
+/// without detaching it if the policy refuses to, if it is another agent's program, or if it
+/// took the interface over `MAX_TAKEOVERS` times in a row.
+fn take_back(itf: &mut AttachedInterface, foreign: XdpAttachment, policy: ConflictPolicy) -> Result<()> {
+    if policy == ConflictPolicy::Replace {
+        if program_name(foreign.prog_id).as_deref() == Some(PROGRAM_NAME) {
+            return Err(anyhow!(
+                "it was taken over by {}, which would take it back in turn",
+                describe_program(foreign.prog_id)
+            ));
+        }
+        let now = Instant::now();
+        let in_a_row = itf.last_takeover.is_some_and(|last| now.duration_since(last) < TAKEOVER_WINDOW);
+        itf.takeovers = if in_a_row { itf.takeovers + 1 } else { 1 };
+        itf.last_takeover = Some(now);
+        if itf.takeovers > MAX_TAKEOVERS {
+            return Err(anyhow!(
+                "{} took it over again after {} takeovers in a row",
+                describe_program(foreign.prog_id),
+                MAX_TAKEOVERS
+            ));
+        }
+    }
+    resolve_conflict(&itf.name, itf.if_index, foreign, policy)
+}
+
+fn resolve_conflict(interface: &str, if_index: u32, existing: XdpAttachment, policy: ConflictPolicy) -> Result<()> {
+    let description = describe_program(existing.prog_id);
+    match policy {
+        ConflictPolicy::Replace => {
+            warn!("Interface {} already has XDP program {} attached, replacing it", interface, description);
+            // A program attached through a bpf_link, as aya does on 5.9+, cannot be cleared over netlink
+            match clear_xdp(if_index, mode_flags(existing.mode)) {
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => detach_xdp_link(if_index),
+                result => result,
+            }
+            .map_err(|e| anyhow!("failed to detach {} from {}: {}", description, interface, e))
+        }
+        ConflictPolicy::Refuse => {
+            error!(
+                "Interface {} already has XDP program {} attached; refusing to attach (set XDP_CONFLICT_POLICY=replace to take over)",
+                interface, description
+            );
+            Err(anyhow!("conflicting XDP program {} on {}", description, interface))
+        }
+    }
+}
+
+fn program_name(prog_id: u32) -> Option<String> {
+    loaded_programs()
+        .filter_map(|p| p.ok())
+        .find(|p| p.id() == prog_id)
+        .and_then(|p| p.name_as_str().map(|s| s.to_string()))
+}
+
+fn describe_program(prog_id: u32) -> String {
+    match program_name(prog_id) {
+        Some(name) if name == PROGRAM_NAME => format!("'{}' (id {}, another scale-to-zero agent)", name, prog_id),
+        Some(name) => format!("'{}' (id {})", name, prog_id),
+        None => format!("id {}", prog_id),
+    }
+}
+
+fn mode_flags(mode: u8) -> u32 {
+    match mode {
+        XDP_ATTACHED_DRV => XdpFlags::DRV_MODE.bits(),
+        XDP_ATTACHED_SKB => XdpFlags::SKB_MODE.bits(),
+        XDP_ATTACHED_HW => XdpFlags::HW_MODE.bits(),
+        _ => 0,
+    }
+}
+
+// rtnetlink and bpf(2) plumbing for the bits of XDP attachment that aya does not expose.
+
+const NLMSG_HDR_LEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+const NLA_HDR_LEN: usize = 4;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_TYPE_MASK: u16 = !(NLA_F_NESTED | (1 << 14));
+
+const IFLA_XDP_FD: u16 = 1;
+const IFLA_XDP_ATTACHED: u16 = 2;
+const IFLA_XDP_FLAGS: u16 = 3;
+const IFLA_XDP_PROG_ID: u16 = 4;
+
+const XDP_ATTACHED_DRV: u8 = 1;
+const XDP_ATTACHED_SKB: u8 = 2;
+const XDP_ATTACHED_HW: u8 = 3;
+
+const BPF_LINK_GET_FD_BY_ID: libc::c_long = 30;
+const BPF_LINK_DETACH: libc::c_long = 34;
+const BPF_LINK_TYPE_XDP: u32 = 6;
+
+pub fn query_xdp_attachment(if_index: u32) -> io::Result<Option<XdpAttachment>> {
+    let request = netlink_message(libc::RTM_GETLINK, libc::NLM_F_REQUEST as u16, if_index, &[]);
+    let response = netlink_roundtrip(&request)?;
+
+    let msg_len = (read_u32(&response, 0) as usize).min(response.len());
+    let attrs_start = align4(NLMSG_HDR_LEN + IFINFOMSG_LEN);
+    if msg_len < attrs_start {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "short RTM_NEWLINK message"));
+    }
+
+    let xdp = match parse_attrs(&response[attrs_start..msg_len]).find(|(ty, _)| *ty == libc::IFLA_XDP) {
+        Some((_, payload)) => payload,
+        None => return Ok(None),
+    };
+
+    let mut prog_id = 0;
+    let mut mode = 0;
+    for (ty, payload) in parse_attrs(xdp) {
+        match ty {
+            IFLA_XDP_PROG_ID if payload.len() >= 4 => prog_id = read_u32(payload, 0),
+            IFLA_XDP_ATTACHED if !payload.is_empty() => mode = payload[0],
+            _ => {}
+        }
+    }
+
+    if prog_id == 0 {
+        return Ok(None);
+    }
+    Ok(Some(XdpAttachment { prog_id, mode }))
+}
+
+fn clear_xdp(if_index: u32, flags: u32) -> io::Result<()> {
+    let mut nested = Vec::new();
+    push_attr(&mut nested, IFLA_XDP_FD, &(-1i32).to_ne_bytes());
+    if flags != 0 {
+        push_attr(&mut nested, IFLA_XDP_FLAGS, &flags.to_ne_bytes());
+    }
+    let mut attrs = Vec::new();
+    push_attr(&mut attrs, libc::IFLA_XDP | NLA_F_NESTED, &nested);
+
+    let request = netlink_message(
+        libc::RTM_SETLINK,
+        (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16,
+        if_index,
+        &attrs,
+    );
+    netlink_roundtrip(&request).map(|_| ())
+}
+
+/// Detaches the bpf_link holding the XDP hook of `if_index`, whoever owns it.
+fn detach_xdp_link(if_index: u32) -> io::Result<()> {
+    let link = loaded_links()
+        .filter_map(|link| link.ok())
+        // SAFETY: `xdp` is the member of the union the kernel fills in for XDP links
+        .find(|link| link.type_ == BPF_LINK_TYPE_XDP && unsafe { link.__bindgen_anon_1.xdp.ifindex } == if_index)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no XDP link on the interface"))?;
+
+    let fd = bpf(BPF_LINK_GET_FD_BY_ID, link.id)?;
+    // SAFETY: the kernel just handed us this descriptor
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    bpf(BPF_LINK_DETACH, fd.as_raw_fd() as u32).map(|_| ())
+}
+
+/// Runs a bpf(2) command whose attributes are a single leading `u32`, such as a link id or fd.
+fn bpf(cmd: libc::c_long, arg: u32) -> io::Result<i32> {
+    let attr = [arg, 0, 0, 0];
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr.as_ptr(), std::mem::size_of_val(&attr)) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as i32)
+}
+
+fn netlink_message(msg_type: u16, flags: u16, if_index: u32, attrs: &[u8]) -> Vec<u8> {
+    let len = NLMSG_HDR_LEN + IFINFOMSG_LEN + attrs.len();
+    let mut msg = Vec::with_capacity(len);
+    // nlmsghdr
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&msg_type.to_ne_bytes());
+    msg.extend_from_slice(&flags.to_ne_bytes());
+    msg.extend_from_slice(&1u32.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    // ifinfomsg
+    msg.push(libc::AF_UNSPEC as u8);
+    msg.push(0);
+    msg.extend_from_slice(&0u16.to_ne_bytes());
+    msg.extend_from_slice(&(if_index as i32).to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(attrs);
+    msg
+}
+
+fn netlink_roundtrip(request: &[u8]) -> io::Result<Vec<u8>> {
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let sock = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let sent = unsafe { libc::send(sock.as_raw_fd(), request.as_ptr() as *const _, request.len(), 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buf = vec![0u8; 16 * 1024];
+    let received = unsafe { libc::recv(sock.as_raw_fd(), buf.as_mut_ptr() as *mut _, buf.len(), 0) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buf.truncate(received as usize);
+
+    if buf.len() < NLMSG_HDR_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "short netlink response"));
+    }
+    if read_u16(&buf, 4) == libc::NLMSG_ERROR as u16 {
+        let code = read_u32(&buf, NLMSG_HDR_LEN) as i32;
+        if code != 0 {
+            return Err(io::Error::from_raw_os_error(-code));
+        }
+    }
+    Ok(buf)
+}
+
+fn push_attr(buf: &mut Vec<u8>, attr_type: u16, payload: &[u8]) {
+    let len = NLA_HDR_LEN + payload.len();
+    buf.extend_from_slice(&(len as u16).to_ne_bytes());
+    buf.extend_from_slice(&attr_type.to_ne_bytes());
+    buf.extend_from_slice(payload);
+    buf.resize(align4(buf.len()), 0);
+}
+
+fn parse_attrs(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < NLA_HDR_LEN {
+            return None;
+        }
+        let len = read_u16(buf, 0) as usize;
+        if len < NLA_HDR_LEN || len > buf.len() {
+            return None;
+        }
+        let attr_type = read_u16(buf, 2) & NLA_TYPE_MASK;
+        let payload = &buf[NLA_HDR_LEN..len];
+        buf = &buf[align4(len).min(buf.len())..];
+        Some((attr_type, payload))
+    })
+}
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}